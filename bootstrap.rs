@@ -0,0 +1,70 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use super::config::Config;
+use super::rbac::Role;
+use super::server_connection_impl::ServerConnection;
+use super::table_models::User;
+
+pub const EMAIL_VAR: &str = "UMS_BOOTSTRAP_ADMIN_EMAIL";
+pub const USERNAME_VAR: &str = "UMS_BOOTSTRAP_ADMIN_USERNAME";
+pub const PASSWORD_VAR: &str = "UMS_BOOTSTRAP_ADMIN_PASSWORD";
+
+// Creates the first admin from the environment on startup. Does nothing when the
+// variables are unset or an admin already exists, so they can be left in place.
+pub fn from_env(config: Arc<Config>) -> Result<bool> {
+    let (email, password) = match (std::env::var(EMAIL_VAR), std::env::var(PASSWORD_VAR)) {
+        (Ok(e), Ok(p)) => (e, p),
+        _ => return Ok(false),
+    };
+
+    let mut conn = ServerConnection::new(config)?;
+    if conn.admin_exists()? {
+        return Ok(false);
+    }
+
+    let username = std::env::var(USERNAME_VAR).unwrap_or_else(|_| String::from("Administrator"));
+    conn.bootstrap_admin(admin(username, email, password))?;
+
+    Ok(true)
+}
+
+// `bootstrap-admin <email> [username]`; the password is taken from the environment
+// or, failing that, read from the first line of stdin so it never appears in argv
+pub fn from_args(config: Arc<Config>, args: &[String]) -> Result<()> {
+    let email = args
+        .first()
+        .ok_or_else(|| anyhow!("Usage: bootstrap-admin <email> [username]"))?
+        .to_owned();
+    let username = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| String::from("Administrator"));
+
+    let password = match std::env::var(PASSWORD_VAR) {
+        Ok(p) => p,
+        Err(_) => {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_owned()
+        }
+    };
+
+    ServerConnection::new(config)?.bootstrap_admin(admin(username, email, password))
+}
+
+fn admin(username: String, email: String, password: String) -> User {
+    User {
+        id: 0,
+        username,
+        password,
+        email,
+        phone: String::new(),
+        verified: true,
+        suspended: false,
+        forcenewpw: false,
+        role: Role::Admin,
+    }
+}
@@ -0,0 +1,94 @@
+use actix_web::http::header::{
+    self, CacheControl, CacheDirective, ContentType, ETag, EntityTag, Header, HttpDate, IfMatch,
+    IfNoneMatch, LastModified,
+};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::error::ApiError;
+use super::tokens;
+
+// Strong validator for a representation: the digest of the JSON it is sent as, so any
+// change to what the client would see changes the tag
+pub fn etag<T: Serialize>(value: &T) -> Result<EntityTag, ApiError> {
+    Ok(tag(&serde_json::to_string(value)?))
+}
+
+// `value` as JSON with its ETag and, when known, Last-Modified. A client whose
+// If-None-Match already names the ETag gets an empty 304 instead. no-cache makes
+// browsers revalidate every time rather than guess a freshness lifetime from
+// Last-Modified, which is what keeps the catalog from going stale in the frontend.
+// What a caller sees depends on who they are, so shared caches must not keep it (private)
+// and anything keyed on the URL has to tell callers apart by every credential header.
+pub fn cached_json<T: Serialize>(
+    req: &HttpRequest,
+    value: &T,
+    last_modified: Option<i64>,
+) -> HttpResponse {
+    let body = match serde_json::to_string(value) {
+        Ok(b) => b,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
+    let tag = tag(&body);
+    let fresh = none_match(req, &tag);
+
+    let mut response = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+
+    response
+        .insert_header(ETag(tag))
+        .insert_header(CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]))
+        .insert_header((header::VARY, VARY));
+    if let Some(secs) = last_modified {
+        let date = HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64));
+        response.insert_header(LastModified(date));
+    }
+
+    if fresh {
+        response.finish()
+    } else {
+        response.content_type(ContentType::json()).body(body)
+    }
+}
+
+// Every header a request can be signed in with; see openapi::Credentials
+const VARY: &str = "Authorization, session_token, api_key, login_email, login_password";
+
+// If-Match on a write: the client states which version it edited, and the write is refused
+// when that is no longer `current`. Requests without the header are let through.
+pub fn check_if_match(req: &HttpRequest, current: &EntityTag) -> Result<(), ApiError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(());
+    }
+
+    let matches = match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => true,
+        Ok(IfMatch::Items(tags)) => tags.iter().any(|t| t.strong_eq(current)),
+        Err(_) => false,
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        Err(ApiError::precondition_failed(
+            "This was changed since you last fetched it; reload it and apply your changes again.",
+        ))
+    }
+}
+
+// If-None-Match compares weakly, as RFC 9110 asks
+fn none_match(req: &HttpRequest, current: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(current)),
+        Err(_) => false,
+    }
+}
+
+fn tag(body: &str) -> EntityTag {
+    EntityTag::new_strong(tokens::hash_token(body))
+}
@@ -0,0 +1,499 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use super::lockout::LockoutPolicy;
+use super::oidc::OidcConfig;
+use super::password::PasswordHashing;
+use super::password_policy::PasswordPolicy;
+
+pub const CONFIG_PATH_VAR: &str = "UMS_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+// Everything the server reads at startup. Values come from the TOML file named by
+// UMS_CONFIG (config.toml by default, optional), then from UMS_* environment variables.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub database: DatabaseConfig,
+    pub accounts: AccountsConfig,
+    pub semesters: Vec<SemesterRule>,
+    pub sessions: SessionConfig,
+    pub lockout: LockoutPolicy,
+    pub password_hashing: PasswordHashing,
+    pub password_policy: PasswordPolicy,
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // "*" allows any origin
+    pub cors_allowed_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from("127.0.0.1"),
+            port: 8080,
+            cors_allowed_origins: vec![String::from("*")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    // PEM files; both are watched and reloaded when they change on disk
+    pub cert_path: String,
+    pub key_path: String,
+    pub reload_interval_secs: u64,
+    // when set, plain HTTP on this port answers with a redirect to HTTPS
+    pub redirect_http_port: Option<u16>,
+    // host name used in redirects, required with redirect_http_port; the request's
+    // Host header is not trusted for it
+    pub public_host: Option<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: String::from("cert.pem"),
+            key_path: String::from("key.pem"),
+            reload_interval_secs: 30,
+            redirect_http_port: None,
+            public_host: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // a tracing filter directive such as "info" or "info,ums::sql=debug"; RUST_LOG wins when set
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: String::from("info"),
+            format: LogFormat::Json,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // /metrics is only served when this is set, to "Authorization: Bearer <token>"
+    #[serde(skip_serializing)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub path: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("system.db"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+    // every account, however it was created, must have an email matching this
+    pub email_pattern: String,
+    // credits a student needs before they can graduate
+    pub graduation_credits: i32,
+    pub admin_invitation_ttl_secs: i64,
+    pub default_api_key_ttl_days: i64,
+    pub max_api_key_ttl_days: i64,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            email_pattern: String::from(r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@aubg\.edu$"),
+            graduation_credits: 120,
+            admin_invitation_ttl_secs: 72 * 60 * 60,
+            default_api_key_ttl_days: 90,
+            max_api_key_ttl_days: 365,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub ttl_secs: i64,
+    pub mfa_challenge_ttl_secs: i64,
+    pub impersonation_ttl_secs: i64,
+    pub sso_login_ttl_secs: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 12 * 60 * 60,
+            mfa_challenge_ttl_secs: 5 * 60,
+            impersonation_ttl_secs: 60 * 60,
+            sso_login_ttl_secs: 10 * 60,
+        }
+    }
+}
+
+// Months (1-12, inclusive) during which enrollments count towards a semester
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SemesterRule {
+    pub name: String,
+    pub start_month: u32,
+    pub end_month: u32,
+}
+
+impl SemesterRule {
+    pub fn defaults() -> Vec<SemesterRule> {
+        vec![
+            SemesterRule {
+                name: String::from("Spring"),
+                start_month: 1,
+                end_month: 5,
+            },
+            SemesterRule {
+                name: String::from("Fall"),
+                start_month: 6,
+                end_month: 12,
+            },
+        ]
+    }
+
+    fn contains(&self, month: u32) -> bool {
+        (self.start_month..=self.end_month).contains(&month)
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let path = std::env::var(CONFIG_PATH_VAR).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_owned());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str::<Config>(&contents)
+                .map_err(|e| anyhow!("Invalid configuration file '{}': {}", path, e))?,
+            // only an explicitly named file has to exist
+            Err(_) if std::env::var(CONFIG_PATH_VAR).is_err() => Config::default(),
+            Err(e) => return Err(anyhow!("Failed to read configuration file '{}': {}", path, e)),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(host) = std::env::var("UMS_HOST") {
+            self.server.host = host;
+        }
+        if let Ok(port) = std::env::var("UMS_PORT") {
+            self.server.port = port.parse().map_err(|_| anyhow!("UMS_PORT must be a port number."))?;
+        }
+        if let Ok(origins) = std::env::var("UMS_CORS_ALLOWED_ORIGINS") {
+            self.server.cors_allowed_origins = origins
+                .split(',')
+                .map(|o| o.trim().to_owned())
+                .filter(|o| !o.is_empty())
+                .collect();
+        }
+        if let Ok(enabled) = std::env::var("UMS_TLS_ENABLED") {
+            self.tls.enabled = enabled
+                .parse()
+                .map_err(|_| anyhow!("UMS_TLS_ENABLED must be true or false."))?;
+        }
+        if let Ok(path) = std::env::var("UMS_TLS_CERT") {
+            self.tls.cert_path = path;
+        }
+        if let Ok(path) = std::env::var("UMS_TLS_KEY") {
+            self.tls.key_path = path;
+        }
+        if let Ok(port) = std::env::var("UMS_TLS_REDIRECT_HTTP_PORT") {
+            self.tls.redirect_http_port = Some(
+                port.parse()
+                    .map_err(|_| anyhow!("UMS_TLS_REDIRECT_HTTP_PORT must be a port number."))?,
+            );
+        }
+        if let Ok(host) = std::env::var("UMS_TLS_PUBLIC_HOST") {
+            self.tls.public_host = Some(host);
+        }
+        if let Ok(level) = std::env::var("UMS_LOG_LEVEL") {
+            self.logging.level = level;
+        }
+        if let Ok(format) = std::env::var("UMS_LOG_FORMAT") {
+            self.logging.format = match format.to_lowercase().as_str() {
+                "json" => LogFormat::Json,
+                "text" => LogFormat::Text,
+                _ => return Err(anyhow!("UMS_LOG_FORMAT must be json or text.")),
+            };
+        }
+        if let Some(token) = std::env::var("UMS_METRICS_TOKEN").ok().filter(|t| !t.is_empty()) {
+            self.metrics.token = Some(token);
+        }
+        if let Ok(path) = std::env::var("UMS_DATABASE_PATH") {
+            self.database.path = path;
+        }
+        if let Ok(pattern) = std::env::var("UMS_EMAIL_PATTERN") {
+            self.accounts.email_pattern = pattern;
+        }
+        if let Ok(credits) = std::env::var("UMS_GRADUATION_CREDITS") {
+            self.accounts.graduation_credits = credits
+                .parse()
+                .map_err(|_| anyhow!("UMS_GRADUATION_CREDITS must be a number."))?;
+        }
+
+        self.password_hashing.apply_env()?;
+        self.password_policy.apply_env()?;
+        if let Some(oidc) = OidcConfig::from_env() {
+            self.oidc = Some(oidc);
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.server.port == 0 {
+            return Err(anyhow!("server.port must not be 0."));
+        }
+
+        if self.tls.enabled {
+            for path in [&self.tls.cert_path, &self.tls.key_path] {
+                if !std::path::Path::new(path).is_file() {
+                    return Err(anyhow!("TLS file '{}' does not exist.", path));
+                }
+            }
+            if self.tls.reload_interval_secs == 0 {
+                return Err(anyhow!("tls.reload_interval_secs must be positive."));
+            }
+            if self.tls.redirect_http_port == Some(self.server.port) {
+                return Err(anyhow!("tls.redirect_http_port must differ from server.port."));
+            }
+            let public_host = self.tls.public_host.as_deref().unwrap_or_default();
+            if self.tls.redirect_http_port.is_some() && public_host.trim().is_empty() {
+                return Err(anyhow!("tls.public_host is required with tls.redirect_http_port."));
+            }
+        }
+
+        tracing_subscriber::EnvFilter::try_new(&self.logging.level)
+            .map_err(|e| anyhow!("logging.level is not a valid filter: {}", e))?;
+
+        if self.database.path.trim().is_empty() {
+            return Err(anyhow!("database.path must not be empty."));
+        }
+
+        Regex::new(&self.accounts.email_pattern)
+            .map_err(|e| anyhow!("accounts.email_pattern is not a valid regex: {}", e))?;
+
+        if self.accounts.graduation_credits <= 0 {
+            return Err(anyhow!("accounts.graduation_credits must be positive."));
+        }
+
+        if self.accounts.default_api_key_ttl_days < 1
+            || self.accounts.default_api_key_ttl_days > self.accounts.max_api_key_ttl_days
+        {
+            return Err(anyhow!(
+                "accounts.default_api_key_ttl_days must be between 1 and max_api_key_ttl_days."
+            ));
+        }
+
+        let session_ttls = [
+            self.sessions.ttl_secs,
+            self.sessions.mfa_challenge_ttl_secs,
+            self.sessions.impersonation_ttl_secs,
+            self.sessions.sso_login_ttl_secs,
+            self.accounts.admin_invitation_ttl_secs,
+        ];
+        if session_ttls.iter().any(|ttl| *ttl <= 0) {
+            return Err(anyhow!("Session and invitation lifetimes must be positive."));
+        }
+
+        // every month has to belong to exactly one semester
+        let semesters = self.semester_rules();
+        for month in 1..=12 {
+            match semesters.iter().filter(|s| s.contains(month)).count() {
+                1 => {}
+                0 => return Err(anyhow!("Month {} is not covered by any semester.", month)),
+                _ => return Err(anyhow!("Month {} is covered by more than one semester.", month)),
+            }
+        }
+
+        if self.lockout.free_attempts < 0
+            || self.lockout.max_account_failures <= self.lockout.free_attempts
+            || self.lockout.max_ip_failures <= self.lockout.free_attempts
+        {
+            return Err(anyhow!("lockout failure limits must exceed free_attempts."));
+        }
+
+        self.password_hashing.validate()?;
+        self.password_policy.validate()?;
+
+        Ok(())
+    }
+
+    pub fn semester_rules(&self) -> Vec<SemesterRule> {
+        if self.semesters.is_empty() {
+            SemesterRule::defaults()
+        } else {
+            self.semesters.clone()
+        }
+    }
+
+    // Name of the semester a given month (1-12) falls in
+    pub fn semester_for(&self, month: u32) -> String {
+        self.semester_rules()
+            .into_iter()
+            .find(|s| s.contains(month))
+            .map(|s| s.name)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the variables load() is tested with; environment changes are process-wide, so only
+    // one test here touches them
+    const VARS: [&str; 4] = [CONFIG_PATH_VAR, "UMS_PORT", "UMS_GRADUATION_CREDITS", "UMS_DATABASE_PATH"];
+
+    fn load_with(vars: &[(&str, &str)]) -> Result<Config> {
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+        for (var, value) in vars {
+            std::env::set_var(var, value);
+        }
+        let config = Config::load();
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+        config
+    }
+
+    #[test]
+    fn loads_the_file_then_the_environment() {
+        let path = std::env::temp_dir().join(format!("ums-config-{}.toml", std::process::id()));
+        let path_str = path.to_string_lossy().into_owned();
+        std::fs::write(
+            &path,
+            r#"
+            [server]
+            port = 9000
+
+            [database]
+            path = "from-file.db"
+
+            [accounts]
+            graduation_credits = 90
+            "#,
+        )
+        .unwrap();
+
+        let config = load_with(&[(CONFIG_PATH_VAR, &path_str)]).unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.database.path, "from-file.db");
+        assert_eq!(config.accounts.graduation_credits, 90);
+        // anything the file leaves out keeps its default
+        assert_eq!(config.server.host, ServerConfig::default().host);
+
+        let config = load_with(&[
+            (CONFIG_PATH_VAR, &path_str),
+            ("UMS_PORT", "9001"),
+            ("UMS_GRADUATION_CREDITS", "60"),
+        ])
+        .unwrap();
+        assert_eq!(config.server.port, 9001);
+        assert_eq!(config.accounts.graduation_credits, 60);
+        assert_eq!(config.database.path, "from-file.db");
+
+        let err = load_with(&[(CONFIG_PATH_VAR, &path_str), ("UMS_PORT", "http")]).unwrap_err();
+        assert_eq!(err.to_string(), "UMS_PORT must be a port number.");
+        // overrides are validated like the file
+        let err = load_with(&[(CONFIG_PATH_VAR, &path_str), ("UMS_GRADUATION_CREDITS", "0")]).unwrap_err();
+        assert_eq!(err.to_string(), "accounts.graduation_credits must be positive.");
+
+        std::fs::write(&path, "[server]\nport = \"9000\"\n").unwrap();
+        let err = load_with(&[(CONFIG_PATH_VAR, &path_str)]).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("Invalid configuration file '{}'", path_str)));
+
+        // a file that was asked for by name has to exist
+        std::fs::remove_file(&path).unwrap();
+        let err = load_with(&[(CONFIG_PATH_VAR, &path_str)]).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to read configuration file"));
+    }
+
+    #[test]
+    fn validation_names_what_is_wrong() {
+        assert!(Config::default().validate().is_ok());
+
+        type Change = fn(&mut Config);
+        let cases: Vec<(Change, &str)> = vec![
+            (|c| c.server.port = 0, "server.port must not be 0."),
+            (|c| c.logging.level = String::from("info,=["), "logging.level is not a valid filter"),
+            (|c| c.database.path = String::from(" "), "database.path must not be empty."),
+            (|c| c.accounts.email_pattern = String::from("("), "accounts.email_pattern is not a valid regex"),
+            (|c| c.accounts.graduation_credits = 0, "accounts.graduation_credits must be positive."),
+            (|c| c.accounts.default_api_key_ttl_days = 400, "accounts.default_api_key_ttl_days must be between"),
+            (|c| c.sessions.ttl_secs = 0, "Session and invitation lifetimes must be positive."),
+            (
+                |c| c.semesters = vec![SemesterRule { name: String::from("All"), start_month: 1, end_month: 11 }],
+                "Month 12 is not covered by any semester.",
+            ),
+            (
+                |c| {
+                    c.semesters = SemesterRule::defaults();
+                    c.semesters[0].end_month = 6;
+                },
+                "Month 6 is covered by more than one semester.",
+            ),
+            (|c| c.lockout.max_account_failures = 1, "lockout failure limits must exceed free_attempts."),
+            (|c| c.password_hashing.memory_kib = 1, "Invalid Argon2 parameters"),
+            (|c| c.password_policy.min_length = 0, "Password length limits are inconsistent."),
+            (
+                |c| {
+                    c.tls.enabled = true;
+                    c.tls.cert_path = String::from("/nonexistent/cert.pem");
+                },
+                "TLS file '/nonexistent/cert.pem' does not exist.",
+            ),
+        ];
+
+        for (change, expected) in cases {
+            let mut config = Config::default();
+            change(&mut config);
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.starts_with(expected), "expected {:?}, got {:?}", expected, err);
+        }
+    }
+}
@@ -1,12 +1,15 @@
 #![allow(dead_code)]
 
-use anyhow::Ok;
+use anyhow::anyhow;
 use anyhow::Result;
 use rusqlite::types::ValueRef;
 use std::cell::Cell;
 use std::collections::HashMap;
+use tracing::instrument;
 
+use super::config::Config;
 use super::filter::*;
+use super::rbac::{Permission, Role};
 use super::sqlite_conn::*;
 use super::table_models::*;
 
@@ -25,6 +28,16 @@ pub enum ReceiverType {
     Course(Courses),
     StudentCourse(StudentCourse),
     Department(Departments),
+    LoginAttempt(LoginAttempt),
+    LoginThrottle(LoginThrottle),
+    Session(Session),
+    UserTotp(UserTotp),
+    RecoveryCode(RecoveryCode),
+    RolePermission(RolePermission),
+    AdminInvitation(AdminInvitation),
+    AuditEntry(AuditEntry),
+    ApiKey(ApiKey),
+    OidcLogin(OidcLogin),
 }
 
 pub struct DbDriver {
@@ -33,13 +46,29 @@ pub struct DbDriver {
 
 // Public methods for DbDriver
 impl DbDriver {
-    pub fn init() -> DbDriver {
-        let mut c = DatabaseConnection::new().expect("Could not establish connection to database.");
-        c.create_tables().expect("Could not create tables.");
+    // Creates, migrates and configures the database; run once at startup, before
+    // requests open their connections with init()
+    pub fn prepare(config: &Config) -> Result<()> {
+        let mut c = DatabaseConnection::new(&config.database.path)
+            .map_err(|e| anyhow!("Could not establish connection to database: {}", e))?;
+        c.create_tables()
+            .map_err(|e| anyhow!("Could not create tables: {}", e))?;
+        c.migrate()
+            .map_err(|e| anyhow!("Could not migrate database: {}", e))?;
+        c.apply_settings(config.accounts.graduation_credits)
+            .map_err(|e| anyhow!("Could not apply database settings: {}", e))?;
 
-        DbDriver { c }
+        Ok(())
+    }
+
+    pub fn init(config: &Config) -> Result<DbDriver> {
+        let c = DatabaseConnection::new(&config.database.path)
+            .map_err(|e| anyhow!("Could not establish connection to database: {}", e))?;
+
+        Ok(DbDriver { c })
     }
 
+    #[instrument(level = "debug", skip(self, filters, join_mode), fields(table = %table), err)]
     pub fn find(
         &self,
         table: Table,
@@ -50,79 +79,146 @@ impl DbDriver {
 
         match table {
             Table::Users => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::Users(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
+                check_filters(&filters, |f| matches!(f, Filter::Users(_)))?;
                 self.find_users(&filters, &join_mode)
             }
 
             Table::StudentAccount => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::StudentAccount(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
+                check_filters(&filters, |f| matches!(f, Filter::StudentAccount(_)))?;
                 self.find_student_accounts(&filters, &join_mode)
             }
 
             Table::TeacherAccount => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::TeacherAccount(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
+                check_filters(&filters, |f| matches!(f, Filter::TeacherAccount(_)))?;
                 self.find_teacher_accounts(&filters, &join_mode)
             }
 
             Table::Courses => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::Courses(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
+                check_filters(&filters, |f| matches!(f, Filter::Courses(_)))?;
                 self.find_courses(&filters, &join_mode)
             }
 
             Table::StudentCourses => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::StudentCourses(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
+                check_filters(&filters, |f| matches!(f, Filter::StudentCourses(_)))?;
                 self.find_student_courses(&filters, &join_mode)
             }
 
             Table::Departments => {
-                assert_eq!(
-                    filters
-                        .iter()
-                        .map(|f| matches!(f, Filter::Departments(_)))
-                        .collect::<Vec<bool>>(),
-                    filters.iter().map(|_| true).collect::<Vec<bool>>(),
-                    "Invalid filter for table."
-                );
-                self.find_departments(&filters, join_mode)
+                check_filters(&filters, |f| matches!(f, Filter::Departments(_)))?;
+                self.find_departments(&filters, &join_mode)
+            }
+
+            Table::LoginAttempts => {
+                check_filters(&filters, |f| matches!(f, Filter::LoginAttempts(_)))?;
+                self.find_login_attempts(&filters, &join_mode)
+            }
+
+            Table::LoginThrottle => {
+                check_filters(&filters, |f| matches!(f, Filter::LoginThrottle(_)))?;
+                self.find_login_throttles(&filters, &join_mode)
+            }
+
+            Table::Sessions => {
+                check_filters(&filters, |f| matches!(f, Filter::Sessions(_)))?;
+                self.find_sessions(&filters, &join_mode)
+            }
+
+            Table::UserTotp => {
+                check_filters(&filters, |f| matches!(f, Filter::UserTotp(_)))?;
+                self.find_user_totps(&filters, &join_mode)
+            }
+
+            Table::RecoveryCodes => {
+                check_filters(&filters, |f| matches!(f, Filter::RecoveryCodes(_)))?;
+                self.find_recovery_codes(&filters, &join_mode)
+            }
+
+            Table::RolePermissions => {
+                check_filters(&filters, |f| matches!(f, Filter::RolePermissions(_)))?;
+                self.find_role_permissions(&filters, &join_mode)
+            }
+
+            Table::AdminInvitations => {
+                check_filters(&filters, |f| matches!(f, Filter::AdminInvitations(_)))?;
+                self.find_admin_invitations(&filters, &join_mode)
+            }
+
+            Table::AuditLog => {
+                check_filters(&filters, |f| matches!(f, Filter::AuditLog(_)))?;
+                self.find_audit_entries(&filters, &join_mode)
+            }
+
+            Table::ApiKeys => {
+                check_filters(&filters, |f| matches!(f, Filter::ApiKeys(_)))?;
+                self.find_api_keys(&filters, &join_mode)
+            }
+
+            Table::OidcLogins => {
+                check_filters(&filters, |f| matches!(f, Filter::OidcLogins(_)))?;
+                self.find_oidc_logins(&filters, &join_mode)
             }
         }
     }
 
+    // When any of `tables` was last written to; only the catalog tables are tracked
+    #[instrument(level = "debug", skip_all, err)]
+    pub fn last_changed(&self, tables: &[Table]) -> Result<Option<i64>> {
+        let names = tables
+            .iter()
+            .map(|t| match t {
+                Table::Users => Ok("USERS"),
+                Table::TeacherAccount => Ok("TEACHER_ACCOUNT"),
+                Table::Courses => Ok("COURSES"),
+                Table::Departments => Ok("DEPARTMENTS"),
+                _ => Err(anyhow!("Changes to {} are not tracked.", t)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.c.last_changed(&names)
+    }
+
+    // Writes `new` only while the row still holds exactly `old`, the state the caller read
+    // (and its ETag was computed from); false when someone changed or removed it since
+    #[instrument(level = "debug", skip_all, err)]
+    pub fn update_course_if_unchanged(&mut self, old: &Courses, new: &Courses) -> Result<bool> {
+        let updated = self.c.execute(&Statement::new(
+            r#"UPDATE "COURSES" SET "teacher_id" = ?1, "course" = ?2, "course_nr" = ?3, "description" = ?4, "cr_cost" = ?5, "timeslots" = ?6
+               WHERE "id" = ?7 AND "teacher_id" = ?8 AND "course" = ?9 AND "course_nr" = ?10 AND "description" IS ?11 AND "cr_cost" = ?12 AND "timeslots" = ?13"#,
+            vec![
+                new.teacher_id.into(), new.course.clone().into(), new.course_nr.clone().into(),
+                new.description.clone().into(), new.cr_cost.into(), new.timeslots.clone().into(),
+                old.id.into(), old.teacher_id.into(), old.course.clone().into(), old.course_nr.clone().into(),
+                old.description.clone().into(), old.cr_cost.into(), old.timeslots.clone().into(),
+            ],
+        ))?;
+
+        Ok(updated == 1)
+    }
+
+    // Marks an unused, unexpired invitation as used in one statement, so two concurrent
+    // redemptions cannot both get it; false when someone else already did
+    #[instrument(level = "debug", skip_all, err)]
+    pub fn claim_invitation(&mut self, id: i32, now: i64) -> Result<bool> {
+        let claimed = self.c.execute(&Statement::new(
+            r#"UPDATE "ADMIN_INVITATIONS" SET "used_at" = ?2 WHERE "id" = ?1 AND "used_at" = 0 AND "expires_at" > ?2"#,
+            vec![id.into(), now.into()],
+        ))?;
+
+        Ok(claimed == 1)
+    }
+
+    // Hands back a claim whose account could not be created
+    #[instrument(level = "debug", skip_all, err)]
+    pub fn release_invitation(&mut self, id: i32, claimed_at: i64) -> Result<()> {
+        self.c.execute(&Statement::new(
+            r#"UPDATE "ADMIN_INVITATIONS" SET "used_at" = 0 WHERE "id" = ?1 AND "used_at" = ?2 AND "used_by" = 0"#,
+            vec![id.into(), claimed_at.into()],
+        ))?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rows = data.len()), err)]
     pub fn insert(&mut self, data: Vec<ReceiverType>) -> Result<()> {
         for receiver in data.iter() {
             match receiver {
@@ -132,12 +228,23 @@ impl DbDriver {
                 ReceiverType::Course(c) => self.insert_course(c)?,
                 ReceiverType::StudentCourse(s) => self.insert_student_course(s)?,
                 ReceiverType::Department(d) => self.insert_department(d)?,
+                ReceiverType::LoginAttempt(l) => self.insert_login_attempt(l)?,
+                ReceiverType::LoginThrottle(l) => self.insert_login_throttle(l)?,
+                ReceiverType::Session(x) => self.insert_session(x)?,
+                ReceiverType::UserTotp(x) => self.insert_user_totp(x)?,
+                ReceiverType::RecoveryCode(x) => self.insert_recovery_code(x)?,
+                ReceiverType::RolePermission(x) => self.insert_role_permission(x)?,
+                ReceiverType::AdminInvitation(x) => self.insert_admin_invitation(x)?,
+                ReceiverType::AuditEntry(x) => self.insert_audit_entry(x)?,
+                ReceiverType::ApiKey(x) => self.insert_api_key(x)?,
+                ReceiverType::OidcLogin(x) => self.insert_oidc_login(x)?,
             }
         }
 
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all, fields(rows = data.len()), err)]
     pub fn update(&mut self, data: Vec<ReceiverType>) -> Result<()> {
         for receiver in data.iter() {
             match receiver {
@@ -147,12 +254,23 @@ impl DbDriver {
                 ReceiverType::Course(c) => self.update_course(c)?,
                 ReceiverType::StudentCourse(s) => self.update_student_course(s)?,
                 ReceiverType::Department(d) => self.update_department(d)?,
+                ReceiverType::LoginAttempt(l) => self.update_login_attempt(l)?,
+                ReceiverType::LoginThrottle(l) => self.update_login_throttle(l)?,
+                ReceiverType::Session(x) => self.update_session(x)?,
+                ReceiverType::UserTotp(x) => self.update_user_totp(x)?,
+                ReceiverType::RecoveryCode(x) => self.update_recovery_code(x)?,
+                ReceiverType::RolePermission(x) => self.update_role_permission(x)?,
+                ReceiverType::AdminInvitation(x) => self.update_admin_invitation(x)?,
+                ReceiverType::AuditEntry(x) => self.update_audit_entry(x)?,
+                ReceiverType::ApiKey(x) => self.update_api_key(x)?,
+                ReceiverType::OidcLogin(x) => self.update_oidc_login(x)?,
             }
         }
 
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all, fields(rows = data.len()), err)]
     pub fn delete(&mut self, data: Vec<ReceiverType>) -> Result<()> {
         for receiver in data.iter() {
             match receiver {
@@ -162,12 +280,23 @@ impl DbDriver {
                 ReceiverType::Course(c) => self.delete_course(c)?,
                 ReceiverType::StudentCourse(s) => self.delete_student_course(s)?,
                 ReceiverType::Department(d) => self.delete_department(d)?,
+                ReceiverType::LoginAttempt(l) => self.delete_login_attempt(l)?,
+                ReceiverType::LoginThrottle(l) => self.delete_login_throttle(l)?,
+                ReceiverType::Session(x) => self.delete_session(x)?,
+                ReceiverType::UserTotp(x) => self.delete_user_totp(x)?,
+                ReceiverType::RecoveryCode(x) => self.delete_recovery_code(x)?,
+                ReceiverType::RolePermission(x) => self.delete_role_permission(x)?,
+                ReceiverType::AdminInvitation(x) => self.delete_admin_invitation(x)?,
+                ReceiverType::AuditEntry(x) => self.delete_audit_entry(x)?,
+                ReceiverType::ApiKey(x) => self.delete_api_key(x)?,
+                ReceiverType::OidcLogin(x) => self.delete_oidc_login(x)?,
             }
         }
 
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all, err)]
     pub fn join_find(
         &mut self,
         tables: &[Table; 2],
@@ -176,15 +305,12 @@ impl DbDriver {
         assoc: Option<Associativity>,
     ) -> Result<Vec<HashMap<String, String>>> {
         let param = tables[0].join(&tables[1], join);
-        let conditions = filters.iter().map(|f| f.to_sql()).collect::<Vec<String>>();
         let join_mode = assoc.unwrap_or(Associativity::And);
-        let sql = format!(
-            "SELECT * FROM {} WHERE {}",
-            param,
-            conditions.join(&join_mode.to_string())
-        );
+        let filter = where_clause(&filters, &join_mode);
+        let sql = format!("SELECT * FROM {}{}", param, filter.sql);
 
-        let mut stmt = self.c.connection.prepare(&sql).unwrap();
+        let _timer = StatementTimer::start(&sql);
+        let mut stmt = self.c.connection.prepare(&sql)?;
         let mut stmt_cols = Cell::new(
             stmt.column_names()
                 .iter()
@@ -192,11 +318,11 @@ impl DbDriver {
                 .collect::<Vec<String>>(),
         );
 
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(rusqlite::params_from_iter(&filter.params), |row| {
             let mut hm = HashMap::new();
 
             for (i, col) in stmt_cols.get_mut().iter().enumerate() {
-                let value = match row.get_ref(i).unwrap_or_else(|_| ValueRef::Null) {
+                let value = match row.get_ref(i)? {
                     ValueRef::Null => "NULL".to_string(),
                     ValueRef::Integer(i) => i.to_string(),
                     ValueRef::Real(f) => f.to_string(),
@@ -209,9 +335,8 @@ impl DbDriver {
             rusqlite::Result::Ok(hm)
         });
 
-        let res: Vec<HashMap<String, String>> = rows
-            .map(|row| row.map(|x| x.unwrap_or_default()))?
-            .collect();
+        // a row that cannot be read fails the whole lookup rather than coming back empty
+        let res = rows?.collect::<rusqlite::Result<Vec<HashMap<String, String>>>>()?;
 
         Ok(res)
     }
@@ -221,148 +346,254 @@ impl DbDriver {
 impl DbDriver {
     fn delete_user(&mut self, data: &User) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn delete_student_account(&mut self, data: &StudentAccount) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn delete_teacher_account(&mut self, data: &TeacherAccount) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn delete_course(&mut self, data: &Courses) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn delete_student_course(&mut self, data: &StudentCourse) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn delete_department(&mut self, data: &Departments) -> Result<()> {
         let sql = data.to_sql(Action::Delete);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_user(&mut self, data: &User) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_student_account(&mut self, data: &StudentAccount) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_teacher_account(&mut self, data: &TeacherAccount) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_course(&mut self, data: &Courses) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_student_course(&mut self, data: &StudentCourse) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn update_department(&mut self, data: &Departments) -> Result<()> {
         let sql = data.to_sql(Action::Update);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_user(&mut self, data: &User) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_student_account(&mut self, data: &StudentAccount) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_teacher_account(&mut self, data: &TeacherAccount) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_course(&mut self, data: &Courses) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_student_course(&mut self, data: &StudentCourse) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
     fn insert_department(&mut self, data: &Departments) -> Result<()> {
         let sql = data.to_sql(Action::Insert);
-        self.c.connection.execute(&sql, [])?;
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn delete_login_attempt(&mut self, data: &LoginAttempt) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn delete_login_throttle(&mut self, data: &LoginThrottle) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_login_attempt(&mut self, data: &LoginAttempt) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_login_throttle(&mut self, data: &LoginThrottle) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_login_attempt(&mut self, data: &LoginAttempt) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
 
         Ok(())
     }
 
+    fn insert_login_throttle(&mut self, data: &LoginThrottle) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_login_attempts(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM LOGIN_ATTEMPTS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut attempts = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let email: String = row.get(1)?;
+            let ip: String = row.get(2)?;
+            let success: bool = row.get(3)?;
+            let reason: String = row.get(4)?;
+            let created_at: i64 = row.get(5)?;
+
+            attempts.push(ReceiverType::LoginAttempt(LoginAttempt {
+                id,
+                email,
+                ip,
+                success,
+                reason,
+                created_at,
+            }))
+        }
+
+        Ok(attempts)
+    }
+
+    fn find_login_throttles(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM LOGIN_THROTTLE{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut throttles = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let scope: String = row.get(1)?;
+            let key: String = row.get(2)?;
+            let failures: i32 = row.get(3)?;
+            let last_failure: i64 = row.get(4)?;
+            let locked_until: i64 = row.get(5)?;
+
+            throttles.push(ReceiverType::LoginThrottle(LoginThrottle {
+                id,
+                scope,
+                key,
+                failures,
+                last_failure,
+                locked_until,
+            }))
+        }
+
+        Ok(throttles)
+    }
+
     fn find_departments(
         &self,
         filters: &[Filter],
-        join_mode: Associativity,
+        join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            format!("SELECT * FROM DEPARTMENTS")
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!(
-                "SELECT * FROM DEPARTMENTS WHERE {}",
-                conditions.join(&separator)
-            )
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM DEPARTMENTS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
 
         let mut stmt = self.c.connection.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut departments = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -383,19 +614,13 @@ impl DbDriver {
         filters: &[Filter],
         join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            format!("SELECT * FROM STUDENT_COURSES")
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!(
-                "SELECT * FROM STUDENT_COURSES WHERE {}",
-                conditions.join(&separator)
-            )
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM STUDENT_COURSES{}", filter.sql);
+        
+        let _timer = StatementTimer::start(&sql);
         
         let mut stmt = self.c.connection.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut student_courses = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -420,19 +645,13 @@ impl DbDriver {
         filters: &[Filter],
         join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            format!("SELECT * FROM COURSES")
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!(
-                "SELECT * FROM COURSES WHERE {}",
-                conditions.join(&separator)
-            )
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM COURSES{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
 
         let mut stmt = self.c.connection.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut courses = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -463,19 +682,13 @@ impl DbDriver {
         filters: &[Filter],
         join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            format!("SELECT * FROM TEACHER_ACCOUNT")
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!(
-                "SELECT * FROM TEACHER_ACCOUNT WHERE {}",
-                conditions.join(&separator)
-            )
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM TEACHER_ACCOUNT{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
 
         let mut stmt = self.c.connection.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut teacher_accounts = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -498,20 +711,14 @@ impl DbDriver {
         filters: &[Filter],
         join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            "SELECT * FROM STUDENT_ACCOUNT".to_owned()
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!(
-                "SELECT * FROM STUDENT_ACCOUNT WHERE {}",
-                conditions.join(&separator)
-            )
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM STUDENT_ACCOUNT{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
 
         let mut stmt = self.c.connection.prepare(&sql)?;
 
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut student_accounts = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -546,16 +753,13 @@ impl DbDriver {
         filters: &[Filter],
         join_mode: &Associativity,
     ) -> Result<Vec<ReceiverType>> {
-        let sql = if filters.is_empty() {
-            format!("SELECT * FROM USERS")
-        } else {
-            let conditions: Vec<String> = filters.iter().map(|f| f.to_sql()).collect();
-            let separator = join_mode.to_string();
-            format!("SELECT * FROM USERS WHERE {}", conditions.join(&separator))
-        };
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM USERS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
 
         let mut stmt = self.c.connection.prepare(&sql)?;
-        let mut rows = stmt.query([])?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
         let mut users = Vec::new();
 
         while let Some(row) = rows.next().unwrap_or(None) {
@@ -567,7 +771,7 @@ impl DbDriver {
             let verified: bool = row.get(5)?;
             let suspended: bool = row.get(6)?;
             let forcenewpw: bool = row.get(7)?;
-            let role: String = row.get(8)?;
+            let role: Role = row.get(8)?;
 
             users.push(ReceiverType::User(User {
                 id,
@@ -584,4 +788,475 @@ impl DbDriver {
 
         Ok(users)
     }
+
+    fn delete_session(&mut self, data: &Session) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_session(&mut self, data: &Session) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_session(&mut self, data: &Session) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_sessions(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM SESSIONS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut sessions = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let token_hash: String = row.get(1)?;
+            let user_id: i32 = row.get(2)?;
+            let created_at: i64 = row.get(3)?;
+            let last_seen: i64 = row.get(4)?;
+            let expires_at: i64 = row.get(5)?;
+            let ip: String = row.get(6)?;
+            let user_agent: String = row.get(7)?;
+            let pending_mfa: bool = row.get(8)?;
+            let impersonator_id: i32 = row.get(9)?;
+
+            sessions.push(ReceiverType::Session(Session {
+                id,
+                token_hash,
+                user_id,
+                created_at,
+                last_seen,
+                expires_at,
+                ip,
+                user_agent,
+                pending_mfa,
+                impersonator_id,
+            }))
+        }
+
+        Ok(sessions)
+    }
+
+    fn delete_user_totp(&mut self, data: &UserTotp) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_user_totp(&mut self, data: &UserTotp) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_user_totp(&mut self, data: &UserTotp) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_user_totps(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM USER_TOTP{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut user_totps = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let user_id: i32 = row.get(1)?;
+            let secret: String = row.get(2)?;
+            let enabled: bool = row.get(3)?;
+            let last_used_step: i64 = row.get(4)?;
+            let created_at: i64 = row.get(5)?;
+
+            user_totps.push(ReceiverType::UserTotp(UserTotp {
+                id,
+                user_id,
+                secret,
+                enabled,
+                last_used_step,
+                created_at,
+            }))
+        }
+
+        Ok(user_totps)
+    }
+
+    fn delete_recovery_code(&mut self, data: &RecoveryCode) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_recovery_code(&mut self, data: &RecoveryCode) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_recovery_code(&mut self, data: &RecoveryCode) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_recovery_codes(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM RECOVERY_CODES{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut recovery_codes = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let user_id: i32 = row.get(1)?;
+            let code_hash: String = row.get(2)?;
+            let used: bool = row.get(3)?;
+
+            recovery_codes.push(ReceiverType::RecoveryCode(RecoveryCode {
+                id,
+                user_id,
+                code_hash,
+                used,
+            }))
+        }
+
+        Ok(recovery_codes)
+    }
+
+    fn delete_role_permission(&mut self, data: &RolePermission) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_role_permission(&mut self, data: &RolePermission) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_role_permission(&mut self, data: &RolePermission) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_role_permissions(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM ROLE_PERMISSIONS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut role_permissions = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let role: Role = row.get(1)?;
+            let permission: Permission = row.get(2)?;
+
+            role_permissions.push(ReceiverType::RolePermission(RolePermission {
+                id,
+                role,
+                permission,
+            }))
+        }
+
+        Ok(role_permissions)
+    }
+
+    fn delete_admin_invitation(&mut self, data: &AdminInvitation) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_admin_invitation(&mut self, data: &AdminInvitation) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_admin_invitation(&mut self, data: &AdminInvitation) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_admin_invitations(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM ADMIN_INVITATIONS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut admin_invitations = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let token_hash: String = row.get(1)?;
+            let email: String = row.get(2)?;
+            let created_by: i32 = row.get(3)?;
+            let created_at: i64 = row.get(4)?;
+            let expires_at: i64 = row.get(5)?;
+            let used_at: i64 = row.get(6)?;
+            let used_by: i32 = row.get(7)?;
+
+            admin_invitations.push(ReceiverType::AdminInvitation(AdminInvitation {
+                id,
+                token_hash,
+                email,
+                created_by,
+                created_at,
+                expires_at,
+                used_at,
+                used_by,
+            }))
+        }
+
+        Ok(admin_invitations)
+    }
+
+    fn delete_audit_entry(&mut self, data: &AuditEntry) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_audit_entry(&mut self, data: &AuditEntry) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_audit_entry(&mut self, data: &AuditEntry) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_audit_entries(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM AUDIT_LOG{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut audit_entries = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let actor_id: i32 = row.get(1)?;
+            let action: String = row.get(2)?;
+            let target: String = row.get(3)?;
+            let details: String = row.get(4)?;
+            let created_at: i64 = row.get(5)?;
+            let impersonator_id: i32 = row.get(6)?;
+
+            audit_entries.push(ReceiverType::AuditEntry(AuditEntry {
+                id,
+                actor_id,
+                action,
+                target,
+                details,
+                created_at,
+                impersonator_id,
+            }))
+        }
+
+        Ok(audit_entries)
+    }
+
+    fn delete_api_key(&mut self, data: &ApiKey) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_api_key(&mut self, data: &ApiKey) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_api_key(&mut self, data: &ApiKey) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_api_keys(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM API_KEYS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut api_keys = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let user_id: i32 = row.get(1)?;
+            let name: String = row.get(2)?;
+            let prefix: String = row.get(3)?;
+            let key_hash: String = row.get(4)?;
+            let scopes: String = row.get(5)?;
+            let created_by: i32 = row.get(6)?;
+            let created_at: i64 = row.get(7)?;
+            let expires_at: i64 = row.get(8)?;
+            let last_used: i64 = row.get(9)?;
+            let revoked: bool = row.get(10)?;
+
+            api_keys.push(ReceiverType::ApiKey(ApiKey {
+                id,
+                user_id,
+                name,
+                prefix,
+                key_hash,
+                scopes,
+                created_by,
+                created_at,
+                expires_at,
+                last_used,
+                revoked,
+            }))
+        }
+
+        Ok(api_keys)
+    }
+
+    fn delete_oidc_login(&mut self, data: &OidcLogin) -> Result<()> {
+        let sql = data.to_sql(Action::Delete);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn update_oidc_login(&mut self, data: &OidcLogin) -> Result<()> {
+        let sql = data.to_sql(Action::Update);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn insert_oidc_login(&mut self, data: &OidcLogin) -> Result<()> {
+        let sql = data.to_sql(Action::Insert);
+        self.c.execute(&sql)?;
+
+        Ok(())
+    }
+
+    fn find_oidc_logins(
+        &self,
+        filters: &[Filter],
+        join_mode: &Associativity,
+    ) -> Result<Vec<ReceiverType>> {
+        let filter = where_clause(filters, join_mode);
+        let sql = format!("SELECT * FROM OIDC_LOGINS{}", filter.sql);
+
+        let _timer = StatementTimer::start(&sql);
+
+        let mut stmt = self.c.connection.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&filter.params))?;
+        let mut oidc_logins = Vec::new();
+
+        while let Some(row) = rows.next().unwrap_or(None) {
+            let id: i32 = row.get(0)?;
+            let state_hash: String = row.get(1)?;
+            let nonce: String = row.get(2)?;
+            let code_verifier: String = row.get(3)?;
+            let created_at: i64 = row.get(4)?;
+            let expires_at: i64 = row.get(5)?;
+
+            oidc_logins.push(ReceiverType::OidcLogin(OidcLogin {
+                id,
+                state_hash,
+                nonce,
+                code_verifier,
+                created_at,
+                expires_at,
+            }))
+        }
+
+        Ok(oidc_logins)
+    }
+}
+
+// A filter for another table is a bug in the caller, reported instead of asserted
+fn check_filters(filters: &[Filter], belongs: impl Fn(&Filter) -> bool) -> Result<()> {
+    if filters.iter().all(belongs) {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid filter for table."))
+    }
 }
@@ -0,0 +1,516 @@
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::rbac::{Permission, Role};
+use super::table_models::{
+    Courses, Departments, StudentAccount, StudentCourse, TeacherAccount, User,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 5000;
+const MAX_TIMESLOTS_LENGTH: usize = 200;
+const MAX_PHONE_LENGTH: usize = 32;
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+// Request bodies check their own shape; rules that need the database (unique
+// emails, the password policy, permissions) stay in ServerConnection
+pub trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+#[derive(Default)]
+struct Errors(Vec<FieldError>);
+
+impl Errors {
+    fn add(&mut self, field: &'static str, message: &str) {
+        self.0.push(FieldError {
+            field,
+            message: message.to_owned(),
+        });
+    }
+
+    fn required(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.trim().is_empty() {
+            self.add(field, "Must not be empty.");
+        } else {
+            self.text(field, value, max);
+        }
+    }
+
+    fn text(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.chars().count() > max {
+            self.0.push(FieldError {
+                field,
+                message: format!("Must be at most {} characters long.", max),
+            });
+        }
+    }
+
+    fn optional(&mut self, field: &'static str, value: &Option<String>, max: usize) {
+        if let Some(v) = value {
+            self.required(field, v, max);
+        }
+    }
+
+    fn id(&mut self, field: &'static str, value: i32) {
+        if value <= 0 {
+            self.add(field, "Must be a positive id.");
+        }
+    }
+
+    fn positive(&mut self, field: &'static str, value: i32) {
+        if value <= 0 {
+            self.add(field, "Must be positive.");
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct NewCourse {
+    pub name: String,
+    pub description: String,
+    pub course_nr: String,
+    pub teacher_id: i32,
+    pub cr_cost: i32,
+    pub timeslots: String,
+}
+
+impl Validate for NewCourse {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.required("name", &self.name, MAX_NAME_LENGTH);
+        e.text("description", &self.description, MAX_DESCRIPTION_LENGTH);
+        e.required("course_nr", &self.course_nr, MAX_NAME_LENGTH);
+        e.id("teacher_id", self.teacher_id);
+        e.positive("cr_cost", self.cr_cost);
+        e.required("timeslots", &self.timeslots, MAX_TIMESLOTS_LENGTH);
+        e.0
+    }
+}
+
+impl NewCourse {
+    pub fn into_course(self) -> Courses {
+        Courses {
+            id: 0, // This will be set by the database.
+            teacher_id: self.teacher_id,
+            course: self.name,
+            course_nr: self.course_nr,
+            description: match self.description.trim() {
+                "" => String::from("No description."),
+                _ => self.description,
+            },
+            cr_cost: self.cr_cost,
+            timeslots: self.timeslots,
+        }
+    }
+}
+
+// PATCH body: only the fields present are changed
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct CourseChanges {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub course_nr: Option<String>,
+    pub teacher_id: Option<i32>,
+    pub cr_cost: Option<i32>,
+    pub timeslots: Option<String>,
+}
+
+impl Validate for CourseChanges {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.optional("name", &self.name, MAX_NAME_LENGTH);
+        if let Some(d) = &self.description {
+            e.text("description", d, MAX_DESCRIPTION_LENGTH);
+        }
+        e.optional("course_nr", &self.course_nr, MAX_NAME_LENGTH);
+        if let Some(t) = self.teacher_id {
+            e.id("teacher_id", t);
+        }
+        if let Some(c) = self.cr_cost {
+            e.positive("cr_cost", c);
+        }
+        e.optional("timeslots", &self.timeslots, MAX_TIMESLOTS_LENGTH);
+        e.0
+    }
+}
+
+impl CourseChanges {
+    pub fn apply(self, course: &mut Courses) {
+        if let Some(name) = self.name {
+            course.course = name;
+        }
+        if let Some(description) = self.description {
+            course.description = description;
+        }
+        if let Some(course_nr) = self.course_nr {
+            course.course_nr = course_nr;
+        }
+        if let Some(teacher_id) = self.teacher_id {
+            course.teacher_id = teacher_id;
+        }
+        if let Some(cr_cost) = self.cr_cost {
+            course.cr_cost = cr_cost;
+        }
+        if let Some(timeslots) = self.timeslots {
+            course.timeslots = timeslots;
+        }
+    }
+}
+
+// What an admin may change on any account
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserChanges {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub verified: Option<bool>,
+    pub suspended: Option<bool>,
+    pub forcenewpw: Option<bool>,
+    pub role: Option<Role>,
+}
+
+impl Validate for UserChanges {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.optional("username", &self.username, MAX_NAME_LENGTH);
+        e.optional("password", &self.password, MAX_PASSWORD_LENGTH);
+        e.optional("email", &self.email, MAX_NAME_LENGTH);
+        if let Some(p) = &self.phone {
+            e.text("phone", p, MAX_PHONE_LENGTH);
+        }
+        e.0
+    }
+}
+
+impl UserChanges {
+    // The password is left empty unless a new one was given, which keeps the stored hash
+    pub fn apply(self, user: &mut User) {
+        user.password = self.password.unwrap_or_default();
+        if let Some(username) = self.username {
+            user.username = username;
+        }
+        if let Some(email) = self.email {
+            user.email = email;
+        }
+        if let Some(phone) = self.phone {
+            user.phone = phone;
+        }
+        if let Some(verified) = self.verified {
+            user.verified = verified;
+        }
+        if let Some(suspended) = self.suspended {
+            user.suspended = suspended;
+        }
+        if let Some(forcenewpw) = self.forcenewpw {
+            user.forcenewpw = forcenewpw;
+        }
+        if let Some(role) = self.role {
+            user.role = role;
+        }
+    }
+}
+
+// What users may change on their own account
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountChanges {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+}
+
+impl Validate for AccountChanges {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.optional("username", &self.username, MAX_NAME_LENGTH);
+        e.optional("password", &self.password, MAX_PASSWORD_LENGTH);
+        e.optional("email", &self.email, MAX_NAME_LENGTH);
+        if let Some(p) = &self.phone {
+            e.text("phone", p, MAX_PHONE_LENGTH);
+        }
+        e.0
+    }
+}
+
+impl AccountChanges {
+    pub fn apply(self, user: &mut User) {
+        user.password = match self.password {
+            Some(p) => {
+                user.forcenewpw = false;
+                p
+            }
+            None => String::new(),
+        };
+        if let Some(username) = self.username {
+            user.username = username;
+        }
+        if let Some(email) = self.email {
+            user.email = email;
+        }
+        if let Some(phone) = self.phone {
+            user.phone = phone;
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub email: String,
+    pub phone: String,
+}
+
+impl Validate for Registration {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.required("username", &self.username, MAX_NAME_LENGTH);
+        e.required("password", &self.password, MAX_PASSWORD_LENGTH);
+        e.required("email", &self.email, MAX_NAME_LENGTH);
+        e.text("phone", &self.phone, MAX_PHONE_LENGTH);
+        e.0
+    }
+}
+
+impl Registration {
+    pub fn into_user(self, role: Role, verified: bool) -> User {
+        User {
+            id: 0,
+            username: self.username,
+            password: self.password,
+            email: self.email,
+            phone: self.phone,
+            verified,
+            suspended: false,
+            forcenewpw: false,
+            role,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct NewDepartment {
+    pub name: String,
+}
+
+impl Validate for NewDepartment {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.required("name", &self.name, MAX_NAME_LENGTH);
+        e.0
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct DepartmentMember {
+    pub teacher_id: i32,
+}
+
+impl Validate for DepartmentMember {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.id("teacher_id", self.teacher_id);
+        e.0
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Grade {
+    pub grade: f32,
+}
+
+impl Validate for Grade {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        // grade points on the same 4.0 scale the CGPA is averaged on
+        if !self.grade.is_finite() || !(0.0..=4.0).contains(&self.grade) {
+            e.add("grade", "Must be between 0.0 and 4.0.");
+        }
+        e.0
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminInvitationRequest {
+    // binds the invitation to a single address
+    pub email: Option<String>,
+}
+
+impl Validate for AdminInvitationRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.optional("email", &self.email, MAX_NAME_LENGTH);
+        e.0
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceAccountRequest {
+    pub username: String,
+    pub email: String,
+}
+
+impl Validate for ServiceAccountRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.required("username", &self.username, MAX_NAME_LENGTH);
+        e.required("email", &self.email, MAX_NAME_LENGTH);
+        e.0
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    // e.g. ["stats.read", "course.update"]
+    pub scopes: Vec<Permission>,
+    pub expires_in_days: Option<i64>,
+}
+
+impl Validate for ApiKeyRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut e = Errors::default();
+        e.required("name", &self.name, MAX_NAME_LENGTH);
+        if self.scopes.is_empty() {
+            e.add("scopes", "Must list at least one permission.");
+        }
+        if self.expires_in_days.is_some_and(|d| d < 1) {
+            e.add("expires_in_days", "Must be at least 1.");
+        }
+        e.0
+    }
+}
+
+// What anyone may see of any user
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PublicUser {
+    pub id: i32,
+    pub username: String,
+    pub role: Role,
+}
+
+// What the user themself, and whoever may read every account (user.read), see. The
+// password hash is never part of any view.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PrivateUser {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub phone: String,
+    pub verified: bool,
+    pub suspended: bool,
+    pub forcenewpw: bool,
+    pub role: Role,
+}
+
+// Who is looking at users, as far as their fields go
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Viewer {
+    pub id: Option<i32>,
+    // holds user.read, through their role or an API key scope
+    pub reads_users: bool,
+}
+
+// Users are never serialized as the User model; every response goes through this
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum UserView {
+    Public(PublicUser),
+    Private(PrivateUser),
+}
+
+impl UserView {
+    // Other users' contact details and account state are only for user.read
+    pub fn new(user: &User, viewer: Viewer) -> Self {
+        if viewer.reads_users || viewer.id == Some(user.id) {
+            return Self::Private(PrivateUser::own(user));
+        }
+
+        Self::Public(PublicUser {
+            id: user.id,
+            username: user.username.clone(),
+            role: user.role,
+        })
+    }
+
+    pub fn list(users: &[User], viewer: Viewer) -> Vec<Self> {
+        users.iter().map(|u| Self::new(u, viewer)).collect()
+    }
+}
+
+impl PrivateUser {
+    pub fn own(user: &User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            phone: user.phone.clone(),
+            verified: user.verified,
+            suspended: user.suspended,
+            forcenewpw: user.forcenewpw,
+            role: user.role,
+        }
+    }
+}
+
+// A course as the catalog serves it. `teacher` and `department` are only embedded when
+// asked for with ?include=, and left out when the course has none.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CourseView {
+    #[serde(flatten)]
+    pub course: Courses,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub teacher: Option<UserView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub department: Option<Departments>,
+}
+
+// GET /account. Students also get their enrollments, standing and enrolled courses;
+// teachers their teacher account.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccountView {
+    pub user: PrivateUser,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enrollments: Option<Vec<StudentCourse>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standing: Option<StudentAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub courses: Option<Vec<Courses>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub teacher_account: Option<TeacherAccount>,
+    // id of the admin viewing this account through an impersonation session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonated_by: Option<i32>,
+}
+
+impl AccountView {
+    pub fn new(user: &User) -> Self {
+        Self {
+            user: PrivateUser::own(user),
+            enrollments: None,
+            standing: None,
+            courses: None,
+            teacher_account: None,
+            impersonated_by: None,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+
+use super::dto::FieldError;
+
+// What went wrong, in terms a client can act on. ServerConnection and DbDriver keep
+// returning anyhow::Result so `?` chains stay as they are; the errors they raise on
+// purpose are ApiErrors inside it, and the HTTP layer recovers them with From<anyhow::Error>.
+// Anything else is an Internal error whose details are logged, not sent.
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(String),
+    Forbidden(String),
+    Conflict(String),
+    // an If-Match (or similar) precondition did not hold
+    PreconditionFailed(String),
+    // 422 when `fields` names what was wrong with a well-formed body, 400 otherwise
+    Validation {
+        message: String,
+        fields: Vec<FieldError>,
+    },
+    Unauthenticated(String),
+    // how long the client should wait, sent as Retry-After
+    TooManyRequests {
+        message: String,
+        retry_after_secs: i64,
+    },
+    // a service we depend on, such as the identity provider, failed
+    Upstream(String),
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound(message.into())
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::Forbidden(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::Conflict(message.into())
+    }
+
+    pub fn precondition_failed(message: impl Into<String>) -> Self {
+        Self::PreconditionFailed(message.into())
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::Validation {
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        Self::Validation {
+            message: String::from("Invalid request body."),
+            fields,
+        }
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::Unauthenticated(message.into())
+    }
+
+    pub fn too_many_requests(message: impl Into<String>, retry_after_secs: i64) -> Self {
+        Self::TooManyRequests {
+            message: message.into(),
+            retry_after_secs,
+        }
+    }
+
+    pub fn upstream(message: impl Into<String>) -> Self {
+        Self::Upstream(message.into())
+    }
+
+    // Stable, machine-readable name of the variant; the message may change wording
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::Forbidden(_) => "forbidden",
+            Self::Conflict(_) => "conflict",
+            Self::PreconditionFailed(_) => "precondition_failed",
+            Self::Validation { .. } => "validation_failed",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::TooManyRequests { .. } => "too_many_requests",
+            Self::Upstream(_) => "upstream_failed",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(m)
+            | Self::Forbidden(m)
+            | Self::Conflict(m)
+            | Self::PreconditionFailed(m)
+            | Self::Validation { message: m, .. }
+            | Self::Unauthenticated(m)
+            | Self::TooManyRequests { message: m, .. }
+            | Self::Upstream(m) => write!(f, "{}", m),
+            Self::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<ApiError>() {
+            Ok(api) => return api,
+            Err(e) => e,
+        };
+
+        match e.downcast_ref::<rusqlite::Error>() {
+            Some(rusqlite::Error::SqliteFailure(f, _))
+                if f.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Self::conflict("This conflicts with an existing record.")
+            }
+            _ => Self::Internal(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+// {"error": "<message>", "code": "<code>"}, plus "fields" for validation failures
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::Validation { fields, .. } if !fields.is_empty() => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Validation { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let body = match self {
+            Self::Validation { message, fields } if !fields.is_empty() => {
+                json!({"error": message, "code": self.code(), "fields": fields})
+            }
+            Self::Internal(e) => {
+                tracing::error!(error = %e, "internal error");
+                json!({"error": "Internal server error.", "code": self.code()})
+            }
+            _ => json!({"error": self.to_string(), "code": self.code()}),
+        };
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::TooManyRequests { retry_after_secs, .. } = self {
+            response.insert_header(("Retry-After", (*retry_after_secs).max(1).to_string()));
+        }
+
+        response.json(body)
+    }
+}
@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
 use super::db_driver::Join;
+use super::rbac::{Permission, Role};
+use super::table_models::Statement;
 use std::fmt::{Display, Formatter};
 
+// A condition with ? placeholders and the values bound to them, in order
 pub trait Filterable {
-    fn to_sql(&self) -> String;
+    fn to_sql(&self) -> Statement;
 }
 
 pub enum Associativity {
@@ -28,6 +31,16 @@ pub enum Filter {
     Courses(CoursesFilter),
     Departments(DepartmentsFilter),
     StudentCourses(StudentCoursesFilter),
+    LoginAttempts(LoginAttemptsFilter),
+    LoginThrottle(LoginThrottleFilter),
+    Sessions(SessionsFilter),
+    UserTotp(UserTotpFilter),
+    RecoveryCodes(RecoveryCodesFilter),
+    RolePermissions(RolePermissionsFilter),
+    AdminInvitations(AdminInvitationsFilter),
+    AuditLog(AuditLogFilter),
+    ApiKeys(ApiKeysFilter),
+    OidcLogins(OidcLoginsFilter),
 }
 
 impl Display for Filter {
@@ -39,6 +52,16 @@ impl Display for Filter {
             Filter::Courses(_) => write!(f, "COURSES"),
             Filter::Departments(_) => write!(f, "DEPARTMENTS"),
             Filter::StudentCourses(_) => write!(f, "STUDENT_COURSES"),
+            Filter::LoginAttempts(_) => write!(f, "LOGIN_ATTEMPTS"),
+            Filter::LoginThrottle(_) => write!(f, "LOGIN_THROTTLE"),
+            Filter::Sessions(_) => write!(f, "SESSIONS"),
+            Filter::UserTotp(_) => write!(f, "USER_TOTP"),
+            Filter::RecoveryCodes(_) => write!(f, "RECOVERY_CODES"),
+            Filter::RolePermissions(_) => write!(f, "ROLE_PERMISSIONS"),
+            Filter::AdminInvitations(_) => write!(f, "ADMIN_INVITATIONS"),
+            Filter::AuditLog(_) => write!(f, "AUDIT_LOG"),
+            Filter::ApiKeys(_) => write!(f, "API_KEYS"),
+            Filter::OidcLogins(_) => write!(f, "OIDC_LOGINS"),
         }
     }
 }
@@ -55,7 +78,7 @@ impl Display for Join {
 }
 
 impl Filterable for Filter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
             Filter::Users(x) => x.to_sql(),
             Filter::StudentAccount(x) => x.to_sql(),
@@ -63,6 +86,16 @@ impl Filterable for Filter {
             Filter::Courses(x) => x.to_sql(),
             Filter::Departments(x) => x.to_sql(),
             Filter::StudentCourses(x) => x.to_sql(),
+            Filter::LoginAttempts(x) => x.to_sql(),
+            Filter::LoginThrottle(x) => x.to_sql(),
+            Filter::Sessions(x) => x.to_sql(),
+            Filter::UserTotp(x) => x.to_sql(),
+            Filter::RecoveryCodes(x) => x.to_sql(),
+            Filter::RolePermissions(x) => x.to_sql(),
+            Filter::AdminInvitations(x) => x.to_sql(),
+            Filter::AuditLog(x) => x.to_sql(),
+            Filter::ApiKeys(x) => x.to_sql(),
+            Filter::OidcLogins(x) => x.to_sql(),
         }
     }
 }
@@ -71,7 +104,7 @@ pub enum UsersFilter {
     Username(String),
     Email(String),
     Phone(String),
-    Role(String),
+    Role(Role),
     Verified(bool),
     Suspended(bool),
     Forcenewpw(bool),
@@ -80,17 +113,25 @@ pub enum UsersFilter {
 }
 
 impl Filterable for UsersFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
-            UsersFilter::Username(username) => format!("username = '{}'", username),
-            UsersFilter::Email(email) => format!("email = '{}'", email),
-            UsersFilter::Phone(phone) => format!("phone = '{}'", phone),
-            UsersFilter::Role(role) => format!("role = '{}'", role),
-            UsersFilter::Verified(verified) => format!("verified = {}", verified),
-            UsersFilter::Suspended(suspended) => format!("suspended = {}", suspended),
-            UsersFilter::Forcenewpw(forcenewpw) => format!("forcenewpw = {}", forcenewpw),
-            UsersFilter::Id(id) => format!("id = {}", id),
-            UsersFilter::All => String::from("1 = 1"), // some condition that's always true
+            UsersFilter::Username(username) => {
+                Statement::new("username = ?", vec![username.clone().into()])
+            }
+            UsersFilter::Email(email) => Statement::new("email = ?", vec![email.clone().into()]),
+            UsersFilter::Phone(phone) => Statement::new("phone = ?", vec![phone.clone().into()]),
+            UsersFilter::Role(role) => Statement::new("role = ?", vec![role.to_string().into()]),
+            UsersFilter::Verified(verified) => {
+                Statement::new("verified = ?", vec![(*verified).into()])
+            }
+            UsersFilter::Suspended(suspended) => {
+                Statement::new("suspended = ?", vec![(*suspended).into()])
+            }
+            UsersFilter::Forcenewpw(forcenewpw) => {
+                Statement::new("forcenewpw = ?", vec![(*forcenewpw).into()])
+            }
+            UsersFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            UsersFilter::All => Statement::new("1 = 1", vec![]), // some condition that's always true
         }
     }
 }
@@ -109,22 +150,32 @@ pub enum StudentAccountFilter {
 }
 
 impl Filterable for StudentAccountFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
-            StudentAccountFilter::StudentId(student_id) => format!("student_id = {}", student_id),
-            StudentAccountFilter::AdvisorId(advisor_id) => format!("advisor_id = {}", advisor_id),
+            StudentAccountFilter::StudentId(student_id) => {
+                Statement::new("student_id = ?", vec![(*student_id).into()])
+            }
+            StudentAccountFilter::AdvisorId(advisor_id) => {
+                Statement::new("advisor_id = ?", vec![(*advisor_id).into()])
+            }
             StudentAccountFilter::Discipline(discipline) => {
-                format!("discipline = '{}'", discipline)
+                Statement::new("discipline = ?", vec![discipline.clone().into()])
             }
             StudentAccountFilter::Enrollment(enrollment) => {
-                format!("enrollment = '{}'", enrollment)
+                Statement::new("enrollment = ?", vec![enrollment.clone().into()])
+            }
+            StudentAccountFilter::Cgpa(cgpa) => Statement::new("cgpa = ?", vec![(*cgpa).into()]),
+            StudentAccountFilter::CanGrad(can_grad) => {
+                Statement::new("can_grad = ?", vec![(*can_grad).into()])
+            }
+            StudentAccountFilter::CurCredit(cur_credit) => {
+                Statement::new("cur_credit = ?", vec![(*cur_credit).into()])
+            }
+            StudentAccountFilter::CumCredit(cum_credit) => {
+                Statement::new("cum_credit = ?", vec![(*cum_credit).into()])
             }
-            StudentAccountFilter::Cgpa(cgpa) => format!("cgpa = {}", cgpa),
-            StudentAccountFilter::CanGrad(can_grad) => format!("can_grad = {}", can_grad),
-            StudentAccountFilter::CurCredit(cur_credit) => format!("cur_credit = {}", cur_credit),
-            StudentAccountFilter::CumCredit(cum_credit) => format!("cum_credit = {}", cum_credit),
-            StudentAccountFilter::Id(id) => format!("id = {}", id),
-            StudentAccountFilter::All => String::from("1 = 1"), // always true
+            StudentAccountFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            StudentAccountFilter::All => Statement::new("1 = 1", vec![]), // always true
         }
     }
 }
@@ -138,13 +189,19 @@ pub enum TeacherAccountFilter {
 }
 
 impl Filterable for TeacherAccountFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
-            TeacherAccountFilter::TeacherId(teacher_id) => format!("teacher_id = {}", teacher_id),
-            TeacherAccountFilter::DeptId(dept_id) => format!("dept_id = {}", dept_id),
-            TeacherAccountFilter::Dept(dept) => format!("dept = '{}'", dept),
-            TeacherAccountFilter::Id(id) => format!("id = {}", id),
-            TeacherAccountFilter::All => String::from("1 = 1"), // always true
+            TeacherAccountFilter::TeacherId(teacher_id) => {
+                Statement::new("teacher_id = ?", vec![(*teacher_id).into()])
+            }
+            TeacherAccountFilter::DeptId(dept_id) => {
+                Statement::new("dept_id = ?", vec![(*dept_id).into()])
+            }
+            TeacherAccountFilter::Dept(dept) => {
+                Statement::new("dept = ?", vec![dept.clone().into()])
+            }
+            TeacherAccountFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            TeacherAccountFilter::All => Statement::new("1 = 1", vec![]), // always true
         }
     }
 }
@@ -160,15 +217,25 @@ pub enum CoursesFilter {
 }
 
 impl Filterable for CoursesFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
-            CoursesFilter::Id(id) => format!("id = {}", id),
-            CoursesFilter::TeacherId(teacher_id) => format!("teacher_id = {}", teacher_id),
-            CoursesFilter::Course(course) => format!("course = '{}'", course),
-            CoursesFilter::CrCost(cr_cost) => format!("cr_cost = {}", cr_cost),
-            CoursesFilter::CreatedAt(created_at) => format!("created_at = '{}'", created_at),
-            CoursesFilter::UpdatedAt(updated_at) => format!("updated_at = '{}'", updated_at),
-            CoursesFilter::All => String::from("1 = 1"), // always true
+            CoursesFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            CoursesFilter::TeacherId(teacher_id) => {
+                Statement::new("teacher_id = ?", vec![(*teacher_id).into()])
+            }
+            CoursesFilter::Course(course) => {
+                Statement::new("course = ?", vec![course.clone().into()])
+            }
+            CoursesFilter::CrCost(cr_cost) => {
+                Statement::new("cr_cost = ?", vec![(*cr_cost).into()])
+            }
+            CoursesFilter::CreatedAt(created_at) => {
+                Statement::new("created_at = ?", vec![created_at.clone().into()])
+            }
+            CoursesFilter::UpdatedAt(updated_at) => {
+                Statement::new("updated_at = ?", vec![updated_at.clone().into()])
+            }
+            CoursesFilter::All => Statement::new("1 = 1", vec![]), // always true
         }
     }
 }
@@ -181,12 +248,14 @@ pub enum DepartmentsFilter {
 }
 
 impl Filterable for DepartmentsFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
         match self {
-            DepartmentsFilter::DeptHead(dept_head) => format!("dept_head = {}", dept_head),
-            DepartmentsFilter::Name(name) => format!("name = '{}'", name),
-            DepartmentsFilter::Id(id) => format!("id = {}", id),
-            DepartmentsFilter::All => String::from("1 = 1"), // always true
+            DepartmentsFilter::DeptHead(dept_head) => {
+                Statement::new("dept_head = ?", vec![(*dept_head).into()])
+            }
+            DepartmentsFilter::Name(name) => Statement::new("name = ?", vec![name.clone().into()]),
+            DepartmentsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            DepartmentsFilter::All => Statement::new("1 = 1", vec![]), // always true
         }
     }
 }
@@ -201,14 +270,283 @@ pub enum StudentCoursesFilter {
 }
 
 impl Filterable for StudentCoursesFilter {
-    fn to_sql(&self) -> String {
+    fn to_sql(&self) -> Statement {
+        match self {
+            StudentCoursesFilter::StudentId(student_id) => {
+                Statement::new("student_id = ?", vec![(*student_id).into()])
+            }
+            StudentCoursesFilter::CourseId(course_id) => {
+                Statement::new("course_id = ?", vec![(*course_id).into()])
+            }
+            StudentCoursesFilter::Grade(grade) => {
+                Statement::new("grade = ?", vec![(*grade).into()])
+            }
+            StudentCoursesFilter::Semester(semester) => {
+                Statement::new("semester = ?", vec![semester.clone().into()])
+            }
+            StudentCoursesFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            StudentCoursesFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum LoginAttemptsFilter {
+    Email(String),
+    Ip(String),
+    Success(bool),
+    Since(i64),
+    Id(i32),
+    All,
+}
+
+impl Filterable for LoginAttemptsFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            LoginAttemptsFilter::Email(email) => {
+                Statement::new("email = ?", vec![email.clone().into()])
+            }
+            LoginAttemptsFilter::Ip(ip) => Statement::new("ip = ?", vec![ip.clone().into()]),
+            LoginAttemptsFilter::Success(success) => {
+                Statement::new("success = ?", vec![(*success).into()])
+            }
+            LoginAttemptsFilter::Since(since) => {
+                Statement::new("created_at >= ?", vec![(*since).into()])
+            }
+            LoginAttemptsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            LoginAttemptsFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum LoginThrottleFilter {
+    Scope(String),
+    Key(String),
+    LockedAfter(i64),
+    Id(i32),
+    All,
+}
+
+impl Filterable for LoginThrottleFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            LoginThrottleFilter::Scope(scope) => {
+                Statement::new("scope = ?", vec![scope.clone().into()])
+            }
+            LoginThrottleFilter::Key(key) => {
+                Statement::new(r#""key" = ?"#, vec![key.clone().into()])
+            }
+            LoginThrottleFilter::LockedAfter(now) => {
+                Statement::new("locked_until > ?", vec![(*now).into()])
+            }
+            LoginThrottleFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            LoginThrottleFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum SessionsFilter {
+    TokenHash(String),
+    UserId(i32),
+    Id(i32),
+    All,
+}
+
+impl Filterable for SessionsFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            SessionsFilter::TokenHash(token_hash) => {
+                Statement::new("token_hash = ?", vec![token_hash.clone().into()])
+            }
+            SessionsFilter::UserId(user_id) => {
+                Statement::new("user_id = ?", vec![(*user_id).into()])
+            }
+            SessionsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            SessionsFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum UserTotpFilter {
+    UserId(i32),
+    Enabled(bool),
+    Id(i32),
+    All,
+}
+
+impl Filterable for UserTotpFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            UserTotpFilter::UserId(user_id) => {
+                Statement::new("user_id = ?", vec![(*user_id).into()])
+            }
+            UserTotpFilter::Enabled(enabled) => {
+                Statement::new("enabled = ?", vec![(*enabled).into()])
+            }
+            UserTotpFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            UserTotpFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum RecoveryCodesFilter {
+    UserId(i32),
+    Used(bool),
+    Id(i32),
+    All,
+}
+
+impl Filterable for RecoveryCodesFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            RecoveryCodesFilter::UserId(user_id) => {
+                Statement::new("user_id = ?", vec![(*user_id).into()])
+            }
+            RecoveryCodesFilter::Used(used) => Statement::new("used = ?", vec![(*used).into()]),
+            RecoveryCodesFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            RecoveryCodesFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum RolePermissionsFilter {
+    Role(Role),
+    Permission(Permission),
+    Id(i32),
+    All,
+}
+
+impl Filterable for RolePermissionsFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            RolePermissionsFilter::Role(role) => {
+                Statement::new("role = ?", vec![role.to_string().into()])
+            }
+            RolePermissionsFilter::Permission(permission) => {
+                Statement::new("permission = ?", vec![permission.to_string().into()])
+            }
+            RolePermissionsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            RolePermissionsFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum AdminInvitationsFilter {
+    TokenHash(String),
+    CreatedBy(i32),
+    Id(i32),
+    All,
+}
+
+impl Filterable for AdminInvitationsFilter {
+    fn to_sql(&self) -> Statement {
         match self {
-            StudentCoursesFilter::StudentId(student_id) => format!("student_id = {}", student_id),
-            StudentCoursesFilter::CourseId(course_id) => format!("course_id = {}", course_id),
-            StudentCoursesFilter::Grade(grade) => format!("grade = {}", grade),
-            StudentCoursesFilter::Semester(semester) => format!("semester = '{}'", semester),
-            StudentCoursesFilter::Id(id) => format!("id = {}", id),
-            StudentCoursesFilter::All => String::from("1 = 1"), // always true
+            AdminInvitationsFilter::TokenHash(token_hash) => {
+                Statement::new("token_hash = ?", vec![token_hash.clone().into()])
+            }
+            AdminInvitationsFilter::CreatedBy(created_by) => {
+                Statement::new("created_by = ?", vec![(*created_by).into()])
+            }
+            AdminInvitationsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            AdminInvitationsFilter::All => Statement::new("1 = 1", vec![]), // always true
         }
     }
 }
+
+pub enum AuditLogFilter {
+    ActorId(i32),
+    Action(String),
+    Target(String),
+    Since(i64),
+    Impersonated,
+    ImpersonatorId(i32),
+    Id(i32),
+    All,
+}
+
+impl Filterable for AuditLogFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            AuditLogFilter::ActorId(actor_id) => {
+                Statement::new("actor_id = ?", vec![(*actor_id).into()])
+            }
+            AuditLogFilter::Action(action) => {
+                Statement::new("action = ?", vec![action.clone().into()])
+            }
+            AuditLogFilter::Target(target) => {
+                Statement::new("target = ?", vec![target.clone().into()])
+            }
+            AuditLogFilter::Since(since) => {
+                Statement::new("created_at >= ?", vec![(*since).into()])
+            }
+            AuditLogFilter::Impersonated => Statement::new("impersonator_id != 0", vec![]),
+            AuditLogFilter::ImpersonatorId(id) => {
+                Statement::new("impersonator_id = ?", vec![(*id).into()])
+            }
+            AuditLogFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            AuditLogFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum ApiKeysFilter {
+    KeyHash(String),
+    UserId(i32),
+    Revoked(bool),
+    Id(i32),
+    All,
+}
+
+impl Filterable for ApiKeysFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            ApiKeysFilter::KeyHash(key_hash) => {
+                Statement::new("key_hash = ?", vec![key_hash.clone().into()])
+            }
+            ApiKeysFilter::UserId(user_id) => {
+                Statement::new("user_id = ?", vec![(*user_id).into()])
+            }
+            ApiKeysFilter::Revoked(revoked) => {
+                Statement::new("revoked = ?", vec![(*revoked).into()])
+            }
+            ApiKeysFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            ApiKeysFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+pub enum OidcLoginsFilter {
+    StateHash(String),
+    Id(i32),
+    All,
+}
+
+impl Filterable for OidcLoginsFilter {
+    fn to_sql(&self) -> Statement {
+        match self {
+            OidcLoginsFilter::StateHash(state_hash) => {
+                Statement::new("state_hash = ?", vec![state_hash.clone().into()])
+            }
+            OidcLoginsFilter::Id(id) => Statement::new("id = ?", vec![(*id).into()]),
+            OidcLoginsFilter::All => Statement::new("1 = 1", vec![]), // always true
+        }
+    }
+}
+
+// The conditions joined by `join_mode` as a WHERE clause, or nothing when there are none
+pub fn where_clause(filters: &[Filter], join_mode: &Associativity) -> Statement {
+    if filters.is_empty() {
+        return Statement::new("", vec![]);
+    }
+
+    let conditions: Vec<Statement> = filters.iter().map(|f| f.to_sql()).collect();
+    let sql = conditions
+        .iter()
+        .map(|c| c.sql.as_str())
+        .collect::<Vec<&str>>()
+        .join(&join_mode.to_string());
+
+    Statement::new(
+        &format!(" WHERE {}", sql),
+        conditions.into_iter().flat_map(|c| c.params).collect(),
+    )
+}
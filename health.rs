@@ -0,0 +1,164 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use super::config::Config;
+use super::sqlite_conn::{DatabaseConnection, SCHEMA_VERSION};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    // not attempted because an earlier check it depends on failed
+    Skipped,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+// Runs against a read-only connection, so a missing database is reported instead of
+// being created empty the way DbDriver::prepare would
+pub fn readiness(config: &Config) -> Readiness {
+    let mut checks = Vec::new();
+
+    let conn = DatabaseConnection::open_read_only(&config.database.path);
+    checks.push(check(
+        "database_open",
+        conn.as_ref().map(|_| config.database.path.clone()).map_err(|e| anyhow!("{}", e)),
+    ));
+
+    match &conn {
+        Ok(c) => {
+            checks.push(check("schema_version", schema(c)));
+            checks.push(check("query", query(c)));
+        }
+        Err(_) => {
+            checks.push(skipped("schema_version"));
+            checks.push(skipped("query"));
+        }
+    }
+
+    Readiness {
+        ready: checks.iter().all(|c| c.status == Status::Pass),
+        checks,
+    }
+}
+
+fn schema(conn: &DatabaseConnection) -> Result<String> {
+    let version = conn.schema_version()?;
+    if version != SCHEMA_VERSION {
+        return Err(anyhow!(
+            "database is at version {}, this build expects {}",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    Ok(format!("version {}", version))
+}
+
+fn query(conn: &DatabaseConnection) -> Result<String> {
+    let users: i64 = conn
+        .connection
+        .query_row(r#"SELECT COUNT(*) FROM "USERS""#, [], |row| row.get(0))?;
+
+    Ok(format!("{} users", users))
+}
+
+fn check(name: &'static str, result: Result<String>) -> Check {
+    match result {
+        Ok(detail) => Check {
+            name,
+            status: Status::Pass,
+            detail,
+        },
+        Err(e) => Check {
+            name,
+            status: Status::Fail,
+            detail: e.to_string(),
+        },
+    }
+}
+
+fn skipped(name: &'static str) -> Check {
+    Check {
+        name,
+        status: Status::Skipped,
+        detail: String::from("database could not be opened"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::TestDb;
+
+    fn statuses(report: &Readiness) -> Vec<(&'static str, Status)> {
+        report.checks.iter().map(|c| (c.name, c.status)).collect()
+    }
+
+    #[test]
+    fn a_migrated_database_is_ready() {
+        let db = TestDb::new();
+        let report = readiness(&db.config);
+
+        assert!(report.ready);
+        assert_eq!(
+            statuses(&report),
+            [("database_open", Status::Pass), ("schema_version", Status::Pass), ("query", Status::Pass)]
+        );
+        assert_eq!(report.checks[1].detail, format!("version {}", SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn an_unreachable_database_is_reported_not_created() {
+        let mut config = Config::default();
+        config.database.path = std::env::temp_dir()
+            .join(format!("ums-missing-{}", std::process::id()))
+            .join("system.db")
+            .to_string_lossy()
+            .into_owned();
+        let report = readiness(&config);
+
+        assert!(!report.ready);
+        assert_eq!(
+            statuses(&report),
+            [("database_open", Status::Fail), ("schema_version", Status::Skipped), ("query", Status::Skipped)]
+        );
+        assert!(!std::path::Path::new(&config.database.path).exists());
+    }
+
+    #[test]
+    fn a_database_at_another_version_is_not_ready() {
+        let db = TestDb::new();
+        for version in [SCHEMA_VERSION - 1, SCHEMA_VERSION + 1] {
+            rusqlite::Connection::open(&db.config.database.path)
+                .unwrap()
+                .pragma_update(None, "user_version", version as i64)
+                .unwrap();
+            let report = readiness(&db.config);
+
+            assert!(!report.ready);
+            assert_eq!(
+                statuses(&report),
+                [("database_open", Status::Pass), ("schema_version", Status::Fail), ("query", Status::Pass)]
+            );
+            assert_eq!(
+                report.checks[1].detail,
+                format!("database is at version {}, this build expects {}", version, SCHEMA_VERSION)
+            );
+        }
+    }
+}
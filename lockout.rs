@@ -0,0 +1,113 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::table_models::LoginThrottle;
+
+pub const SCOPE_ACCOUNT: &str = "account";
+pub const SCOPE_IP: &str = "ip";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutPolicy {
+    // failures allowed before any delay is imposed
+    pub free_attempts: i32,
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+    // failures after which the account (or client ip) is locked outright
+    pub max_account_failures: i32,
+    pub max_ip_failures: i32,
+    pub lockout_secs: i64,
+    // a quiet period after which the failure counter starts over
+    pub reset_after_secs: i64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            base_delay_secs: 2,
+            max_delay_secs: 300,
+            max_account_failures: 10,
+            max_ip_failures: 50,
+            lockout_secs: 15 * 60,
+            reset_after_secs: 60 * 60,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    // Computes the throttle state that follows a failed attempt
+    pub fn register_failure(
+        &self,
+        previous: Option<&LoginThrottle>,
+        scope: &str,
+        key: &str,
+        now: i64,
+    ) -> LoginThrottle {
+        let failures = match previous {
+            Some(p) if now - p.last_failure < self.reset_after_secs => p.failures + 1,
+            _ => 1,
+        };
+
+        let max_failures = if scope == SCOPE_IP {
+            self.max_ip_failures
+        } else {
+            self.max_account_failures
+        };
+
+        let locked_until = if failures >= max_failures {
+            now + self.lockout_secs
+        } else if failures > self.free_attempts {
+            now + self.backoff(failures - self.free_attempts)
+        } else {
+            0
+        };
+
+        LoginThrottle {
+            id: previous.map(|p| p.id).unwrap_or(0),
+            scope: scope.to_owned(),
+            key: key.to_owned(),
+            failures,
+            last_failure: now,
+            locked_until,
+        }
+    }
+
+    // Exponential delay, doubling with every failure past the free attempts
+    pub fn backoff(&self, excess_failures: i32) -> i64 {
+        let exponent = (excess_failures - 1).clamp(0, 30) as u32;
+        self.base_delay_secs
+            .saturating_mul(2_i64.saturating_pow(exponent))
+            .min(self.max_delay_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = LockoutPolicy::default();
+        let delays: Vec<i64> = (1..=10).map(|n| policy.backoff(n)).collect();
+        assert_eq!(&delays[..4], &[2, 4, 8, 16]);
+        assert_eq!(delays[9], policy.max_delay_secs);
+    }
+
+    #[test]
+    fn failures_lock_out_and_reset_after_a_quiet_period() {
+        let policy = LockoutPolicy::default();
+        let mut previous = None;
+        for n in 1..=policy.max_account_failures {
+            let next = policy.register_failure(previous.as_ref(), SCOPE_ACCOUNT, "a@aubg.edu", 1000);
+            assert_eq!(next.failures, n);
+            assert_eq!(next.locked_until > 0, n > policy.free_attempts);
+            previous = Some(next);
+        }
+        assert_eq!(previous.as_ref().unwrap().locked_until, 1000 + policy.lockout_secs);
+
+        let later = 1000 + policy.reset_after_secs;
+        let next = policy.register_failure(previous.as_ref(), SCOPE_ACCOUNT, "a@aubg.edu", later);
+        assert_eq!(next.failures, 1);
+        assert_eq!(next.locked_until, 0);
+    }
+}
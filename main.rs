@@ -1,43 +1,110 @@
 use actix_cors::Cors;
-use actix_web::{App, HttpServer};
-use backend::rest_api::*;
+use actix_web::{web, App, HttpServer};
+use backend::bootstrap;
+use backend::config::{Config, ServerConfig};
+use backend::db_driver::DbDriver;
+use backend::rest_api::{json_config, query_config};
+use backend::telemetry::{self, RequestSpan};
+use backend::tls;
+use std::sync::Arc;
+use tracing_actix_web::TracingLogger;
 
+// the backend module lives in mod.rs next to this file
+#[path = "mod.rs"]
 mod backend;
 
 extern crate actix_web;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let http_server = HttpServer::new(|| {
+    // refuse to start on a bad configuration rather than discovering it mid-request
+    let config = match Config::load() {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    telemetry::init(&config.logging);
+
+    // schema and settings are brought up to date here, so requests only open connections
+    if let Err(e) = DbDriver::prepare(&config) {
+        tracing::error!(error = %e, "failed to prepare the database");
+        std::process::exit(1);
+    }
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("bootstrap-admin") {
+        match bootstrap::from_args(config.clone(), &args[2..]) {
+            Ok(_) => tracing::info!("administrator account created"),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to create administrator");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    match bootstrap::from_env(config.clone()) {
+        Ok(true) => tracing::info!("administrator account created from the environment"),
+        Ok(false) => {}
+        Err(e) => tracing::error!(error = %e, "failed to create administrator from the environment"),
+    }
+
+    let bind_address = (config.server.host.clone(), config.server.port);
+    let app_config = web::Data::from(config.clone());
+
+    let http_server = HttpServer::new(move || {
         App::new()
-            .wrap(Cors::permissive())
-            .service(index)
-            .service(get_users)
-            .service(get_students)
-            .service(get_teachers)
-            .service(get_departments)
-            .service(get_department)
-            .service(new_department)
-            .service(invite_to_department)
-            .service(kick_from_department)
-            .service(get_courses)
-            .service(get_course)
-            .service(new_course)
-            .service(update_course)
-            .service(remove_course)
-            .service(update_user)
-            .service(delete_user)
-            .service(get_self)
-            .service(update_self)
-            .service(admin)
-            .service(enroll)
-            .service(unenroll)
-            .service(login)
-            .service(logout)
-            .service(register)
-            .service(register_admin)
-    })
-    .bind(("127.0.0.1", 8080))?;
+            .app_data(app_config.clone())
+            .app_data(json_config())
+            .app_data(query_config())
+            .wrap(cors(&app_config.server))
+            .wrap(TracingLogger::<RequestSpan>::new())
+            .configure(backend::configure)
+    });
+
+    let http_server = if config.tls.enabled {
+        let tls_config = match tls::server_config(&config.tls) {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to load TLS certificate");
+                std::process::exit(1);
+            }
+        };
+
+        if let Some(port) = config.tls.redirect_http_port {
+            let redirect_config = web::Data::from(config.clone());
+            let redirect_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(redirect_config.clone())
+                    .wrap(TracingLogger::<RequestSpan>::new())
+                    .default_service(web::to(tls::redirect_to_https))
+            })
+            .bind((config.server.host.clone(), port))?
+            .run();
+
+            actix_web::rt::spawn(redirect_server);
+        }
+
+        http_server.bind_rustls_0_23(bind_address, tls_config)?
+    } else {
+        http_server.bind(bind_address)?
+    };
 
     http_server.run().await
 }
+
+fn cors(config: &ServerConfig) -> Cors {
+    if config.cors_allowed_origins.iter().any(|o| o == "*") {
+        return Cors::permissive();
+    }
+
+    config
+        .cors_allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allow_any_method()
+        .allow_any_header()
+        .expose_any_header()
+}
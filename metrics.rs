@@ -0,0 +1,177 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    logins: IntCounterVec,
+    db_statement_duration: HistogramVec,
+    // every ServerConnection opens its own SQLite connection, so there is no pool to
+    // size; the number currently open is what utilisation means here
+    db_connections_open: IntGauge,
+    enrolled_students: IntGaugeVec,
+}
+
+impl Metrics {
+    // The names below are fixed, so registering them can only fail on a programming error
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("ums")), None)
+            .expect("metrics registry prefix is valid");
+
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests served"),
+                &["method", "route", "status"],
+            )
+            .expect("valid metric"),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+                &["method", "route"],
+            )
+            .expect("valid metric"),
+            logins: IntCounterVec::new(
+                Opts::new("logins_total", "Sign-in attempts by outcome"),
+                &["outcome", "reason"],
+            )
+            .expect("valid metric"),
+            db_statement_duration: HistogramVec::new(
+                HistogramOpts::new("db_statement_duration_seconds", "Time spent running SQL statements")
+                    .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]),
+                &["operation"],
+            )
+            .expect("valid metric"),
+            db_connections_open: IntGauge::new("db_connections_open", "Open database connections")
+                .expect("valid metric"),
+            enrolled_students: IntGaugeVec::new(
+                Opts::new("enrolled_students", "Distinct students enrolled in at least one course"),
+                &["semester"],
+            )
+            .expect("valid metric"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 6] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.logins.clone()),
+            Box::new(metrics.db_statement_duration.clone()),
+            Box::new(metrics.db_connections_open.clone()),
+            Box::new(metrics.enrolled_students.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric names are unique");
+        }
+
+        metrics
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+// `route` is the matched pattern, e.g. /courses/{id}, so ids do not explode the label set
+pub fn observe_request(method: &str, route: &str, status: u16, seconds: f64) {
+    let m = metrics();
+    m.http_requests
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    m.http_request_duration
+        .with_label_values(&[method, route])
+        .observe(seconds);
+}
+
+pub fn record_login(success: bool, reason: &str) {
+    let outcome = if success { "success" } else { "failure" };
+    metrics().logins.with_label_values(&[outcome, reason]).inc();
+}
+
+pub fn observe_statement(sql: &str, seconds: f64) {
+    let operation = sql
+        .split_whitespace()
+        .next()
+        .map(|w| w.to_lowercase())
+        .filter(|w| ["select", "insert", "update", "delete"].contains(&w.as_str()))
+        .unwrap_or_else(|| String::from("other"));
+
+    metrics()
+        .db_statement_duration
+        .with_label_values(&[&operation])
+        .observe(seconds);
+}
+
+pub fn connection_opened() {
+    metrics().db_connections_open.inc();
+}
+
+pub fn connection_closed() {
+    metrics().db_connections_open.dec();
+}
+
+// Replaces the whole set, so semesters nobody is enrolled in any more disappear
+pub fn set_enrolled_students(counts: &[(String, i64)]) {
+    let gauge = &metrics().enrolled_students;
+    gauge.reset();
+    for (semester, count) in counts {
+        gauge.with_label_values(&[semester]).set(*count);
+    }
+}
+
+// Prometheus text exposition format
+pub fn render() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics().registry.gather(), &mut buffer)
+        .map_err(|e| anyhow!("Failed to encode metrics: {}", e))?;
+
+    String::from_utf8(buffer).map_err(|e| anyhow!("Failed to encode metrics: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the registry is shared by every test in the process, so each test uses labels of its own
+    fn line<'a>(rendered: &'a str, prefix: &str) -> Option<&'a str> {
+        rendered.lines().find(|l| l.starts_with(prefix))
+    }
+
+    #[test]
+    fn requests_and_logins_are_counted_under_their_labels() {
+        observe_request("GET", "/metrics-test/{id}", 200, 0.01);
+        observe_request("GET", "/metrics-test/{id}", 200, 0.02);
+        record_login(false, "metrics test");
+
+        let rendered = render().unwrap();
+        let requests = r#"ums_http_requests_total{method="GET",route="/metrics-test/{id}",status="200"}"#;
+        assert_eq!(line(&rendered, requests), Some(&*format!("{} 2", requests)));
+        let logins = r#"ums_logins_total{outcome="failure",reason="metrics test"}"#;
+        assert_eq!(line(&rendered, logins), Some(&*format!("{} 1", logins)));
+    }
+
+    #[test]
+    fn statements_are_grouped_by_their_first_keyword() {
+        let count = |operation: &str| {
+            let prefix = format!(r#"ums_db_statement_duration_seconds_count{{operation="{}"}}"#, operation);
+            line(&render().unwrap(), &prefix)
+                .and_then(|l| l.rsplit(' ').next()?.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+
+        // other tests run statements too, so only growth is compared
+        let before = count("other");
+        observe_statement("PRAGMA user_version", 0.001);
+        observe_statement("  ", 0.001);
+        assert!(count("other") >= before + 2);
+        observe_statement("select 1", 0.001);
+        assert!(count("select") >= 1);
+    }
+}
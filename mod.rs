@@ -1,7 +1,29 @@
 pub mod server_connection_impl;
 pub mod db_driver;
 pub mod rest_api;
+pub mod bootstrap;
+pub mod config;
+pub mod telemetry;
+pub mod tls;
+mod conditional;
+mod dto;
+mod error;
 mod filter;
+mod health;
+mod lockout;
+mod metrics;
+mod oidc;
+mod openapi;
 mod password;
+mod password_policy;
+mod query;
+mod rbac;
+mod routes;
 mod sqlite_conn;
 mod table_models;
+mod tokens;
+mod totp;
+#[cfg(test)]
+mod testing;
+
+pub use routes::configure;
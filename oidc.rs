@@ -0,0 +1,293 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use super::tokens;
+
+pub const ISSUER_VAR: &str = "UMS_OIDC_ISSUER";
+pub const CLIENT_ID_VAR: &str = "UMS_OIDC_CLIENT_ID";
+pub const CLIENT_SECRET_VAR: &str = "UMS_OIDC_CLIENT_SECRET";
+pub const REDIRECT_URI_VAR: &str = "UMS_OIDC_REDIRECT_URI";
+pub const FRONTEND_URL_VAR: &str = "UMS_OIDC_FRONTEND_URL";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+    // must match what is registered with the identity provider, e.g. https://ums.aubg.edu/api/v1/login/sso/callback
+    pub redirect_uri: String,
+    // where the browser lands after signing in; the session token travels in the URL
+    // fragment, which is never sent to a server
+    pub frontend_url: String,
+}
+
+impl OidcConfig {
+    // SSO is optional; it is only offered when every variable is set
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            issuer: std::env::var(ISSUER_VAR).ok()?.trim_end_matches('/').to_owned(),
+            client_id: std::env::var(CLIENT_ID_VAR).ok()?,
+            client_secret: std::env::var(CLIENT_SECRET_VAR).ok()?,
+            redirect_uri: std::env::var(REDIRECT_URI_VAR).ok()?,
+            frontend_url: std::env::var(FRONTEND_URL_VAR).ok()?,
+        })
+    }
+}
+
+// The parts of /.well-known/openid-configuration the login flow needs
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdClaims {
+    pub email: String,
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub async fn discover(config: &OidcConfig) -> Result<Discovery> {
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let discovery: Discovery = get_json(&url).await?;
+
+    if discovery.issuer.trim_end_matches('/') != config.issuer {
+        return Err(anyhow!("Identity provider reported an unexpected issuer."));
+    }
+
+    Ok(discovery)
+}
+
+pub fn authorization_url(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> String {
+    format!(
+        "{}?response_type=code&scope=openid%20email%20profile&client_id={}&redirect_uri={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        tokens::url_encode(&config.client_id),
+        tokens::url_encode(&config.redirect_uri),
+        tokens::url_encode(state),
+        tokens::url_encode(nonce),
+        tokens::pkce_challenge(code_verifier),
+    )
+}
+
+// Trades the authorization code for an ID token and returns its verified claims
+pub async fn exchange_code(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<IdClaims> {
+    let client = awc::Client::default();
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("client_secret", config.client_secret.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+
+    let mut response = client
+        .post(&discovery.token_endpoint)
+        .send_form(&form)
+        .await
+        .map_err(|e| anyhow!("Failed to reach the identity provider: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Identity provider rejected the authorization code ({}).",
+            response.status()
+        ));
+    }
+
+    let token: TokenResponse = response
+        .json()
+        .await
+        .map_err(|e| anyhow!("Invalid token response from the identity provider: {}", e))?;
+
+    let claims = verify_id_token(config, discovery, &token.id_token).await?;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(anyhow!("ID token nonce does not match the login request."));
+    }
+
+    // an address the provider does not vouch for could belong to anyone
+    if claims.email_verified != Some(true) {
+        return Err(anyhow!("The identity provider has not verified this email address."));
+    }
+
+    Ok(claims)
+}
+
+async fn verify_id_token(config: &OidcConfig, discovery: &Discovery, id_token: &str) -> Result<IdClaims> {
+    let header = decode_header(id_token)?;
+    let jwks: JwkSet = get_json(&discovery.jwks_uri).await?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or_else(|| anyhow!("ID token was signed with an unknown key."))?;
+
+    let key = DecodingKey::from_jwk(jwk)?;
+
+    // the token header names its own algorithm, so only trust the ones the key allows
+    let algorithms = allowed_algorithms(jwk);
+    if !algorithms.contains(&header.alg) {
+        return Err(anyhow!("ID token was signed with an algorithm its key does not allow."));
+    }
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+
+    Ok(decode::<IdClaims>(id_token, &key, &validation)?.claims)
+}
+
+// What the provider says the key is for, narrowed to what its type can do. Symmetric
+// keys never qualify: anyone holding one could mint tokens, not just the provider.
+fn allowed_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    let by_type = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => vec![],
+    };
+
+    match jwk.common.key_algorithm {
+        Some(advertised) => Algorithm::from_str(&advertised.to_string())
+            .ok()
+            .filter(|alg| by_type.contains(alg))
+            .into_iter()
+            .collect(),
+        None => by_type,
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T> {
+    let mut response = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to reach the identity provider: {}", e))?;
+
+    response
+        .json()
+        .limit(1 << 20)
+        .await
+        .map_err(|e| anyhow!("Invalid response from the identity provider: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{MockIdp, MOCK_IDP_KEY_ID};
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    async fn exchange(idp: &MockIdp, id_token: &str) -> Result<IdClaims> {
+        let config = idp.config();
+        let discovery = discover(&config).await?;
+        exchange_code(&config, &discovery, id_token, "verifier", "nonce").await
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_token_signed_with_the_published_key() {
+        let idp = MockIdp::start().await;
+        let token = idp.id_token(&idp.claims("student@aubg.edu", "nonce"));
+
+        let claims = exchange(&idp, &token).await.unwrap();
+        assert_eq!(claims.email, "student@aubg.edu");
+        assert_eq!(claims.name.as_deref(), Some("Mock User"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_algorithms_the_key_does_not_advertise() {
+        let idp = MockIdp::start().await;
+        let claims = idp.claims("student@aubg.edu", "nonce");
+
+        for alg in [Algorithm::HS256, Algorithm::HS512] {
+            let mut header = Header::new(alg);
+            header.kid = Some(MOCK_IDP_KEY_ID.to_owned());
+            let forged = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"guessable")).unwrap();
+            assert!(exchange(&idp, &forged).await.is_err());
+        }
+
+        // "none": the header says so and the signature is empty
+        let unsigned = format!(
+            "{}.{}.",
+            tokens::base64_url(br#"{"alg":"none","kid":"mock-key"}"#),
+            tokens::base64_url(claims.to_string().as_bytes()),
+        );
+        assert!(exchange(&idp, &unsigned).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn requires_a_verified_email_and_the_right_nonce() {
+        let idp = MockIdp::start().await;
+
+        let mut unverified = idp.claims("student@aubg.edu", "nonce");
+        unverified["email_verified"] = serde_json::Value::Bool(false);
+        assert!(exchange(&idp, &idp.id_token(&unverified)).await.is_err());
+
+        let mut unstated = idp.claims("student@aubg.edu", "nonce");
+        unstated.as_object_mut().unwrap().remove("email_verified");
+        assert!(exchange(&idp, &idp.id_token(&unstated)).await.is_err());
+
+        let replayed = idp.claims("student@aubg.edu", "another login's nonce");
+        assert!(exchange(&idp, &idp.id_token(&replayed)).await.is_err());
+
+        let mut foreign = idp.claims("student@aubg.edu", "nonce");
+        foreign["aud"] = serde_json::Value::from("another-client");
+        assert!(exchange(&idp, &idp.id_token(&foreign)).await.is_err());
+    }
+
+    #[test]
+    fn symmetric_keys_allow_no_algorithm() {
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "oct", "k": "c2VjcmV0", "alg": "HS256",
+        }))
+        .unwrap();
+        assert!(allowed_algorithms(&jwk).is_empty());
+
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "RSA", "n": "AQAB", "e": "AQAB", "alg": "RS256",
+        }))
+        .unwrap();
+        assert_eq!(allowed_algorithms(&jwk), vec![Algorithm::RS256]);
+    }
+}
@@ -0,0 +1,196 @@
+use serde_derive::Serialize;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+use super::dto::*;
+use super::health::{Check, Readiness, Status};
+use super::rbac::{Permission, Role};
+use super::rest_api;
+use super::server_connection_impl::{
+    IssuedApiKey, IssuedInvitation, SessionInfo, Statistics, TotpEnrollment,
+};
+use super::table_models::{
+    AdminInvitation, ApiKey as StoredApiKey, AuditEntry, Courses, Departments, LoginAttempt,
+    LoginThrottle, RolePermission, StudentAccount, StudentCourse, TeacherAccount,
+};
+
+// Schemas for the ad hoc json!() bodies in rest_api.rs; documentation only, never built
+
+// {"message": "..."}, what most writes answer with
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct Message {
+    pub message: String,
+}
+
+// The envelope every error is sent in; see ApiError
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: String,
+    // only for validation failures
+    pub fields: Option<Vec<FieldError>>,
+}
+
+// Generated from the #[utoipa::path] attributes on the handlers in rest_api.rs. The paths
+// listed here and in V1Api have to follow routes::configure; a test in routes.rs fails when they drift.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "University Management System",
+        description = "Credentials travel in request headers: `session_token` after signing in, \
+                       `api_key` for service accounts, or `login_email` and `login_password` on every request."
+    ),
+    paths(rest_api::healthz, rest_api::readyz, rest_api::get_metrics),
+    nest((path = "/api/v1", api = V1Api)),
+    components(schemas(
+        Message,
+        ErrorBody,
+        FieldError,
+        NewCourse,
+        CourseChanges,
+        UserChanges,
+        AccountChanges,
+        Registration,
+        NewDepartment,
+        DepartmentMember,
+        Grade,
+        AdminInvitationRequest,
+        ServiceAccountRequest,
+        ApiKeyRequest,
+        PublicUser,
+        PrivateUser,
+        UserView,
+        CourseView,
+        AccountView,
+        StudentAccount,
+        TeacherAccount,
+        Courses,
+        StudentCourse,
+        Departments,
+        LoginAttempt,
+        LoginThrottle,
+        RolePermission,
+        AdminInvitation,
+        AuditEntry,
+        StoredApiKey,
+        Role,
+        Permission,
+        Statistics,
+        TotpEnrollment,
+        IssuedInvitation,
+        IssuedApiKey,
+        SessionInfo,
+        Readiness,
+        Check,
+        Status,
+    )),
+    modifiers(&Credentials),
+    // any one of these signs a request in; public endpoints override it with an empty list
+    security(
+        ("session_token" = []),
+        ("api_key" = []),
+        ("login_email" = [], "login_password" = []),
+    ),
+    tags(
+        (name = "health", description = "Liveness, readiness and metrics"),
+        (name = "auth", description = "Signing in and out, two-factor authentication and SSO"),
+        (name = "account", description = "The signed in user's own account"),
+        (name = "courses", description = "Courses, enrollment and grades"),
+        (name = "departments", description = "Departments and their teachers"),
+        (name = "users", description = "Listing and administering accounts"),
+        (name = "admin", description = "Roles, invitations, API keys and the audit log"),
+    )
+)]
+pub struct ApiDoc;
+
+// Everything routes::configure mounts under /api/v1
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        rest_api::index,
+        rest_api::get_users,
+        rest_api::get_students,
+        rest_api::get_teachers,
+        rest_api::get_departments,
+        rest_api::get_department,
+        rest_api::new_department,
+        rest_api::delete_department,
+        rest_api::invite_to_department,
+        rest_api::kick_from_department,
+        rest_api::get_courses,
+        rest_api::get_course,
+        rest_api::new_course,
+        rest_api::remove_course,
+        rest_api::update_course,
+        rest_api::grade_student,
+        rest_api::admin,
+        rest_api::update_user,
+        rest_api::delete_user,
+        rest_api::get_self,
+        rest_api::update_self,
+        rest_api::enroll,
+        rest_api::unenroll,
+        rest_api::login,
+        rest_api::login_totp,
+        rest_api::login_sso,
+        rest_api::login_sso_callback,
+        rest_api::enroll_totp,
+        rest_api::confirm_totp,
+        rest_api::disable_totp,
+        rest_api::regenerate_recovery_codes,
+        rest_api::logout,
+        rest_api::register,
+        rest_api::register_admin,
+        rest_api::get_stats,
+        rest_api::get_login_attempts,
+        rest_api::get_lockouts,
+        rest_api::unlock_user,
+        rest_api::get_role_permissions,
+        rest_api::grant_permission,
+        rest_api::revoke_permission,
+        rest_api::invite_admin,
+        rest_api::get_admin_invitations,
+        rest_api::revoke_admin_invitation,
+        rest_api::get_audit_log,
+        rest_api::create_service_account,
+        rest_api::get_api_keys,
+        rest_api::create_api_key,
+        rest_api::revoke_api_key,
+        rest_api::impersonate_user,
+        rest_api::get_impersonations,
+        rest_api::get_sessions,
+        rest_api::revoke_other_sessions,
+        rest_api::revoke_session,
+        rest_api::get_user_sessions,
+        rest_api::revoke_user_sessions,
+        rest_api::revoke_user_session,
+    )
+)]
+struct V1Api;
+
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        for header in ["session_token", "api_key", "login_email", "login_password"] {
+            components.add_security_scheme(
+                header,
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(header))),
+            );
+        }
+        components.add_security_scheme(
+            "metrics_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+// The spec at /openapi.json and Swagger UI at /docs/; mounted by routes::configure
+pub fn docs() -> SwaggerUi {
+    SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", ApiDoc::openapi())
+}
@@ -1,22 +1,225 @@
+use anyhow::anyhow;
+use anyhow::Result;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
+use serde_derive::{Deserialize, Serialize};
 
-pub fn generate_salt() -> SaltString {
-    SaltString::generate(&mut OsRng)
+pub const MEMORY_VAR: &str = "UMS_ARGON2_MEMORY_KIB";
+pub const ITERATIONS_VAR: &str = "UMS_ARGON2_ITERATIONS";
+pub const PARALLELISM_VAR: &str = "UMS_ARGON2_PARALLELISM";
+pub const PEPPER_VAR: &str = "UMS_PASSWORD_PEPPER";
+
+// Outcome of checking a password against a stored hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // correct, but stored with outdated parameters (or without the pepper)
+    ValidNeedsRehash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashing {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    // server-side secret mixed into every hash; never stored in the database
+    #[serde(skip_serializing)]
+    pub pepper: Option<String>,
 }
 
-pub fn hash(password: &str, salt: SaltString) -> String {
-    let password_hash = Argon2::default()
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap();
-    password_hash.to_string()
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            pepper: None,
+        }
+    }
 }
 
-pub fn verify(password_hash: &str, password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(password_hash).unwrap();
-    Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+impl PasswordHashing {
+    // Overrides with whichever UMS_ARGON2_* / UMS_PASSWORD_PEPPER variables are set
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Ok(m) = std::env::var(MEMORY_VAR) {
+            self.memory_kib = m.parse().map_err(|_| anyhow!("{} must be a number.", MEMORY_VAR))?;
+        }
+        if let Ok(t) = std::env::var(ITERATIONS_VAR) {
+            self.iterations = t.parse().map_err(|_| anyhow!("{} must be a number.", ITERATIONS_VAR))?;
+        }
+        if let Ok(p) = std::env::var(PARALLELISM_VAR) {
+            self.parallelism = p.parse().map_err(|_| anyhow!("{} must be a number.", PARALLELISM_VAR))?;
+        }
+        if let Some(pepper) = std::env::var(PEPPER_VAR).ok().filter(|p| !p.is_empty()) {
+            self.pepper = Some(pepper);
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.params().map(|_| ())
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .argon2(true)?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+
+        Ok(hash.to_string())
+    }
+
+    // Errors only when the stored hash itself is unusable, never on a wrong password
+    pub fn verify(&self, password_hash: &str, password: &str) -> Result<Verification> {
+        let parsed = PasswordHash::new(password_hash)
+            .map_err(|e| anyhow!("Stored password hash is malformed: {}", e))?;
+
+        if self.check(&parsed, password, true)? {
+            return Ok(if self.is_current(&parsed) {
+                Verification::Valid
+            } else {
+                Verification::ValidNeedsRehash
+            });
+        }
+
+        // hashes created before a pepper was configured still verify, once, without it
+        if self.pepper.is_some() && self.check(&parsed, password, false)? {
+            return Ok(Verification::ValidNeedsRehash);
+        }
+
+        Ok(Verification::Invalid)
+    }
+
+    // As slow as verify on a wrong password, for sign-ins to accounts that do not exist
+    pub fn verify_nothing(&self, password: &str) -> Result<()> {
+        self.hash(password)?;
+        // verify tries a wrong password a second time, without the pepper
+        if self.pepper.is_some() {
+            self.hash(password)?;
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, parsed: &PasswordHash, password: &str, peppered: bool) -> Result<bool> {
+        Ok(self
+            .argon2(peppered)?
+            .verify_password(password.as_bytes(), parsed)
+            .is_ok())
+    }
+
+    fn is_current(&self, parsed: &PasswordHash) -> bool {
+        let params = match Params::try_from(parsed) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+
+        parsed.algorithm == Algorithm::Argon2id.ident()
+            && parsed.version == Some(Version::V0x13.into())
+            && params.m_cost() == self.memory_kib
+            && params.t_cost() == self.iterations
+            && params.p_cost() == self.parallelism
+    }
+
+    fn params(&self) -> Result<Params> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))
+    }
+
+    fn argon2(&self, peppered: bool) -> Result<Argon2<'_>> {
+        let params = self.params()?;
+
+        match (&self.pepper, peppered) {
+            (Some(pepper), true) => Argon2::new_with_secret(
+                pepper.as_bytes(),
+                Algorithm::Argon2id,
+                Version::V0x13,
+                params,
+            )
+            .map_err(|e| anyhow!("Invalid password pepper: {}", e)),
+            _ => Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)),
+        }
+    }
+}
+
+// Anything shaped like a PHC string; clients must always send the plain password
+pub fn looks_hashed(password: &str) -> bool {
+    password.starts_with('$') && PasswordHash::new(password).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the smallest parameters Argon2 accepts, so the tests stay fast
+    fn hashing(pepper: Option<&str>) -> PasswordHashing {
+        PasswordHashing {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+            pepper: pepper.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn hashes_verify_only_the_password_they_were_made_from() {
+        let h = hashing(None);
+        let hash = h.hash("secret").unwrap();
+
+        assert_ne!(hash, "secret");
+        assert_ne!(hash, h.hash("secret").unwrap(), "every hash gets its own salt");
+        assert_eq!(h.verify(&hash, "secret").unwrap(), Verification::Valid);
+        assert_eq!(h.verify(&hash, "Secret").unwrap(), Verification::Invalid);
+        assert!(h.verify("not a hash", "secret").is_err());
+    }
+
+    #[test]
+    fn the_pepper_is_required_once_configured() {
+        let peppered = hashing(Some("pepper"));
+        let hash = peppered.hash("secret").unwrap();
+
+        assert_eq!(peppered.verify(&hash, "secret").unwrap(), Verification::Valid);
+        assert_eq!(hashing(None).verify(&hash, "secret").unwrap(), Verification::Invalid);
+        assert_eq!(hashing(Some("other")).verify(&hash, "secret").unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn outdated_hashes_verify_but_ask_to_be_rehashed() {
+        let current = hashing(Some("pepper"));
+
+        // made before the pepper was configured
+        let unpeppered = hashing(None).hash("secret").unwrap();
+        assert_eq!(current.verify(&unpeppered, "secret").unwrap(), Verification::ValidNeedsRehash);
+        assert_eq!(current.verify(&unpeppered, "wrong").unwrap(), Verification::Invalid);
+
+        // made with weaker parameters
+        let weaker = PasswordHashing { memory_kib: 16, ..hashing(Some("pepper")) }.hash("secret").unwrap();
+        assert_eq!(current.verify(&weaker, "secret").unwrap(), Verification::ValidNeedsRehash);
+
+        let rehashed = current.hash("secret").unwrap();
+        assert_eq!(current.verify(&rehashed, "secret").unwrap(), Verification::Valid);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        assert!(hashing(None).validate().is_ok());
+        assert!(PasswordHashing { memory_kib: 1, ..hashing(None) }.validate().is_err());
+        assert!(PasswordHashing { iterations: 0, ..hashing(None) }.hash("secret").is_err());
+    }
+
+    #[test]
+    fn only_phc_strings_look_hashed() {
+        let hash = hashing(None).hash("secret").unwrap();
+
+        assert!(looks_hashed(&hash));
+        assert!(!looks_hashed("secret"));
+        assert!(!looks_hashed("$not$a$hash"));
+        assert!(!looks_hashed(""));
+    }
 }
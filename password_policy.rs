@@ -0,0 +1,318 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::error::ApiError;
+
+pub const MIN_LENGTH_VAR: &str = "UMS_PASSWORD_MIN_LENGTH";
+pub const MIN_STRENGTH_VAR: &str = "UMS_PASSWORD_MIN_STRENGTH";
+pub const BREACHED_LIST_VAR: &str = "UMS_BREACHED_PASSWORDS_FILE";
+
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz";
+
+// Breached lists can be large, so each file is read once per process and shared
+static BREACHED_LISTS: OnceLock<Mutex<HashMap<String, Arc<HashSet<String>>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_special: bool,
+    pub special_characters: String,
+    // 0 (trivially guessable) to 4 (very strong), on the same scale as zxcvbn
+    pub min_strength: u8,
+    // one password per line; matched case-insensitively. An unreadable file
+    // rejects password changes rather than silently skipping the screening.
+    pub breached_list: Option<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            special_characters: String::from("@$!%*?&"),
+            min_strength: 2,
+            breached_list: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Ok(l) = std::env::var(MIN_LENGTH_VAR) {
+            self.min_length = l.parse().map_err(|_| anyhow!("{} must be a number.", MIN_LENGTH_VAR))?;
+        }
+        if let Ok(s) = std::env::var(MIN_STRENGTH_VAR) {
+            self.min_strength = s.parse().map_err(|_| anyhow!("{} must be a number.", MIN_STRENGTH_VAR))?;
+        }
+        if let Some(path) = std::env::var(BREACHED_LIST_VAR).ok().filter(|p| !p.is_empty()) {
+            self.breached_list = Some(path);
+        }
+
+        Ok(())
+    }
+
+    // Also loads the breached list, so a missing file is caught at startup
+    pub fn validate(&self) -> Result<()> {
+        if self.min_length == 0 || self.min_length > self.max_length {
+            return Err(anyhow!("Password length limits are inconsistent."));
+        }
+        if self.min_strength > 4 {
+            return Err(anyhow!("Password strength must be between 0 and 4."));
+        }
+        if let Some(path) = &self.breached_list {
+            breached_list(path)?;
+        }
+
+        Ok(())
+    }
+
+    // Checks a new password, using the account's own details as known-bad words
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Result<()> {
+        let mut problems = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            problems.push(format!("Must be at least {} characters long", self.min_length));
+        }
+        if length > self.max_length {
+            problems.push(format!("Must be at most {} characters long", self.max_length));
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            problems.push(String::from("Must contain at least 1 uppercase letter"));
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            problems.push(String::from("Must contain at least 1 lowercase letter"));
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            problems.push(String::from("Must contain at least 1 number"));
+        }
+        if self.require_special && !password.chars().any(|c| self.special_characters.contains(c)) {
+            problems.push(format!(
+                "Must contain at least 1 special character ({})",
+                self.special_characters
+                    .chars()
+                    .map(String::from)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+
+        if !problems.is_empty() {
+            return Err(ApiError::validation(format!(
+                "The password does not meet the following criteria:\n{}",
+                problems
+                    .iter()
+                    .map(|p| format!("- {}", p))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ))
+            .into());
+        }
+
+        if let Some(path) = &self.breached_list {
+            if breached_list(path)?.contains(&password.to_lowercase()) {
+                return Err(ApiError::validation(
+                    "This password has appeared in a data breach. Choose a different one.",
+                )
+                .into());
+            }
+        }
+
+        if strength(password, user_inputs) < self.min_strength {
+            return Err(ApiError::validation(
+                "This password is too easy to guess. Use a longer phrase or avoid names, dates and keyboard patterns.",
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+// zxcvbn-style score: estimate the guesses an attacker needs and bucket them 0..=4
+pub fn strength(password: &str, user_inputs: &[&str]) -> u8 {
+    let lower = password.to_lowercase();
+    let chars: Vec<char> = lower.chars().collect();
+    if chars.is_empty() {
+        return 0;
+    }
+
+    let mut charset = 0.0_f64;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        charset += 26.0;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        charset += 26.0;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        charset += 10.0;
+    }
+    if password.chars().any(|c| !c.is_ascii_alphanumeric()) {
+        charset += 33.0;
+    }
+
+    // characters that merely continue a pattern add (almost) nothing
+    let mut effective = 1.0_f64;
+    for i in 1..chars.len() {
+        let repeated = chars[i] == chars[i - 1];
+        let sequential = (chars[i] as i32 - chars[i - 1] as i32).abs() == 1;
+        let keyboard = KEYBOARD_ROWS
+            .iter()
+            .chain(std::iter::once(&ALPHABET))
+            .any(|row| row.contains(&format!("{}{}", chars[i - 1], chars[i])));
+
+        effective += if repeated || sequential || keyboard { 0.25 } else { 1.0 };
+    }
+
+    // anything containing the user's own details is only as strong as what is left over
+    for input in user_inputs {
+        for part in input
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|p| p.len() >= 3)
+        {
+            if lower.contains(part) {
+                effective -= part.chars().count() as f64 * 0.75;
+            }
+        }
+    }
+
+    let log10_guesses = effective.max(1.0) * charset.max(10.0).log10();
+
+    match log10_guesses {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn breached_list(path: &str) -> Result<Arc<HashSet<String>>> {
+    let cache = BREACHED_LISTS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut cache = cache
+        .lock()
+        .map_err(|_| anyhow!("Breached password cache is unavailable."))?;
+
+    if let Some(list) = cache.get(path) {
+        return Ok(list.clone());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read breached password list '{}': {}", path, e))?;
+    let list: Arc<HashSet<String>> = Arc::new(
+        contents
+            .lines()
+            .map(|l| l.trim().to_lowercase())
+            .filter(|l| !l.is_empty())
+            .collect(),
+    );
+    cache.insert(path.to_owned(), list.clone());
+
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // meets every rule of the default policy
+    const STRONG: &str = "Corr3ct&Horse!Battery";
+
+    fn problems(policy: &PasswordPolicy, password: &str) -> String {
+        match policy.check(password, &[]) {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn every_missing_requirement_is_listed() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(problems(&policy, STRONG), "");
+
+        let cases = [
+            ("Sh0rt!", "at least 8 characters"),
+            ("nouppercase1!abc", "1 uppercase letter"),
+            ("NOLOWERCASE1!ABC", "1 lowercase letter"),
+            ("NoDigitsHere!abc", "1 number"),
+            ("NoSpecials1abcde", "1 special character (@, $, !, %, *, ?, &)"),
+        ];
+        for (password, problem) in cases {
+            let found = problems(&policy, password);
+            assert!(found.contains(problem), "{:?} gave {:?}", password, found);
+            assert_eq!(found.matches("\n- ").count(), 1, "{:?} gave {:?}", password, found);
+        }
+
+        // all of them at once
+        assert_eq!(problems(&policy, "abc").matches("\n- ").count(), 4);
+        assert!(problems(&policy, &format!("{}{}", STRONG, "a".repeat(128))).contains("at most 128"));
+    }
+
+    #[test]
+    fn rules_can_be_switched_off() {
+        let policy = PasswordPolicy {
+            require_uppercase: false,
+            require_digit: false,
+            require_special: false,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(problems(&policy, "lowercase words only here"), "");
+    }
+
+    #[test]
+    fn patterns_and_the_users_own_details_weaken_a_password() {
+        assert!(strength("Aaaaaaaa1!", &[]) < strength("Tq8#vLp2!x", &[]));
+        assert!(strength("Qwertyui1!", &[]) < strength("Tq8#vLp2!x", &[]));
+        assert!(strength("Abcdefgh1!", &[]) < strength("Tq8#vLp2!x", &[]));
+        assert_eq!(strength("", &[]), 0);
+
+        let policy = PasswordPolicy::default();
+        let own = "Johnsmith2024!";
+        assert!(policy.check(own, &[]).is_ok());
+        let err = policy.check(own, &["john.smith@aubg.edu", "johnsmith"]).unwrap_err();
+        assert!(err.to_string().contains("too easy to guess"));
+    }
+
+    #[test]
+    fn breached_passwords_are_rejected_whatever_their_case() {
+        let path = std::env::temp_dir().join(format!("ums-breached-{}.txt", std::process::id()));
+        std::fs::write(&path, format!("123456\n\n  {}  \n", STRONG.to_uppercase())).unwrap();
+        let policy = PasswordPolicy {
+            breached_list: Some(path.to_string_lossy().into_owned()),
+            ..PasswordPolicy::default()
+        };
+
+        assert!(policy.validate().is_ok());
+        assert!(problems(&policy, STRONG).contains("data breach"));
+        assert_eq!(problems(&policy, "Tq8#vLp2!xW"), "");
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = PasswordPolicy {
+            breached_list: Some(String::from("/nonexistent/breached.txt")),
+            ..PasswordPolicy::default()
+        };
+        assert!(missing.validate().is_err());
+    }
+
+    #[test]
+    fn inconsistent_limits_are_rejected() {
+        assert!(PasswordPolicy::default().validate().is_ok());
+        assert!(PasswordPolicy { min_length: 0, ..PasswordPolicy::default() }.validate().is_err());
+        assert!(PasswordPolicy { min_length: 200, ..PasswordPolicy::default() }.validate().is_err());
+        assert!(PasswordPolicy { min_strength: 5, ..PasswordPolicy::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use serde_derive::Deserialize;
+use serde_json::Value;
+use utoipa::IntoParams;
+
+use super::dto::CourseView;
+use super::error::ApiError;
+use super::filter::*;
+use super::rbac::Role;
+
+// more clauses than any screen needs; keeps a single request from building huge WHERE clauses
+const MAX_CLAUSES: usize = 16;
+
+// what ?fields= may name on a course; follows table_models::Courses
+const COURSE_FIELDS: [&str; 7] = [
+    "id",
+    "teacher_id",
+    "course",
+    "course_nr",
+    "description",
+    "cr_cost",
+    "timeslots",
+];
+
+// Query string shared by the list endpoints
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// Comma separated `field:operator:value` clauses, all of which must hold, e.g.
+    /// `role:eq:teacher,suspended:eq:false`. Only `eq` is supported and each endpoint
+    /// documents which fields it accepts.
+    filter: Option<String>,
+}
+
+impl ListQuery {
+    pub fn filters(&self, listing: Listing) -> Result<Vec<Filter>, ApiError> {
+        match &self.filter {
+            Some(raw) => parse(listing, raw),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+// How GET /courses and /courses/{id} shape each course
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CourseQuery {
+    /// Comma separated course fields to return, e.g. `id,course,cr_cost`; every field when absent.
+    fields: Option<String>,
+    /// Comma separated relations to embed in each course: `teacher`, `department`.
+    include: Option<String>,
+}
+
+impl CourseQuery {
+    pub fn shape(&self) -> Result<CourseShape, ApiError> {
+        let fields = self.fields.as_deref().map(names).filter(|f| !f.is_empty());
+        if let Some(unknown) = fields
+            .iter()
+            .flatten()
+            .find(|f| !COURSE_FIELDS.contains(&f.as_str()))
+        {
+            return Err(ApiError::validation(format!(
+                "Unknown field '{}'; expected one of {}.",
+                unknown,
+                COURSE_FIELDS.join(", ")
+            )));
+        }
+
+        let mut shape = CourseShape {
+            fields,
+            teacher: false,
+            department: false,
+        };
+
+        for relation in names(self.include.as_deref().unwrap_or_default()) {
+            match relation.as_str() {
+                "teacher" => shape.teacher = true,
+                "department" => shape.department = true,
+                _ => {
+                    return Err(ApiError::validation(format!(
+                        "Unknown relation '{}'; expected teacher or department.",
+                        relation
+                    )))
+                }
+            }
+        }
+
+        Ok(shape)
+    }
+}
+
+// A validated CourseQuery; the default is every field and no relations
+#[derive(Default)]
+pub struct CourseShape {
+    fields: Option<Vec<String>>,
+    pub teacher: bool,
+    pub department: bool,
+}
+
+impl CourseShape {
+    // Drops the course fields that were not asked for; embedded relations are kept whole
+    pub fn apply(&self, view: &CourseView) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(view)?;
+
+        if let (Some(fields), Value::Object(map)) = (&self.fields, &mut value) {
+            map.retain(|k, _| {
+                k == "teacher" || k == "department" || fields.iter().any(|f| f == k)
+            });
+        }
+
+        Ok(value)
+    }
+}
+
+// Which list a ?filter= is for; each has its own whitelist of fields
+#[derive(Debug, Clone, Copy)]
+pub enum Listing {
+    Users,
+    Students,
+    Teachers,
+    Courses,
+    Departments,
+}
+
+// Clauses are checked against the listing's whitelist and their values parsed into the
+// field's type here, so nothing the client sent reaches SQL except through a Filter
+fn parse(listing: Listing, raw: &str) -> Result<Vec<Filter>, ApiError> {
+    let clauses = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+
+    if clauses.len() > MAX_CLAUSES {
+        return Err(ApiError::validation(format!(
+            "At most {} filter clauses are allowed.",
+            MAX_CLAUSES
+        )));
+    }
+
+    clauses
+        .into_iter()
+        .map(|clause| {
+            let mut parts = clause.splitn(3, ':');
+            let (field, op, value) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(field), Some(op), Some(value)) => (field.trim(), op.trim(), value.trim()),
+                _ => {
+                    return Err(ApiError::validation(format!(
+                        "Invalid filter '{}': expected field:operator:value.",
+                        clause
+                    )))
+                }
+            };
+
+            if op != "eq" {
+                return Err(ApiError::validation(format!(
+                    "Invalid filter '{}': unsupported operator '{}', only eq is allowed.",
+                    clause, op
+                )));
+            }
+
+            field_filter(listing, field, value)
+                .map_err(|e| ApiError::validation(format!("Invalid filter '{}': {}", clause, e)))
+        })
+        .collect()
+}
+
+// The whitelist. Phone numbers, password hashes and the like are deliberately absent.
+fn field_filter(listing: Listing, field: &str, value: &str) -> Result<Filter, String> {
+    use Listing::*;
+
+    let filter = match (listing, field) {
+        (Users | Students | Teachers, "id") => Filter::Users(UsersFilter::Id(number(value)?)),
+        (Users | Students | Teachers, "username") => {
+            Filter::Users(UsersFilter::Username(value.to_string()))
+        }
+        (Users | Students | Teachers, "email") => {
+            Filter::Users(UsersFilter::Email(value.to_lowercase()))
+        }
+        (Users, "role") => Filter::Users(UsersFilter::Role(
+            value.parse::<Role>().map_err(|e| e.to_string())?,
+        )),
+        (Users | Students | Teachers, "verified") => {
+            Filter::Users(UsersFilter::Verified(boolean(value)?))
+        }
+        (Users | Students | Teachers, "suspended") => {
+            Filter::Users(UsersFilter::Suspended(boolean(value)?))
+        }
+        (Students, "advisor_id") => {
+            Filter::StudentAccount(StudentAccountFilter::AdvisorId(number(value)?))
+        }
+        (Students, "discipline") => {
+            Filter::StudentAccount(StudentAccountFilter::Discipline(value.to_string()))
+        }
+        (Students, "enrollment") => {
+            Filter::StudentAccount(StudentAccountFilter::Enrollment(value.to_string()))
+        }
+        (Students, "can_grad") => {
+            Filter::StudentAccount(StudentAccountFilter::CanGrad(boolean(value)?))
+        }
+        (Courses, "id") => Filter::Courses(CoursesFilter::Id(number(value)?)),
+        (Courses, "teacher_id") => Filter::Courses(CoursesFilter::TeacherId(number(value)?)),
+        (Courses, "course") => Filter::Courses(CoursesFilter::Course(value.to_string())),
+        (Courses, "cr_cost") => Filter::Courses(CoursesFilter::CrCost(number(value)?)),
+        (Departments, "id") => Filter::Departments(DepartmentsFilter::Id(number(value)?)),
+        (Departments, "name") => Filter::Departments(DepartmentsFilter::Name(value.to_string())),
+        _ => return Err(format!("'{}' cannot be filtered on here.", field)),
+    };
+
+    Ok(filter)
+}
+
+fn number(value: &str) -> Result<i32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a whole number.", value))
+}
+
+fn boolean(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("'{}' is not true or false.", value)),
+    }
+}
+
+fn names(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::table_models::{Courses, Departments};
+    use super::super::testing::TestDb;
+    use super::*;
+
+    fn rejected(listing: Listing, raw: &str) -> bool {
+        matches!(parse(listing, raw), Err(ApiError::Validation { .. }))
+    }
+
+    #[test]
+    fn parses_clauses_into_typed_filters() {
+        let filters = parse(Listing::Users, " role:eq:teacher , suspended:eq:false,").unwrap();
+        assert_eq!(filters.len(), 2);
+        assert!(matches!(filters[0], Filter::Users(UsersFilter::Role(Role::Teacher))));
+        assert!(matches!(filters[1], Filter::Users(UsersFilter::Suspended(false))));
+
+        let filters = parse(Listing::Students, "advisor_id:eq:7,email:eq:Student@AUBG.edu").unwrap();
+        assert!(matches!(filters[0], Filter::StudentAccount(StudentAccountFilter::AdvisorId(7))));
+        assert!(matches!(&filters[1], Filter::Users(UsersFilter::Email(e)) if e == "student@aubg.edu"));
+
+        assert!(parse(Listing::Courses, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn values_are_kept_verbatim_after_the_second_colon() {
+        let filters = parse(Listing::Courses, "course:eq:Logic: an intro' OR '1'='1").unwrap();
+        assert!(matches!(
+            &filters[0],
+            Filter::Courses(CoursesFilter::Course(c)) if c == "Logic: an intro' OR '1'='1"
+        ));
+    }
+
+    #[test]
+    fn rejects_what_the_language_does_not_allow() {
+        // shape and operators
+        assert!(rejected(Listing::Users, "role"));
+        assert!(rejected(Listing::Users, "role:teacher"));
+        assert!(rejected(Listing::Users, "role:ne:teacher"));
+        assert!(rejected(Listing::Users, "role:like:%"));
+
+        // values of the wrong type
+        assert!(rejected(Listing::Users, "id:eq:1 OR 1=1"));
+        assert!(rejected(Listing::Users, "verified:eq:1"));
+        assert!(rejected(Listing::Users, "role:eq:superuser"));
+        assert!(rejected(Listing::Courses, "cr_cost:eq:3.5"));
+
+        // fields outside the listing's whitelist
+        for field in ["password", "phone", "forcenewpw"] {
+            assert!(rejected(Listing::Users, &format!("{}:eq:x", field)), "{}", field);
+        }
+        assert!(rejected(Listing::Users, "advisor_id:eq:1"));
+        assert!(rejected(Listing::Teachers, "role:eq:student"));
+        assert!(rejected(Listing::Departments, "course:eq:Ethics"));
+
+        let too_many = vec!["id:eq:1"; MAX_CLAUSES + 1].join(",");
+        assert!(rejected(Listing::Courses, &too_many));
+        assert!(parse(Listing::Courses, &vec!["id:eq:1"; MAX_CLAUSES].join(",")).is_ok());
+    }
+
+    fn shape(fields: Option<&str>, include: Option<&str>) -> Result<CourseShape, ApiError> {
+        CourseQuery {
+            fields: fields.map(String::from),
+            include: include.map(String::from),
+        }
+        .shape()
+    }
+
+    #[test]
+    fn course_shapes_name_only_known_fields_and_relations() {
+        let s = shape(Some(" id, course ,"), Some("teacher")).unwrap();
+        assert_eq!(s.fields, Some(vec![String::from("id"), String::from("course")]));
+        assert!(s.teacher && !s.department);
+
+        // an empty list means every field, not none
+        assert!(shape(Some(" , "), None).unwrap().fields.is_none());
+
+        for (fields, include) in [
+            (Some("id,password"), None),
+            (Some("ID"), None),
+            (None, Some("teacher,students")),
+            (None, Some("teacher.password")),
+        ] {
+            assert!(
+                matches!(shape(fields, include), Err(ApiError::Validation { .. })),
+                "{:?} {:?}",
+                fields,
+                include
+            );
+        }
+    }
+
+    #[test]
+    fn unrequested_fields_are_dropped_but_relations_kept() {
+        let view = CourseView {
+            course: Courses {
+                id: 7,
+                teacher_id: 3,
+                course: String::from("Ethics"),
+                course_nr: String::from("PHI 101"),
+                description: String::from("Right and wrong"),
+                cr_cost: 3,
+                timeslots: String::from("MWF 10:00"),
+            },
+            teacher: None,
+            department: Some(Departments { id: 1, name: String::from("Philosophy") }),
+        };
+
+        let value = shape(Some("id,cr_cost"), Some("department")).unwrap().apply(&view).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"id": 7, "cr_cost": 3, "department": {"id": 1, "name": "Philosophy"}})
+        );
+
+        let whole = CourseShape::default().apply(&view).unwrap();
+        assert_eq!(whole.as_object().unwrap().len(), COURSE_FIELDS.len() + 1);
+    }
+
+    #[test]
+    fn filters_narrow_the_catalog() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        let mut conn = db.signed_in(&teacher);
+        for (name, cr_cost) in [("Ethics", 3), ("Logic", 4), ("Ethics' OR '1'='1", 3)] {
+            conn.register_courses(vec![Courses {
+                id: 0,
+                teacher_id: teacher.id,
+                course: name.to_owned(),
+                course_nr: String::from("PHI 101"),
+                description: String::new(),
+                cr_cost,
+                timeslots: String::from("TTh 12:00"),
+            }])
+            .unwrap();
+        }
+
+        let names = |raw: &str| -> Vec<String> {
+            let mut names: Vec<String> = conn
+                .list_courses(parse(Listing::Courses, raw).unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|c| c.course)
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(names("cr_cost:eq:4"), vec!["Logic"]);
+        let by_teacher = format!("teacher_id:eq:{},cr_cost:eq:3", teacher.id);
+        assert_eq!(names(&by_teacher), vec!["Ethics", "Ethics' OR '1'='1"]);
+        assert_eq!(names("course:eq:Ethics"), vec!["Ethics"]);
+        assert_eq!(names("course:eq:Ethics' OR '1'='1"), vec!["Ethics' OR '1'='1"]);
+        assert!(names("course:eq:x' OR '1'='1").is_empty());
+        assert!(names("teacher_id:eq:0").is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::anyhow;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Teacher,
+    Student,
+    // non-human accounts that only ever authenticate with API keys
+    Service,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Admin, Role::Teacher, Role::Student, Role::Service];
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::Teacher => write!(f, "teacher"),
+            Role::Student => write!(f, "student"),
+            Role::Service => write!(f, "service"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        Role::ALL
+            .into_iter()
+            .find(|r| r.to_string() == name)
+            .ok_or_else(|| anyhow!("Unknown role '{}'.", s))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: anyhow::Error| FromSqlError::Other(e.into()))
+    }
+}
+
+// What a role is allowed to do; the role-to-permission mapping itself lives in ROLE_PERMISSIONS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+pub enum Permission {
+    #[serde(rename = "account.update")]
+    AccountUpdate,
+    #[serde(rename = "account.delete")]
+    AccountDelete,
+    #[serde(rename = "user.update")]
+    UserUpdate,
+    #[serde(rename = "user.suspend")]
+    UserSuspend,
+    #[serde(rename = "user.role")]
+    UserChangeRole,
+    #[serde(rename = "user.delete")]
+    UserDelete,
+    #[serde(rename = "user.unlock")]
+    UserUnlock,
+    #[serde(rename = "course.create")]
+    CourseCreate,
+    #[serde(rename = "course.update")]
+    CourseUpdate,
+    #[serde(rename = "course.delete")]
+    CourseDelete,
+    #[serde(rename = "course.manage_any")]
+    CourseManageAny,
+    #[serde(rename = "grade.write")]
+    GradeWrite,
+    #[serde(rename = "enrollment.self")]
+    EnrollmentSelf,
+    #[serde(rename = "department.create")]
+    DepartmentCreate,
+    #[serde(rename = "department.delete")]
+    DepartmentDelete,
+    #[serde(rename = "department.manage")]
+    DepartmentManage,
+    #[serde(rename = "stats.read")]
+    StatsRead,
+    #[serde(rename = "security.audit")]
+    SecurityAudit,
+    #[serde(rename = "role.manage")]
+    RoleManage,
+    #[serde(rename = "admin.access")]
+    AdminAccess,
+    #[serde(rename = "admin.invite")]
+    AdminInvite,
+    #[serde(rename = "apikey.manage")]
+    ApiKeyManage,
+    #[serde(rename = "user.impersonate")]
+    UserImpersonate,
+    #[serde(rename = "session.manage")]
+    SessionManage,
+    #[serde(rename = "user.read")]
+    UserRead,
+    #[serde(rename = "roster.read")]
+    RosterRead,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 26] = [
+        Permission::AccountUpdate,
+        Permission::AccountDelete,
+        Permission::UserUpdate,
+        Permission::UserSuspend,
+        Permission::UserChangeRole,
+        Permission::UserDelete,
+        Permission::UserUnlock,
+        Permission::CourseCreate,
+        Permission::CourseUpdate,
+        Permission::CourseDelete,
+        Permission::CourseManageAny,
+        Permission::GradeWrite,
+        Permission::EnrollmentSelf,
+        Permission::DepartmentCreate,
+        Permission::DepartmentDelete,
+        Permission::DepartmentManage,
+        Permission::StatsRead,
+        Permission::SecurityAudit,
+        Permission::RoleManage,
+        Permission::AdminAccess,
+        Permission::AdminInvite,
+        Permission::ApiKeyManage,
+        Permission::UserImpersonate,
+        Permission::SessionManage,
+        Permission::UserRead,
+        Permission::RosterRead,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::AccountUpdate => "account.update",
+            Permission::AccountDelete => "account.delete",
+            Permission::UserUpdate => "user.update",
+            Permission::UserSuspend => "user.suspend",
+            Permission::UserChangeRole => "user.role",
+            Permission::UserDelete => "user.delete",
+            Permission::UserUnlock => "user.unlock",
+            Permission::CourseCreate => "course.create",
+            Permission::CourseUpdate => "course.update",
+            Permission::CourseDelete => "course.delete",
+            Permission::CourseManageAny => "course.manage_any",
+            Permission::GradeWrite => "grade.write",
+            Permission::EnrollmentSelf => "enrollment.self",
+            Permission::DepartmentCreate => "department.create",
+            Permission::DepartmentDelete => "department.delete",
+            Permission::DepartmentManage => "department.manage",
+            Permission::StatsRead => "stats.read",
+            Permission::SecurityAudit => "security.audit",
+            Permission::RoleManage => "role.manage",
+            Permission::AdminAccess => "admin.access",
+            Permission::AdminInvite => "admin.invite",
+            Permission::ApiKeyManage => "apikey.manage",
+            Permission::UserImpersonate => "user.impersonate",
+            Permission::SessionManage => "session.manage",
+            Permission::UserRead => "user.read",
+            Permission::RosterRead => "roster.read",
+        }
+    }
+
+    // Human readable action, used to build "You do not have permission to ..." errors
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Permission::AccountUpdate => "update your account",
+            Permission::AccountDelete => "delete your account",
+            Permission::UserUpdate => "update other users",
+            Permission::UserSuspend => "suspend users",
+            Permission::UserChangeRole => "change user roles",
+            Permission::UserDelete => "delete other users",
+            Permission::UserUnlock => "unlock accounts",
+            Permission::CourseCreate => "register courses",
+            Permission::CourseUpdate => "update courses",
+            Permission::CourseDelete => "remove courses",
+            Permission::CourseManageAny => "manage courses on someone else's behalf",
+            Permission::GradeWrite => "grade students",
+            Permission::EnrollmentSelf => "enroll in courses",
+            Permission::DepartmentCreate => "create departments",
+            Permission::DepartmentDelete => "remove departments",
+            Permission::DepartmentManage => "manage department membership",
+            Permission::StatsRead => "view statistics",
+            Permission::SecurityAudit => "review login and audit activity",
+            Permission::RoleManage => "manage role permissions",
+            Permission::AdminAccess => "access the administration panel",
+            Permission::AdminInvite => "invite administrators",
+            Permission::ApiKeyManage => "manage service accounts and API keys",
+            Permission::UserImpersonate => "view the site as another user",
+            Permission::SessionManage => "manage other users' sessions",
+            Permission::UserRead => "view the user directory",
+            Permission::RosterRead => "view the students enrolled in your courses",
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .iter()
+            .find(|p| p.as_str() == s.trim())
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown permission '{}'.", s))
+    }
+}
+
+impl FromSql for Permission {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: anyhow::Error| FromSqlError::Other(e.into()))
+    }
+}
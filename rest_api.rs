@@ -1,223 +1,484 @@
-use actix_web::{delete, get, patch, post, HttpRequest, HttpResponse, Responder};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie, SameSite};
+use actix_web::http::header::{ETag, EntityTag};
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde_derive::Deserialize;
 use serde_json::{json, Value};
+use std::sync::Arc;
+use utoipa::IntoParams;
 
-use crate::backend::table_models::{User, TeacherAccount};
+use crate::connect_macro as connect;
+use crate::enrollment_login_macro as enrollment_login;
 use crate::login_macro as login;
+use crate::optional_login_macro as optional_login;
 
 use super::{
-    filter::{Filter, UsersFilter},
+    conditional,
+    config::Config,
+    dto::*,
+    error::ApiError,
+    filter::{CoursesFilter, Filter, UsersFilter},
+    health::{self, Readiness},
+    metrics, oidc,
+    openapi::{ErrorBody, Message},
+    query::{CourseQuery, CourseShape, ListQuery, Listing},
+    rbac::{Permission, Role},
     server_connection_impl::*,
-    table_models::Courses,
+    table_models::{
+        AdminInvitation, ApiKey as StoredApiKey, AuditEntry, Courses, Departments, LoginAttempt,
+        LoginThrottle, RolePermission, Table,
+    },
+    tokens,
 };
 
+// Carries the state of a pending SSO login in the browser that started it, so a callback
+// URL someone else obtained cannot sign this browser in to their account
+const SSO_STATE_COOKIE: &str = "ums_sso_state";
+
+// The configuration main() registered as app data; defaults only if it is missing
+fn app_config(req: &HttpRequest) -> Arc<Config> {
+    req.app_data::<web::Data<Config>>()
+        .map(|c| c.clone().into_inner())
+        .unwrap_or_default()
+}
+
+// Opens a connection that knows which client it is serving
+fn open_connection(req: &HttpRequest) -> Result<ServerConnection, ApiError> {
+    let mut conn = ServerConnection::new(app_config(req))?;
+    if let Some(addr) = req.peer_addr() {
+        conn.set_client_ip(&addr.ip().to_string());
+    }
+    if let Some(agent) = req.headers().get("user-agent").and_then(|a| a.to_str().ok()) {
+        conn.set_client_agent(agent);
+    }
+    conn.set_request_line(req.method().as_str(), req.path());
+
+    Ok(conn)
+}
+
+// Rejects a body that fails validation, listing every offending field
+fn invalid<T: Validate>(body: &T) -> Option<HttpResponse> {
+    let errors = body.validate();
+    if errors.is_empty() {
+        return None;
+    }
+
+    Some(ApiError::invalid_fields(errors).error_response())
+}
+
+// The caller, as far as other users' fields go
+fn viewer(conn: &ServerConnection) -> Viewer {
+    Viewer {
+        id: conn.current_user().map(|u| u.id),
+        reads_users: conn.can(Permission::UserRead),
+    }
+}
+
+// Body limits and error format for every web::Json extractor; registered in main()
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(64 * 1024)
+        .error_handler(|err, _req| ApiError::validation(format!("Invalid request body: {}", err)).into())
+}
+
+// Same error format for every web::Query extractor; registered in main()
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err, _req| ApiError::validation(format!("Invalid query string: {}", err)).into())
+}
+
+// Shared by every way of signing in: the token always travels in the session_token header
+fn login_response(conn: &ServerConnection, outcome: LoginOutcome) -> HttpResponse {
+    match outcome {
+        LoginOutcome::Authenticated(token) => HttpResponse::Ok()
+            .insert_header(("session_token", token))
+            .json(conn.current_user().map(PrivateUser::own)),
+        LoginOutcome::TwoFactorRequired(token) => HttpResponse::Ok()
+            .insert_header(("session_token", token))
+            .json(json!({"mfa_required": true})),
+        LoginOutcome::TwoFactorEnrollmentRequired(token) => HttpResponse::Ok()
+            .insert_header(("session_token", token))
+            .json(json!({"mfa_enrollment_required": true})),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "health",
+    responses(
+        (status = 200, description = "The server is up"),
+    ),
+    security(()),
+)]
 #[get("/")]
 pub async fn index() -> impl Responder {
     HttpResponse::Ok().json(json!({"success": true}))
 }
 
+// Liveness: answering at all is the whole check
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The process is alive"),
+    ),
+    security(()),
+)]
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({"status": "ok"}))
+}
+
+// Readiness: 503 with the failing checks until the database is usable
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready to serve requests", body = Readiness),
+        (status = 503, description = "A check failed", body = Readiness),
+    ),
+    security(()),
+)]
+#[get("/readyz")]
+pub async fn readyz(req: HttpRequest) -> impl Responder {
+    let report = health::readiness(&app_config(&req));
+
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses(
+        (status = 200, description = "Prometheus text exposition format", body = String),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "No metrics token is configured, so metrics are not served", body = ErrorBody),
+    ),
+    security(("metrics_token" = [])),
+)]
+#[get("/metrics")]
+pub async fn get_metrics(req: HttpRequest) -> impl Responder {
+    // sign-in outcomes and traffic are not for everyone, so without a token nothing is served
+    let Some(token) = &app_config(&req).metrics.token else {
+        return ApiError::not_found("Metrics are disabled.").error_response();
+    };
+
+    let presented = req
+        .headers()
+        .get("authorization")
+        .and_then(|a| a.to_str().ok())
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // compared as hashes so the check takes the same time however much matches
+    if tokens::hash_token(presented) != tokens::hash_token(token) {
+        return ApiError::unauthenticated("Unauthorized").error_response();
+    }
+
+    // domain gauges are refreshed on scrape rather than kept in step with every write
+    let conn = connect!(req);
+    match conn.enrolled_students_per_semester() {
+        Ok(counts) => metrics::set_enrolled_students(&counts),
+        Err(e) => tracing::warn!(error = %e, "failed to refresh enrollment metrics"),
+    }
+
+    match metrics::render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "Every user with user.read; with only roster.read, the students in the caller's courses; anyone else only finds themselves", body = [UserView]),
+        (status = 400, description = "Invalid filter", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
 #[get("/users")]
-pub async fn get_users() -> impl Responder {
-    let conn = ServerConnection::new();
-    let users = conn.get_users();
+pub async fn get_users(req: HttpRequest, query: web::Query<ListQuery>) -> impl Responder {
+    let mut conn = connect!(req);
+    optional_login!(req, conn);
+
+    let filters = match query.filters(Listing::Users) {
+        Ok(f) => f,
+        Err(e) => return e.error_response(),
+    };
+
+    let users = conn.list_users(None, filters);
     match users {
-        Ok(u) => {
-            let json = serde_json::to_string(&u);
-            match json {
-                Ok(j) => HttpResponse::Ok().body(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(u) => HttpResponse::Ok().json(UserView::list(&u, viewer(&conn))),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/students",
+    tag = "users",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "Every student with user.read; with only roster.read, the students in the caller's courses; anyone else only finds themselves", body = [UserView]),
+        (status = 400, description = "Invalid filter", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
 #[get("/students")]
-pub async fn get_students() -> impl Responder {
-    let conn = ServerConnection::new();
-    let students = conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-        "student".to_string(),
-    ))]);
+pub async fn get_students(req: HttpRequest, query: web::Query<ListQuery>) -> impl Responder {
+    let mut conn = connect!(req);
+    optional_login!(req, conn);
+
+    let filters = match query.filters(Listing::Students) {
+        Ok(f) => f,
+        Err(e) => return e.error_response(),
+    };
+
+    let students = conn.list_users(Some(Role::Student), filters);
     match students {
-        Ok(s) => {
-            let json = serde_json::to_string(&s);
-            match json {
-                Ok(j) => HttpResponse::Ok().body(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(s) => HttpResponse::Ok().json(UserView::list(&s, viewer(&conn))),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/teachers",
+    tag = "users",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "Every teacher with user.read; roster.read alone is refused, and anyone else only finds themselves", body = [UserView]),
+        (status = 400, description = "Invalid filter", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
 #[get("/teachers")]
-pub async fn get_teachers() -> impl Responder {
-    let conn = ServerConnection::new();
-    let teachers = conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-        "teacher".to_string(),
-    ))]);
+pub async fn get_teachers(req: HttpRequest, query: web::Query<ListQuery>) -> impl Responder {
+    let mut conn = connect!(req);
+    optional_login!(req, conn);
+
+    let filters = match query.filters(Listing::Teachers) {
+        Ok(f) => f,
+        Err(e) => return e.error_response(),
+    };
+
+    let teachers = conn.list_users(Some(Role::Teacher), filters);
     match teachers {
-        Ok(t) => {
-            let json = serde_json::to_string(&t);
-            match json {
-                Ok(j) => HttpResponse::Ok().body(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(t) => HttpResponse::Ok().json(UserView::list(&t, viewer(&conn))),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/departments",
+    tag = "departments",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "Every department", body = [Departments], headers(("ETag"), ("Last-Modified"))),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Invalid filter", body = ErrorBody),
+    ),
+    security(()),
+)]
 #[get("/departments")]
-pub async fn get_departments() -> impl Responder {
-    let conn = ServerConnection::new();
-    let departments = conn.get_departments();
+pub async fn get_departments(req: HttpRequest, query: web::Query<ListQuery>) -> impl Responder {
+    let conn = connect!(req);
+
+    let filters = match query.filters(Listing::Departments) {
+        Ok(f) => f,
+        Err(e) => return e.error_response(),
+    };
+
+    let last_modified = match conn.last_changed(&[Table::Departments]) {
+        Ok(t) => t,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
+
+    let departments = conn.get_departments(filters);
     match departments {
-        Ok(d) => {
-            let json = serde_json::to_string(&d);
-            match json {
-                Ok(j) => HttpResponse::Ok().body(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(d) => conditional::cached_json(&req, &d, last_modified),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/departments/{id}",
+    tag = "departments",
+    params(
+        ("id" = i32, Path, description = "Department id"),
+    ),
+    responses(
+        (status = 200, description = "The department", body = Departments),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "Department not found", body = ErrorBody),
+    ),
+    security(()),
+)]
 #[get("/departments/{id}")]
 pub async fn get_department(req: HttpRequest) -> impl Responder {
-    let conn = ServerConnection::new();
-    let request_headers = req.headers();
-    let id = match request_headers.get("id") {
-        Some(id) => id,
-        None => return HttpResponse::BadRequest().json(json!({"error": "Missing department id."})),
-    }
-    .to_str()
-    .unwrap();
+    let conn = connect!(req);
 
-    let id = match id.parse::<i32>() {
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
         Ok(id) => id,
         Err(_) => {
-            return HttpResponse::BadRequest().json(json!({"error": "Invalid department id."}))
+            return ApiError::validation("Invalid department id.").error_response()
         }
     };
 
     let department = conn.get_department(id);
     match department {
-        Ok(d) => {
-            let json = serde_json::to_string(&d);
-            match json {
-                Ok(j) => HttpResponse::Ok().body(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(d) => HttpResponse::Ok().json(d),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/departments",
+    tag = "departments",
+    request_body = NewDepartment,
+    responses(
+        (status = 200, description = "Department created", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 409, description = "A department with this name exists", body = ErrorBody),
+    ),
+)]
 #[post("/departments")]
-pub async fn new_department(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+pub async fn new_department(req: HttpRequest, body: web::Json<NewDepartment>) -> impl Responder {
+    let mut conn = connect!(req);
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::DepartmentCreate) {
+        return ApiError::from(e).error_response();
     }
 
-    let department = request_headers.get("name").unwrap().to_str().unwrap();
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
 
-    let department = conn.new_department(department);
+    let department = conn.new_department(body.name.trim());
     match department {
         Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully created department."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/departments/{id}",
+    tag = "departments",
+    params(
+        ("id" = i32, Path, description = "Department id"),
+    ),
+    responses(
+        (status = 200, description = "Department deleted", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Department not found", body = ErrorBody),
+    ),
+)]
 #[delete("/departments/{id}")]
 pub async fn delete_department(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    let mut conn = connect!(req);
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::DepartmentDelete) {
+        return ApiError::from(e).error_response();
     }
 
-    let department = req.match_info().get("id").unwrap_or_else(|| "0");
+    let department = req.match_info().get("id").unwrap_or("0");
     let department = department.parse::<i32>().unwrap_or_default();
 
     if department == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing department id."}));
+        return ApiError::validation("Missing department id.").error_response();
     }
 
     let department = conn.get_department(department);
     let department = match department {
         Ok(d) => d,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
     let department = conn.remove_department(department);
     match department {
         Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully deleted department."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/admin/department/{id}",
+    tag = "departments",
+    params(
+        ("id" = i32, Path, description = "Department id"),
+    ),
+    request_body = DepartmentMember,
+    responses(
+        (status = 200, description = "Teacher added to the department", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Department or teacher not found", body = ErrorBody),
+    ),
+)]
 #[post("/admin/department/{id}")]
-pub async fn invite_to_department(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+pub async fn invite_to_department(req: HttpRequest, body: web::Json<DepartmentMember>) -> impl Responder {
+    let mut conn = connect!(req);
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::DepartmentManage) {
+        return ApiError::from(e).error_response();
     }
 
-    let department = req.match_info().get("id").unwrap();
+    let department = req.match_info().get("id").unwrap_or_default();
     let department = department.parse::<i32>().unwrap_or_default();
 
     if department == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing department id."}));
-    }
-
-    let teacher = request_headers.get("teacher_id");
-
-    if teacher.is_none() {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing teacher id."}));
+        return ApiError::validation("Missing department id.").error_response();
     }
 
-    let teacher = teacher.unwrap().to_str().unwrap();
-    let teacher = teacher.parse::<i32>().unwrap_or_default();
-
-    if teacher == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Invalid teacher id."}));
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
     }
+    let teacher = body.teacher_id;
 
     let department = conn.get_department(department);
     let department = match department {
         Ok(d) => d,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
     let teachers = conn.get_teacher_accounts();
     let mut teacher = match teachers {
-        Ok(t) => match t
-            .into_iter()
-            .filter(|t| t.teacher_id == teacher)
-            .collect::<Vec<_>>()
-        {
-            v if v.is_empty() => {
-                return HttpResponse::BadRequest().json(json!({"error": "Teacher not found."}))
-            }
-            v => v[0].to_owned(),
+        Ok(t) => match t.into_iter().find(|t| t.teacher_id == teacher) {
+            Some(t) => t,
+            None => return ApiError::not_found("Teacher not found.").error_response(),
         },
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
     teacher.dept_id = department.id;
@@ -227,54 +488,56 @@ pub async fn invite_to_department(req: HttpRequest) -> impl Responder {
     match invitation {
         Ok(_) => HttpResponse::Ok()
             .json(json!({"message": "Successfully invited teacher to department."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/admin/department/{id}",
+    tag = "departments",
+    params(
+        ("id" = i32, Path, description = "Department id"),
+    ),
+    request_body = DepartmentMember,
+    responses(
+        (status = 200, description = "Teacher removed from the department", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Teacher not found", body = ErrorBody),
+    ),
+)]
 #[delete("/admin/department/{id}")]
-pub async fn kick_from_department(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+pub async fn kick_from_department(req: HttpRequest, body: web::Json<DepartmentMember>) -> impl Responder {
+    let mut conn = connect!(req);
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::DepartmentManage) {
+        return ApiError::from(e).error_response();
     }
 
-    let department = req.match_info().get("id").unwrap();
+    let department = req.match_info().get("id").unwrap_or_default();
     let department = department.parse::<i32>().unwrap_or_default();
 
     if department == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing department id."}));
-    }
-
-    let teacher = request_headers.get("teacher_id");
-    if teacher.is_none() {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing teacher id."}));
+        return ApiError::validation("Missing department id.").error_response();
     }
 
-    let teacher = teacher.unwrap().to_str().unwrap();
-    let teacher = teacher.parse::<i32>().unwrap_or_default();
-
-    if teacher == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Invalid teacher id."}));
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
     }
+    let teacher = body.teacher_id;
 
     let teachers = conn.get_teacher_accounts();
     let mut teacher = match teachers {
-        Ok(t) => match t
-            .into_iter()
-            .filter(|t| t.teacher_id == teacher)
-            .collect::<Vec<_>>()
-        {
-            v if v.is_empty() => {
-                return HttpResponse::BadRequest().json(json!({"error": "Teacher not found."}))
-            }
-            v => v[0].to_owned(),
+        Ok(t) => match t.into_iter().find(|t| t.teacher_id == teacher) {
+            Some(t) => t,
+            None => return ApiError::not_found("Teacher not found.").error_response(),
         },
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
     teacher.dept_id = 0;
@@ -284,1043 +547,2574 @@ pub async fn kick_from_department(req: HttpRequest) -> impl Responder {
     match invitation {
         Ok(_) => HttpResponse::Ok()
             .json(json!({"message": "Successfully kicked teacher from department."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/courses",
+    tag = "courses",
+    params(ListQuery, CourseQuery),
+    responses(
+        (status = 200, description = "The catalog, with the requested fields and relations", body = [CourseView], headers(("ETag"), ("Last-Modified"))),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Invalid filter, field or relation", body = ErrorBody),
+    ),
+    security(()),
+)]
 #[get("/courses")]
-pub async fn get_courses() -> impl Responder {
-    let conn = ServerConnection::new();
-
-    let courses = match conn.search_courses("".to_string()) {
-        Ok(c) => {
-            let json = serde_json::to_string(&c);
-            match json {
-                Ok(j) => j,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-    };
-
-    let users = match conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-        "teacher".to_string(),
-    ))]) {
-        Ok(t) => {
-            let json = serde_json::to_string(&t);
-            match json {
-                Ok(j) => j,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+pub async fn get_courses(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    view: web::Query<CourseQuery>,
+) -> impl Responder {
+    let conn = connect!(req);
+
+    let filters = match query.filters(Listing::Courses) {
+        Ok(f) => f,
+        Err(e) => return e.error_response(),
     };
 
-    let departments = match conn.get_departments() {
-        Ok(d) => {
-            let json = serde_json::to_string(&d);
-            match json {
-                Ok(j) => j,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let shape = match view.shape() {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
     };
 
-    let teacher_accounts = match conn.get_teacher_accounts() {
-        Ok(t) => {
-            let json = serde_json::to_string(&t);
-            match json {
-                Ok(j) => j,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let courses = match conn.list_courses(filters) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
-    let json_prep = format!(
-        "{{\"courses\": {}, \"users\": {}, \"teacher_accounts\": {}, \"departments\": {}}}",
-        courses, users, teacher_accounts, departments
-    );
+    let last_modified = match conn.last_changed(&catalog_tables(&shape)) {
+        Ok(t) => t,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
-    match serde_json::from_str::<Value>(&json_prep) {
-        Ok(json3) => HttpResponse::Ok().json(json3),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    match course_views(&conn, courses, &shape) {
+        Ok(v) => conditional::cached_json(&req, &v, last_modified),
+        Err(e) => e.error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/courses/{id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+        CourseQuery,
+    ),
+    responses(
+        (status = 200, description = "The course, with the requested fields and relations", body = CourseView, headers(("ETag"), ("Last-Modified"))),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "Course not found", body = ErrorBody),
+    ),
+    security(()),
+)]
 #[get("/courses/{id}")]
-pub async fn get_course(req: HttpRequest) -> impl Responder {
-    let conn = ServerConnection::new();
-    let id = req.match_info().get("id").unwrap_or_else(|| "0");
+pub async fn get_course(req: HttpRequest, view: web::Query<CourseQuery>) -> impl Responder {
+    let conn = connect!(req);
 
-    if id == "0" {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing course id."}));
-    }
+    let id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid course id.").error_response(),
+    };
 
-    let course = match conn.search_courses(id.to_string()) {
-        Ok(c) => c[0].to_owned(),
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let shape = match view.shape() {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
     };
 
-    let user = match conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-        "teacher".to_string(),
-    ))]) {
-        Ok(t) => {
-            let u = t
-                .iter()
-                .filter_map(|u| {
-                    if course.teacher_id == u.id {
-                        Some(u.to_owned())
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
+    let course = match conn.list_courses(vec![Filter::Courses(CoursesFilter::Id(id))]) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
-            match u.get(0) {
-                Some(u) => u.to_owned(),
-                None => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": "A teacher with this ID does not exist."}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let last_modified = match conn.last_changed(&catalog_tables(&shape)) {
+        Ok(t) => t,
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
-    let teacher_account = match conn.get_teacher_accounts() {
-        Ok(t) => {
-            let a = t
-                .into_iter()
-                .filter_map(|t| {
-                    if course.teacher_id == t.teacher_id {
-                        Some(t.to_owned())
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
+    match course_views(&conn, course, &shape).map(|v| v.into_iter().next()) {
+        Ok(Some(v)) => conditional::cached_json(&req, &v, last_modified),
+        Ok(None) => ApiError::not_found("Course not found.").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
 
-            match a.get(0) {
-                Some(a) => a.to_owned(),
-                None => {
-                    return HttpResponse::InternalServerError().json(
-                        json!({"error": "A teacher account with this Teacher ID does not exist."}),
-                    )
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-    };
+// What a catalog response with this shape is read from, for its Last-Modified
+fn catalog_tables(shape: &CourseShape) -> Vec<Table> {
+    let mut tables = vec![Table::Courses];
+    if shape.teacher {
+        tables.push(Table::Users);
+    }
+    if shape.department {
+        tables.extend([Table::TeacherAccount, Table::Departments]);
+    }
+    tables
+}
 
-    let departments = match conn.get_departments() {
-        Ok(d) => {
-            let dep = d
-                .into_iter()
-                .filter_map(|d| {
-                    if teacher_account.dept_id == d.id {
-                        Some(d)
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
+// The ETag GET /courses/{id} sends for this course without ?fields= or ?include=;
+// what If-Match on PATCH /courses/{id} is checked against
+fn course_tag(conn: &ServerConnection, course: &Courses) -> Result<EntityTag, ApiError> {
+    let view = course_views(conn, vec![course.clone()], &CourseShape::default())?;
+    conditional::etag(&view.into_iter().next())
+}
 
-            match dep.get(0) {
-                Some(d) => d.to_owned(),
-                None => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": "A department with such ID does not exist."}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+// The catalog entries for `courses`, loading only the relations the shape asks for
+fn course_views(
+    conn: &ServerConnection,
+    courses: Vec<Courses>,
+    shape: &CourseShape,
+) -> Result<Vec<Value>, ApiError> {
+    let teachers = if shape.teacher {
+        conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Role(Role::Teacher))])?
+    } else {
+        Vec::new()
     };
 
-    let course = serde_json::to_string(&course).unwrap();
-    let user = serde_json::to_string(&user).unwrap();
-    let teacher_account = serde_json::to_string(&teacher_account).unwrap();
-    let departments = serde_json::to_string(&departments).unwrap();
+    let (accounts, departments) = if shape.department {
+        (conn.get_teacher_accounts()?, conn.get_departments(vec![])?)
+    } else {
+        (Vec::new(), Vec::new())
+    };
 
-    let json_prep = format!(
-        "{{\"course\": {}, \"user\": {}, \"teacher_account\": {}, \"department\": {}}}",
-        course, user, teacher_account, departments
-    );
+    courses
+        .into_iter()
+        .map(|course| {
+            let teacher = teachers
+                .iter()
+                .find(|t| t.id == course.teacher_id)
+                .map(|t| UserView::new(t, viewer(conn)));
 
-    match serde_json::from_str::<Value>(&json_prep) {
-        Ok(j) => HttpResponse::Ok().json(j),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-    }
+            let department = accounts
+                .iter()
+                .find(|a| a.teacher_id == course.teacher_id)
+                .and_then(|a| departments.iter().find(|d| d.id == a.dept_id))
+                .cloned();
+
+            Ok(shape.apply(&CourseView {
+                course,
+                teacher,
+                department,
+            })?)
+        })
+        .collect()
 }
 
+#[utoipa::path(
+    post,
+    path = "/courses",
+    tag = "courses",
+    request_body = NewCourse,
+    responses(
+        (status = 200, description = "Course created", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
 #[post("/courses")]
-pub async fn new_course(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+pub async fn new_course(req: HttpRequest, body: web::Json<NewCourse>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    login!(req, conn);
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
-
-    let course = request_headers.get("name");
-    let description = request_headers.get("description");
-    let course_nr = request_headers.get("course_nr");
-    let teacher_id = request_headers.get("id");
-    let cr_cost = request_headers.get("cr_cost");
-    let timeslots = request_headers.get("timeslots");
-
-    if course.is_none()
-        || teacher_id.is_none()
-        || course_nr.is_none()
-        || cr_cost.is_none()
-        || timeslots.is_none()
-    {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing required data."}));
-    }
-
-    let course = course.unwrap().to_str().unwrap().to_string();
-    let description = description
-        .unwrap()
-        .to_str()
-        .unwrap_or("No description.")
-        .to_string();
-    let course_nr = course_nr.unwrap().to_str().unwrap().to_string();
-    let teacher_id = teacher_id
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .parse::<i32>()
-        .unwrap_or(0);
-    let cr_cost = cr_cost
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .parse::<i32>()
-        .unwrap_or(0);
-    let timeslots = timeslots.unwrap().to_str().unwrap().to_string();
-
-    if teacher_id == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Invalid teacher id."}));
-    }
-
-    if cr_cost == 0 {
-        return HttpResponse::BadRequest().json(json!({"error": "Invalid course cost."}));
-    }
-
-    let course = Courses {
-        id: 0, // This will be set by the database.
-        description,
-        teacher_id,
-        course,
-        course_nr,
-        cr_cost,
-        timeslots,
-    };
-
-    match conn.register_courses(vec![course]) {
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
+
+    match conn.register_courses(vec![body.into_course()]) {
         Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully registered course."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/courses/{id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+    ),
+    responses(
+        (status = 200, description = "Course removed", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Course not found", body = ErrorBody),
+    ),
+)]
 #[delete("/courses/{id}")]
 pub async fn remove_course(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let id = req.match_info().get("id").unwrap();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-
-    login!(login_email, login_password, conn);
-
-    let find_course = conn.search_courses(id.to_string());
-
-    match find_course {
-        Ok(c) => {
-            if c.len() == 0 {
-                return HttpResponse::BadRequest().json(json!({"error": "Course not found."}));
-            }
-
-            if c.len() > 1 {
-                return HttpResponse::InternalServerError()
-                    .json(json!({"error": "Multiple courses found."}));
-            }
+    let mut conn = connect!(req);
+    let id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid course id.").error_response(),
+    };
 
-            let course = c.get(0).unwrap().clone();
+    login!(req, conn);
 
-            match conn.remove_courses(vec![course]) {
-                Ok(_) => {
-                    HttpResponse::Ok().json(json!({"message": "Successfully removed course."}))
-                }
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
+    let course = match conn.course_by_id(id) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-        }
+    match conn.remove_courses(vec![course]) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully removed course."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    patch,
+    path = "/courses/{id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the course as last fetched from GET /courses/{id} without ?fields= or ?include="),
+    ),
+    request_body = CourseChanges,
+    responses(
+        (status = 200, description = "Course updated", body = Message, headers(("ETag" = String, description = "The course's new ETag"))),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Course not found", body = ErrorBody),
+        (status = 412, description = "The course changed since the ETag in If-Match", body = ErrorBody),
+    ),
+)]
 #[patch("/courses/{id}")]
-pub async fn update_course(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+pub async fn update_course(req: HttpRequest, body: web::Json<CourseChanges>) -> impl Responder {
+    let mut conn = connect!(req);
+    let id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid course id.").error_response(),
+    };
 
-    let name = request_headers.get("name");
-    let description = request_headers.get("description");
-    let course_nr = request_headers.get("course_nr");
-    let teacher_id = request_headers.get("id");
-    let cr_cost = request_headers.get("cr_cost");
-    let timeslots = request_headers.get("timeslots");
-
-    let id = req.match_info().get("id").unwrap();
-
-    if teacher_id.is_none()
-        || cr_cost.is_none()
-        || timeslots.is_none()
-        || name.is_none()
-        || course_nr.is_none()
-    {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing data."}));
-    }
-
-    let teacher_id = teacher_id.unwrap().to_str().unwrap();
-    let cr_cost = cr_cost.unwrap().to_str().unwrap();
-    let timeslots = timeslots.unwrap().to_str().unwrap();
-    let name = name.unwrap().to_str().unwrap();
-    let course_nr = course_nr.unwrap().to_str().unwrap().to_string();
-    let description = description
-        .unwrap()
-        .to_str()
-        .unwrap_or("No description.")
-        .to_string();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-
-    login!(login_email, login_password, conn);
-
-    let find_course = conn.search_courses(id.to_string());
-
-    match find_course {
-        Ok(c) => {
-            if c.len() == 0 {
-                return HttpResponse::BadRequest().json(json!({"error": "Course not found."}));
-            }
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
 
-            if c.len() > 1 {
-                return HttpResponse::InternalServerError()
-                    .json(json!({"error": "Multiple courses found."}));
-            }
+    login!(req, conn);
+
+    let mut course = match conn.list_courses(vec![Filter::Courses(CoursesFilter::Id(id))]) {
+        Ok(c) => match c.into_iter().next() {
+            Some(c) => c,
+            None => return ApiError::not_found("Course not found.").error_response(),
+        },
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
-            let mut course = c.get(0).unwrap().clone();
+    // refuse to overwrite an edit the caller has not seen
+    if let Err(e) = course_tag(&conn, &course).and_then(|t| conditional::check_if_match(&req, &t)) {
+        return e.error_response();
+    }
 
-            course.course = name.to_string();
-            course.description = description;
-            course.course_nr = course_nr;
-            course.cr_cost = cr_cost.parse::<i32>().unwrap();
-            course.timeslots = timeslots.to_string();
-            course.teacher_id = teacher_id.parse::<i32>().unwrap();
+    // the write itself also checks that the row is still what was tagged, so an edit landing
+    // between the check above and here is not overwritten either
+    let seen = course.clone();
+    body.apply(&mut course);
 
-            match conn.update_courses(vec![course]) {
-                Ok(_) => {
-                    HttpResponse::Ok().json(json!({"message": "Successfully updated course."}))
-                }
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
-        }
+    if let Err(e) = conn.update_course(&seen, course.clone()) {
+        return ApiError::from(e).error_response();
+    }
 
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-        }
+    match course_tag(&conn, &course) {
+        Ok(t) => HttpResponse::Ok()
+            .insert_header(ETag(t))
+            .json(json!({"message": "Successfully updated course."})),
+        Err(e) => e.error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/admin",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The caller may use the admin area", body = Message),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
 #[get("/admin")]
 pub async fn admin(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    let mut conn = connect!(req);
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::AdminAccess) {
+        return ApiError::from(e).error_response();
     }
 
-    return HttpResponse::Ok().json(json!({"message": "Success"}));
+    HttpResponse::Ok().json(json!({"message": "Success"}))
 }
 
+#[utoipa::path(
+    patch,
+    path = "/admin/users/{id}",
+    tag = "users",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    request_body = UserChanges,
+    responses(
+        (status = 200, description = "The updated user", body = UserView),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+    ),
+)]
 #[patch("/admin/users/{id}")]
-pub async fn update_user(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
+pub async fn update_user(req: HttpRequest, body: web::Json<UserChanges>) -> impl Responder {
+    let mut conn = connect!(req);
 
-    login!(login_email, login_password, conn);
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    login!(req, conn);
+    if let Err(e) = conn.authorize(Permission::UserUpdate) {
+        return ApiError::from(e).error_response();
     }
 
-    let id = match req.match_info().get("id").unwrap().parse::<i32>() {
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
         Ok(i) => i,
-        Err(_) => return HttpResponse::BadRequest().json(json!({"error": "Invalid id."})),
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
     };
 
-    let mut lookup_user = match conn.search_users(format!("{}", id)) {
-        Ok(u) => match u.get(0) {
-            Some(u) => u.to_owned(),
-            None => return HttpResponse::BadRequest().json(json!({"error": "User not found."})),
-        },
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-        }
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
     }
-    .to_owned();
-
-    let mut username = match request_headers.get("username") {
-        Some(u) => u.to_str().unwrap().to_string(),
-        None => String::new(),
-    };
-    let password = match request_headers.get("password") {
-        Some(p) => p.to_str().unwrap().to_string(),
-        None => String::new(),
-    };
-    let mut email = match request_headers.get("email") {
-        Some(e) => e.to_str().unwrap().to_string(),
-        None => String::new(),
-    };
-    let mut phone = match request_headers.get("phone") {
-        Some(p) => p.to_str().unwrap().to_string(),
-        None => String::new(),
-    };
-    let verified = match request_headers.get("verified") {
-        Some(v) => match v.to_str().unwrap().parse::<bool>() {
-            Ok(b) => b,
-            Err(_) => {
-                return HttpResponse::BadRequest().json(json!({"error": "Invalid verified."}))
-            }
-        },
-        None => lookup_user.verified,
-    };
-
-    let suspended = match request_headers.get("suspended") {
-        Some(s) => match s.to_str().unwrap().parse::<bool>() {
-            Ok(b) => b,
-            Err(_) => {
-                return HttpResponse::BadRequest().json(json!({"error": "Invalid suspended."}))
-            }
-        },
-        None => lookup_user.suspended,
-    };
-
-    let forcenewpw = match request_headers.get("forcenewpw") {
-        Some(f) => match f.to_str().unwrap().parse::<bool>() {
-            Ok(b) => b,
-            Err(_) => {
-                return HttpResponse::BadRequest().json(json!({"error": "Invalid forcenewpw."}))
-            }
-        },
-        None => lookup_user.forcenewpw,
-    };
 
-    let mut role = match request_headers.get("role") {
-        Some(r) => r.to_str().unwrap().to_string(),
-        None => String::new(),
+    let mut lookup_user = match conn.user_by_id(id) {
+        Ok(u) => u,
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
-    if username == "" {
-        username = lookup_user.username;
-    }
-    if email == "" {
-        email = lookup_user.email;
-    }
-    if phone == "" {
-        phone = lookup_user.phone;
-    }
-    if role == "" {
-        role = lookup_user.role;
-    }
-
-    lookup_user.username = username;
-    lookup_user.password = password;
-    lookup_user.email = email;
-    lookup_user.phone = phone;
-    lookup_user.verified = verified;
-    lookup_user.suspended = suspended;
-    lookup_user.forcenewpw = forcenewpw;
-    lookup_user.role = role;
+    body.apply(&mut lookup_user);
 
     match conn.update_user(lookup_user.clone()) {
-        Ok(_) => {
-            let json = serde_json::to_string(&lookup_user);
-            match json {
-                Ok(j) => return HttpResponse::Ok().body(j),
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}))
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(_) => HttpResponse::Ok().json(UserView::new(&lookup_user, viewer(&conn))),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}",
+    tag = "users",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "User deleted", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+    ),
+)]
 #[delete("/admin/users/{id}")]
 pub async fn delete_user(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-
-    let login_email = req.headers().get("login_email");
-    let login_password = req.headers().get("login_password");
+    let mut conn = connect!(req);
 
-    let id = match req.match_info().get("id") {
-        Some(id) => id,
-        None => return HttpResponse::BadRequest().json(json!({"error": "Invalid id."})),
+    let id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid id.").error_response(),
     };
 
-    login!(login_email, login_password, conn);
+    login!(req, conn);
 
-    let user = conn.search_users(format!("{}", id)).unwrap()[0].clone();
+    // before the lookup, so that a 404 never tells an unprivileged caller which ids exist
+    if conn.current_user().map(|u| u.id) != Some(id) {
+        if let Err(e) = conn.authorize(Permission::UserDelete) {
+            return ApiError::from(e).error_response();
+        }
+    }
+
+    let user = match conn.user_by_id(id) {
+        Ok(u) => u,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
     match conn.delete_user(user) {
-        Ok(_) => return HttpResponse::Ok().json(json!({"message": "Successfully deleted user."})),
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully deleted user."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/account",
+    tag = "account",
+    responses(
+        (status = 200, description = "The caller's user, plus enrollments and standing for students or the teacher account for teachers", body = AccountView),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
 #[get("/account")]
 pub async fn get_self(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let email = request_headers.get("login_email");
-    let password = request_headers.get("login_password");
+    let mut conn = connect!(req);
+    login!(req, conn);
 
-    login!(email, password, conn);
-
-    let user_email = email.unwrap().to_str().unwrap();
-    let user = match conn.search_users(format!("{}", user_email)) {
-        Ok(users) => match users.get(0) {
-            Some(u) => u.to_owned(),
-            None => return HttpResponse::InternalServerError().json(json!({"error": "User not found."})),
-        },
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-        }
+    let user = match conn.current_user() {
+        Some(u) => u.to_owned(),
+        None => return ApiError::not_found("User not found.").error_response(),
     };
 
-    if conn.is_student() {
-        let enrolled_in;
-
-        let enrollments_json = match conn.list_enrollments() {
-            Ok(e) => {
-                enrolled_in = e;
-                match serde_json::to_string(&enrolled_in) {
-                    Ok(j) => j,
-                    Err(e) => {
-                        return HttpResponse::InternalServerError()
-                            .json(json!({"error": e.to_string()}));
-                    }
-                }
-            }
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
-        };
+    let mut account = AccountView::new(&user);
+    account.impersonated_by = conn.impersonator().map(|a| a.id);
 
-        let courses_json = match conn.search_courses("".to_owned()) {
-            Ok(c) => {
-                let c = c
-                    .into_iter()
-                    .filter_map(|c| {
-                        if enrolled_in.iter().any(|e| e.course_id == c.id) {
-                            Some(c)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<Courses>>();
-                match serde_json::to_string(&c) {
-                    Ok(j) => j,
-                    Err(e) => {
-                        return HttpResponse::InternalServerError()
-                            .json(json!({"error": e.to_string()}));
-                    }
-                }
-            }
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
+    if user.role == Role::Student {
+        let enrolled_in = match conn.list_enrollments() {
+            Ok(e) => e,
+            Err(e) => return ApiError::from(e).error_response(),
         };
 
-        let standing_json = match conn.get_student_standing() {
-            Ok(s) => match serde_json::to_string(&s) {
-                Ok(j) => j,
-                Err(e) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": e.to_string()}));
-                }
-            },
-
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
+        let courses = match conn.search_courses("".to_owned()) {
+            Ok(c) => c
+                .into_iter()
+                .filter(|c| enrolled_in.iter().any(|e| e.course_id == c.id))
+                .collect::<Vec<Courses>>(),
+            Err(e) => return ApiError::from(e).error_response(),
         };
 
-        let user_json = match serde_json::to_string(&user) {
-            Ok(j) => j,
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
+        let standing = match conn.get_student_standing() {
+            Ok(s) => s,
+            Err(e) => return ApiError::from(e).error_response(),
         };
 
-        let json_prep = format!(
-            "{{\"user\": {}, \"enrollments\": {}, \"standing\": {}, \"courses\": {}}}",
-            user_json, enrollments_json, standing_json, courses_json
-        );
-
-        match serde_json::from_str::<Value>(&json_prep) {
-            Ok(json3) => HttpResponse::Ok().json(json3),
-            Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-        }
+        account.enrollments = Some(enrolled_in);
+        account.standing = Some(standing);
+        account.courses = Some(courses);
     }
 
-    else if conn.is_teacher() {
-        let user_json = match serde_json::to_string(&user) {
-            Ok(j) => j,
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
+    else if user.role == Role::Teacher {
+        let teacher_account = match conn.get_teacher_accounts() {
+            Ok(t) => match t.into_iter().find(|t| t.teacher_id == user.id) {
+                Some(t) => t,
+                None => {
+                    return ApiError::not_found("A teacher account with this Teacher ID does not exist.").error_response();
+                }
+            },
+            Err(e) => return ApiError::from(e).error_response(),
         };
 
-        let teacher_account;
-
-        let teacher_accounts = match conn.get_teacher_accounts() {
-            Ok(t) => {
-                teacher_account = match t.into_iter().filter_map(|t| {
-                    if t.teacher_id == user.id {
-                        Some(t)
-                    } else {
-                        None
-                    }})
-                    .collect::<Vec<TeacherAccount>>().get(0) {
-                        Some(t) => t.to_owned(),
-                        None => {
-                            return HttpResponse::InternalServerError().json(json!({"error": "A teacher account with this Teacher ID does not exist."}));
-                        },
-                    };
-
-                match serde_json::to_string(&teacher_account) {
-                    Ok(s) => s,
-                    Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "Failed to serialize teacher account."})),
-                }
-            }
+        account.teacher_account = Some(teacher_account);
+    }
 
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
-        };
+    HttpResponse::Ok().json(account)
+}
 
-        let json_prep = format!("{{\"user\": {}, \"teacher_account\": {}}}", user_json, teacher_accounts);
+#[utoipa::path(
+    patch,
+    path = "/account",
+    tag = "account",
+    request_body = AccountChanges,
+    responses(
+        (status = 200, description = "Account updated", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[patch("/account")]
+pub async fn update_self(req: HttpRequest, body: web::Json<AccountChanges>) -> impl Responder {
+    let mut conn = connect!(req);
 
-        match serde_json::from_str::<Value>(&json_prep) {
-            Ok(j) => HttpResponse::Ok().json(j),
-            Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-        }
+    login!(req, conn);
+
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
     }
 
-    else {
-        let user_json = match serde_json::to_string(&user) {
-            Ok(j) => j,
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(json!({"error": e.to_string()}));
-            }
-        };
+    let mut user = match conn.current_user() {
+        Some(u) => u.to_owned(),
+        None => return ApiError::not_found("User not found.").error_response(),
+    };
 
-        let json_prep = format!("{{\"user\": {}}}", user_json);
+    body.apply(&mut user);
 
-        match serde_json::from_str::<Value>(&json_prep) {
-            Ok(json3) => HttpResponse::Ok().json(json3),
-            Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-        }
-    
+    match conn.update_user(user.clone()) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully updated."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[patch("/account")]
-pub async fn update_self(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+#[utoipa::path(
+    post,
+    path = "/enroll/{id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+    ),
+    responses(
+        (status = 200, description = "Enrolled", body = PrivateUser),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Course not found", body = ErrorBody),
+    ),
+)]
+#[post("/enroll/{id}")]
+pub async fn enroll(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
+    let user = match conn.current_user() {
+        Some(u) => u.to_owned(),
+        None => return ApiError::not_found("User not found.").error_response(),
+    };
 
-    login!(login_email, login_password, conn);
+    let course_id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid course id.").error_response(),
+    };
 
-    let mut user = conn
-        .search_users(format!("{}", login_email.unwrap().to_str().unwrap()))
-        .unwrap()[0]
-        .to_owned();
+    let course = match conn.course_by_id(course_id) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
 
-    let username = request_headers.get("username");
-    let email = request_headers.get("email");
-    let password = request_headers.get("password");
-    let phone = request_headers.get("phone");
+    match conn.enroll_courses(vec![course]) {
+        Ok(_) => HttpResponse::Ok().json(PrivateUser::own(&user)),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
 
-    let mut username = match username {
-        Some(u) => u.to_str().unwrap().to_string(),
-        None => String::new(),
-    };
-    let mut email = match email {
-        Some(e) => e.to_str().unwrap().to_string(),
-        None => String::new(),
+#[utoipa::path(
+    post,
+    path = "/unenroll/{id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+    ),
+    responses(
+        (status = 200, description = "Enrollment dropped", body = PrivateUser),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Course not found", body = ErrorBody),
+    ),
+)]
+#[post("/unenroll/{id}")]
+pub async fn unenroll(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    let user = match conn.current_user() {
+        Some(u) => u.to_owned(),
+        None => return ApiError::not_found("User not found.").error_response(),
     };
-    let password = match password {
-        Some(p) => {
-            user.forcenewpw = false;
-            p.to_str().unwrap().to_string()
-        }
-        None => String::new(),
+
+    let course_id = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => id,
+        _ => return ApiError::validation("Invalid course id.").error_response(),
     };
-    let mut phone = match phone {
-        Some(p) => p.to_str().unwrap().to_string(),
-        None => String::new(),
+
+    let course = match conn.course_by_id(course_id) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
-    if username == "" {
-        username = user.username;
-    }
-    if email == "" {
-        email = user.email;
-    }
-    if phone == "" {
-        phone = user.phone;
+    match conn.drop_courses(vec![course]) {
+        Ok(_) => HttpResponse::Ok().json(PrivateUser::own(&user)),
+        Err(e) => ApiError::from(e).error_response(),
     }
+}
 
-    user.username = username;
-    user.email = email;
-    user.password = password;
-    user.phone = phone;
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "auth",
+    params(
+        ("login_email" = String, Header, description = "Account email"),
+        ("login_password" = String, Header, description = "Account password"),
+    ),
+    responses(
+        (status = 200, description = "Signed in; the session token is in the `session_token` response header. Answers `{\"mfa_required\": true}` or `{\"mfa_enrollment_required\": true}` when a second factor is still needed.", body = PrivateUser),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Account suspended or password change required", body = ErrorBody),
+        (status = 429, description = "Too many failed attempts", body = ErrorBody, headers(("Retry-After" = i64, description = "Seconds until sign-in is allowed again"))),
+    ),
+    security(()),
+)]
+#[post("/login")]
+pub async fn login(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    let request_headers = req.headers();
 
-    match conn.update_user(user.clone()) {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully updated."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let (email, password) = match (
+        request_headers.get("login_email"),
+        request_headers.get("login_password"),
+    ) {
+        (Some(e), Some(p)) => (e.to_str().unwrap_or_default(), p.to_str().unwrap_or_default()),
+        _ => return ApiError::validation("Missing username or password").error_response(),
+    };
+
+    // unknown accounts go through the same throttled check and get the same 401 as a
+    // wrong password, so this endpoint cannot be used to probe for registered emails
+    match conn.start_session(email.to_owned(), password.to_owned()) {
+        Ok(outcome) => login_response(&conn, outcome),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[post("/enroll/{id}")]
-pub async fn enroll(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
+#[utoipa::path(
+    post,
+    path = "/login/totp",
+    tag = "auth",
+    params(
+        ("session_token" = String, Header, description = "The token /login answered with"),
+        ("code" = String, Header, description = "Current authenticator code or an unused recovery code"),
+    ),
+    responses(
+        (status = 200, description = "Signed in", body = PrivateUser),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 429, description = "Too many failed attempts", body = ErrorBody, headers(("Retry-After" = i64, description = "Seconds until sign-in is allowed again"))),
+    ),
+    security(()),
+)]
+#[post("/login/totp")]
+pub async fn login_totp(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
     let request_headers = req.headers();
 
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
+    let token = request_headers.get("session_token").and_then(|t| t.to_str().ok());
+    let code = request_headers.get("code").and_then(|c| c.to_str().ok());
+
+    let (token, code) = match (token, code) {
+        (Some(t), Some(c)) => (t, c),
+        _ => {
+            return ApiError::validation("Missing session token or authentication code.").error_response()
+        }
+    };
 
-    login!(login_email, login_password, conn);
+    match conn.complete_two_factor(token, code) {
+        Ok(_) => HttpResponse::Ok().json(conn.current_user().map(PrivateUser::own)),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
 
-    let user = conn
-        .search_users(format!("{}", login_email.unwrap().to_str().unwrap()))
-        .unwrap()[0]
-        .clone();
+#[utoipa::path(
+    post,
+    path = "/account/totp",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Secret to add to an authenticator app", body = TotpEnrollment),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 409, description = "Two-factor authentication is already enabled", body = ErrorBody),
+    ),
+)]
+#[post("/account/totp")]
+pub async fn enroll_totp(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    enrollment_login!(req, conn);
+
+    match conn.enroll_totp() {
+        Ok(e) => HttpResponse::Ok().json(e),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
 
-    let course_id = req.match_info().get("id");
+#[utoipa::path(
+    post,
+    path = "/account/totp/confirm",
+    tag = "auth",
+    params(
+        ("code" = String, Header, description = "Current authenticator code or an unused recovery code"),
+    ),
+    responses(
+        (status = 200, description = "Two-factor authentication enabled; answers the one-time recovery codes"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[post("/account/totp/confirm")]
+pub async fn confirm_totp(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    enrollment_login!(req, conn);
+
+    let code = match req.headers().get("code").and_then(|c| c.to_str().ok()) {
+        Some(c) => c.to_owned(),
+        None => return ApiError::validation("Missing authentication code.").error_response(),
+    };
 
-    if course_id.is_none() {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing course id"}));
+    match conn.confirm_totp(&code) {
+        Ok(codes) => HttpResponse::Ok().json(json!({"recovery_codes": codes})),
+        Err(e) => ApiError::from(e).error_response(),
     }
+}
 
-    let course_id = course_id.unwrap().to_owned();
+#[utoipa::path(
+    delete,
+    path = "/account/totp",
+    tag = "auth",
+    params(
+        ("code" = String, Header, description = "Current authenticator code or an unused recovery code"),
+    ),
+    responses(
+        (status = 200, description = "Two-factor authentication disabled", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[delete("/account/totp")]
+pub async fn disable_totp(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    let code = match req.headers().get("code").and_then(|c| c.to_str().ok()) {
+        Some(c) => c.to_owned(),
+        None => return ApiError::validation("Missing authentication code.").error_response(),
+    };
 
-    match conn.enroll_courses(
-        conn.search_courses(format!("{}", course_id))
-            .unwrap()
-            .iter()
-            .filter_map(|c| Some(c.clone()))
-            .collect(),
-    ) {
-        Ok(_) => {
-            let json = serde_json::to_string(&user);
-            match json {
-                Ok(j) => return HttpResponse::Ok().json(j),
-                Err(_) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": "Failed to serialize user"}))
-                }
-            }
-        }
+    match conn.disable_totp(&code) {
+        Ok(_) => HttpResponse::Ok()
+            .json(json!({"message": "Successfully disabled two-factor authentication."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/totp/recovery-codes",
+    tag = "auth",
+    params(
+        ("code" = String, Header, description = "Current authenticator code or an unused recovery code"),
+    ),
+    responses(
+        (status = 200, description = "New recovery codes; the old ones stop working"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[post("/account/totp/recovery-codes")]
+pub async fn regenerate_recovery_codes(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    let code = match req.headers().get("code").and_then(|c| c.to_str().ok()) {
+        Some(c) => c.to_owned(),
+        None => return ApiError::validation("Missing authentication code.").error_response(),
+    };
 
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    match conn.regenerate_recovery_codes(&code) {
+        Ok(codes) => HttpResponse::Ok().json(json!({"recovery_codes": codes})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[post("/unenroll/{id}")]
-pub async fn unenroll(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
+#[utoipa::path(
+    get,
+    path = "/logout",
+    tag = "auth",
+    params(
+        ("session_token" = Option<String>, Header, description = "Session to end"),
+    ),
+    responses(
+        (status = 200, description = "Signed out", body = Message),
+        (status = 400, description = "Signed in with login_email and login_password, which leave no session to end", body = ErrorBody),
+    ),
+    security(()),
+)]
+#[get("/logout")]
+pub async fn logout(req: HttpRequest) -> impl Responder {
     let request_headers = req.headers();
 
+    if let Some(token) = request_headers.get("session_token") {
+        let mut conn = connect!(req);
+        let token = token.to_str().unwrap_or_default();
+
+        return match conn.end_session(token) {
+            Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully logged out."})),
+            Err(e) => ApiError::from(e).error_response(),
+        };
+    }
+
     let email = request_headers.get("login_email");
     let password = request_headers.get("login_password");
 
-    login!(email, password, conn);
+    if email.is_none() || password.is_none() {
+        HttpResponse::Ok().json(json!({"message": "Successfully logged out."}))
+    } else {
+        ApiError::validation("Only a session_token can be signed out.").error_response()
+    }
+}
 
-    let user = conn
-        .search_users(format!("{}", email.unwrap().to_str().unwrap()))
-        .unwrap()[0]
-        .to_owned();
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "auth",
+    request_body = Registration,
+    responses(
+        (status = 200, description = "Student account created", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 409, description = "The email is already registered", body = ErrorBody),
+    ),
+    security(()),
+)]
+#[post("/register")]
+pub async fn register(req: HttpRequest, body: web::Json<Registration>) -> impl Responder {
+    let mut conn = connect!(req);
 
-    let course_id = match req.match_info().get("id") {
-        Some(id) => id,
-        None => {
-            return HttpResponse::BadRequest().json(json!({"error": "Missing course id"}));
-        }
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
+
+    match conn.register_user(body.into_user(Role::Student, false)) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully registered."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/register",
+    tag = "auth",
+    params(
+        ("invitation_token" = String, Header, description = "Token from an admin invitation"),
+    ),
+    request_body = Registration,
+    responses(
+        (status = 200, description = "Administrator account created", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 409, description = "The email is already registered", body = ErrorBody),
+    ),
+    security(()),
+)]
+#[post("/admin/register")]
+pub async fn register_admin(req: HttpRequest, body: web::Json<Registration>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    // a credential, so it travels in a header like session_token rather than in the body
+    let invitation = match req.headers().get("invitation_token") {
+        Some(t) => match t.to_str() {
+            Ok(t) => t.to_owned(),
+            Err(_) => return ApiError::validation("Invalid invitation token.").error_response(),
+        },
+        None => return ApiError::validation("Missing invitation token.").error_response(),
     };
 
-    let course_list = conn
-        .search_courses(format!("{}", course_id))
-        .unwrap()
-        .iter()
-        .filter_map(|c| Some(c.clone()))
-        .collect::<Vec<Courses>>();
-
-    match conn.drop_courses(course_list) {
-        Ok(_) => {
-            let json = serde_json::to_string(&user);
-            match json {
-                Ok(j) => return HttpResponse::Ok().body(j),
-                Err(_) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({"error": "Failed to serialize user"}))
-                }
-            }
-        }
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
 
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    match conn.register_admin(body.into_user(Role::Admin, true), &invitation) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully registered."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[post("/login")]
-pub async fn login(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Counts of users, students, courses and departments", body = Statistics),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/stats")]
+pub async fn get_stats(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
 
-    let email = request_headers.get("login_email");
-    let password = request_headers.get("login_password");
+    if let Err(e) = conn.authorize(Permission::StatsRead) {
+        return ApiError::from(e).error_response();
+    }
 
-    if email.is_none() || password.is_none() {
-        return HttpResponse::BadRequest().json(json!({"error": "Missing username or password"}));
-    }
-
-    let email = email.unwrap().to_str().unwrap();
-    let password = password.unwrap().to_str().unwrap();
-
-    let user = conn.search_users(format!("{}", email));
-
-    match user {
-        Ok(u) => {
-            if u.len() == 0 {
-                return HttpResponse::BadRequest().json(json!({"error": "User not found"}));
-            } else {
-                let user = u.get(0).unwrap();
-
-                match conn.login(email.to_owned(), password.to_owned()) {
-                    Ok(_) => {
-                        let json = serde_json::to_string(&user);
-                        match json {
-                            Ok(j) => return HttpResponse::Ok().body(j),
-                            Err(e) => {
-                                return HttpResponse::InternalServerError()
-                                    .json(json!({"error": e.to_string()}))
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        return HttpResponse::InternalServerError()
-                            .json(json!({"error": e.to_string()}))
-                    }
-                }
-            }
-        }
-        Err(e) => return HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    let stats = conn.generate_statistics();
+
+    match stats {
+        Ok(s) => HttpResponse::Ok().json(s),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[get("/logout")]
-pub async fn logout(req: HttpRequest) -> impl Responder {
+#[utoipa::path(
+    get,
+    path = "/admin/login-attempts",
+    tag = "admin",
+    params(
+        ("email" = Option<String>, Header, description = "Only attempts for this email"),
+    ),
+    responses(
+        (status = 200, description = "Recent login attempts", body = [LoginAttempt]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/login-attempts")]
+pub async fn get_login_attempts(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
     let request_headers = req.headers();
 
-    let email = request_headers.get("login_email");
-    let password = request_headers.get("login_password");
+    login!(req, conn);
 
-    if email.is_none() || password.is_none() {
-        HttpResponse::Ok().json(json!({"message": "Successfully logged out."}))
-    } else {
-        HttpResponse::InternalServerError().json(json!({"error": "Failed to logout."}))
+    if let Err(e) = conn.authorize(Permission::SecurityAudit) {
+        return ApiError::from(e).error_response();
+    }
+
+    let email = match request_headers.get("email") {
+        Some(e) => match e.to_str() {
+            Ok(e) => Some(e.to_owned()),
+            Err(_) => return ApiError::validation("Invalid email.").error_response(),
+        },
+        None => None,
+    };
+
+    match conn.get_login_attempts(email) {
+        Ok(a) => HttpResponse::Ok().json(a),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[post("/register")]
-pub async fn register(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+#[utoipa::path(
+    get,
+    path = "/admin/lockouts",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Accounts and addresses currently locked out", body = [LoginThrottle]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/lockouts")]
+pub async fn get_lockouts(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::SecurityAudit) {
+        return ApiError::from(e).error_response();
+    }
 
-    let username = request_headers.get("username");
-    let password = request_headers.get("password");
-    let email = request_headers.get("email");
-    let phone = request_headers.get("phone");
+    match conn.get_lockouts() {
+        Ok(l) => HttpResponse::Ok().json(l),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
 
-    if username.is_none() || password.is_none() || email.is_none() {
-        return HttpResponse::BadRequest()
-            .json(json!({"error": "Missing username, password, email, or role"}));
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}/lock",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "Lockout cleared", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/users/{id}/lock")]
+pub async fn unlock_user(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::UserUnlock) {
+        return ApiError::from(e).error_response();
     }
 
-    let username = username.unwrap().to_str().unwrap().to_owned();
-    let password = password.unwrap().to_str().unwrap().to_owned();
-    let email = email.unwrap().to_str().unwrap().to_owned();
-    let phone = phone
-        .is_some_and(|_| true)
-        .then(|| phone.unwrap().to_str().unwrap().to_owned())
-        .or_else(|| Some(String::from("")))
-        .unwrap_or_default();
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
 
-    let u = User {
-        id: 0,
-        username,
-        password,
-        email,
-        phone,
-        verified: false,
-        suspended: false,
-        forcenewpw: false,
-        role: String::from("student"),
+    let user = match conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(id))]) {
+        Ok(u) => match u.first() {
+            Some(u) => u.to_owned(),
+            None => return ApiError::not_found("User not found.").error_response(),
+        },
+        Err(e) => return ApiError::from(e).error_response(),
     };
 
-    match conn.register_user(u) {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully registered."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    match conn.unlock_user(user) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully unlocked user."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[post("/admin/register")]
-pub async fn register_admin(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
+#[utoipa::path(
+    get,
+    path = "/admin/roles",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Every permission granted to every role", body = [RolePermission]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/roles")]
+pub async fn get_role_permissions(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::RoleManage) {
+        return ApiError::from(e).error_response();
+    }
 
-    let username = request_headers.get("username");
-    let password = request_headers.get("password");
-    let email = request_headers.get("email");
-    let phone = request_headers.get("phone");
+    match conn.get_role_permissions() {
+        Ok(p) => HttpResponse::Ok().json(p),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
 
-    match request_headers.get("access_code") {
-        Some(c) => {
-            if c.to_str().unwrap() != "I_BECOME_THY_ADMIN_AND_I_FUCK_YOUR_MOTHER32131!@#@!#@!" {
-                return HttpResponse::BadRequest().json(json!({"error": "Invalid access code."}));
-            }
-        }
-        None => return HttpResponse::BadRequest().json(json!({"error": "Missing access code."})),
+#[utoipa::path(
+    post,
+    path = "/admin/roles/{role}/permissions/{permission}",
+    tag = "admin",
+    params(
+        ("role" = Role, Path, description = "Role name"),
+        ("permission" = Permission, Path, description = "Permission name, e.g. course.update"),
+    ),
+    responses(
+        (status = 200, description = "Permission granted", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[post("/admin/roles/{role}/permissions/{permission}")]
+pub async fn grant_permission(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::RoleManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let role = match req.match_info().get("role").unwrap_or_default().parse::<Role>() {
+        Ok(r) => r,
+        Err(e) => return ApiError::validation(e.to_string()).error_response(),
+    };
+    let permission = match req.match_info().get("permission").unwrap_or_default().parse::<Permission>() {
+        Ok(p) => p,
+        Err(e) => return ApiError::validation(e.to_string()).error_response(),
     };
 
-    if username.is_none() || password.is_none() || email.is_none() {
-        return HttpResponse::BadRequest()
-            .json(json!({"error": "Missing username, password or email."}));
+    match conn.grant_permission(role, permission) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully granted permission."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
+}
 
-    let username = username.unwrap().to_str().unwrap().to_owned();
-    let password = password.unwrap().to_str().unwrap().to_owned();
-    let email = email.unwrap().to_str().unwrap().to_owned();
-    let phone = phone
-        .is_some_and(|_| true)
-        .then(|| phone.unwrap().to_str().unwrap().to_owned())
-        .or_else(|| Some(String::from("")))
-        .unwrap_or_default();
+#[utoipa::path(
+    delete,
+    path = "/admin/roles/{role}/permissions/{permission}",
+    tag = "admin",
+    params(
+        ("role" = Role, Path, description = "Role name"),
+        ("permission" = Permission, Path, description = "Permission name, e.g. course.update"),
+    ),
+    responses(
+        (status = 200, description = "Permission revoked", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/roles/{role}/permissions/{permission}")]
+pub async fn revoke_permission(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::RoleManage) {
+        return ApiError::from(e).error_response();
+    }
 
-    let u = User {
-        id: 0,
-        username,
-        password,
-        email,
-        phone,
-        verified: true,
-        suspended: false,
-        forcenewpw: false,
-        role: String::from("admin"),
+    let role = match req.match_info().get("role").unwrap_or_default().parse::<Role>() {
+        Ok(r) => r,
+        Err(e) => return ApiError::validation(e.to_string()).error_response(),
+    };
+    let permission = match req.match_info().get("permission").unwrap_or_default().parse::<Permission>() {
+        Ok(p) => p,
+        Err(e) => return ApiError::validation(e.to_string()).error_response(),
     };
 
-    match conn.register_user(u) {
-        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully registered."})),
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    match conn.revoke_permission(role, permission) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked permission."})),
+        Err(e) => ApiError::from(e).error_response(),
     }
 }
 
-#[get("/admin/stats")]
-pub async fn get_stats(req: HttpRequest) -> impl Responder {
-    let mut conn = ServerConnection::new();
-    let request_headers = req.headers();
-
-    let login_email = request_headers.get("login_email");
-    let login_password = request_headers.get("login_password");
-    login!(login_email, login_password, conn);
+#[utoipa::path(
+    patch,
+    path = "/courses/{id}/grades/{student_id}",
+    tag = "courses",
+    params(
+        ("id" = i32, Path, description = "Course id"),
+        ("student_id" = i32, Path, description = "Student's user id"),
+    ),
+    request_body = Grade,
+    responses(
+        (status = 200, description = "Grade recorded", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Course or enrollment not found", body = ErrorBody),
+    ),
+)]
+#[patch("/courses/{id}/grades/{student_id}")]
+pub async fn grade_student(req: HttpRequest, body: web::Json<Grade>) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    let student_id = match req.match_info().get("student_id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid student id.").error_response(),
+    };
 
-    if !conn.is_admin() {
-        return HttpResponse::Unauthorized().json(json!({"error": "Unauthorized"}));
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
     }
 
-    let stats = conn.generate_statistics();
+    let course = match req.match_info().get("id").map(str::parse::<i32>) {
+        Some(Ok(id)) => match conn.course_by_id(id) {
+            Ok(c) => c,
+            Err(e) => return ApiError::from(e).error_response(),
+        },
+        _ => return ApiError::validation("Invalid course id.").error_response(),
+    };
 
-    match stats {
-        Ok(s) => {
-            let json = serde_json::to_string(&s);
-            match json {
-                Ok(j) => HttpResponse::Ok().json(j),
-                Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
-            }
+    match conn.grade_student(course, student_id, body.grade) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully graded student."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+// Every handler starts from a connection; if the database cannot be opened the
+// request is answered with the error instead
+#[macro_export]
+macro_rules! connect_macro {
+    ($req:expr) => {
+        match open_connection(&$req) {
+            Ok(c) => c,
+            Err(e) => return e.error_response(),
         }
-        Err(e) => HttpResponse::InternalServerError().json(json!({"error": e.to_string()})),
+    }
+}
+
+// Lockouts, suspensions and missing second factors keep their own status so clients can
+// react to them. Everything decided before the password was verified is an
+// Unauthenticated error (authenticate() only reports account state to the password's
+// holder), and all of those read the same as an unknown account.
+pub fn credential_error(e: anyhow::Error) -> ApiError {
+    match ApiError::from(e) {
+        ApiError::Unauthenticated(_) => ApiError::unauthenticated("Invalid login credentials."),
+        e => e,
     }
 }
 
 // So that I don't have to repeat myself over and over again
 #[macro_export]
 macro_rules! login_macro {
-    ($login_email:expr, $login_password:expr, $conn:expr) => {
+    ($req:expr, $conn:expr) => {
         {
-            match ($login_email, $login_password) {
-                (Some(a), Some(b)) => {
-                    let username = a.to_str().unwrap().to_owned();
-                    let password = b.to_str().unwrap().to_owned();
-
-                    match $conn.login(username, password) {
-                        Ok(_) => {},
-                        Err(_) => {
-                            return HttpResponse::BadRequest().json(json!({"error": "Invalid login credentials."}));
-                        }
+            let request_headers = $req.headers();
+
+            match (
+                request_headers.get("api_key"),
+                request_headers.get("session_token"),
+                request_headers.get("login_email"),
+                request_headers.get("login_password"),
+            ) {
+                (Some(k), _, _, _) => {
+                    let key = k.to_str().unwrap_or_default().to_owned();
+
+                    if let Err(e) = $conn.resume_api_key(&key) {
+                        return ApiError::from(e).error_response();
+                    }
+                },
+                (None, Some(t), _, _) => {
+                    let token = t.to_str().unwrap_or_default().to_owned();
+
+                    if let Err(e) = $conn.resume_session(&token) {
+                        return ApiError::from(e).error_response();
+                    }
+                },
+                (None, None, Some(a), Some(b)) => {
+                    let username = a.to_str().unwrap_or_default().to_owned();
+                    let password = b.to_str().unwrap_or_default().to_owned();
+
+                    if let Err(e) = $conn.login(username, password) {
+                        return credential_error(e).error_response();
                     }
                 },
-                (_, None) => {
-                    return HttpResponse::BadRequest().json(json!({"error": "Missing login password."}));
+                (None, None, _, None) => {
+                    return ApiError::validation("Missing login password.").error_response();
+                },
+                (None, None, None, _) => {
+                    return ApiError::validation("Missing login email.").error_response();
                 },
-                (None, _) => {
-                    return HttpResponse::BadRequest().json(json!({"error": "Missing login email."}));
+            }
+        }
+    }
+}
+
+// For endpoints that also serve signed out visitors: credentials are checked when
+// present, and ServerConnection decides what an anonymous caller may see
+#[macro_export]
+macro_rules! optional_login_macro {
+    ($req:expr, $conn:expr) => {
+        {
+            let request_headers = $req.headers();
+
+            if request_headers.contains_key("api_key")
+                || request_headers.contains_key("session_token")
+                || request_headers.contains_key("login_email")
+                || request_headers.contains_key("login_password")
+            {
+                $crate::login_macro!($req, $conn);
+            }
+        }
+    }
+}
+
+// Same as login_macro, but lets an admin who has not enrolled a second factor yet through
+#[macro_export]
+macro_rules! enrollment_login_macro {
+    ($req:expr, $conn:expr) => {
+        {
+            match $req.headers().get("session_token") {
+                Some(t) => {
+                    let token = t.to_str().unwrap_or_default().to_owned();
+
+                    if let Err(e) = $conn.resume_enrollment_session(&token) {
+                        return ApiError::from(e).error_response();
+                    }
                 },
+                None => $crate::login_macro!($req, $conn),
             }
         }
     }
 }
+
+#[utoipa::path(
+    post,
+    path = "/admin/invitations",
+    tag = "admin",
+    request_body = Option<AdminInvitationRequest>,
+    responses(
+        (status = 200, description = "The invitation; its token is only shown once", body = IssuedInvitation),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[post("/admin/invitations")]
+pub async fn invite_admin(req: HttpRequest, body: Option<web::Json<AdminInvitationRequest>>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::AdminInvite) {
+        return ApiError::from(e).error_response();
+    }
+
+    // the body is optional; without one the invitation works for any address
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
+
+    match conn.invite_admin(body.email) {
+        Ok(i) => HttpResponse::Ok().json(i),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/invitations",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Every invitation", body = [AdminInvitation]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/invitations")]
+pub async fn get_admin_invitations(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::AdminInvite) {
+        return ApiError::from(e).error_response();
+    }
+
+    match conn.get_admin_invitations() {
+        Ok(i) => HttpResponse::Ok().json(i),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/invitations/{id}",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "Invitation id"),
+    ),
+    responses(
+        (status = 200, description = "Invitation revoked", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Invitation not found", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/invitations/{id}")]
+pub async fn revoke_admin_invitation(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::AdminInvite) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.revoke_admin_invitation(id) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked invitation."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(
+        ("actor_id" = Option<i32>, Header, description = "Only entries by this user"),
+    ),
+    responses(
+        (status = 200, description = "Audit entries, newest first", body = [AuditEntry]),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/audit")]
+pub async fn get_audit_log(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    let request_headers = req.headers();
+
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::SecurityAudit) {
+        return ApiError::from(e).error_response();
+    }
+
+    let actor = match request_headers.get("actor_id") {
+        Some(a) => match a.to_str().unwrap_or_default().parse::<i32>() {
+            Ok(a) => Some(a),
+            Err(_) => return ApiError::validation("Invalid actor id.").error_response(),
+        },
+        None => None,
+    };
+
+    match conn.get_audit_log(actor) {
+        Ok(a) => HttpResponse::Ok().json(a),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/service-accounts",
+    tag = "admin",
+    request_body = ServiceAccountRequest,
+    responses(
+        (status = 200, description = "The new service account", body = UserView),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 409, description = "The email is already registered", body = ErrorBody),
+    ),
+)]
+#[post("/admin/service-accounts")]
+pub async fn create_service_account(req: HttpRequest, body: web::Json<ServiceAccountRequest>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::ApiKeyManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
+
+    match conn.create_service_account(body.username, body.email) {
+        Ok(u) => HttpResponse::Ok().json(UserView::new(&u, viewer(&conn))),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}/api-keys",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "The user's API keys, without the keys themselves", body = [StoredApiKey]),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/users/{id}/api-keys")]
+pub async fn get_api_keys(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::ApiKeyManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.get_api_keys(id) {
+        Ok(k) => HttpResponse::Ok().json(k),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/api-keys",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    request_body = ApiKeyRequest,
+    responses(
+        (status = 200, description = "The new key; it is only shown once", body = IssuedApiKey),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Fields failed validation, each listed in `fields`", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+    ),
+)]
+#[post("/admin/users/{id}/api-keys")]
+pub async fn create_api_key(req: HttpRequest, body: web::Json<ApiKeyRequest>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::ApiKeyManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    let body = body.into_inner();
+    if let Some(r) = invalid(&body) {
+        return r;
+    }
+
+    match conn.create_api_key(id, body.name.trim().to_owned(), body.scopes, body.expires_in_days) {
+        Ok(k) => HttpResponse::Ok().json(k),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{id}",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "API key id"),
+    ),
+    responses(
+        (status = 200, description = "API key revoked", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "API key not found", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/api-keys/{id}")]
+pub async fn revoke_api_key(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::ApiKeyManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.revoke_api_key(id) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked API key."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SsoCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// Sends the browser to the identity provider
+#[utoipa::path(
+    get,
+    path = "/login/sso",
+    tag = "auth",
+    responses(
+        (status = 302, description = "Redirect to the identity provider"),
+        (status = 404, description = "Single sign-on configuration not found", body = ErrorBody),
+        (status = 502, description = "The identity provider could not be reached", body = ErrorBody),
+    ),
+    security(()),
+)]
+#[get("/login/sso")]
+pub async fn login_sso(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+
+    let config = match app_config(&req).oidc.clone() {
+        Some(c) => c,
+        None => return ApiError::not_found("Single sign-on is not configured.").error_response(),
+    };
+
+    let discovery = match oidc::discover(&config).await {
+        Ok(d) => d,
+        Err(e) => return ApiError::upstream(e.to_string()).error_response(),
+    };
+
+    let ttl = app_config(&req).sessions.sso_login_ttl_secs;
+
+    match conn.begin_sso() {
+        Ok(c) => HttpResponse::Found()
+            .insert_header((
+                "Location",
+                oidc::authorization_url(&config, &discovery, &c.state, &c.nonce, &c.code_verifier),
+            ))
+            .cookie(sso_state_cookie(&config, c.state.clone(), CookieDuration::seconds(ttl)))
+            .finish(),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+// Lax, because the identity provider's redirect back is a cross-site navigation
+fn sso_state_cookie(config: &oidc::OidcConfig, state: String, max_age: CookieDuration) -> Cookie<'static> {
+    Cookie::build(SSO_STATE_COOKIE, state)
+        .path("/")
+        .http_only(true)
+        .secure(config.redirect_uri.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
+
+// Sends the browser on to the frontend with the session token in the URL fragment; a
+// redirect's headers and body never reach the page that follows it
+fn sso_redirect(config: &oidc::OidcConfig, outcome: LoginOutcome) -> HttpResponse {
+    let fragment = match outcome {
+        LoginOutcome::Authenticated(token) => format!("session_token={}", tokens::url_encode(&token)),
+        LoginOutcome::TwoFactorRequired(token) => {
+            format!("session_token={}&mfa_required=true", tokens::url_encode(&token))
+        }
+        LoginOutcome::TwoFactorEnrollmentRequired(token) => {
+            format!("session_token={}&mfa_enrollment_required=true", tokens::url_encode(&token))
+        }
+    };
+
+    HttpResponse::Found()
+        .insert_header(("Location", format!("{}#{}", config.frontend_url, fragment)))
+        .cookie(sso_state_cookie(config, String::new(), CookieDuration::ZERO))
+        .finish()
+}
+
+// Where the identity provider redirects back to
+#[utoipa::path(
+    get,
+    path = "/login/sso/callback",
+    tag = "auth",
+    params(
+        SsoCallback,
+    ),
+    responses(
+        (status = 302, description = "Signed in; redirects to the configured frontend URL with `#session_token=...` in the fragment, plus `&mfa_required=true` or `&mfa_enrollment_required=true` when a second factor is still needed"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 502, description = "The identity provider could not be reached", body = ErrorBody),
+    ),
+    security(()),
+)]
+#[get("/login/sso/callback")]
+pub async fn login_sso_callback(req: HttpRequest, query: web::Query<SsoCallback>) -> impl Responder {
+    let mut conn = connect!(req);
+
+    let config = match app_config(&req).oidc.clone() {
+        Some(c) => c,
+        None => return ApiError::not_found("Single sign-on is not configured.").error_response(),
+    };
+
+    if let Some(error) = &query.error {
+        return ApiError::unauthenticated(format!("Sign-in was not completed: {}", error)).error_response();
+    }
+
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(c), Some(s)) => (c.to_owned(), s.to_owned()),
+        _ => return ApiError::validation("Missing code or state.").error_response(),
+    };
+
+    // the state must come back to the browser it was issued to
+    if req.cookie(SSO_STATE_COOKIE).map(|c| c.value().to_owned()) != Some(state.clone()) {
+        return ApiError::unauthenticated("This sign-in was not started in this browser.").error_response();
+    }
+
+    let challenge = match conn.take_sso_challenge(&state) {
+        Ok(c) => c,
+        Err(e) => return ApiError::from(e).error_response(),
+    };
+
+    let discovery = match oidc::discover(&config).await {
+        Ok(d) => d,
+        Err(e) => return ApiError::upstream(e.to_string()).error_response(),
+    };
+
+    let claims = match oidc::exchange_code(&config, &discovery, &code, &challenge.code_verifier, &challenge.nonce).await {
+        Ok(c) => c,
+        Err(e) => return ApiError::unauthenticated(e.to_string()).error_response(),
+    };
+
+    match conn.complete_sso(&claims.email, claims.name) {
+        Ok(outcome) => sso_redirect(&config, outcome),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/impersonate",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "Impersonating; the session token is in the `session_token` response header"),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "User not found", body = ErrorBody),
+    ),
+)]
+#[post("/admin/users/{id}/impersonate")]
+pub async fn impersonate_user(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::UserImpersonate) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    // the returned token is used like any other session_token; GET /logout ends it
+    match conn.start_impersonation(id) {
+        Ok(token) => HttpResponse::Ok()
+            .insert_header(("session_token", token))
+            .json(json!({"impersonating": id})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/impersonations",
+    tag = "account",
+    responses(
+        (status = 200, description = "Actions admins took while impersonating the caller", body = [AuditEntry]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[get("/account/impersonations")]
+pub async fn get_impersonations(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    match conn.get_impersonation_history() {
+        Ok(h) => HttpResponse::Ok().json(h),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/sessions",
+    tag = "account",
+    responses(
+        (status = 200, description = "The caller's sessions", body = [SessionInfo]),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[get("/account/sessions")]
+pub async fn get_sessions(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    match conn.get_sessions() {
+        Ok(s) => HttpResponse::Ok().json(s),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+// Signs out every other device
+#[utoipa::path(
+    delete,
+    path = "/account/sessions",
+    tag = "account",
+    responses(
+        (status = 200, description = "Every other session ended", body = Message),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    ),
+)]
+#[delete("/account/sessions")]
+pub async fn revoke_other_sessions(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    match conn.revoke_other_sessions() {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked sessions."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/sessions/{id}",
+    tag = "account",
+    params(
+        ("id" = i32, Path, description = "Session id"),
+    ),
+    responses(
+        (status = 200, description = "Session ended", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "Session not found", body = ErrorBody),
+    ),
+)]
+#[delete("/account/sessions/{id}")]
+pub async fn revoke_session(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.revoke_session(id) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked session."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}/sessions",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "The user's sessions", body = [SessionInfo]),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[get("/admin/users/{id}/sessions")]
+pub async fn get_user_sessions(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::SessionManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.get_user_sessions(id) {
+        Ok(s) => HttpResponse::Ok().json(s),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}/sessions",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "Every session of the user ended", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/users/{id}/sessions")]
+pub async fn revoke_user_sessions(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::SessionManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    match conn.revoke_user_sessions(id, None) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked sessions."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}/sessions/{session_id}",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "User id"),
+        ("session_id" = i32, Path, description = "Session id"),
+    ),
+    responses(
+        (status = 200, description = "Session ended", body = Message),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "Missing permission", body = ErrorBody),
+        (status = 404, description = "Session not found", body = ErrorBody),
+    ),
+)]
+#[delete("/admin/users/{id}/sessions/{session_id}")]
+pub async fn revoke_user_session(req: HttpRequest) -> impl Responder {
+    let mut conn = connect!(req);
+    login!(req, conn);
+
+    if let Err(e) = conn.authorize(Permission::SessionManage) {
+        return ApiError::from(e).error_response();
+    }
+
+    let id = match req.match_info().get("id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid id.").error_response(),
+    };
+
+    let session_id = match req.match_info().get("session_id").unwrap_or_default().parse::<i32>() {
+        Ok(i) => i,
+        Err(_) => return ApiError::validation("Invalid session id.").error_response(),
+    };
+
+    match conn.revoke_user_sessions(id, Some(session_id)) {
+        Ok(_) => HttpResponse::Ok().json(json!({"message": "Successfully revoked session."})),
+        Err(e) => ApiError::from(e).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rbac::{Permission, Role};
+    use super::super::table_models::{Courses, User};
+    use super::super::testing::{MockIdp, TestDb, PASSWORD};
+    use super::*;
+    use actix_web::http::Method;
+    use actix_web::{test, App};
+
+    fn query_param<'a>(url: &'a str, name: &str) -> &'a str {
+        url.split(['?', '&'])
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn sso_signs_in_only_the_browser_that_started_it() {
+        let idp = MockIdp::start().await;
+        let mut config = Config::default();
+        config.oidc = Some(idp.config());
+        let db = TestDb::with_config(config);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(login_sso)
+                .service(login_sso_callback),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/login/sso").to_request()).await;
+        assert_eq!(res.status(), 302);
+        let location = res.headers().get("Location").unwrap().to_str().unwrap().to_owned();
+        let cookie = res.response().cookies().find(|c| c.name() == SSO_STATE_COOKIE).unwrap().into_owned();
+        assert!(cookie.http_only().unwrap_or(false));
+
+        let state = query_param(&location, "state");
+        assert_eq!(cookie.value(), state);
+        let id_token = idp.id_token(&idp.claims("student@aubg.edu", query_param(&location, "nonce")));
+        let callback = format!("/login/sso/callback?code={}&state={}", id_token, state);
+
+        // a callback URL replayed in another browser (login CSRF) carries no cookie
+        let res = test::call_service(&app, test::TestRequest::get().uri(&callback).to_request()).await;
+        assert_eq!(res.status(), 401);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&callback).cookie(cookie.clone()).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 302);
+        let landing = res.headers().get("Location").unwrap().to_str().unwrap();
+        let token = landing.strip_prefix("http://localhost/signed-in#session_token=").unwrap();
+        db.connect().resume_session(token).unwrap();
+
+        // the pending login is gone once used
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&callback).cookie(cookie).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn every_invalid_field_is_reported_by_name() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .app_data(json_config())
+                .service(register)
+                .service(new_course),
+        )
+        .await;
+
+        let cases = [
+            (
+                "/register",
+                json!({"username": " ", "password": "", "email": "a".repeat(101), "phone": "1".repeat(33)}),
+                vec!["username", "password", "email", "phone"],
+            ),
+            (
+                "/courses",
+                json!({"name": "", "course_nr": "COS 101", "teacher_id": 0, "cr_cost": -3, "timeslots": "MWF", "description": "d".repeat(5001)}),
+                vec!["name", "description", "teacher_id", "cr_cost"],
+            ),
+        ];
+
+        for (uri, body, fields) in cases {
+            let res = test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .uri(uri)
+                    .insert_header(("login_email", teacher.email.as_str()))
+                    .insert_header(("login_password", PASSWORD))
+                    .set_json(body)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(res.status(), 422, "{}", uri);
+
+            let error: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(error["code"], "validation_failed");
+            let named: Vec<&str> = error["fields"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| {
+                    assert!(!f["message"].as_str().unwrap().is_empty());
+                    f["field"].as_str().unwrap()
+                })
+                .collect();
+            assert_eq!(named, fields, "{}", uri);
+        }
+        assert_eq!(db.connect().list_courses(vec![]).unwrap().len(), 0);
+    }
+
+    #[actix_web::test]
+    async fn readiness_failures_answer_503_with_the_report() {
+        let db = TestDb::new();
+        let app = test::init_service(App::new().app_data(web::Data::from(db.config.clone())).service(readyz)).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        rusqlite::Connection::open(&db.config.database.path)
+            .unwrap()
+            .pragma_update(None, "user_version", 1)
+            .unwrap();
+        let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), 503);
+        let report: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(report["ready"], false);
+        assert_eq!(report["checks"][1]["name"], "schema_version");
+        assert_eq!(report["checks"][1]["status"], "fail");
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_only_served_to_the_configured_token() {
+        let scrape = |config: Config, authorization: Option<&'static str>| async move {
+            let db = TestDb::with_config(config);
+            let app = test::init_service(
+                App::new().app_data(web::Data::from(db.config.clone())).service(get_metrics),
+            )
+            .await;
+            let mut req = test::TestRequest::get().uri("/metrics");
+            if let Some(a) = authorization {
+                req = req.insert_header(("Authorization", a));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            (res.status().as_u16(), String::from_utf8(test::read_body(res).await.to_vec()).unwrap())
+        };
+
+        // nothing is published by default
+        let (status, body) = scrape(Config::default(), Some("Bearer anything")).await;
+        assert_eq!(status, 404);
+        assert!(!body.contains("ums_"));
+
+        let mut config = Config::default();
+        config.metrics.token = Some(String::from("scrape-token"));
+        for authorization in [None, Some("Bearer wrong"), Some("scrape-token"), Some("Bearer ")] {
+            let (status, body) = scrape(config.clone(), authorization).await;
+            assert_eq!(status, 401, "{:?}", authorization);
+            assert!(body.contains(r#""code":"unauthenticated""#));
+        }
+
+        let (status, body) = scrape(config, Some("Bearer scrape-token")).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("ums_db_connections_open"));
+    }
+
+    #[actix_web::test]
+    async fn header_sign_in_keeps_lockout_suspension_and_second_factor_answers() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let administrator = db.user(Role::Admin, "admin@aubg.edu");
+        let suspended = db.user(Role::Student, "suspended@aubg.edu");
+        db.suspend(&suspended);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(get_self),
+        )
+        .await;
+        let sign_in = |email: &str, password: &str| {
+            test::TestRequest::get()
+                .uri("/account")
+                .insert_header(("login_email", email))
+                .insert_header(("login_password", password))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, sign_in("student@aubg.edu", PASSWORD)).await;
+        assert_eq!(res.status(), 200);
+
+        // a wrong password and an unknown account are indistinguishable
+        for (email, password) in [("student@aubg.edu", "wrong"), ("nobody@aubg.edu", PASSWORD)] {
+            let res = test::call_service(&app, sign_in(email, password)).await;
+            assert_eq!(res.status(), 401);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], "Invalid login credentials.");
+        }
+
+        let res = test::call_service(&app, sign_in(&administrator.email, PASSWORD)).await;
+        assert_eq!(res.status(), 403);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert!(body["error"].as_str().unwrap().contains("Two-factor authentication"));
+
+        let res = test::call_service(&app, sign_in(&suspended.email, PASSWORD)).await;
+        assert_eq!(res.status(), 403);
+
+        for _ in 0..db.config.lockout.free_attempts {
+            test::call_service(&app, sign_in(&student.email, "wrong")).await;
+        }
+        let res = test::call_service(&app, sign_in(&student.email, PASSWORD)).await;
+        assert_eq!(res.status(), 429);
+        let retry_after: i64 = res.headers().get("Retry-After").unwrap().to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 0);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/account")
+                .insert_header(("session_token", "not-a-session"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn header_sign_in_reveals_account_state_only_to_the_password_holder() {
+        let db = TestDb::new();
+        let suspended = db.user(Role::Student, "suspended@aubg.edu");
+        db.suspend(&suspended);
+        let service = db.user(Role::Service, "service@aubg.edu");
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(get_self),
+        )
+        .await;
+        // every request from its own address, so the client throttle stays out of the way
+        let client = std::cell::Cell::new(1);
+        let sign_in = |email: &str, password: &str| {
+            client.set(client.get() + 1);
+            test::TestRequest::get()
+                .uri("/account")
+                .peer_addr(format!("10.0.0.{}:4000", client.get()).parse().unwrap())
+                .insert_header(("login_email", email))
+                .insert_header(("login_password", password))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, sign_in("nobody@aubg.edu", "wrong")).await;
+        let unknown = (res.status(), test::read_body(res).await);
+        assert_eq!(unknown.0, 401);
+        for user in [&suspended, &service] {
+            let res = test::call_service(&app, sign_in(&user.email, "wrong")).await;
+            assert_eq!((res.status(), test::read_body(res).await), unknown, "{}", user.email);
+        }
+
+        let res = test::call_service(&app, sign_in(&suspended.email, PASSWORD)).await;
+        assert_eq!(res.status(), 403);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "User is suspended.");
+    }
+
+    #[actix_web::test]
+    async fn id_routes_act_on_exactly_the_row_they_name() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        db.grant(Role::Teacher, Permission::UserDelete);
+        let students: Vec<User> = (0..12).map(|n| db.user(Role::Student, &format!("s{}@aubg.edu", n))).collect();
+
+        let mut conn = db.signed_in(&teacher);
+        for n in 0..12 {
+            conn.register_courses(vec![Courses {
+                id: 0,
+                teacher_id: teacher.id,
+                course: format!("Course {}", n),
+                course_nr: format!("COS {}", n),
+                description: String::new(),
+                cr_cost: 3,
+                timeslots: String::from("MWF 10:00"),
+            }])
+            .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(delete_user)
+                .service(remove_course)
+                .service(enroll),
+        )
+        .await;
+        let as_user = |method: Method, uri: &str, user: &User| {
+            test::TestRequest::default()
+                .method(method)
+                .uri(uri)
+                .insert_header(("login_email", user.email.as_str()))
+                .insert_header(("login_password", PASSWORD))
+                .to_request()
+        };
+        let counts = || {
+            let conn = db.connect();
+            (conn.get_users_by_filters(vec![]).unwrap().len(), conn.list_courses(vec![]).unwrap().len())
+        };
+        let before = counts();
+
+        // nothing that is not a whole id reaches the database, and missing ids are 404s
+        for (segment, status) in [
+            ("abc", 400),
+            ("1%20OR%201=1", 400),
+            ("1.0", 400),
+            ("%27", 400),
+            ("2147483648", 400),
+            ("-1", 404),
+            ("0", 404),
+            ("999", 404),
+        ] {
+            for (method, uri) in [
+                (Method::DELETE, format!("/admin/users/{}", segment)),
+                (Method::DELETE, format!("/courses/{}", segment)),
+            ] {
+                let res = test::call_service(&app, as_user(method, &uri, &teacher)).await;
+                assert_eq!(res.status(), status, "{}", uri);
+            }
+            let res = test::call_service(&app, as_user(Method::POST, &format!("/enroll/{}", segment), &students[0])).await;
+            assert_eq!(res.status(), status, "/enroll/{}", segment);
+        }
+        assert_eq!(counts(), before);
+
+        // id 1 also "contains" in 10, 11, 12, ...
+        let courses = db.connect().list_courses(vec![]).unwrap();
+        let first = courses.iter().map(|c| c.id).min().unwrap();
+        let res = test::call_service(&app, as_user(Method::POST, &format!("/enroll/{}", first), &students[0])).await;
+        assert_eq!(res.status(), 200);
+        let enrolled = db.signed_in(&students[0]).list_enrollments().unwrap();
+        assert_eq!(enrolled.iter().map(|e| e.course_id).collect::<Vec<i32>>(), vec![first]);
+
+        let res = test::call_service(&app, as_user(Method::DELETE, &format!("/admin/users/{}", students[1].id), &teacher)).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(counts().0, before.0 - 1);
+        assert!(db.connect().user_by_id(students[1].id).is_err());
+    }
+
+    #[actix_web::test]
+    async fn course_etags_revalidate_reads_and_guard_writes() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        db.signed_in(&teacher)
+            .register_courses(vec![Courses {
+                id: 0,
+                teacher_id: teacher.id,
+                course: String::from("Ethics"),
+                course_nr: String::from("PHI 101"),
+                description: String::new(),
+                cr_cost: 3,
+                timeslots: String::from("MWF 10:00"),
+            }])
+            .unwrap();
+        let id = db.connect().list_courses(vec![]).unwrap()[0].id;
+        let uri = format!("/courses/{}", id);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(get_course)
+                .service(update_course),
+        )
+        .await;
+        let header = |res: &actix_web::dev::ServiceResponse, name: &str| {
+            res.headers().get(name).map(|v| v.to_str().unwrap().to_owned()).unwrap_or_default()
+        };
+        let patch = |tag: &str, name: &str| {
+            test::TestRequest::patch()
+                .uri(&uri)
+                .insert_header(("login_email", teacher.email.as_str()))
+                .insert_header(("login_password", PASSWORD))
+                .insert_header(("If-Match", tag))
+                .set_json(json!({"name": name}))
+                .to_request()
+        };
+
+        // reads are per caller: revalidated every time and never kept by shared caches
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let tag = header(&res, "ETag");
+        assert!(!tag.is_empty());
+        assert!(header(&res, "Cache-Control").contains("private"));
+        for credential in ["Authorization", "session_token", "api_key"] {
+            assert!(header(&res, "Vary").contains(credential), "Vary misses {}", credential);
+        }
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&uri).insert_header(("If-None-Match", tag.as_str())).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 304);
+        assert!(test::read_body(res).await.is_empty());
+
+        // the current tag lets the write through and hands back the new one
+        let res = test::call_service(&app, patch(&tag, "Applied Ethics")).await;
+        assert_eq!(res.status(), 200);
+        let updated = header(&res, "ETag");
+        assert_ne!(updated, tag);
+
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(header(&res, "ETag"), updated);
+
+        // the tag from before that edit no longer matches, and nothing is written
+        let res = test::call_service(&app, patch(&tag, "Ethics Again")).await;
+        assert_eq!(res.status(), 412);
+        assert_eq!(db.connect().course_by_id(id).unwrap().course, "Applied Ethics");
+    }
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    fn mentions_password(value: &Value) -> bool {
+        match value {
+            Value::Object(map) => map.iter().any(|(k, v)| k == "password" || mentions_password(v)),
+            Value::Array(items) => items.iter().any(mentions_password),
+            _ => false,
+        }
+    }
+
+    #[actix_web::test]
+    async fn enrollment_and_statistics_answer_json_objects() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        let student = db.user(Role::Student, "student@aubg.edu");
+        db.grant(Role::Teacher, Permission::StatsRead);
+        db.signed_in(&teacher)
+            .register_courses(vec![Courses {
+                id: 0,
+                teacher_id: teacher.id,
+                course: String::from("Ethics"),
+                course_nr: String::from("PHI 101"),
+                description: String::new(),
+                cr_cost: 3,
+                timeslots: String::from("MWF 10:00"),
+            }])
+            .unwrap();
+        let id = db.connect().list_courses(vec![]).unwrap()[0].id;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(enroll)
+                .service(get_stats),
+        )
+        .await;
+        let as_user = |req: test::TestRequest, user: &User| {
+            req.insert_header(("login_email", user.email.as_str()))
+                .insert_header(("login_password", PASSWORD))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, as_user(test::TestRequest::post().uri(&format!("/enroll/{}", id)), &student)).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+        let enrolled: PrivateUser = test::read_body_json(res).await;
+        assert_eq!((enrolled.id, enrolled.email.as_str()), (student.id, "student@aubg.edu"));
+
+        let res = test::call_service(&app, as_user(test::TestRequest::get().uri("/admin/stats"), &teacher)).await;
+        assert_eq!(res.status(), 200);
+        let stats: Statistics = test::read_body_json(res).await;
+        assert_eq!((stats.registered_users, stats.courses), (2, 1));
+    }
+
+    #[actix_web::test]
+    async fn account_details_are_only_shown_to_user_readers_and_the_user_themself() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let mut conn = db.signed_in(&teacher);
+        conn.register_courses(vec![Courses {
+            id: 0,
+            teacher_id: teacher.id,
+            course: String::from("Ethics"),
+            course_nr: String::from("PHI 101"),
+            description: String::new(),
+            cr_cost: 3,
+            timeslots: String::from("MWF 10:00"),
+        }])
+        .unwrap();
+        let course = db.connect().list_courses(vec![]).unwrap().remove(0);
+        db.signed_in(&student).enroll_courses(vec![course]).unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(get_users)
+                .service(get_students)
+                .service(get_courses),
+        )
+        .await;
+        let get = |uri: &str, user: Option<&User>| {
+            let mut req = test::TestRequest::get().uri(uri);
+            if let Some(user) = user {
+                req = req
+                    .insert_header(("login_email", user.email.as_str()))
+                    .insert_header(("login_password", PASSWORD));
+            }
+            req.to_request()
+        };
+        let public = vec!["id", "role", "username"];
+        let private = vec!["email", "forcenewpw", "id", "phone", "role", "suspended", "username", "verified"];
+
+        // (viewer, uri, the user listed, the fields they are shown)
+        let cases = [
+            (None, "/courses?include=teacher", teacher.id, &public),
+            (Some(&student), "/users", student.id, &private),
+            (Some(&teacher), "/students", student.id, &public),
+        ];
+        for (viewer, uri, listed, expected) in cases {
+            let res = test::call_service(&app, get(uri, viewer)).await;
+            assert_eq!(res.status(), 200, "{}", uri);
+            let body: Value = test::read_body_json(res).await;
+            assert!(!mentions_password(&body), "{}: {}", uri, body);
+
+            let shown = body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item.get("teacher").unwrap_or(item))
+                .find(|u| u["id"] == listed)
+                .unwrap_or_else(|| panic!("{}: {}", uri, body));
+            assert_eq!(&keys(shown), expected, "{}", uri);
+        }
+
+        // roster.read alone shows who is enrolled, user.read also shows their details
+        db.grant(Role::Teacher, Permission::UserRead);
+        for uri in ["/students", "/users"] {
+            let res = test::call_service(&app, get(uri, Some(&teacher))).await;
+            let body: Value = test::read_body_json(res).await;
+            assert!(!mentions_password(&body), "{}: {}", uri, body);
+            for user in body.as_array().unwrap() {
+                assert_eq!(keys(user), private, "{}", uri);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn deleting_users_refuses_the_unprivileged_whether_or_not_the_id_exists() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let other = db.user(Role::Student, "other@aubg.edu");
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(delete_user),
+        )
+        .await;
+
+        for id in [other.id, other.id + 100] {
+            let res = test::call_service(
+                &app,
+                test::TestRequest::delete()
+                    .uri(&format!("/admin/users/{}", id))
+                    .insert_header(("login_email", student.email.as_str()))
+                    .insert_header(("login_password", PASSWORD))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(res.status(), 403, "{}", id);
+        }
+        assert!(db.connect().user_by_id(other.id).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn course_views_follow_fields_and_include() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        db.grant(Role::Teacher, Permission::DepartmentCreate);
+        db.grant(Role::Teacher, Permission::DepartmentManage);
+
+        let mut conn = db.signed_in(&teacher);
+        conn.new_department("Philosophy").unwrap();
+        let department = conn.get_departments(vec![]).unwrap().remove(0);
+        let mut account = conn
+            .get_teacher_accounts()
+            .unwrap()
+            .into_iter()
+            .find(|a| a.teacher_id == teacher.id)
+            .unwrap();
+        account.dept_id = department.id;
+        conn.update_teacher_account(account).unwrap();
+        conn.register_courses(vec![Courses {
+            id: 0,
+            teacher_id: teacher.id,
+            course: String::from("Ethics"),
+            course_nr: String::from("PHI 101"),
+            description: String::from("Right and wrong"),
+            cr_cost: 3,
+            timeslots: String::from("MWF 10:00"),
+        }])
+        .unwrap();
+        let id = db.connect().list_courses(vec![]).unwrap()[0].id;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .service(get_courses)
+                .service(get_course),
+        )
+        .await;
+        let get = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+
+        let res = test::call_service(&app, get(String::from("/courses?fields=id,cr_cost"))).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body, json!([{"id": id, "cr_cost": 3}]));
+
+        for uri in [
+            String::from("/courses?fields=course&include=teacher,department"),
+            format!("/courses/{}?fields=course&include=teacher,department", id),
+        ] {
+            let res = test::call_service(&app, get(uri.clone())).await;
+            assert_eq!(res.status(), 200, "{}", uri);
+            let body: Value = test::read_body_json(res).await;
+            let course = body.as_array().map_or(&body, |b| &b[0]);
+            assert_eq!(keys(course), vec!["course", "department", "teacher"], "{}", uri);
+            assert_eq!(course["course"], "Ethics");
+            assert_eq!(course["teacher"], json!({"id": teacher.id, "username": "teacher", "role": "teacher"}));
+            assert_eq!(course["department"], json!({"id": department.id, "name": "Philosophy"}));
+        }
+
+        for uri in [
+            String::from("/courses?fields=id,password"),
+            String::from("/courses?include=students"),
+            format!("/courses/{}?include=teacher,roster", id),
+        ] {
+            let res = test::call_service(&app, get(uri.clone())).await;
+            assert_eq!(res.status(), 400, "{}", uri);
+            let error: Value = test::read_body_json(res).await;
+            assert_eq!(error["code"], "validation_failed", "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use actix_web::web;
+
+use super::openapi;
+use super::rest_api::*;
+
+pub const V1_PREFIX: &str = "/api/v1";
+
+// Every route the server answers. Probes, metrics and the docs stay unversioned so
+// orchestrators and scrapers do not have to follow API versions; everything else is
+// mounted per version, which lets a v2 scope live next to v1 while clients move over.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz)
+        .service(readyz)
+        .service(get_metrics)
+        .service(openapi::docs())
+        .service(web::scope(V1_PREFIX).configure(v1));
+}
+
+fn v1(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
+        // users
+        .service(get_users)
+        .service(get_students)
+        .service(get_teachers)
+        .service(update_user)
+        .service(delete_user)
+        // departments
+        .service(get_departments)
+        .service(get_department)
+        .service(new_department)
+        .service(delete_department)
+        .service(invite_to_department)
+        .service(kick_from_department)
+        // courses
+        .service(get_courses)
+        .service(get_course)
+        .service(new_course)
+        .service(update_course)
+        .service(remove_course)
+        .service(grade_student)
+        .service(enroll)
+        .service(unenroll)
+        // account
+        .service(get_self)
+        .service(update_self)
+        .service(get_impersonations)
+        .service(get_sessions)
+        .service(revoke_other_sessions)
+        .service(revoke_session)
+        // auth
+        .service(login)
+        .service(login_totp)
+        .service(login_sso)
+        .service(login_sso_callback)
+        .service(enroll_totp)
+        .service(confirm_totp)
+        .service(disable_totp)
+        .service(regenerate_recovery_codes)
+        .service(logout)
+        .service(register)
+        .service(register_admin)
+        // admin
+        .service(admin)
+        .service(get_stats)
+        .service(invite_admin)
+        .service(get_admin_invitations)
+        .service(revoke_admin_invitation)
+        .service(get_audit_log)
+        .service(create_service_account)
+        .service(get_api_keys)
+        .service(create_api_key)
+        .service(revoke_api_key)
+        .service(impersonate_user)
+        .service(get_user_sessions)
+        .service(revoke_user_sessions)
+        .service(revoke_user_session)
+        .service(get_login_attempts)
+        .service(get_lockouts)
+        .service(unlock_user)
+        .service(get_role_permissions)
+        .service(grant_permission)
+        .service(revoke_permission);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::Config;
+    use super::super::openapi::ApiDoc;
+    use super::super::rbac::{Permission, Role};
+    use super::super::rest_api::{json_config, query_config};
+    use super::super::testing::{TestDb, PASSWORD};
+    use super::*;
+    use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+    use utoipa::OpenApi;
+
+    // "GET /api/v1/courses/{id}" for every operation in the generated spec
+    fn documented() -> BTreeSet<String> {
+        let mut operations = BTreeSet::new();
+
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            let methods = [
+                ("GET", item.get.is_some()),
+                ("POST", item.post.is_some()),
+                ("PUT", item.put.is_some()),
+                ("PATCH", item.patch.is_some()),
+                ("DELETE", item.delete.is_some()),
+            ];
+            for (method, _) in methods.iter().filter(|(_, present)| *present) {
+                operations.insert(format!("{} {}", method, path));
+            }
+        }
+
+        operations
+    }
+
+    // The same for what configure() mounts, read from the .service() calls here and the
+    // route attributes on the handlers they name
+    fn registered() -> BTreeSet<String> {
+        let routes = include_str!("routes.rs");
+        let handlers = include_str!("rest_api.rs");
+        let v1_start = routes.find("fn v1(").unwrap();
+
+        let mut operations = BTreeSet::new();
+        for (at, _) in routes.match_indices(".service(") {
+            let name: String = routes[at + ".service(".len()..]
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+
+            // the docs and the v1 scope are not handlers
+            let Some(definition) = handlers.find(&format!("pub async fn {}(", name)) else {
+                continue;
+            };
+
+            let attribute = handlers[..definition].rfind("\n#[").unwrap();
+            let route = handlers[attribute + 3..].split_once(")]").unwrap().0;
+            let (method, path) = route.split_once('(').unwrap();
+            let path = path.trim_matches('"');
+
+            let prefix = if at > v1_start { V1_PREFIX } else { "" };
+            operations.insert(format!("{} {}{}", method.to_uppercase(), prefix, path));
+        }
+
+        operations
+    }
+
+    #[test]
+    fn the_spec_documents_exactly_the_mounted_routes() {
+        let documented = documented();
+        let registered = registered();
+
+        let undocumented: Vec<&String> = registered.difference(&documented).collect();
+        let unmounted: Vec<&String> = documented.difference(&registered).collect();
+        assert!(undocumented.is_empty(), "mounted but missing from the spec: {:?}", undocumented);
+        assert!(unmounted.is_empty(), "in the spec but not mounted: {:?}", unmounted);
+        assert!(registered.len() > 50);
+    }
+
+    // Path parameters filled in, so the router has something to match
+    fn concrete(path: &str) -> String {
+        path.split('/')
+            .map(|segment| if segment.starts_with('{') { "1" } else { segment })
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+
+    #[actix_web::test]
+    async fn every_documented_route_is_answered() {
+        let db = TestDb::new();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .app_data(json_config())
+                .app_data(query_config())
+                .configure(configure),
+        )
+        .await;
+
+        for operation in documented().into_iter().chain([String::from("GET /openapi.json")]) {
+            let (method, path) = operation.split_once(' ').unwrap();
+            let req = TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri(&concrete(path))
+                .to_request();
+            let res = call_service(&app, req).await;
+
+            // a handler's own 404 ("Course not found.") comes with an error body; the
+            // router's is empty
+            let status = res.status();
+            let body = read_body(res).await;
+            assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{}", operation);
+            assert!(status != StatusCode::NOT_FOUND || !body.is_empty(), "{} is not routed", operation);
+        }
+    }
+
+    // What the spec says about one operation, as far as malformed input goes
+    struct Operation {
+        method: Method,
+        path: String,
+        // signed out callers are served too, so bad credentials may be ignored
+        public: bool,
+        integer_path_params: Vec<String>,
+        query_params: Vec<String>,
+        body_required: bool,
+    }
+
+    impl Operation {
+        fn name(&self) -> String {
+            format!("{} {}", self.method, self.path)
+        }
+
+        // `id` for the integer parameters, a harmless string for the others
+        fn uri(&self, id: &str, query: &str) -> String {
+            let path: Vec<String> = self
+                .path
+                .split('/')
+                .map(|segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) if self.integer_path_params.iter().any(|p| p == name) => id.to_owned(),
+                    Some(_) => String::from("unknown"),
+                    None => segment.to_owned(),
+                })
+                .collect();
+            format!("{}{}", path.join("/"), query)
+        }
+    }
+
+    fn operations() -> Vec<Operation> {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut operations = Vec::new();
+
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                let Ok(method) = Method::from_bytes(method.to_uppercase().as_bytes()) else {
+                    continue;
+                };
+                let parameters = operation["parameters"].as_array().cloned().unwrap_or_default();
+                let named = |location: &str, integer: bool| -> Vec<String> {
+                    parameters
+                        .iter()
+                        .filter(|p| p["in"] == location)
+                        .filter(|p| !integer || p["schema"]["type"] == "integer")
+                        .map(|p| p["name"].as_str().unwrap().to_owned())
+                        .collect()
+                };
+
+                operations.push(Operation {
+                    method,
+                    path: path.clone(),
+                    public: operation["security"]
+                        .as_array()
+                        .is_some_and(|s| s.iter().any(|r| r.as_object().is_some_and(|r| r.is_empty()))),
+                    integer_path_params: named("path", true),
+                    query_params: named("query", false),
+                    body_required: operation["requestBody"]["required"] == true,
+                });
+            }
+        }
+
+        operations
+    }
+
+    // A request that went wrong must say so in the usual envelope, never as a server fault
+    async fn rejected(res: actix_web::dev::ServiceResponse, case: &str, must_reject: bool) {
+        let status = res.status();
+        let body = read_body(res).await;
+        assert!(!status.is_server_error(), "{}: {}", case, status);
+        if must_reject {
+            assert!(status.is_client_error(), "{}: accepted with {}", case, status);
+        }
+        if status.is_client_error() {
+            let error: Value = serde_json::from_slice(&body)
+                .unwrap_or_else(|_| panic!("{}: {} without a JSON body: {:?}", case, status, body));
+            assert!(error["error"].is_string() && error["code"].is_string(), "{}: {}", case, error);
+        }
+    }
+
+    #[actix_web::test]
+    async fn malformed_requests_get_a_client_error_on_every_operation() {
+        // no throttling, so every request reaches the part it is meant to exercise
+        let mut config = Config::default();
+        config.lockout.free_attempts = 1_000_000;
+        config.lockout.max_account_failures = 2_000_000;
+        config.lockout.max_ip_failures = 2_000_000;
+        config.metrics.token = Some(String::from("scrape-token"));
+        let db = TestDb::with_config(config);
+
+        // signed in with every permission, so handlers get past authorization to the input
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        for permission in Permission::ALL {
+            db.grant(Role::Teacher, permission);
+        }
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(db.config.clone()))
+                .app_data(json_config())
+                .app_data(query_config())
+                .configure(configure),
+        )
+        .await;
+        let signed_in = |operation: &Operation, uri: &str| {
+            TestRequest::default()
+                .method(operation.method.clone())
+                .uri(uri)
+                .insert_header(("login_email", teacher.email.as_str()))
+                .insert_header(("login_password", PASSWORD))
+                .insert_header(("Authorization", "Bearer scrape-token"))
+        };
+
+        let bad_ids = ["abc", "1.5", "-", "%20", "%00", "99999999999999999999", "0x10", "1;2", "%F0%28%8C%28"];
+        let bad_bodies: [(&str, Vec<u8>); 8] = [
+            ("application/json", br#"{"name": "x"#.to_vec()),
+            ("application/json", br#"{"name": 5, "teacher_id": "five"}"#.to_vec()),
+            ("application/json", br#""a string""#.to_vec()),
+            ("application/json", b"null".to_vec()),
+            ("application/json", b"\xff\xfe{}".to_vec()),
+            ("application/json", format!(r#"{{"name": "{}"}}"#, "a".repeat(70 * 1024)).into_bytes()),
+            ("text/plain", br#"{"name": "x"}"#.to_vec()),
+            ("application/json", b"".to_vec()),
+        ];
+        let bad_queries = [
+            ("filter", "nonsense"),
+            ("filter", "password:eq:x"),
+            ("filter", "name:like"),
+            ("filter", "%ZZ"),
+            ("fields", "password"),
+            ("fields", "id,secret"),
+            ("include", "everything"),
+            ("state", ""),
+        ];
+        let bad_credentials: [(&str, &[u8]); 6] = [
+            ("session_token", b"not-a-session"),
+            ("session_token", b"\xff\xfe"),
+            ("api_key", b""),
+            ("api_key", b"ums_not-a-key"),
+            ("login_email", b"\xff"),
+            ("login_password", PASSWORD.as_bytes()),
+        ];
+
+        let operations = operations();
+        assert!(operations.len() > 50);
+        // the spec is read correctly, or the cases below would test nothing
+        assert!(operations.iter().filter(|o| !o.integer_path_params.is_empty()).count() > 10);
+        assert!(operations.iter().filter(|o| o.body_required).count() > 10);
+        assert!(operations.iter().filter(|o| o.query_params.iter().any(|p| p == "filter")).count() >= 5);
+        assert!(operations.iter().filter(|o| o.public).count() > 5);
+        for operation in &operations {
+            let name = operation.name();
+            let missing = operation.uri("999999", "");
+
+            for id in bad_ids.iter().filter(|_| !operation.integer_path_params.is_empty()) {
+                let uri = operation.uri(id, "");
+                let res = call_service(&app, signed_in(operation, &uri).to_request()).await;
+                rejected(res, &format!("{} with id {:?}", name, id), true).await;
+            }
+
+            for (content_type, body) in &bad_bodies {
+                let req = signed_in(operation, &missing)
+                    .insert_header((CONTENT_TYPE, *content_type))
+                    .set_payload(body.clone())
+                    .to_request();
+                let case = format!("{} with a {} byte {} body", name, body.len(), content_type);
+                rejected(call_service(&app, req).await, &case, operation.body_required).await;
+            }
+
+            for (param, value) in &bad_queries {
+                let declared = operation.query_params.iter().any(|p| p == param);
+                let uri = operation.uri("999999", &format!("?{}={}", param, value));
+                let res = call_service(&app, signed_in(operation, &uri).to_request()).await;
+                rejected(res, &format!("{} with ?{}={}", name, param, value), declared).await;
+            }
+            let res = call_service(&app, signed_in(operation, &operation.uri("999999", "?%ZZ=%%&&=&a")).to_request()).await;
+            rejected(res, &format!("{} with a garbled query", name), false).await;
+
+            for (header, value) in &bad_credentials {
+                let req = TestRequest::default()
+                    .method(operation.method.clone())
+                    .uri(&missing)
+                    .insert_header((*header, HeaderValue::from_bytes(value).unwrap()))
+                    .to_request();
+                let case = format!("{} with {} {:?}", name, header, String::from_utf8_lossy(value));
+                rejected(call_service(&app, req).await, &case, !operation.public).await;
+            }
+        }
+    }
+}
@@ -1,17 +1,32 @@
+use super::config::Config;
 use super::db_driver::*;
+use super::error::ApiError;
 use super::filter::*;
-use super::password;
+use super::lockout::*;
+use super::metrics;
+use super::password::{self, Verification};
+use super::rbac::{Permission, Role};
 use super::table_models::*;
+use super::tokens;
+use super::totp;
 
 use anyhow::anyhow;
-use anyhow::Ok;
 use anyhow::Result;
 use chrono::Datelike;
 use regex::Regex;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use utoipa::ToSchema;
+use std::sync::Arc;
+use tracing::{instrument, Span};
 
-#[derive(Debug, Serialize, Deserialize)]
+const RECOVERY_CODE_COUNT: usize = 10;
+const TOTP_ISSUER: &str = "AUBG University Management System";
+const API_KEY_PREFIX: &str = "ums_";
+// actor id recorded for audit entries that no signed in user caused (e.g. bootstrap)
+const SYSTEM_ACTOR: i32 = 0;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Statistics {
     pub registered_users: i32,
     pub suspended_users: i32,
@@ -22,30 +37,127 @@ pub struct Statistics {
     pub departments: i32,
 }
 
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+// Handed out exactly once; only the hash of `token` is stored
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct IssuedInvitation {
+    pub id: i32,
+    pub token: String,
+    pub email: String,
+    pub expires_at: i64,
+}
+
+// Like IssuedInvitation, the plaintext key is only ever returned at creation
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct IssuedApiKey {
+    pub id: i32,
+    pub key: String,
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+    pub expires_at: i64,
+}
+
+// Secrets for one authorization request; the state travels through the browser,
+// the rest stays here until the identity provider redirects back
+#[derive(Debug)]
+pub struct SsoChallenge {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+// A session as shown to its owner (or an admin); never includes the token
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionInfo {
+    pub id: i32,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub expires_at: i64,
+    pub ip: String,
+    pub user_agent: String,
+    pub pending_mfa: bool,
+    pub impersonator_id: i32,
+    // the session this request was made with
+    pub current: bool,
+}
+
+// What POST /login hands back; each variant carries the new session token
+pub enum LoginOutcome {
+    Authenticated(String),
+    TwoFactorRequired(String),
+    TwoFactorEnrollmentRequired(String),
+}
+
 pub struct ServerConnection {
     db: DbDriver,
     session: Option<User>,
+    current_session: Option<Session>,
+    // set when the caller authenticated with an API key; limits what authorize() allows
+    api_scopes: Option<Vec<Permission>>,
+    // the admin behind an impersonation session; `session` is then the impersonated user
+    impersonator: Option<User>,
+    // "METHOD /path" of the request being served, recorded for impersonated actions
+    request_line: String,
+    client_ip: String,
+    client_agent: String,
+    config: Arc<Config>,
+    // the request's root span, tagged with who is acting once that is known
+    request_span: Span,
 }
 
 // Public methods
 impl ServerConnection {
-    pub fn new() -> Self {
-        Self {
-            db: DbDriver::init(),
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        Ok(Self {
+            db: DbDriver::init(&config)?,
             session: None,
-        }
+            current_session: None,
+            api_scopes: None,
+            impersonator: None,
+            request_line: String::new(),
+            client_ip: String::from("unknown"),
+            client_agent: String::new(),
+            config,
+            request_span: Span::current(),
+        })
+    }
+
+    pub fn set_client_ip(&mut self, ip: &str) {
+        self.client_ip = ip.to_owned();
+    }
+
+    pub fn set_client_agent(&mut self, agent: &str) {
+        self.client_agent = agent.to_owned();
+    }
+
+    pub fn set_request_line(&mut self, method: &str, path: &str) {
+        self.request_line = format!("{} {}", method, path);
+    }
+
+    pub fn impersonator(&self) -> Option<&User> {
+        self.impersonator.as_ref()
+    }
+
+    pub fn current_user(&self) -> Option<&User> {
+        self.session.as_ref()
     }
 
     // fetch all users from the database
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
     pub fn get_users(&self) -> Result<Vec<User>> {
         let users = self.db.find(Table::Users, vec![], None)?;
         let u = users
             .into_iter()
-            .map(|x| {
+            .filter_map(|x| {
                 if let ReceiverType::User(user) = x {
-                    user
+                    Some(user)
                 } else {
-                    unreachable!()
+                    None
                 }
             })
             .collect();
@@ -53,6 +165,7 @@ impl ServerConnection {
         Ok(u)
     }
 
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
     pub fn get_users_by_filters(&self, filters: Vec<Filter>) -> Result<Vec<User>> {
         let finding = self.db.find(Table::Users, filters, None)?;
 
@@ -70,690 +183,2194 @@ impl ServerConnection {
         Ok(users)
     }
 
-    pub fn register_user(&mut self, user: User) -> Result<()> {
-        if !self.session.is_none() {
-            return Err(anyhow!("Must be signed out."));
-        }
+    // The read policy for user listings. The directory takes user.read (admins); with
+    // roster.read (teachers) only the students enrolled in the caller's own courses are
+    // listed. Anyone else who is signed in (students) only ever finds their own account,
+    // and signed out callers are refused; the course catalog is what they may browse.
+    // Filters come from ?filter= and only ever narrow what the policy lets through; student
+    // account filters (advisor, discipline, ...) keep the users whose account matches.
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn list_users(&self, role: Option<Role>, filters: Vec<Filter>) -> Result<Vec<User>> {
+        let (accounts, mut filters): (Vec<Filter>, Vec<Filter>) = filters
+            .into_iter()
+            .partition(|f| matches!(f, Filter::StudentAccount(_)));
+        filters.extend(role.map(|r| Filter::Users(UsersFilter::Role(r))));
+
+        let users = if self.can(Permission::UserRead) {
+            self.get_users_by_filters(filters)?
+        } else if !self.can(Permission::RosterRead) {
+            let me = self
+                .session
+                .as_ref()
+                .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+            filters.push(Filter::Users(UsersFilter::Id(me.id)));
+            self.get_users_by_filters(filters)?
+        } else {
+            // only students are ever on a roster
+            if role.is_some_and(|r| r != Role::Student) {
+                self.authorize(Permission::UserRead)?;
+            }
 
-        let email_regex = Regex::new(r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@aubg\.edu$")?;
-        let phone_regex = Regex::new(r#"^\+?[0-9]{2}[-. ]?[0-9]{4}[-. ]?[0-9]{4}$"#)?;
-        let password_rules = user.password.len() >= 8
-            && user.password.chars().any(|c| c.is_ascii_lowercase())
-            && user.password.chars().any(|c| c.is_ascii_uppercase())
-            && user.password.chars().any(|c| c.is_ascii_digit())
-            && user.password.chars().any(|c| "@$!%*?&".contains(c));
+            let teacher = self.authorize(Permission::RosterRead)?;
+            let roster = self.roster(teacher.id)?;
 
-        if self
-            .get_users_by_filters(vec![Filter::Users(UsersFilter::Email(
-                user.email.to_lowercase().clone(),
-            ))])?
-            .len()
-            > 0
-        {
-            return Err(anyhow!("A user with this email already exists."));
-        }
+            filters.push(Filter::Users(UsersFilter::Role(Role::Student)));
+            self.get_users_by_filters(filters)?
+                .into_iter()
+                .filter(|u| roster.contains(&u.id))
+                .collect()
+        };
 
-        if user.username.is_empty() {
-            return Err(anyhow!("Account name cannot be empty."));
+        if accounts.is_empty() {
+            return Ok(users);
         }
 
-        if !email_regex.is_match(&user.email) {
-            return Err(anyhow!("Must be a valid AUBG email."));
-        }
+        let students = self
+            .db
+            .find(Table::StudentAccount, accounts, None)?
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::StudentAccount(account) = x {
+                    Some(account.student_id)
+                } else {
+                    None
+                }
+            })
+            .collect::<std::collections::HashSet<_>>();
 
-        if !phone_regex.is_match(&user.phone) && !user.phone.is_empty() {
-            return Err(anyhow!("Invalid phone number."));
-        }
+        Ok(users
+            .into_iter()
+            .filter(|u| students.contains(&u.id))
+            .collect())
+    }
 
-        if !password_rules {
-            return Err(anyhow!(
-                "The password does not meet the following criteria:\n
-            - Must be at least 8 characters long\n
-            - Must contain at least 1 uppercase letter\n
-            - Must contain at least 1 lowercase letter\n
-            - Must contain at least 1 number\n
-            - Must contain at least 1 special character (@, $, !, %, *, ?, &)\n"
-            ));
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn register_user(&mut self, user: User) -> Result<()> {
+        if self.session.is_some() {
+            return Err(ApiError::forbidden("Must be signed out.").into());
         }
 
-        let mut user = user.to_owned();
-
-        let salt = password::generate_salt();
-        user.password = password::hash(&user.password, salt);
-
-        self.db.insert(vec![ReceiverType::User(user)])?;
+        self.create_account(user, false)?;
 
         Ok(())
     }
 
-    pub fn login(&mut self, email: String, password: String) -> Result<()> {
-        let binding = self.get_users_by_filters(vec![Filter::Users(UsersFilter::Email(email))])?;
-        let user = binding.get(0).ok_or_else(|| anyhow!("User not found."))?; // if none, user not found
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn admin_exists(&self) -> Result<bool> {
+        Ok(!self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(Role::Admin))])?
+            .is_empty())
+    }
 
-        // If the user is suspended, they cannot login
-        if user.suspended {
-            return Err(anyhow!("User is suspended."));
+    // First-run setup: only succeeds while no admin account exists
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn bootstrap_admin(&mut self, mut user: User) -> Result<()> {
+        if self.admin_exists()? {
+            return Err(ApiError::conflict("An administrator already exists; use an invitation instead.").into());
         }
 
-        if user.forcenewpw {
-            return Err(anyhow!("User must change password."));
-        }
+        user.role = Role::Admin;
+        user.verified = true;
+        let admin = self.create_account(user, false)?;
 
-        // check hash for validity and then compare both server and client password hashes
-        if password::verify(&user.password, &password) {
-            self.session = Some(user.to_owned());
-            Ok(())
-        } else {
-            Err(anyhow!("Invalid username or password."))
-        }
+        self.audit(SYSTEM_ACTOR, "admin.bootstrap", &format!("user:{}", admin.id), &admin.email)
     }
 
-    pub fn update_user(&mut self, user: User) -> Result<()> {
-        if let Some(s) = &self.session {
-            match s.role.to_lowercase().as_str() {
-                "admin" => self.update_user_as_admin(user)?,
-                _ => self.update_user_as_student(user)?,
-            }
-        } else {
-            return Err(anyhow!("Must be signed in."));
-        }
-        Ok(())
-    }
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn invite_admin(&mut self, email: Option<String>) -> Result<IssuedInvitation> {
+        let inviter = self.authorize(Permission::AdminInvite)?;
+        let email = email.unwrap_or_default().to_lowercase();
+        let now = chrono::Utc::now().timestamp();
+
+        let token = tokens::generate_token();
+        let token_hash = tokens::hash_token(&token);
+        let expires_at = now + self.config.accounts.admin_invitation_ttl_secs;
+
+        self.db.insert(vec![ReceiverType::AdminInvitation(AdminInvitation {
+            id: 0,
+            token_hash: token_hash.clone(),
+            email: email.clone(),
+            created_by: inviter.id,
+            created_at: now,
+            expires_at,
+            used_at: 0,
+            used_by: 0,
+        })])?;
+
+        let invitation = self.find_invitation(&token_hash)?
+            .ok_or_else(|| anyhow!("Failed to create invitation."))?;
+
+        self.audit(
+            inviter.id,
+            "admin.invitation.create",
+            &format!("invitation:{}", invitation.id),
+            &email,
+        )?;
 
-    pub fn delete_user(&mut self, user: User) -> Result<()> {
-        if let Some(s) = &self.session {
-            match s.role.to_lowercase().as_str() {
-                "admin" => {
-                    if user.id != s.id {
-                        self.db.delete(vec![ReceiverType::User(user.clone())])?;
-
-                        Ok(())
-                    } else {
-                        Err(anyhow!(
-                            "You cannot delete your own account as an administrator."
-                        ))
-                    }
-                }
-                _ => {
-                    if user.id == s.id {
-                        self.db.delete(vec![ReceiverType::User(user.clone())])?;
-
-                        Ok(())
-                    } else {
-                        Err(anyhow!("You do not have permission to delete this user."))
-                    }
-                }
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
-        }
+        Ok(IssuedInvitation {
+            id: invitation.id,
+            token,
+            email,
+            expires_at,
+        })
     }
 
-    pub fn register_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
-        if let Some(s) = &self.session {
-            match s.role.to_lowercase().as_str() {
-                "admin" => {
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_admin_invitations(&self) -> Result<Vec<AdminInvitation>> {
+        self.authorize(Permission::AdminInvite)?;
 
-                    self.db.insert(upcast)?;
+        let findings = self.db.find(
+            Table::AdminInvitations,
+            vec![Filter::AdminInvitations(AdminInvitationsFilter::All)],
+            None,
+        )?;
 
-                    Ok(())
+        let mut result: Vec<AdminInvitation> = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::AdminInvitation(invitation) = x {
+                    Some(invitation)
+                } else {
+                    None
                 }
-                "teacher" => {
-                    let errors = courses
-                        .iter()
-                        .filter_map(|x| {
-                            if x.teacher_id != s.id {
-                                Some(
-                                    anyhow!(
-                                        "You do not have permission to register courses on someone else's behalf."
-                                    )
-                                )
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    if errors.len() > 0 {
-                        return Err(
-                            anyhow!(
-                                "You do not have permission to register courses on someone else's behalf. No action was taken."
-                            )
-                        );
-                    }
-
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
-
-                    self.db.insert(upcast)?;
-
-                    Ok(())
+            })
+            .collect();
+        result.sort_by_key(|x| std::cmp::Reverse(x.created_at));
+
+        Ok(result)
+    }
+
+    // Expires an unused invitation immediately; the record stays for the audit trail
+    #[instrument(level = "debug", skip_all, fields(id = id), err(level = "info"))]
+    pub fn revoke_admin_invitation(&mut self, id: i32) -> Result<()> {
+        let admin = self.authorize(Permission::AdminInvite)?;
+        let now = chrono::Utc::now().timestamp();
+
+        let findings = self.db.find(
+            Table::AdminInvitations,
+            vec![Filter::AdminInvitations(AdminInvitationsFilter::Id(id))],
+            None,
+        )?;
+        let mut invitation = findings
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::AdminInvitation(invitation) = x {
+                    Some(invitation)
+                } else {
+                    None
                 }
-                _ => Err(anyhow!("You do not have permission to register courses.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+            })
+            .ok_or_else(|| ApiError::not_found("Invitation not found."))?;
+
+        if invitation.used_at != 0 {
+            return Err(ApiError::conflict("Invitation has already been used.").into());
         }
+
+        invitation.expires_at = now.min(invitation.expires_at);
+        self.db.update(vec![ReceiverType::AdminInvitation(invitation)])?;
+
+        self.audit(admin.id, "admin.invitation.revoke", &format!("invitation:{}", id), "")
     }
 
-    pub fn remove_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "admin" => {
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
+    // Redeems an invitation token, creating the admin account it was issued for
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn register_admin(&mut self, mut user: User, token: &str) -> Result<()> {
+        if self.session.is_some() {
+            return Err(ApiError::forbidden("Must be signed out.").into());
+        }
 
-                    self.db.delete(upcast)?;
+        let now = chrono::Utc::now().timestamp();
+        let mut invitation = match self.find_invitation(&tokens::hash_token(token))? {
+            Some(i) if i.used_at == 0 && i.expires_at > now => i,
+            _ => return Err(ApiError::forbidden("Invalid or expired invitation.").into()),
+        };
 
-                    Ok(())
-                }
-                "teacher" => {
-                    let errors = courses
-                        .iter()
-                        .filter_map(|x| {
-                            if x.teacher_id != session.id {
-                                Some(anyhow!("You do not have permission to remove this course."))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    if errors.len() > 0 {
-                        return Err(anyhow!(
-                            "Some courses to not belong to you. No action was taken."
-                        ));
-                    }
-
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
-
-                    self.db.delete(upcast)?;
-
-                    Ok(())
-                }
-                _ => Err(anyhow!("You do not have permission to remove courses.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+        if !invitation.email.is_empty() && invitation.email != user.email.to_lowercase() {
+            return Err(ApiError::forbidden("This invitation was issued for a different email address.").into());
         }
+
+        // claim the invitation before creating the account so it cannot be redeemed twice
+        if !self.db.claim_invitation(invitation.id, now)? {
+            return Err(ApiError::forbidden("Invalid or expired invitation.").into());
+        }
+
+        user.role = Role::Admin;
+        user.verified = true;
+        let created = self.create_account(user, false);
+        if created.is_err() {
+            // hand the invitation back so a typo in the form does not burn it
+            self.db.release_invitation(invitation.id, now)?;
+        }
+        let admin = created?;
+
+        invitation.used_at = now;
+        invitation.used_by = admin.id;
+        self.db.update(vec![ReceiverType::AdminInvitation(invitation.clone())])?;
+
+        self.audit(
+            admin.id,
+            "admin.invitation.accept",
+            &format!("invitation:{}", invitation.id),
+            &format!("invited by user:{}", invitation.created_by),
+        )
     }
 
-    pub fn update_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "admin" => {
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn create_service_account(&mut self, username: String, email: String) -> Result<User> {
+        let admin = self.authorize(Permission::ApiKeyManage)?;
+
+        let account = self.create_account(User {
+            id: 0,
+            username,
+            password: String::new(),
+            email,
+            phone: String::new(),
+            verified: true,
+            suspended: false,
+            forcenewpw: false,
+            role: Role::Service,
+        }, true)?;
+
+        self.audit(admin.id, "service_account.create", &format!("user:{}", account.id), &account.email)?;
+
+        Ok(account)
+    }
+
+    // Scopes can never exceed what the issuing admin is allowed to do
+    #[instrument(level = "debug", skip_all, fields(user_id = user_id), err(level = "info"))]
+    pub fn create_api_key(
+        &mut self,
+        user_id: i32,
+        name: String,
+        scopes: Vec<Permission>,
+        ttl_days: Option<i64>,
+    ) -> Result<IssuedApiKey> {
+        let admin = self.authorize(Permission::ApiKeyManage)?;
+
+        let owner = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user_id))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::not_found("User not found."))?;
+        if owner.role != Role::Service {
+            return Err(ApiError::validation("API keys can only be issued to service accounts.").into());
+        }
 
-                    self.db.update(upcast)?;
+        if scopes.is_empty() {
+            return Err(ApiError::validation("An API key needs at least one scope.").into());
+        }
+        for scope in &scopes {
+            self.authorize(*scope)?;
+        }
 
-                    Ok(())
-                }
-                "teacher" => {
-                    let errors = courses
-                        .iter()
-                        .filter_map(|x| {
-                            if x.teacher_id != session.id {
-                                Some(anyhow!("You do not have permission to update this course."))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    if errors.len() > 0 {
-                        return Err(anyhow!(
-                            "Some courses to not belong to you. No action was taken."
-                        ));
-                    }
-
-                    let upcast = courses
-                        .iter()
-                        .map(|x| ReceiverType::Course(x.to_owned()))
-                        .collect();
-
-                    self.db.update(upcast)?;
-
-                    Ok(())
-                }
-                _ => Err(anyhow!("You do not have permission to update courses.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+        let max_ttl_days = self.config.accounts.max_api_key_ttl_days;
+        let ttl_days = ttl_days.unwrap_or(self.config.accounts.default_api_key_ttl_days);
+        if ttl_days < 1 || ttl_days > max_ttl_days {
+            return Err(ApiError::validation(format!(
+                "API keys must expire within 1 to {} days.",
+                max_ttl_days
+            )).into());
         }
-    }
 
-    pub fn search_users(&self, query: String) -> Result<Vec<User>> {
+        let now = chrono::Utc::now().timestamp();
+        let key = format!("{}{}", API_KEY_PREFIX, tokens::generate_token());
+        let prefix = key[..API_KEY_PREFIX.len() + 8].to_owned();
+        let key_hash = tokens::hash_token(&key);
+        let expires_at = now + ttl_days * 24 * 60 * 60;
+
+        self.db.insert(vec![ReceiverType::ApiKey(ApiKey {
+            id: 0,
+            user_id,
+            name,
+            prefix: prefix.clone(),
+            key_hash: key_hash.clone(),
+            scopes: scopes
+                .iter()
+                .map(|p| p.as_str())
+                .collect::<Vec<&str>>()
+                .join(","),
+            created_by: admin.id,
+            created_at: now,
+            expires_at,
+            last_used: 0,
+            revoked: false,
+        })])?;
+
         let findings = self.db.find(
-            Table::Users,
-            vec![],
+            Table::ApiKeys,
+            vec![Filter::ApiKeys(ApiKeysFilter::KeyHash(key_hash))],
             None,
         )?;
-
-        let users = findings
+        let id = findings
             .into_iter()
-            .filter_map(|x| {
-                if let ReceiverType::User(user) = x {
-                    Some(user)
+            .find_map(|x| {
+                if let ReceiverType::ApiKey(api_key) = x {
+                    Some(api_key.id)
                 } else {
                     None
                 }
             })
-            .filter(|x| {
-                    x.email.contains(&query)
-                    || x.id.to_string().contains(&query)
-            })
-            .collect();
+            .ok_or_else(|| anyhow!("Failed to create API key."))?;
 
-        Ok(users)
+        self.audit(admin.id, "api_key.create", &format!("api_key:{}", id), &format!("user:{}", user_id))?;
+
+        Ok(IssuedApiKey {
+            id,
+            key,
+            prefix,
+            scopes,
+            expires_at,
+        })
     }
 
-    pub fn search_courses(&self, query: String) -> Result<Vec<Courses>> {
+    #[instrument(level = "debug", skip_all, fields(user_id = user_id), err(level = "info"))]
+    pub fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        self.authorize(Permission::ApiKeyManage)?;
+
         let findings = self.db.find(
-            Table::Courses,
-            vec![],
+            Table::ApiKeys,
+            vec![Filter::ApiKeys(ApiKeysFilter::UserId(user_id))],
             None,
         )?;
 
-        let courses = findings
+        Ok(findings
             .into_iter()
             .filter_map(|x| {
-                if let ReceiverType::Course(course) = x {
-                    Some(course)
+                if let ReceiverType::ApiKey(api_key) = x {
+                    Some(api_key)
                 } else {
                     None
                 }
             })
-            .filter(|x| {
-                x.id.to_string().contains(&query)
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip_all, fields(id = id), err(level = "info"))]
+    pub fn revoke_api_key(&mut self, id: i32) -> Result<()> {
+        let admin = self.authorize(Permission::ApiKeyManage)?;
+
+        let findings = self.db.find(
+            Table::ApiKeys,
+            vec![Filter::ApiKeys(ApiKeysFilter::Id(id))],
+            None,
+        )?;
+        let mut api_key = findings
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::ApiKey(api_key) = x {
+                    Some(api_key)
+                } else {
+                    None
+                }
             })
-            .collect();
+            .ok_or_else(|| ApiError::not_found("API key not found."))?;
 
-        Ok(courses)
+        api_key.revoked = true;
+        self.db.update(vec![ReceiverType::ApiKey(api_key)])?;
+
+        self.audit(admin.id, "api_key.revoke", &format!("api_key:{}", id), "")
     }
 
-    pub fn get_departments(&self) -> Result<Vec<Departments>> {
-        let findings = self.db.find(
-            Table::Departments,
-            vec![],
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_sessions(&mut self) -> Result<Vec<SessionInfo>> {
+        let session = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        self.sessions_of(session.id)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(session_id = session_id), err(level = "info"))]
+    pub fn revoke_session(&mut self, session_id: i32) -> Result<()> {
+        let user = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        self.remove_sessions(user.id, Some(session_id))
+    }
+
+    // Signs out everywhere except here
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn revoke_other_sessions(&mut self) -> Result<()> {
+        let user = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        self.remove_sessions(user.id, None)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(user_id = user_id), err(level = "info"))]
+    pub fn get_user_sessions(&mut self, user_id: i32) -> Result<Vec<SessionInfo>> {
+        self.authorize(Permission::SessionManage)?;
+        self.sessions_of(user_id)
+    }
+
+    // `session_id` of None revokes every session the user has
+    #[instrument(level = "debug", skip_all, fields(user_id = user_id), err(level = "info"))]
+    pub fn revoke_user_sessions(&mut self, user_id: i32, session_id: Option<i32>) -> Result<()> {
+        let admin = self.authorize(Permission::SessionManage)?;
+
+        self.remove_sessions(user_id, session_id)?;
+        self.audit(
+            admin.id,
+            "session.revoke",
+            &format!("user:{}", user_id),
+            &session_id.map(|id| format!("session:{}", id)).unwrap_or_else(|| String::from("all")),
+        )
+    }
+
+    // Returns a session token that acts as `user_id` until it expires or is logged out
+    #[instrument(level = "debug", skip_all, fields(user_id = user_id), err(level = "info"))]
+    pub fn start_impersonation(&mut self, user_id: i32) -> Result<String> {
+        let admin = self.authorize(Permission::UserImpersonate)?;
+
+        if self.impersonator.is_some() {
+            return Err(ApiError::conflict("Already impersonating a user.").into());
+        }
+
+        let target = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user_id))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::not_found("User not found."))?;
+
+        if target.id == admin.id {
+            return Err(ApiError::forbidden("You cannot impersonate yourself.").into());
+        }
+
+        if target.role == Role::Admin || target.role == Role::Service {
+            return Err(ApiError::forbidden("Only students and teachers can be impersonated.").into());
+        }
+
+        let token = self.create_session(&target, false, admin.id)?;
+        self.audit(admin.id, "impersonation.start", &format!("user:{}", target.id), "")?;
+
+        Ok(token)
+    }
+
+    // Lets users see when staff viewed the site as them, and what was done
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_impersonation_history(&self) -> Result<Vec<AuditEntry>> {
+        let session = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        let mut findings = self.db.find(
+            Table::AuditLog,
+            vec![
+                Filter::AuditLog(AuditLogFilter::Target(format!("user:{}", session.id))),
+                Filter::AuditLog(AuditLogFilter::Action(String::from("impersonation.start"))),
+            ],
             None,
         )?;
+        findings.extend(self.db.find(
+            Table::AuditLog,
+            vec![
+                Filter::AuditLog(AuditLogFilter::Target(format!("user:{}", session.id))),
+                Filter::AuditLog(AuditLogFilter::Action(String::from("impersonation.end"))),
+            ],
+            None,
+        )?);
+        findings.extend(self.db.find(
+            Table::AuditLog,
+            vec![
+                Filter::AuditLog(AuditLogFilter::ActorId(session.id)),
+                Filter::AuditLog(AuditLogFilter::Impersonated),
+            ],
+            None,
+        )?);
 
-        let departments = findings
+        let mut result: Vec<AuditEntry> = findings
             .into_iter()
             .filter_map(|x| {
-                if let ReceiverType::Department(department) = x {
-                    Some(department)
+                if let ReceiverType::AuditEntry(entry) = x {
+                    Some(entry)
                 } else {
                     None
                 }
             })
             .collect();
+        result.sort_by_key(|x| std::cmp::Reverse(x.created_at));
 
-        Ok(departments)
+        Ok(result)
     }
 
-    pub fn get_department(&self, id: i32) -> Result<Departments> {
-        let findings = self.db.find(
-            Table::Departments,
-            vec![Filter::Departments(DepartmentsFilter::Id(id))],
-            None,
-        )?;
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_audit_log(&self, actor_id: Option<i32>) -> Result<Vec<AuditEntry>> {
+        self.authorize(Permission::SecurityAudit)?;
 
-        let departments: Vec<Departments> = findings
+        let filter = match actor_id {
+            Some(id) => AuditLogFilter::ActorId(id),
+            None => AuditLogFilter::All,
+        };
+
+        let findings = self.db.find(Table::AuditLog, vec![Filter::AuditLog(filter)], None)?;
+
+        let mut result: Vec<AuditEntry> = findings
             .into_iter()
             .filter_map(|x| {
-                if let ReceiverType::Department(department) = x {
-                    Some(department)
+                if let ReceiverType::AuditEntry(entry) = x {
+                    Some(entry)
                 } else {
                     None
                 }
             })
             .collect();
+        result.sort_by_key(|x| std::cmp::Reverse(x.created_at));
 
-        let department = departments.get(0).ok_or_else(|| anyhow!("Department not found."))?;
-
-        Ok(department.to_owned())
+        Ok(result)
     }
 
-    pub fn new_department(&mut self, department: &str) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "admin" => {
-                    let department = Departments {
-                        id: 0,
-                        name: department.to_owned(),
-                    };
-                    self.db.insert(vec![ReceiverType::Department(department)])?;
-
-                    Ok(())
-                }
-                _ => Err(anyhow!("Only admins can create departments.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+    // Credential check for requests that carry login_email and login_password headers
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn login(&mut self, email: String, password: String) -> Result<()> {
+        let user = self.authenticate(&email, &password)?;
+
+        if user.role == Role::Admin || self.totp_enabled(user.id)? {
+            // the password was right, so this is not a credential mismatch
+            return Err(ApiError::forbidden(
+                "Two-factor authentication is required. Sign in through /login instead."
+            ).into());
         }
+
+        self.set_session(user);
+        Ok(())
     }
 
-    pub fn remove_department(&mut self, department: Departments) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "admin" => {
-                    self.db.delete(vec![ReceiverType::Department(department)])?;
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn start_session(&mut self, email: String, password: String) -> Result<LoginOutcome> {
+        let user = self.authenticate(&email, &password)?;
+        self.open_session(user)
+    }
 
-                    Ok(())
-                }
-                _ => Err(anyhow!("Only admins can remove departments.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
-        }
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn begin_sso(&mut self) -> Result<SsoChallenge> {
+        let now = chrono::Utc::now().timestamp();
+        let challenge = SsoChallenge {
+            state: tokens::generate_token(),
+            nonce: tokens::generate_token(),
+            code_verifier: tokens::generate_token(),
+        };
+
+        self.db.insert(vec![ReceiverType::OidcLogin(OidcLogin {
+            id: 0,
+            state_hash: tokens::hash_token(&challenge.state),
+            nonce: challenge.nonce.clone(),
+            code_verifier: challenge.code_verifier.clone(),
+            created_at: now,
+            expires_at: now + self.config.sessions.sso_login_ttl_secs,
+        })])?;
+
+        Ok(challenge)
     }
 
-    pub fn get_teacher_accounts(&self) -> Result<Vec<TeacherAccount>> {
+    // Single use: the pending login is removed whether or not it is still valid
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn take_sso_challenge(&mut self, state: &str) -> Result<SsoChallenge> {
         let findings = self.db.find(
-            Table::TeacherAccount,
-            vec![],
+            Table::OidcLogins,
+            vec![Filter::OidcLogins(OidcLoginsFilter::StateHash(tokens::hash_token(state)))],
             None,
         )?;
 
-        let teacher_accounts = findings
+        let login = findings
             .into_iter()
-            .filter_map(|x| {
-                if let ReceiverType::TeacherAccount(teacher_account) = x {
-                    Some(teacher_account)
+            .find_map(|x| {
+                if let ReceiverType::OidcLogin(login) = x {
+                    Some(login)
                 } else {
                     None
                 }
             })
-            .collect();
+            .ok_or_else(|| ApiError::unauthenticated("Unknown or expired sign-in request."))?;
 
-        Ok(teacher_accounts)
-    }
+        self.db.delete(vec![ReceiverType::OidcLogin(login.clone())])?;
 
-    pub fn update_teacher_account(&mut self, teacher_account: TeacherAccount) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "admin" => {
-                    self.db.update(vec![ReceiverType::TeacherAccount(teacher_account)])?;
-                    Ok(())
-                }
-                _ => Err(anyhow!("Only admins can update teacher accounts.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+        if login.expires_at <= chrono::Utc::now().timestamp() {
+            return Err(ApiError::unauthenticated("Unknown or expired sign-in request.").into());
         }
+
+        Ok(SsoChallenge {
+            state: state.to_owned(),
+            nonce: login.nonce,
+            code_verifier: login.code_verifier,
+        })
     }
 
-    pub fn enroll_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "student" => {
-                    let errors = courses
-                        .iter()
-                        .filter_map(|x| {
-                            let c = self.transmute_course_to_student_course(x.to_owned());
-                            if c.student_id != session.id {
-                                Some(
-                                    anyhow!(
-                                        "You do not have permission to register courses on someone else's behalf."
-                                    )
-                                )
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    if errors.len() > 0 {
-                        return Err(
-                            anyhow!(
-                                "You do not have permission to register courses on someone else's behalf. No action was taken."
-                            )
-                        );
-                    }
-
-                    let upcast = courses
-                        .iter()
-                        .map(|x| {
-                            ReceiverType::StudentCourse(
-                                self.transmute_course_to_student_course(x.to_owned()),
-                            )
-                        })
-                        .collect();
-
-                    self.db.insert(upcast)?;
-
-                    Ok(())
-                }
-                _ => Err(anyhow!("You do not have permission to enroll courses.")),
-            }
-        } else {
-            Err(anyhow!("Must be signed in."))
+    // Signs in the owner of an email address the identity provider has vouched for,
+    // creating a student account on first use
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn complete_sso(&mut self, email: &str, name: Option<String>) -> Result<LoginOutcome> {
+        let now = chrono::Utc::now().timestamp();
+        let email = email.trim().to_lowercase();
+
+        if !Regex::new(&self.config.accounts.email_pattern)?.is_match(&email) {
+            self.record_login_attempt(&email, false, "sso: outside allowed domain", now)?;
+            return Err(ApiError::validation("Must be a valid AUBG email.").into());
         }
-    }
 
-    pub fn list_enrollments(&self) -> Result<Vec<StudentCourse>> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "student" => {
-                    let findings = self.db.find(
-                        Table::StudentCourses,
-                        vec![Filter::StudentCourses(StudentCoursesFilter::StudentId(session.id))],
-                        None,
-                    )?;
-
-                    let courses = findings
-                        .into_iter()
-                        .filter_map(|x| {
-                            if let ReceiverType::StudentCourse(course) = x {
-                                Some(course)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect();
-
-                    Ok(courses)
-                }
-                _ => Err(anyhow!("You are not a student.")),
+        let existing = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Email(email.clone()))])?
+            .into_iter()
+            .next();
+
+        let user = match existing {
+            Some(u) => u,
+            None => {
+                let username = name
+                    .filter(|n| !n.trim().is_empty())
+                    .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_owned());
+
+                self.create_account(User {
+                    id: 0,
+                    username,
+                    password: String::new(),
+                    email: email.clone(),
+                    phone: String::new(),
+                    verified: true,
+                    suspended: false,
+                    forcenewpw: false,
+                    role: Role::Student,
+                }, true)?
             }
-        } else {
-            Err(anyhow!("Must be signed in."))
-        }
-    }
+        };
 
+        if user.suspended {
+            self.record_login_attempt(&email, false, "sso: suspended", now)?;
+            return Err(ApiError::forbidden("User is suspended.").into());
+        }
+
+        if user.role == Role::Service {
+            self.record_login_attempt(&email, false, "sso: service account", now)?;
+            return Err(ApiError::unauthenticated("Service accounts must authenticate with an API key.").into());
+        }
+
+        self.record_login_attempt(&email, true, "sso", now)?;
+        self.open_session(user)
+    }
+
+    // Issues the session for an authenticated user, holding it back until the second factor is done
+    fn open_session(&mut self, user: User) -> Result<LoginOutcome> {
+        let enrolled = self.totp_enabled(user.id)?;
+
+        // admins must have a second factor, so they get a session that can only enroll one
+        let must_enroll = !enrolled && user.role == Role::Admin;
+        let token = self.create_session(&user, enrolled || must_enroll, 0)?;
+
+        if enrolled {
+            Ok(LoginOutcome::TwoFactorRequired(token))
+        } else if must_enroll {
+            self.set_session(user);
+            Ok(LoginOutcome::TwoFactorEnrollmentRequired(token))
+        } else {
+            self.set_session(user);
+            Ok(LoginOutcome::Authenticated(token))
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn resume_session(&mut self, token: &str) -> Result<()> {
+        let (session, user) = self.lookup_session(token)?;
+
+        if session.pending_mfa {
+            return Err(ApiError::unauthenticated("Two-factor authentication has not been completed.").into());
+        }
+
+        let impersonator_id = session.impersonator_id;
+        self.touch_session(session)?;
+
+        if impersonator_id != 0 {
+            // the admin must still exist and still be allowed to impersonate
+            let impersonator = self
+                .get_users_by_filters(vec![Filter::Users(UsersFilter::Id(impersonator_id))])?
+                .into_iter()
+                .next()
+                .filter(|a| !a.suspended)
+                .ok_or_else(|| ApiError::unauthenticated("Invalid or expired session."))?;
+
+            if !self.role_has_permission(impersonator.role, Permission::UserImpersonate)? {
+                return Err(ApiError::unauthenticated("Invalid or expired session.").into());
+            }
+
+            self.impersonator = Some(impersonator);
+            let request_line = self.request_line.clone();
+            self.audit(user.id, "impersonation.request", &request_line, "")?;
+        }
+
+        self.set_session(user);
+        Ok(())
+    }
+
+    // Like resume_session, but also accepts the restricted session of a user who still has to enroll
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn resume_api_key(&mut self, key: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        let findings = self.db.find(
+            Table::ApiKeys,
+            vec![Filter::ApiKeys(ApiKeysFilter::KeyHash(tokens::hash_token(key.trim())))],
+            None,
+        )?;
+        let mut api_key = findings
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::ApiKey(api_key) = x {
+                    Some(api_key)
+                } else {
+                    None
+                }
+            })
+            .filter(|k| !k.revoked && k.expires_at > now)
+            .ok_or_else(|| ApiError::unauthenticated("Invalid, revoked or expired API key."))?;
+
+        let user = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Id(api_key.user_id))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::unauthenticated("Invalid, revoked or expired API key."))?;
+
+        if user.suspended {
+            return Err(ApiError::forbidden("User is suspended.").into());
+        }
+
+        api_key.last_used = now;
+        let scopes = parse_scopes(&api_key.scopes);
+        self.db.update(vec![ReceiverType::ApiKey(api_key)])?;
+
+        self.api_scopes = Some(scopes);
+        self.set_session(user);
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn resume_enrollment_session(&mut self, token: &str) -> Result<()> {
+        let (session, user) = self.lookup_session(token)?;
+
+        if session.pending_mfa && self.totp_enabled(user.id)? {
+            return Err(ApiError::unauthenticated("Two-factor authentication has not been completed.").into());
+        }
+
+        self.touch_session(session)?;
+        self.set_session(user);
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn complete_two_factor(&mut self, token: &str, code: &str) -> Result<()> {
+        let (mut session, user) = self.lookup_session(token)?;
+
+        if !session.pending_mfa {
+            return Err(ApiError::conflict("This session is not awaiting a second factor.").into());
+        }
+
+        let mut totp = match self.find_totp(user.id)? {
+            Some(t) if t.enabled => t,
+            _ => return Err(ApiError::conflict("Two-factor authentication is not enabled.").into()),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+
+        if let Some(until) = self.locked_until(&user.email, now)? {
+            self.record_login_attempt(&user.email, false, "locked out", now)?;
+            return Err(ApiError::too_many_requests(
+                format!("Too many failed login attempts. Try again in {} seconds.", until - now),
+                until - now,
+            ).into());
+        }
+
+        if !self.verify_second_factor(&user, &mut totp, code)? {
+            return self.reject_login(
+                &user.email,
+                "invalid second factor",
+                now,
+                ApiError::unauthenticated("Invalid authentication code.").into(),
+            );
+        }
+
+        self.record_login_attempt(&user.email, true, "second factor", now)?;
+        session.pending_mfa = false;
+        session.expires_at = now + self.config.sessions.ttl_secs;
+        self.touch_session(session)?;
+        self.set_session(user);
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn end_session(&mut self, token: &str) -> Result<()> {
+        let (session, user) = self.lookup_session(token)?;
+
+        if session.impersonator_id != 0 {
+            self.audit(
+                session.impersonator_id,
+                "impersonation.end",
+                &format!("user:{}", user.id),
+                "",
+            )?;
+        }
+
+        self.db.delete(vec![ReceiverType::Session(session)])?;
+        self.current_session = None;
+        self.session = None;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn enroll_totp(&mut self) -> Result<TotpEnrollment> {
+        self.deny_while_impersonating("change two-factor settings")?;
+
+        if let Some(session) = &self.session {
+            if self.totp_enabled(session.id)? {
+                return Err(ApiError::conflict("Two-factor authentication is already enabled.").into());
+            }
+
+            let secret = totp::generate_secret();
+            let provisioning_uri = totp::provisioning_uri(&secret, &session.email, TOTP_ISSUER);
+
+            // stays disabled until the user proves their authenticator produces valid codes
+            self.db.insert(vec![ReceiverType::UserTotp(UserTotp {
+                id: 0,
+                user_id: session.id,
+                secret: secret.clone(),
+                enabled: false,
+                last_used_step: 0,
+                created_at: chrono::Utc::now().timestamp(),
+            })])?;
+
+            Ok(TotpEnrollment {
+                secret,
+                provisioning_uri,
+            })
+        } else {
+            Err(ApiError::unauthenticated("Must be signed in.").into())
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn confirm_totp(&mut self, code: &str) -> Result<Vec<String>> {
+        self.deny_while_impersonating("change two-factor settings")?;
+
+        let user = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        let mut totp = match self.find_totp(user.id)? {
+            Some(t) if !t.enabled => t,
+            Some(_) => return Err(ApiError::conflict("Two-factor authentication is already enabled.").into()),
+            None => return Err(ApiError::conflict("Start the enrollment first.").into()),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let step = totp::verify(&totp.secret, code, now)
+            .ok_or_else(|| ApiError::validation("Invalid authentication code."))?;
+
+        totp.enabled = true;
+        totp.last_used_step = step;
+        self.db.update(vec![ReceiverType::UserTotp(totp)])?;
+
+        // an admin finishing mandatory enrollment gets a full session out of it
+        if let Some(mut session) = self.current_session.clone() {
+            if session.pending_mfa {
+                session.pending_mfa = false;
+                session.expires_at = now + self.config.sessions.ttl_secs;
+                self.touch_session(session)?;
+            }
+        }
+
+        self.replace_recovery_codes(user.id)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn disable_totp(&mut self, code: &str) -> Result<()> {
+        self.deny_while_impersonating("change two-factor settings")?;
+
+        let user = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        if user.role == Role::Admin {
+            return Err(ApiError::forbidden("Administrators cannot disable two-factor authentication.").into());
+        }
+
+        let mut totp = match self.find_totp(user.id)? {
+            Some(t) if t.enabled => t,
+            _ => return Err(ApiError::conflict("Two-factor authentication is not enabled.").into()),
+        };
+
+        if !self.verify_second_factor(&user, &mut totp, code)? {
+            return Err(ApiError::forbidden("Invalid authentication code.").into());
+        }
+
+        self.db.delete(vec![
+            ReceiverType::UserTotp(totp),
+            ReceiverType::RecoveryCode(RecoveryCode {
+                id: 0,
+                user_id: user.id,
+                code_hash: String::new(),
+                used: false,
+            }),
+        ])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn regenerate_recovery_codes(&mut self, code: &str) -> Result<Vec<String>> {
+        self.deny_while_impersonating("change two-factor settings")?;
+
+        let user = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        let mut totp = match self.find_totp(user.id)? {
+            Some(t) if t.enabled => t,
+            _ => return Err(ApiError::conflict("Two-factor authentication is not enabled.").into()),
+        };
+
+        if !self.verify_second_factor(&user, &mut totp, code)? {
+            return Err(ApiError::forbidden("Invalid authentication code.").into());
+        }
+
+        self.replace_recovery_codes(user.id)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_login_attempts(&self, email: Option<String>) -> Result<Vec<LoginAttempt>> {
+        self.authorize(Permission::SecurityAudit)?;
+
+        let filters = match email {
+            Some(e) => vec![Filter::LoginAttempts(LoginAttemptsFilter::Email(e))],
+            None => vec![],
+        };
+
+        let findings = self.db.find(Table::LoginAttempts, filters, None)?;
+
+        let mut attempts: Vec<LoginAttempt> = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::LoginAttempt(attempt) = x {
+                    Some(attempt)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        attempts.sort_by_key(|x| std::cmp::Reverse(x.created_at));
+
+        Ok(attempts)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_lockouts(&self) -> Result<Vec<LoginThrottle>> {
+        self.authorize(Permission::SecurityAudit)?;
+
+        let now = chrono::Utc::now().timestamp();
+        let findings = self.db.find(
+            Table::LoginThrottle,
+            vec![Filter::LoginThrottle(LoginThrottleFilter::LockedAfter(now))],
+            None,
+        )?;
+
+        let lockouts = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::LoginThrottle(throttle) = x {
+                    Some(throttle)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(lockouts)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn unlock_user(&mut self, user: User) -> Result<()> {
+        self.authorize(Permission::UserUnlock)?;
+
+        self.db.delete(vec![ReceiverType::LoginThrottle(LoginThrottle {
+            id: 0,
+            scope: SCOPE_ACCOUNT.to_owned(),
+            key: user.email.to_lowercase(),
+            failures: 0,
+            last_failure: 0,
+            locked_until: 0,
+        })])?;
+
+        Ok(())
+    }
+
+    // The single authorization check: returns the signed in user if their role grants the permission
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn authorize(&self, permission: Permission) -> Result<User> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        if let Some(scopes) = &self.api_scopes {
+            return if scopes.contains(&permission) {
+                Ok(session.to_owned())
+            } else {
+                Err(ApiError::forbidden(format!(
+                    "This API key is not allowed to {}.",
+                    permission.describe()
+                )).into())
+            };
+        }
+
+        if self.role_has_permission(session.role, permission)? {
+            Ok(session.to_owned())
+        } else {
+            Err(ApiError::forbidden(format!(
+                "You do not have permission to {}.",
+                permission.describe()
+            )).into())
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.authorize(permission).is_ok()
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_role_permissions(&self) -> Result<Vec<RolePermission>> {
+        self.authorize(Permission::RoleManage)?;
+
+        let findings = self.db.find(Table::RolePermissions, vec![], None)?;
+
+        let permissions = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::RolePermission(permission) = x {
+                    Some(permission)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(permissions)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn grant_permission(&mut self, role: Role, permission: Permission) -> Result<()> {
+        self.authorize(Permission::RoleManage)?;
+
+        self.db.insert(vec![ReceiverType::RolePermission(RolePermission {
+            id: 0,
+            role,
+            permission,
+        })])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn revoke_permission(&mut self, role: Role, permission: Permission) -> Result<()> {
+        let session = self.authorize(Permission::RoleManage)?;
+
+        // otherwise nobody would be left who could give it back
+        if role == session.role && permission == Permission::RoleManage {
+            return Err(ApiError::forbidden("You cannot revoke role management from your own role.").into());
+        }
+
+        self.db.delete(vec![ReceiverType::RolePermission(RolePermission {
+            id: 0,
+            role,
+            permission,
+        })])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn update_user(&mut self, user: User) -> Result<()> {
+        if !user.password.is_empty() {
+            self.deny_while_impersonating("change passwords")?;
+        }
+
+        let session = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        if self.can(Permission::UserUpdate) {
+            self.update_user_as_admin(user)
+        } else if user.id == session.id {
+            self.authorize(Permission::AccountUpdate)?;
+            self.update_user_as_student(user)
+        } else {
+            Err(ApiError::forbidden("You do not have permission to update this user.").into())
+        }
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn delete_user(&mut self, user: User) -> Result<()> {
+        self.deny_while_impersonating("delete accounts")?;
+
+        let session = self
+            .session
+            .clone()
+            .ok_or_else(|| ApiError::unauthenticated("Must be signed in."))?;
+
+        if user.id != session.id {
+            self.authorize(Permission::UserDelete)?;
+        } else if self.can(Permission::UserDelete) {
+            return Err(ApiError::forbidden(
+                "You cannot delete your own account as an administrator."
+            ).into());
+        } else {
+            self.authorize(Permission::AccountDelete)?;
+        }
+
+        self.db.delete(vec![ReceiverType::User(user)])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn register_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
+        let session = self.authorize(Permission::CourseCreate)?;
+
+        if courses.iter().any(|x| x.teacher_id != session.id) {
+            self.authorize(Permission::CourseManageAny).map_err(|_| {
+                ApiError::forbidden(
+                    "You do not have permission to register courses on someone else's behalf. No action was taken."
+                )
+            })?;
+        }
+
+        let upcast = courses
+            .iter()
+            .map(|x| ReceiverType::Course(x.to_owned()))
+            .collect();
+
+        self.db.insert(upcast)?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn remove_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
+        let session = self.authorize(Permission::CourseDelete)?;
+
+        // ownership is whatever the database says, not what the caller sent
+        let stored = self.stored_courses(&courses)?;
+        if stored.iter().any(|x| x.teacher_id != session.id) {
+            self.authorize(Permission::CourseManageAny).map_err(|_| {
+                ApiError::forbidden("Some courses do not belong to you. No action was taken.")
+            })?;
+        }
+
+        let upcast = courses
+            .iter()
+            .map(|x| ReceiverType::Course(x.to_owned()))
+            .collect();
+
+        self.db.delete(upcast)?;
+
+        Ok(())
+    }
+
+    // Writes `changed` over the course the caller read as `seen`, and only while the row is
+    // still exactly that; an edit made in between fails with 412 instead of being overwritten.
+    // Ownership is checked on `seen`, which the write itself holds the row to.
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn update_course(&mut self, seen: &Courses, changed: Courses) -> Result<()> {
+        let session = self.authorize(Permission::CourseUpdate)?;
+
+        // a course is only the caller's if it already was, and handing it to someone
+        // else is a reassignment, which needs the same permission
+        if seen.id != changed.id {
+            return Err(ApiError::validation("A course's id cannot be changed.").into());
+        }
+        if seen.teacher_id != session.id || changed.teacher_id != seen.teacher_id {
+            self.authorize(Permission::CourseManageAny).map_err(|_| {
+                ApiError::forbidden("Some courses do not belong to you. No action was taken.")
+            })?;
+        }
+
+        if !self.db.update_course_if_unchanged(seen, &changed)? {
+            // tell a deleted course apart from one that was edited
+            self.course_by_id(seen.id)?;
+            return Err(ApiError::precondition_failed(
+                "This course was changed since you last fetched it; reload it and apply your changes again.",
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    // The rows as they are now, in the same order; a course that is gone is not found
+    fn stored_courses(&self, courses: &[Courses]) -> Result<Vec<Courses>> {
+        courses.iter().map(|c| self.course_by_id(c.id)).collect()
+    }
+
+    #[instrument(level = "debug", skip_all, fields(student_id = student_id), err(level = "info"))]
+    pub fn grade_student(&mut self, course: Courses, student_id: i32, grade: f32) -> Result<()> {
+        let session = self.authorize(Permission::GradeWrite)?;
+
+        if course.teacher_id != session.id {
+            self.authorize(Permission::CourseManageAny)
+                .map_err(|_| ApiError::forbidden("You can only grade students in your own courses."))?;
+        }
+
+        let findings = self.db.find(
+            Table::StudentCourses,
+            vec![
+                Filter::StudentCourses(StudentCoursesFilter::StudentId(student_id)),
+                Filter::StudentCourses(StudentCoursesFilter::CourseId(course.id)),
+            ],
+            None,
+        )?;
+
+        let mut enrollment = findings
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::StudentCourse(enrollment) = x {
+                    Some(enrollment)
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| ApiError::not_found("The student is not enrolled in this course."))?;
+
+        enrollment.grade = grade;
+        self.db.update(vec![ReceiverType::StudentCourse(enrollment)])?;
+
+        Ok(())
+    }
+
+    // Exactly the row with this id, for the /{id} routes
+    #[instrument(level = "debug", skip_all, fields(id = id), err(level = "info"))]
+    pub fn user_by_id(&self, id: i32) -> Result<User> {
+        self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(id))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::not_found("User not found.").into())
+    }
+
+    #[instrument(level = "debug", skip_all, fields(id = id), err(level = "info"))]
+    pub fn course_by_id(&self, id: i32) -> Result<Courses> {
+        self.list_courses(vec![Filter::Courses(CoursesFilter::Id(id))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::not_found("Course not found.").into())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn search_courses(&self, query: String) -> Result<Vec<Courses>> {
+        let findings = self.db.find(
+            Table::Courses,
+            vec![],
+            None,
+        )?;
+
+        let courses = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::Course(course) = x {
+                    Some(course)
+                } else {
+                    None
+                }
+            })
+            .filter(|x| {
+                x.id.to_string().contains(&query)
+            })
+            .collect();
+
+        Ok(courses)
+    }
+
+    // Unix time of the last write to any of `tables`; catalog reads send it as Last-Modified
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn last_changed(&self, tables: &[Table]) -> Result<Option<i64>> {
+        self.db.last_changed(tables)
+    }
+
+    // The catalog; ?filter= on GET /courses narrows it
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn list_courses(&self, filters: Vec<Filter>) -> Result<Vec<Courses>> {
+        let findings = self.db.find(
+            Table::Courses,
+            filters,
+            None,
+        )?;
+
+        let courses = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::Course(course) = x {
+                    Some(course)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(courses)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_departments(&self, filters: Vec<Filter>) -> Result<Vec<Departments>> {
+        let findings = self.db.find(
+            Table::Departments,
+            filters,
+            None,
+        )?;
+
+        let departments = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::Department(department) = x {
+                    Some(department)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(departments)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(id = id), err(level = "info"))]
+    pub fn get_department(&self, id: i32) -> Result<Departments> {
+        let findings = self.db.find(
+            Table::Departments,
+            vec![Filter::Departments(DepartmentsFilter::Id(id))],
+            None,
+        )?;
+
+        let departments: Vec<Departments> = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::Department(department) = x {
+                    Some(department)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let department = departments.first().ok_or_else(|| ApiError::not_found("Department not found."))?;
+
+        Ok(department.to_owned())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn new_department(&mut self, department: &str) -> Result<()> {
+        self.authorize(Permission::DepartmentCreate)?;
+
+        let department = Departments {
+            id: 0,
+            name: department.to_owned(),
+        };
+        self.db.insert(vec![ReceiverType::Department(department)])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn remove_department(&mut self, department: Departments) -> Result<()> {
+        self.authorize(Permission::DepartmentDelete)?;
+
+        self.db.delete(vec![ReceiverType::Department(department)])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn get_teacher_accounts(&self) -> Result<Vec<TeacherAccount>> {
+        let findings = self.db.find(
+            Table::TeacherAccount,
+            vec![],
+            None,
+        )?;
+
+        let teacher_accounts = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::TeacherAccount(teacher_account) = x {
+                    Some(teacher_account)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(teacher_accounts)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn update_teacher_account(&mut self, teacher_account: TeacherAccount) -> Result<()> {
+        self.authorize(Permission::DepartmentManage)?;
+
+        self.db.update(vec![ReceiverType::TeacherAccount(teacher_account)])?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn enroll_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
+        let student = self.authorize(Permission::EnrollmentSelf)?;
+
+        let upcast = courses
+            .iter()
+            .map(|x| {
+                ReceiverType::StudentCourse(
+                    self.transmute_course_to_student_course(student.id, x.to_owned()),
+                )
+            })
+            .collect();
+
+        self.db.insert(upcast)?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn list_enrollments(&self) -> Result<Vec<StudentCourse>> {
+        let session = self.authorize(Permission::EnrollmentSelf)?;
+
+        let findings = self.db.find(
+            Table::StudentCourses,
+            vec![Filter::StudentCourses(StudentCoursesFilter::StudentId(session.id))],
+            None,
+        )?;
+
+        let courses = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::StudentCourse(course) = x {
+                    Some(course)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(courses)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
     pub fn get_student_standing(&self) -> Result<StudentAccount> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "student" => {
-                    let findings = self.db.find(
-                        Table::StudentAccount,
-                        vec![Filter::StudentAccount(StudentAccountFilter::StudentId(
-                        session.id
-                        ))],
-                        None
-                    );
-
-                    let student = findings?
-                        .into_iter()
-                        .filter_map(|x| {
-                            if let ReceiverType::StudentAccount(student) = x {
-                                Some(student)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    Ok(student[0].to_owned())
+        let session = self.authorize(Permission::EnrollmentSelf)?;
+
+        let findings = self.db.find(
+            Table::StudentAccount,
+            vec![Filter::StudentAccount(StudentAccountFilter::StudentId(
+                session.id
+            ))],
+            None
+        );
+
+        let student = findings?
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::StudentAccount(student) = x {
+                    Some(student)
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| ApiError::not_found("Student account not found."))?;
+
+        Ok(student)
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn drop_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
+        let student = self.authorize(Permission::EnrollmentSelf)?;
+
+        let upcast = courses
+            .iter()
+            .map(|x| {
+                ReceiverType::StudentCourse(
+                    self.transmute_course_to_student_course(student.id, x.to_owned()),
+                )
+            })
+            .collect();
+
+        self.db.delete(upcast)?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn generate_statistics(&self) -> Result<Statistics> {
+        self.authorize(Permission::StatsRead)?;
+
+        let registered_users = self.get_users()?.len() as i32;
+        let suspended_users = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Suspended(true))])?
+            .len() as i32;
+        let faculty_members = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
+                Role::Teacher,
+            ))])?
+            .len() as i32;
+        let active_students = self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
+                Role::Student,
+            ))])?
+            .len() as i32
+            - suspended_users;
+        let graduated_students = self
+            .db
+            .find(
+                Table::StudentAccount,
+                vec![Filter::StudentAccount(StudentAccountFilter::CanGrad(true))],
+                None,
+            )?
+            .len() as i32;
+        let courses = self.db.find(Table::Courses, vec![], None)?.len() as i32;
+        let departments = self.db.find(Table::Departments, vec![], None)?.len() as i32;
+
+        Ok(Statistics {
+            registered_users,
+            suspended_users,
+            faculty_members,
+            active_students,
+            graduated_students,
+            courses,
+            departments,
+        })
+    }
+
+    // Distinct students per semester they are enrolled in. Not permission checked:
+    // it only feeds /metrics, which has its own token.
+    #[instrument(level = "debug", skip_all, err(level = "info"))]
+    pub fn enrolled_students_per_semester(&self) -> Result<Vec<(String, i64)>> {
+        let mut semesters: std::collections::BTreeMap<String, std::collections::HashSet<i32>> =
+            std::collections::BTreeMap::new();
+
+        for finding in self.db.find(Table::StudentCourses, vec![], None)? {
+            if let ReceiverType::StudentCourse(s) = finding {
+                semesters.entry(s.semester).or_default().insert(s.student_id);
+            }
+        }
+
+        Ok(semesters
+            .into_iter()
+            .map(|(semester, students)| (semester, students.len() as i64))
+            .collect())
+    }
+}
+
+// Private methods
+impl ServerConnection {
+    // Remembers who is acting and tags the request's log lines with it
+    fn set_session(&mut self, user: User) {
+        self.request_span.record("user_id", user.id);
+        if let Some(impersonator) = &self.impersonator {
+            self.request_span.record("impersonator_id", impersonator.id);
+        }
+        self.session = Some(user);
+    }
+
+    // Lists live sessions, clearing out expired ones on the way
+    fn sessions_of(&mut self, user_id: i32) -> Result<Vec<SessionInfo>> {
+        let now = chrono::Utc::now().timestamp();
+        let current = self.current_session.as_ref().map(|c| c.id);
+
+        let findings = self.db.find(
+            Table::Sessions,
+            vec![Filter::Sessions(SessionsFilter::UserId(user_id))],
+            None,
+        )?;
+
+        let mut sessions = Vec::new();
+        for finding in findings {
+            if let ReceiverType::Session(session) = finding {
+                if session.expires_at <= now {
+                    self.db.delete(vec![ReceiverType::Session(session)])?;
+                    continue;
+                }
+
+                sessions.push(SessionInfo {
+                    id: session.id,
+                    created_at: session.created_at,
+                    last_seen: session.last_seen,
+                    expires_at: session.expires_at,
+                    ip: session.ip,
+                    user_agent: session.user_agent,
+                    pending_mfa: session.pending_mfa,
+                    impersonator_id: session.impersonator_id,
+                    current: Some(session.id) == current,
+                });
+            }
+        }
+        sessions.sort_by_key(|x| std::cmp::Reverse(x.last_seen));
+
+        Ok(sessions)
+    }
+
+    // One session of `user_id`, or with None all of them except the one in use
+    fn remove_sessions(&mut self, user_id: i32, session_id: Option<i32>) -> Result<()> {
+        let current = self.current_session.as_ref().map(|c| c.id);
+
+        let findings = self.db.find(
+            Table::Sessions,
+            vec![Filter::Sessions(SessionsFilter::UserId(user_id))],
+            None,
+        )?;
+
+        let doomed: Vec<ReceiverType> = findings
+            .into_iter()
+            .filter(|x| match x {
+                ReceiverType::Session(session) => match session_id {
+                    Some(id) => session.id == id,
+                    None => Some(session.id) != current,
+                },
+                _ => false,
+            })
+            .collect();
+
+        if session_id.is_some() && doomed.is_empty() {
+            return Err(ApiError::not_found("Session not found.").into());
+        }
+
+        self.db.delete(doomed)?;
+
+        Ok(())
+    }
+
+    // Credentials stay with their owner: an admin viewing the site as someone cannot change them
+    fn deny_while_impersonating(&self, what: &str) -> Result<()> {
+        if self.impersonator.is_some() {
+            return Err(ApiError::forbidden(format!("You cannot {} while impersonating a user.", what)).into());
+        }
+
+        Ok(())
+    }
+
+    // `external_login` accounts (service accounts, SSO users) get a password nobody knows
+    fn create_account(&mut self, user: User, external_login: bool) -> Result<User> {
+        let email_regex = Regex::new(&self.config.accounts.email_pattern)?;
+        let phone_regex = Regex::new(r#"^\+?[0-9]{2}[-. ]?[0-9]{4}[-. ]?[0-9]{4}$"#)?;
+
+        if !self
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Email(
+                user.email.to_lowercase().clone(),
+            ))])?.is_empty()
+        {
+            return Err(ApiError::conflict("A user with this email already exists.").into());
+        }
+
+        if user.username.is_empty() {
+            return Err(ApiError::validation("Account name cannot be empty.").into());
+        }
+
+        if !email_regex.is_match(&user.email) {
+            return Err(ApiError::validation("Must be a valid AUBG email.").into());
+        }
+
+        if !phone_regex.is_match(&user.phone) && !user.phone.is_empty() {
+            return Err(ApiError::validation("Invalid phone number.").into());
+        }
+
+        let mut user = user.to_owned();
+        user.password = if external_login {
+            self.config.password_hashing.hash(&tokens::generate_token())?
+        } else {
+            self.resolve_password(&user, "")?
+        };
+
+        self.db.insert(vec![ReceiverType::User(user.clone())])?;
+
+        self.get_users_by_filters(vec![Filter::Users(UsersFilter::Email(user.email.clone()))])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Failed to create account."))
+    }
+
+    fn find_invitation(&self, token_hash: &str) -> Result<Option<AdminInvitation>> {
+        let findings = self.db.find(
+            Table::AdminInvitations,
+            vec![Filter::AdminInvitations(AdminInvitationsFilter::TokenHash(token_hash.to_owned()))],
+            None,
+        )?;
+
+        Ok(findings.into_iter().find_map(|x| {
+            if let ReceiverType::AdminInvitation(invitation) = x {
+                Some(invitation)
+            } else {
+                None
+            }
+        }))
+    }
+
+    fn audit(&mut self, actor_id: i32, action: &str, target: &str, details: &str) -> Result<()> {
+        self.db.insert(vec![ReceiverType::AuditEntry(AuditEntry {
+            id: 0,
+            actor_id,
+            action: action.to_owned(),
+            target: target.to_owned(),
+            details: details.to_owned(),
+            created_at: chrono::Utc::now().timestamp(),
+            impersonator_id: self.impersonator.as_ref().map(|a| a.id).unwrap_or(0),
+        })])
+    }
+
+    // Password check with brute-force accounting; does not establish a session by itself
+    fn authenticate(&mut self, email: &str, password: &str) -> Result<User> {
+        let now = chrono::Utc::now().timestamp();
+
+        // Refuse outright while either the account or the client is locked out
+        if let Some(until) = self.locked_until(email, now)? {
+            self.record_login_attempt(email, false, "locked out", now)?;
+            return Err(ApiError::too_many_requests(
+                format!("Too many failed login attempts. Try again in {} seconds.", until - now),
+                until - now,
+            ).into());
+        }
+
+        let binding =
+            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Email(email.to_owned()))])?;
+        let user = match binding.first() {
+            Some(u) => u.to_owned(),
+            // same answer, in the same time, as a wrong password, so sign-in cannot be used
+            // to probe for accounts
+            None => {
+                self.config.password_hashing.verify_nothing(password)?;
+                return self.reject_login(
+                    email,
+                    "unknown user",
+                    now,
+                    ApiError::unauthenticated("Invalid username or password.").into(),
+                );
+            }
+        };
+
+        let verification = match self.config.password_hashing.verify(&user.password, password) {
+            Err(e) => {
+                self.record_login_attempt(email, false, "unusable password hash", now)?;
+                return Err(e);
+            }
+            v => v?,
+        };
+
+        if verification == Verification::Invalid {
+            return self.reject_login(
+                email,
+                "invalid password",
+                now,
+                ApiError::unauthenticated("Invalid username or password.").into(),
+            );
+        }
+
+        // the state of the account is only told to someone who knows its password
+
+        // If the user is suspended, they cannot login
+        if user.suspended {
+            return self.reject_login(
+                email,
+                "suspended",
+                now,
+                ApiError::forbidden("User is suspended.").into(),
+            );
+        }
+
+        if user.role == Role::Service {
+            return self.reject_login(
+                email,
+                "service account",
+                now,
+                ApiError::unauthenticated("Service accounts must authenticate with an API key.").into(),
+            );
+        }
+
+        if user.forcenewpw {
+            self.record_login_attempt(email, false, "password change required", now)?;
+            return Err(ApiError::forbidden("User must change password.").into());
+        }
+
+        self.record_login_attempt(email, true, "ok", now)?;
+
+        // the plain password is only at hand here, so upgrade outdated hashes now
+        let mut user = user;
+        if verification == Verification::ValidNeedsRehash {
+            user.password = self.config.password_hashing.hash(password)?;
+            self.db.update(vec![ReceiverType::User(user.clone())])?;
+        }
+
+        self.db.delete(vec![ReceiverType::LoginThrottle(LoginThrottle {
+            id: 0,
+            scope: SCOPE_ACCOUNT.to_owned(),
+            key: email.to_lowercase(),
+            failures: 0,
+            last_failure: 0,
+            locked_until: 0,
+        })])?;
+        Ok(user)
+    }
+
+    fn create_session(&mut self, user: &User, pending_mfa: bool, impersonator_id: i32) -> Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let token = tokens::generate_token();
+        let ttl = if pending_mfa {
+            self.config.sessions.mfa_challenge_ttl_secs
+        } else if impersonator_id != 0 {
+            self.config.sessions.impersonation_ttl_secs
+        } else {
+            self.config.sessions.ttl_secs
+        };
+
+        self.db.insert(vec![ReceiverType::Session(Session {
+            id: 0,
+            token_hash: tokens::hash_token(&token),
+            user_id: user.id,
+            created_at: now,
+            last_seen: now,
+            expires_at: now + ttl,
+            ip: self.client_ip.clone(),
+            user_agent: self.client_agent.clone(),
+            pending_mfa,
+            impersonator_id,
+        })])?;
+
+        let findings = self.db.find(
+            Table::Sessions,
+            vec![Filter::Sessions(SessionsFilter::TokenHash(tokens::hash_token(&token)))],
+            None,
+        )?;
+
+        self.current_session = findings.into_iter().find_map(|x| {
+            if let ReceiverType::Session(session) = x {
+                Some(session)
+            } else {
+                None
+            }
+        });
+
+        Ok(token)
+    }
+
+    fn lookup_session(&mut self, token: &str) -> Result<(Session, User)> {
+        let findings = self.db.find(
+            Table::Sessions,
+            vec![Filter::Sessions(SessionsFilter::TokenHash(tokens::hash_token(token)))],
+            None,
+        )?;
+
+        let session = findings
+            .into_iter()
+            .find_map(|x| {
+                if let ReceiverType::Session(session) = x {
+                    Some(session)
+                } else {
+                    None
                 }
-                _ => Err(anyhow!("You are not a student.")),
+            })
+            .ok_or_else(|| ApiError::unauthenticated("Invalid or expired session."))?;
+
+        if session.expires_at <= chrono::Utc::now().timestamp() {
+            self.db.delete(vec![ReceiverType::Session(session)])?;
+            return Err(ApiError::unauthenticated("Invalid or expired session.").into());
+        }
+
+        let binding =
+            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(session.user_id))])?;
+        let user = binding
+            .first()
+            .ok_or_else(|| ApiError::unauthenticated("Invalid or expired session."))?
+            .to_owned();
+
+        if user.suspended {
+            return Err(ApiError::forbidden("User is suspended.").into());
+        }
+
+        Ok((session, user))
+    }
+
+    fn touch_session(&mut self, mut session: Session) -> Result<()> {
+        session.last_seen = chrono::Utc::now().timestamp();
+        self.db.update(vec![ReceiverType::Session(session.clone())])?;
+        self.current_session = Some(session);
+
+        Ok(())
+    }
+
+    fn find_totp(&self, user_id: i32) -> Result<Option<UserTotp>> {
+        let findings = self.db.find(
+            Table::UserTotp,
+            vec![Filter::UserTotp(UserTotpFilter::UserId(user_id))],
+            None,
+        )?;
+
+        Ok(findings.into_iter().find_map(|x| {
+            if let ReceiverType::UserTotp(totp) = x {
+                Some(totp)
+            } else {
+                None
             }
-        } else {
-            Err(anyhow!("Must be signed in."))
-        }
+        }))
     }
 
-    pub fn drop_courses(&mut self, courses: Vec<Courses>) -> Result<()> {
-        if let Some(session) = &self.session {
-            match session.role.to_lowercase().as_str() {
-                "student" => {
-                    let errors = courses
-                        .iter()
-                        .filter_map(|x| {
-                            let c = self.transmute_course_to_student_course(x.to_owned());
-                            if c.student_id != session.id {
-                                Some(
-                                    anyhow!(
-                                        "You do not have permission to register courses on someone else's behalf."
-                                    )
-                                )
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
-
-                    if errors.len() > 0 {
-                        return Err(
-                            anyhow!(
-                                "You do not have permission to register courses on someone else's behalf. No action was taken."
-                            )
-                        );
-                    }
-
-                    let upcast = courses
-                        .iter()
-                        .map(|x| {
-                            ReceiverType::StudentCourse(
-                                self.transmute_course_to_student_course(x.to_owned()),
-                            )
-                        })
-                        .collect();
-
-                    self.db.delete(upcast)?;
-
-                    Ok(())
+    fn totp_enabled(&self, user_id: i32) -> Result<bool> {
+        Ok(self.find_totp(user_id)?.map(|t| t.enabled).unwrap_or(false))
+    }
+
+    // Accepts a current TOTP code or one of the user's unused recovery codes
+    fn verify_second_factor(&mut self, user: &User, totp: &mut UserTotp, code: &str) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+
+        if let Some(step) = totp::verify(&totp.secret, code, now) {
+            // a code is only good once, even within its validity window
+            if step <= totp.last_used_step {
+                return Ok(false);
+            }
+
+            totp.last_used_step = step;
+            self.db.update(vec![ReceiverType::UserTotp(totp.clone())])?;
+
+            return Ok(true);
+        }
+
+        let findings = self.db.find(
+            Table::RecoveryCodes,
+            vec![
+                Filter::RecoveryCodes(RecoveryCodesFilter::UserId(user.id)),
+                Filter::RecoveryCodes(RecoveryCodesFilter::Used(false)),
+            ],
+            None,
+        )?;
+
+        let matched = findings
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::RecoveryCode(recovery_code) = x {
+                    Some(recovery_code)
+                } else {
+                    None
                 }
-                _ => Err(anyhow!("You do not have permission to drop courses.")),
+            })
+            .find(|c| {
+                self.config.password_hashing
+                    .verify(&c.code_hash, code.trim())
+                    .map(|v| v != Verification::Invalid)
+                    .unwrap_or(false)
+            });
+
+        match matched {
+            Some(mut recovery_code) => {
+                recovery_code.used = true;
+                self.db.update(vec![ReceiverType::RecoveryCode(recovery_code)])?;
+
+                Ok(true)
             }
-        } else {
-            Err(anyhow!("Must be signed in."))
+            None => Ok(false),
         }
     }
 
-    pub fn is_student(&self) -> bool {
-        if let Some(session) = &self.session {
-            session.role.to_lowercase() == "student"
-        } else {
-            false
+    // Issues a fresh set of recovery codes; the plaintext only ever leaves through the return value
+    fn replace_recovery_codes(&mut self, user_id: i32) -> Result<Vec<String>> {
+        self.db.delete(vec![ReceiverType::RecoveryCode(RecoveryCode {
+            id: 0,
+            user_id,
+            code_hash: String::new(),
+            used: false,
+        })])?;
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let hex = tokens::to_hex(&tokens::random_bytes(5));
+                format!("{}-{}", &hex[..5], &hex[5..])
+            })
+            .collect();
+
+        let mut upcast = Vec::new();
+        for code in &codes {
+            upcast.push(ReceiverType::RecoveryCode(RecoveryCode {
+                id: 0,
+                user_id,
+                code_hash: self.config.password_hashing.hash(code)?,
+                used: false,
+            }));
         }
+
+        self.db.insert(upcast)?;
+
+        Ok(codes)
     }
 
-    pub fn is_teacher(&self) -> bool {
-        if let Some(session) = &self.session {
-            session.role.to_lowercase() == "teacher"
-        } else {
-            false
+    fn throttle(&self, scope: &str, key: &str) -> Result<Option<LoginThrottle>> {
+        let findings = self.db.find(
+            Table::LoginThrottle,
+            vec![
+                Filter::LoginThrottle(LoginThrottleFilter::Scope(scope.to_owned())),
+                Filter::LoginThrottle(LoginThrottleFilter::Key(key.to_owned())),
+            ],
+            None,
+        )?;
+
+        let throttle = findings.into_iter().find_map(|x| {
+            if let ReceiverType::LoginThrottle(throttle) = x {
+                Some(throttle)
+            } else {
+                None
+            }
+        });
+
+        Ok(throttle)
+    }
+
+    fn locked_until(&self, email: &str, now: i64) -> Result<Option<i64>> {
+        let throttles = [
+            self.throttle(SCOPE_ACCOUNT, &email.to_lowercase())?,
+            self.throttle(SCOPE_IP, &self.client_ip)?,
+        ];
+
+        Ok(throttles
+            .iter()
+            .flatten()
+            .map(|t| t.locked_until)
+            .filter(|until| *until > now)
+            .max())
+    }
+
+    fn record_login_attempt(&mut self, email: &str, success: bool, reason: &str, now: i64) -> Result<()> {
+        metrics::record_login(success, reason);
+
+        self.db.insert(vec![ReceiverType::LoginAttempt(LoginAttempt {
+            id: 0,
+            email: email.to_owned(),
+            ip: self.client_ip.clone(),
+            success,
+            reason: reason.to_owned(),
+            created_at: now,
+        })])
+    }
+
+    // Records the failure against both the account and the client ip and returns the error
+    fn reject_login<T>(&mut self, email: &str, reason: &str, now: i64, error: anyhow::Error) -> Result<T> {
+        self.record_login_attempt(email, false, reason, now)?;
+
+        let keys = [
+            (SCOPE_ACCOUNT, email.to_lowercase()),
+            (SCOPE_IP, self.client_ip.clone()),
+        ];
+
+        for (scope, key) in keys {
+            let previous = self.throttle(scope, &key)?;
+            let next = self.config.lockout.register_failure(previous.as_ref(), scope, &key, now);
+            self.db.insert(vec![ReceiverType::LoginThrottle(next)])?;
         }
+
+        Err(error)
     }
 
-    pub fn is_admin(&self) -> bool {
-        if let Some(session) = &self.session {
-            session.role.to_lowercase() == "admin"
-        } else {
-            false
+    // Ids of the students enrolled in any course the teacher teaches
+    fn roster(&self, teacher_id: i32) -> Result<std::collections::HashSet<i32>> {
+        let courses = self
+            .db
+            .find(
+                Table::Courses,
+                vec![Filter::Courses(CoursesFilter::TeacherId(teacher_id))],
+                None,
+            )?
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::Course(course) = x {
+                    Some(Filter::StudentCourses(StudentCoursesFilter::CourseId(course.id)))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if courses.is_empty() {
+            return Ok(std::collections::HashSet::new());
         }
+
+        let students = self
+            .db
+            .find(Table::StudentCourses, courses, Some(Associativity::Or))?
+            .into_iter()
+            .filter_map(|x| {
+                if let ReceiverType::StudentCourse(enrollment) = x {
+                    Some(enrollment.student_id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(students)
     }
 
-    pub fn generate_statistics(&self) -> Result<Statistics> {
-        let registered_users = self.get_users()?.len() as i32;
-        let suspended_users = self
-            .get_users_by_filters(vec![Filter::Users(UsersFilter::Suspended(true))])?
-            .len() as i32;
-        let faculty_members = self
-            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-                "teacher".to_string(),
-            ))])?
-            .len() as i32;
-        let active_students = self
-            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(
-                "student".to_string(),
-            ))])?
-            .len() as i32
-            - suspended_users;
-        let graduated_students = self
-            .get_users_by_filters(vec![Filter::StudentAccount(StudentAccountFilter::CanGrad(
-                true,
-            ))])?
-            .len() as i32;
-        let courses = self.db.find(Table::Courses, vec![], None)?.len() as i32;
-        let departments = self.db.find(Table::Departments, vec![], None)?.len() as i32;
+    fn role_has_permission(&self, role: Role, permission: Permission) -> Result<bool> {
+        let findings = self.db.find(
+            Table::RolePermissions,
+            vec![
+                Filter::RolePermissions(RolePermissionsFilter::Role(role)),
+                Filter::RolePermissions(RolePermissionsFilter::Permission(permission)),
+            ],
+            None,
+        )?;
 
-        Ok(Statistics {
-            registered_users,
-            suspended_users,
-            faculty_members,
-            active_students,
-            graduated_students,
-            courses,
-            departments,
-        })
+        Ok(!findings.is_empty())
     }
-}
 
-// Private methods
-impl ServerConnection {
-    fn transmute_course_to_student_course(&self, course: Courses) -> StudentCourse {
+    fn transmute_course_to_student_course(&self, student_id: i32, course: Courses) -> StudentCourse {
         StudentCourse {
-            student_id: self.session.as_ref().unwrap().id,
+            student_id,
             course_id: course.id,
             grade: -1.0,
-            semester: match chrono::Local::now().month() {
-                6..=12 => "Fall".to_string(),
-                _ => "Spring".to_string(),
-            },
+            semester: self.config.semester_for(chrono::Local::now().month()),
+        }
+    }
+
+    // An empty password keeps the stored hash (if any); anything else must satisfy the
+    // password policy and is hashed here, never taken as-is
+    fn resolve_password(&self, user: &User, current: &str) -> Result<String> {
+        if user.password.is_empty() && !current.is_empty() {
+            return Ok(current.to_owned());
+        }
+
+        if password::looks_hashed(&user.password) {
+            return Err(ApiError::validation("Passwords must be submitted in plain text.").into());
         }
+
+        self.config.password_policy
+            .check(&user.password, &[&user.username, &user.email])?;
+
+        self.config.password_hashing.hash(&user.password)
     }
 
     fn update_user_as_student(&mut self, mut user: User) -> Result<()> {
         let binding =
-            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user.id.clone()))])?;
-        let u = binding.get(0).ok_or_else(|| anyhow!("User not found."))?;
+            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user.id))])?;
+        let u = binding.first().ok_or_else(|| ApiError::not_found("User not found."))?;
 
         // Check permissions
         if user.suspended != u.suspended {
-            return Err(anyhow!("Suspended cannot be changed."));
+            return Err(ApiError::forbidden("Suspended cannot be changed.").into());
         }
 
         if user.verified != u.verified {
-            return Err(anyhow!("Verified cannot be changed."));
+            return Err(ApiError::forbidden("Verified cannot be changed.").into());
         }
 
         if user.role != u.role {
-            return Err(anyhow!("Role cannot be changed."));
+            return Err(ApiError::forbidden("Role cannot be changed.").into());
         }
         
-        if user.password.is_empty() || user.password.starts_with("$argon2id") {
-            user.password = u.password.to_owned();
-        } else {
-            let salt = password::generate_salt();
-            user.password = password::hash(&user.password, salt);
-        }
+        user.password = self.resolve_password(&user, &u.password)?;
 
         self.db.update(vec![ReceiverType::User(user)])?;
 
@@ -762,18 +2379,733 @@ impl ServerConnection {
 
     fn update_user_as_admin(&mut self, mut user: User) -> Result<()> {
         let binding =
-            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user.id.clone()))])?;
-        let u = binding.get(0).ok_or_else(|| anyhow!("User not found."))?;
+            self.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(user.id))])?;
+        let u = binding.first().ok_or_else(|| ApiError::not_found("User not found."))?;
 
-        if user.password.is_empty() || user.password.starts_with("$argon2id") {
-            user.password = u.password.to_owned();
-        } else {
-            let salt = password::generate_salt();
-            user.password = password::hash(&user.password, salt);
+        if user.suspended != u.suspended {
+            self.authorize(Permission::UserSuspend)?;
+        }
+
+        if user.role != u.role {
+            self.authorize(Permission::UserChangeRole)?;
         }
 
+        user.password = self.resolve_password(&user, &u.password)?;
+
         self.db.update(vec![ReceiverType::User(user)])?;
 
         Ok(())
     }
 }
+
+// Unknown entries are dropped rather than failing the request; they can only appear
+// if a permission is removed after a key was issued
+fn parse_scopes(scopes: &str) -> Vec<Permission> {
+    scopes
+        .split(',')
+        .filter_map(|s| s.parse::<Permission>().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::{TestDb, PASSWORD};
+
+    fn login_attempts(conn: &ServerConnection, email: &str) -> Vec<LoginAttempt> {
+        conn.db
+            .find(
+                Table::LoginAttempts,
+                vec![Filter::LoginAttempts(LoginAttemptsFilter::Email(email.to_owned()))],
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .filter_map(|x| match x {
+                ReceiverType::LoginAttempt(a) => Some(a),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn login_email_is_stored_as_data_not_sql() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let mut conn = db.connect();
+
+        let email = "x'); DROP TABLE USERS;--";
+        let err = conn.start_session(email.to_owned(), PASSWORD.to_owned()).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "unauthenticated");
+
+        let attempts = login_attempts(&conn, email);
+        assert_eq!(attempts.len(), 1);
+        assert!(!attempts[0].success);
+        assert_eq!(attempts[0].email, email);
+
+        let users = conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Id(student.id))]).unwrap();
+        assert_eq!(users.len(), 1);
+    }
+
+    #[test]
+    fn repeated_failures_lock_the_account_until_an_admin_unlocks_it() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let mut conn = db.connect();
+
+        let free = db.config.lockout.free_attempts;
+        for _ in 0..=free {
+            let err = conn.start_session(student.email.clone(), "wrong".to_owned()).err().unwrap();
+            assert_eq!(ApiError::from(err).code(), "unauthenticated");
+        }
+
+        // past the free attempts even the right password is refused for a while
+        let err = conn.start_session(student.email.clone(), PASSWORD.to_owned()).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "too_many_requests");
+        let reasons: Vec<String> = login_attempts(&conn, &student.email).into_iter().map(|a| a.reason).collect();
+        assert_eq!(reasons.last().map(String::as_str), Some("locked out"));
+
+        let mut admin_conn = db.connect();
+        admin_conn.set_session(admin);
+        admin_conn.unlock_user(student.clone()).unwrap();
+
+        // the unlock clears the account; this client's ip is still backing off
+        let mut other_client = db.connect();
+        other_client.set_client_ip("10.0.0.2");
+        assert!(other_client.start_session(student.email.clone(), PASSWORD.to_owned()).is_ok());
+    }
+
+    #[test]
+    fn unknown_accounts_are_rejected_like_wrong_passwords() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let mut conn = db.connect();
+
+        let unknown = conn.start_session("nobody@aubg.edu".to_owned(), PASSWORD.to_owned()).err().unwrap();
+        let wrong = conn.start_session(student.email.clone(), "wrong".to_owned()).err().unwrap();
+
+        assert_eq!(unknown.to_string(), wrong.to_string());
+        assert_eq!(ApiError::from(unknown).code(), "unauthenticated");
+
+        // both count towards the client's throttle
+        let throttle = conn.throttle(SCOPE_IP, "unknown").unwrap().unwrap();
+        assert_eq!(throttle.failures, 2);
+    }
+
+    #[test]
+    fn account_state_is_only_told_to_whoever_knows_the_password() {
+        let db = TestDb::new();
+        let suspended = db.user(Role::Student, "suspended@aubg.edu");
+        db.suspend(&suspended);
+        let service = db.user(Role::Service, "service@aubg.edu");
+        let renewing = db.user(Role::Student, "renewing@aubg.edu");
+        rusqlite::Connection::open(&db.config.database.path)
+            .unwrap()
+            .execute("UPDATE USERS SET forcenewpw = 1 WHERE id = ?1", [renewing.id])
+            .unwrap();
+        // each sign-in from its own address, so the client's throttle stays out of the way
+        let client = |n: usize| {
+            let mut conn = db.connect();
+            conn.set_client_ip(&format!("10.0.0.{}", n));
+            conn
+        };
+
+        let unknown = client(0).start_session("nobody@aubg.edu".to_owned(), "wrong".to_owned()).err().unwrap();
+        for (n, user) in [&suspended, &service, &renewing].into_iter().enumerate() {
+            let err = client(n + 1).start_session(user.email.clone(), "wrong".to_owned()).err().unwrap();
+            assert_eq!(err.to_string(), unknown.to_string(), "{} gives itself away", user.email);
+        }
+
+        let answers: Vec<(String, &str)> = [&suspended, &service, &renewing]
+            .into_iter()
+            .enumerate()
+            .map(|(n, u)| {
+                let err = client(n + 10).start_session(u.email.clone(), PASSWORD.to_owned()).err().unwrap();
+                let err = ApiError::from(err);
+                (err.to_string(), err.code())
+            })
+            .collect();
+        assert_eq!(answers[0], (String::from("User is suspended."), "forbidden"));
+        assert_eq!(answers[1].1, "unauthenticated");
+        assert_eq!(answers[2], (String::from("User must change password."), "forbidden"));
+
+        // a suspended account is throttled like any other failure
+        let throttle = client(0).throttle(SCOPE_ACCOUNT, &suspended.email).unwrap().unwrap();
+        assert_eq!(throttle.failures, 2);
+    }
+
+    #[test]
+    fn signing_in_upgrades_outdated_password_hashes() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+
+        // the same database after the hashing parameters were raised
+        let mut config = (*db.config).clone();
+        config.password_hashing.memory_kib *= 2;
+        let mut conn = ServerConnection::new(Arc::new(config.clone())).unwrap();
+        conn.start_session(student.email.clone(), PASSWORD.to_owned()).unwrap();
+
+        let stored = conn.user_by_id(student.id).unwrap();
+        assert_ne!(stored.password, student.password);
+        assert_eq!(config.password_hashing.verify(&stored.password, PASSWORD).unwrap(), Verification::Valid);
+    }
+
+    #[test]
+    fn second_factor_is_required_and_codes_cannot_be_replayed() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+
+        let mut conn = db.connect();
+        conn.start_session(student.email.clone(), PASSWORD.to_owned()).unwrap();
+        let enrollment = conn.enroll_totp().unwrap();
+        let code = totp::code(&enrollment.secret, chrono::Utc::now().timestamp());
+        let recovery_codes = conn.confirm_totp(&code).unwrap();
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+
+        let mut conn = db.connect();
+        let token = match conn.start_session(student.email.clone(), PASSWORD.to_owned()).unwrap() {
+            LoginOutcome::TwoFactorRequired(token) => token,
+            _ => panic!("expected a second factor to be required"),
+        };
+        assert!(db.connect().resume_session(&token).is_err());
+
+        // the code that confirmed the enrollment has been used already
+        assert!(conn.complete_two_factor(&token, &code).is_err());
+        conn.complete_two_factor(&token, &recovery_codes[0]).unwrap();
+        db.connect().resume_session(&token).unwrap();
+
+        let mut conn = db.connect();
+        let token = match conn.start_session(student.email.clone(), PASSWORD.to_owned()).unwrap() {
+            LoginOutcome::TwoFactorRequired(token) => token,
+            _ => panic!("expected a second factor to be required"),
+        };
+        assert!(conn.complete_two_factor(&token, &recovery_codes[0]).is_err());
+        conn.complete_two_factor(&token, &recovery_codes[1]).unwrap();
+    }
+
+    #[test]
+    fn session_client_details_are_stored_verbatim() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+
+        let agent = "Mozilla/5.0 ', 0, 0); DELETE FROM SESSIONS; --";
+        let mut conn = db.connect();
+        conn.set_client_agent(agent);
+        conn.start_session(student.email.clone(), PASSWORD.to_owned()).unwrap();
+
+        let sessions = conn.get_sessions().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].user_agent, agent);
+    }
+
+    #[test]
+    fn granted_permissions_take_effect_and_can_be_revoked() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+
+        let mut admin_conn = db.connect();
+        admin_conn.set_session(admin);
+
+        let teacher_conn = db.signed_in(&teacher);
+        assert!(!teacher_conn.can(Permission::UserRead));
+
+        admin_conn.grant_permission(Role::Teacher, Permission::UserRead).unwrap();
+        assert!(teacher_conn.can(Permission::UserRead));
+
+        admin_conn.revoke_permission(Role::Teacher, Permission::UserRead).unwrap();
+        assert!(!teacher_conn.can(Permission::UserRead));
+
+        let err = admin_conn.revoke_permission(Role::Admin, Permission::RoleManage).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+    }
+
+    fn audit_entries(conn: &ServerConnection) -> Vec<AuditEntry> {
+        conn.db
+            .find(Table::AuditLog, vec![Filter::AuditLog(AuditLogFilter::All)], None)
+            .unwrap()
+            .into_iter()
+            .filter_map(|x| match x {
+                ReceiverType::AuditEntry(a) => Some(a),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn invitation_token(db: &TestDb, email: Option<&str>) -> String {
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let mut conn = db.connect();
+        conn.set_session(admin);
+        conn.invite_admin(email.map(str::to_owned)).unwrap().token
+    }
+
+    fn new_admin(email: &str) -> User {
+        User {
+            id: 0,
+            username: String::from("invited"),
+            password: PASSWORD.to_owned(),
+            email: email.to_owned(),
+            phone: String::new(),
+            verified: false,
+            suspended: false,
+            forcenewpw: false,
+            role: Role::Student,
+        }
+    }
+
+    #[test]
+    fn an_invitation_creates_exactly_one_admin() {
+        let db = TestDb::new();
+        let token = invitation_token(&db, None);
+
+        db.connect().register_admin(new_admin("first@aubg.edu"), &token).unwrap();
+        let err = db.connect().register_admin(new_admin("second@aubg.edu"), &token).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+
+        let admins = db.connect()
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Role(Role::Admin))])
+            .unwrap();
+        assert_eq!(admins.len(), 2);
+        assert!(admins.iter().any(|a| a.email == "first@aubg.edu"));
+
+        let entries = audit_entries(&db.connect());
+        assert!(entries.iter().any(|e| e.action == "admin.invitation.accept"));
+    }
+
+    #[test]
+    fn an_invitation_can_only_be_claimed_once() {
+        let db = TestDb::new();
+        let token = invitation_token(&db, None);
+        let mut conn = db.connect();
+        let invitation = conn.find_invitation(&tokens::hash_token(&token)).unwrap().unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        // what two redemptions racing past the used_at check would both attempt
+        assert!(conn.db.claim_invitation(invitation.id, now).unwrap());
+        assert!(!db.connect().db.claim_invitation(invitation.id, now).unwrap());
+
+        conn.db.release_invitation(invitation.id, now).unwrap();
+        assert!(conn.db.claim_invitation(invitation.id, now).unwrap());
+    }
+
+    #[test]
+    fn a_rejected_registration_hands_the_invitation_back() {
+        let db = TestDb::new();
+        let token = invitation_token(&db, None);
+
+        let err = db.connect().register_admin(new_admin("not-an-aubg-address@example.com"), &token).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "validation_failed");
+        db.connect().register_admin(new_admin("invited@aubg.edu"), &token).unwrap();
+    }
+
+    #[test]
+    fn audit_details_are_stored_verbatim() {
+        let db = TestDb::new();
+        let mut conn = db.connect();
+        conn.audit(SYSTEM_ACTOR, "test", "target", "it's").unwrap();
+
+        assert_eq!(audit_entries(&conn)[0].details, "it's");
+    }
+
+    #[test]
+    fn api_keys_authenticate_within_their_scopes_until_revoked() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let mut admin_conn = db.connect();
+        admin_conn.set_session(admin);
+
+        let service = admin_conn
+            .create_service_account(String::from("boards"), String::from("boards@aubg.edu"))
+            .unwrap();
+        let issued = admin_conn
+            .create_api_key(service.id, String::from("lobby board's key"), vec![Permission::CourseCreate], None)
+            .unwrap();
+
+        let stored = admin_conn.get_api_keys(service.id).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].name, "lobby board's key");
+        assert_eq!(stored[0].last_used, 0);
+        assert_ne!(stored[0].key_hash, issued.key);
+
+        let mut conn = db.connect();
+        conn.resume_api_key(&issued.key).unwrap();
+        assert_eq!(conn.current_user().map(|u| u.id), Some(service.id));
+        assert!(conn.can(Permission::CourseCreate));
+        assert!(!conn.can(Permission::UserRead));
+        assert!(admin_conn.get_api_keys(service.id).unwrap()[0].last_used > 0);
+
+        // service accounts cannot fall back to a password
+        let err = db.connect().start_session(service.email.clone(), PASSWORD.to_owned()).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "unauthenticated");
+
+        admin_conn.revoke_api_key(issued.id).unwrap();
+        let err = db.connect().resume_api_key(&issued.key).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "unauthenticated");
+    }
+
+    #[test]
+    fn api_key_scopes_cannot_exceed_the_issuer() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let mut admin_conn = db.connect();
+        admin_conn.set_session(admin);
+        let service = admin_conn
+            .create_service_account(String::from("reports"), String::from("reports@aubg.edu"))
+            .unwrap();
+
+        admin_conn.revoke_permission(Role::Admin, Permission::UserDelete).unwrap();
+        let err = admin_conn
+            .create_api_key(service.id, String::from("reports"), vec![Permission::UserDelete], None)
+            .err()
+            .unwrap();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+    }
+
+    #[test]
+    fn an_sso_challenge_is_single_use() {
+        let db = TestDb::new();
+        let mut conn = db.connect();
+        let challenge = conn.begin_sso().unwrap();
+
+        let taken = db.connect().take_sso_challenge(&challenge.state).unwrap();
+        assert_eq!(taken.nonce, challenge.nonce);
+        assert_eq!(taken.code_verifier, challenge.code_verifier);
+
+        let err = db.connect().take_sso_challenge(&challenge.state).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "unauthenticated");
+    }
+
+    #[test]
+    fn impersonation_acts_as_the_user_and_is_recorded_for_them() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let student = db.user(Role::Student, "student@aubg.edu");
+
+        let mut admin_conn = db.connect();
+        admin_conn.set_session(admin.clone());
+        let token = admin_conn.start_impersonation(student.id).unwrap();
+
+        let mut conn = db.connect();
+        conn.set_request_line("GET", "/api/v1/account");
+        conn.resume_session(&token).unwrap();
+        assert_eq!(conn.current_user().map(|u| u.id), Some(student.id));
+        assert_eq!(conn.impersonator().map(|a| a.id), Some(admin.id));
+
+        // acting as someone is not the same as holding their credentials
+        let err = conn.enroll_totp().err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+        assert!(conn.start_impersonation(student.id).is_err());
+
+        conn.end_session(&token).unwrap();
+        assert!(db.connect().resume_session(&token).is_err());
+
+        let history = db.signed_in(&student).get_impersonation_history().unwrap();
+        let actions: Vec<&str> = history.iter().map(|e| e.action.as_str()).collect();
+        assert!(actions.contains(&"impersonation.start"));
+        assert!(actions.contains(&"impersonation.end"));
+        let request = history.iter().find(|e| e.action == "impersonation.request").unwrap();
+        assert_eq!(request.target, "GET /api/v1/account");
+        assert_eq!(request.impersonator_id, admin.id);
+    }
+
+    #[test]
+    fn admins_cannot_be_impersonated() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let other = db.user(Role::Admin, "other@aubg.edu");
+
+        let mut conn = db.connect();
+        conn.set_session(admin);
+        let err = conn.start_impersonation(other.id).err().unwrap();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+    }
+
+    fn session_token(conn: &mut ServerConnection, user: &User) -> String {
+        match conn.start_session(user.email.clone(), PASSWORD.to_owned()).unwrap() {
+            LoginOutcome::Authenticated(token) => token,
+            _ => panic!("expected a full session"),
+        }
+    }
+
+    #[test]
+    fn signing_out_elsewhere_keeps_only_the_current_session() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+
+        let laptop = session_token(&mut db.connect(), &student);
+        let phone = session_token(&mut db.connect(), &student);
+
+        let mut conn = db.connect();
+        conn.resume_session(&laptop).unwrap();
+        let sessions = conn.get_sessions().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|s| s.current).count(), 1);
+
+        conn.revoke_other_sessions().unwrap();
+        assert!(db.connect().resume_session(&phone).is_err());
+        assert!(db.connect().resume_session(&laptop).is_ok());
+        assert_eq!(conn.get_sessions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn users_cannot_revoke_sessions_they_do_not_own() {
+        let db = TestDb::new();
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let other = db.user(Role::Student, "other@aubg.edu");
+
+        let token = session_token(&mut db.connect(), &other);
+        let mut owner = db.connect();
+        owner.resume_session(&token).unwrap();
+        let id = owner.get_sessions().unwrap()[0].id;
+
+        let mut conn = db.signed_in(&student);
+        let _ = conn.revoke_session(id);
+        assert!(db.connect().resume_session(&token).is_ok());
+    }
+
+    fn course(conn: &mut ServerConnection, teacher_id: i32, name: &str) -> Courses {
+        conn.register_courses(vec![Courses {
+            id: 0,
+            teacher_id,
+            course: name.to_owned(),
+            course_nr: String::from("COS 101"),
+            description: String::new(),
+            cr_cost: 3,
+            timeslots: String::from("MWF 10:00"),
+        }])
+        .unwrap();
+
+        conn.list_courses(vec![Filter::Courses(CoursesFilter::Course(name.to_owned()))])
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn filter_values_are_never_read_as_sql() {
+        let db = TestDb::new();
+        db.user(Role::Student, "student@aubg.edu");
+        let conn = db.connect();
+
+        for email in ["x' OR '1'='1", "' OR 1=1 --", "student@aubg.edu' --"] {
+            let found = conn
+                .get_users_by_filters(vec![Filter::Users(UsersFilter::Email(email.to_owned()))])
+                .unwrap();
+            assert!(found.is_empty(), "{} matched {} users", email, found.len());
+        }
+    }
+
+    #[test]
+    fn quotes_in_account_and_course_fields_are_stored_verbatim() {
+        let db = TestDb::new();
+        let mut teacher = db.user(Role::Teacher, "obrien@aubg.edu");
+        teacher.username = String::from("O'Brien'); DROP TABLE USERS;--");
+        teacher.password = String::new();
+        db.connect().db.update(vec![ReceiverType::User(teacher.clone())]).unwrap();
+
+        let found = db
+            .connect()
+            .get_users_by_filters(vec![Filter::Users(UsersFilter::Username(teacher.username.clone()))])
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].email, "obrien@aubg.edu");
+
+        let mut conn = db.signed_in(&teacher);
+        let stored = course(&mut conn, teacher.id, "Logic'); DROP TABLE COURSES;--");
+        assert_eq!(stored.course, "Logic'); DROP TABLE COURSES;--");
+        assert_eq!(conn.list_courses(vec![]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn teachers_cannot_take_over_courses_by_claiming_them() {
+        let db = TestDb::new();
+        let owner = db.user(Role::Teacher, "owner@aubg.edu");
+        let other = db.user(Role::Teacher, "other@aubg.edu");
+        let theirs = course(&mut db.signed_in(&owner), owner.id, "Ethics");
+
+        let mut conn = db.signed_in(&other);
+        let claimed = Courses { teacher_id: other.id, course: String::from("Mine now"), ..theirs.clone() };
+
+        let err = conn.update_course(&theirs, claimed.clone()).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+        let err = conn.remove_courses(vec![claimed]).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+
+        let stored = conn.list_courses(vec![Filter::Courses(CoursesFilter::Id(theirs.id))]).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].teacher_id, owner.id);
+        assert_eq!(stored[0].course, "Ethics");
+    }
+
+    #[test]
+    fn owners_edit_their_courses_but_cannot_hand_them_away() {
+        let db = TestDb::new();
+        let owner = db.user(Role::Teacher, "owner@aubg.edu");
+        let other = db.user(Role::Teacher, "other@aubg.edu");
+        let mut conn = db.signed_in(&owner);
+        let mine = course(&mut conn, owner.id, "Ethics");
+
+        let renamed = Courses { course: String::from("Applied Ethics"), ..mine.clone() };
+        conn.update_course(&mine, renamed.clone()).unwrap();
+
+        let given = Courses { teacher_id: other.id, ..renamed.clone() };
+        let err = conn.update_course(&renamed, given).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+
+        let gone = Courses { id: mine.id + 100, ..renamed };
+        let err = conn.update_course(&gone, gone.clone()).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "not_found");
+    }
+
+    #[test]
+    fn course_updates_only_land_on_the_version_that_was_read() {
+        let db = TestDb::new();
+        let owner = db.user(Role::Teacher, "owner@aubg.edu");
+        let mut conn = db.signed_in(&owner);
+        let read = course(&mut conn, owner.id, "Ethics");
+
+        // someone else's edit lands first
+        let first = Courses { course: String::from("Applied Ethics"), ..read.clone() };
+        conn.update_course(&read, first.clone()).unwrap();
+
+        // the same change made on the stale copy must not overwrite it
+        let second = Courses { cr_cost: read.cr_cost + 1, ..read.clone() };
+        let err = conn.update_course(&read, second).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "precondition_failed");
+        let stored = conn.course_by_id(read.id).unwrap();
+        assert_eq!((stored.teacher_id, stored.course, stored.cr_cost), (owner.id, first.course.clone(), first.cr_cost));
+
+        // another teacher claiming to have read the course as theirs passes the ownership
+        // check, but the claim does not match the row, so nothing is written
+        let other = db.user(Role::Teacher, "other@aubg.edu");
+        let forged = Courses { teacher_id: other.id, ..first.clone() };
+        let err = db.signed_in(&other).update_course(&forged, forged.clone()).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "precondition_failed");
+        let stored = conn.course_by_id(read.id).unwrap();
+        assert_eq!((stored.teacher_id, stored.course, stored.cr_cost), (owner.id, first.course.clone(), first.cr_cost));
+    }
+
+    #[test]
+    fn graduation_follows_the_configured_credit_threshold() {
+        let mut config = Config::default();
+        config.accounts.graduation_credits = 6;
+        let db = TestDb::with_config(config);
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        // registered after the teacher, so user ids and student account ids differ
+        let student = db.user(Role::Student, "student@aubg.edu");
+        let classmate = db.user(Role::Student, "classmate@aubg.edu");
+
+        let mut conn = db.signed_in(&teacher);
+        let courses = vec![course(&mut conn, teacher.id, "Ethics"), course(&mut conn, teacher.id, "Logic")];
+        db.signed_in(&student).enroll_courses(courses.clone()).unwrap();
+        db.signed_in(&classmate).enroll_courses(courses.clone()).unwrap();
+        let can_grad = |user: &User| db.signed_in(user).get_student_standing().unwrap().can_grad;
+
+        conn.grade_student(courses[0].clone(), student.id, 4.0).unwrap();
+        assert!(!can_grad(&student));
+
+        // the second course reaches 6 credits, for this student only
+        conn.grade_student(courses[1].clone(), student.id, 3.0).unwrap();
+        let standing = db.signed_in(&student).get_student_standing().unwrap();
+        assert!(standing.can_grad);
+        assert_eq!(standing.cgpa, 3.5);
+        assert!(!can_grad(&classmate));
+
+        // a higher threshold on the next start re-evaluates existing students
+        let mut raised = (*db.config).clone();
+        raised.accounts.graduation_credits = 9;
+        DbDriver::prepare(&raised).unwrap();
+        assert!(!can_grad(&student));
+    }
+
+    // ids from the listing, their neighbours, the extremes and random ones; a lookup must
+    // answer with exactly the row that has the id, or not found
+    fn probe_ids(existing: &[i32]) -> Vec<i32> {
+        let mut ids: Vec<i32> = existing.iter().flat_map(|id| [id - 1, *id, id + 1, id * 10, id * 11]).collect();
+        ids.extend([0, -1, i32::MIN, i32::MAX]);
+        ids.extend((0..64).map(|_| {
+            let bytes = tokens::random_bytes(4);
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3] & 0x3f]) % 200
+        }));
+        ids
+    }
+
+    #[test]
+    fn id_lookups_find_exactly_the_named_row() {
+        let db = TestDb::new();
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        for n in 0..12 {
+            db.user(Role::Student, &format!("student{}@aubg.edu", n));
+        }
+        let mut conn = db.signed_in(&teacher);
+        for n in 0..12 {
+            course(&mut conn, teacher.id, &format!("Course {}", n));
+        }
+
+        let users: Vec<i32> = conn.get_users_by_filters(vec![]).unwrap().iter().map(|u| u.id).collect();
+        for id in probe_ids(&users) {
+            match conn.user_by_id(id) {
+                Ok(user) => assert_eq!(user.id, id),
+                Err(e) => {
+                    assert!(!users.contains(&id), "user {} exists but was not found", id);
+                    assert_eq!(ApiError::from(e).code(), "not_found");
+                }
+            }
+        }
+
+        let courses: Vec<i32> = conn.list_courses(vec![]).unwrap().iter().map(|c| c.id).collect();
+        for id in probe_ids(&courses) {
+            match conn.course_by_id(id) {
+                Ok(course) => assert_eq!(course.id, id),
+                Err(e) => {
+                    assert!(!courses.contains(&id), "course {} exists but was not found", id);
+                    assert_eq!(ApiError::from(e).code(), "not_found");
+                }
+            }
+        }
+    }
+
+    fn ids(users: &[User]) -> Vec<i32> {
+        let mut ids: Vec<i32> = users.iter().map(|u| u.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn user_listings_follow_the_read_policy_for_each_role() {
+        let db = TestDb::new();
+        let admin = db.user(Role::Admin, "admin@aubg.edu");
+        let teacher = db.user(Role::Teacher, "teacher@aubg.edu");
+        let enrolled = db.user(Role::Student, "enrolled@aubg.edu");
+        let other = db.user(Role::Student, "other@aubg.edu");
+
+        let theirs = course(&mut db.signed_in(&teacher), teacher.id, "Ethics");
+        db.signed_in(&enrolled).enroll_courses(vec![theirs]).unwrap();
+
+        // admins: the whole directory
+        let mut conn = db.connect();
+        conn.set_session(admin.clone());
+        let everyone = ids(&[admin, teacher.clone(), enrolled.clone(), other.clone()]);
+        assert_eq!(ids(&conn.list_users(None, vec![]).unwrap()), everyone);
+        assert_eq!(ids(&conn.list_users(Some(Role::Teacher), vec![]).unwrap()), vec![teacher.id]);
+
+        // teachers: the students on their rosters, and no one else
+        let conn = db.signed_in(&teacher);
+        assert_eq!(ids(&conn.list_users(None, vec![]).unwrap()), vec![enrolled.id]);
+        assert_eq!(ids(&conn.list_users(Some(Role::Student), vec![]).unwrap()), vec![enrolled.id]);
+        let err = conn.list_users(Some(Role::Teacher), vec![]).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "forbidden");
+
+        // students: themselves, whatever they ask for
+        let conn = db.signed_in(&other);
+        assert_eq!(ids(&conn.list_users(None, vec![]).unwrap()), vec![other.id]);
+        assert_eq!(ids(&conn.list_users(Some(Role::Student), vec![]).unwrap()), vec![other.id]);
+        assert!(conn.list_users(Some(Role::Teacher), vec![]).unwrap().is_empty());
+        let by_email = vec![Filter::Users(UsersFilter::Email(enrolled.email.clone()))];
+        assert!(conn.list_users(None, by_email).unwrap().is_empty());
+
+        // signed out: nothing
+        let err = db.connect().list_users(None, vec![]).unwrap_err();
+        assert_eq!(ApiError::from(err).code(), "unauthenticated");
+    }
+}
@@ -1,17 +1,42 @@
 use anyhow::{Ok, Result};
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
+use std::time::Instant;
+
+use super::error::ApiError;
+use super::metrics;
+use super::table_models::Statement;
+use super::telemetry::{redact_sql, SQL_TARGET};
 
 pub struct DatabaseConnection {
     pub connection: Connection,
 }
 
 impl DatabaseConnection {
-    pub fn new() -> Result<Self> {
-        let connection = Connection::open("system.db")?;
+    pub fn new(path: &str) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        metrics::connection_opened();
+
+        Ok(Self { connection })
+    }
+
+    // Opens a database that must already exist, without the ability to change it
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        metrics::connection_opened();
 
         Ok(Self { connection })
     }
 
+    // Number of migrations applied so far
+    pub fn schema_version(&self) -> Result<usize> {
+        Ok(self
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize)
+    }
+
     pub fn create_tables(&mut self) -> Result<&mut Self> {
         self.connection.execute_batch(
                 r#"
@@ -197,4 +222,592 @@ impl DatabaseConnection {
 
         Ok(self)
     }
+
+    // Applies every migration newer than the database's user_version, in order
+    pub fn migrate(&mut self) -> Result<&mut Self> {
+        let version = self.schema_version()?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.connection.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+            tracing::info!(version = i + 1, "applied database migration");
+        }
+
+        Ok(self)
+    }
+
+    // Runs a single statement with its values bound; failures are logged with the
+    // (redacted) statement that caused them
+    pub fn execute(&self, statement: &Statement) -> Result<usize> {
+        let sql = statement.sql.as_str();
+        let _timer = StatementTimer::start(sql);
+
+        let params = rusqlite::params_from_iter(&statement.params);
+        self.connection.execute(sql, params).map_err(|e| {
+            // UNIQUE and foreign key violations are the caller's doing, not a server fault
+            let constraint = matches!(
+                &e,
+                rusqlite::Error::SqliteFailure(f, _) if f.code == rusqlite::ErrorCode::ConstraintViolation
+            );
+
+            if constraint {
+                tracing::info!(target: SQL_TARGET, sql = %redact_sql(sql), error = %e, "constraint violation");
+                ApiError::conflict("This conflicts with an existing record.").into()
+            } else {
+                tracing::error!(target: SQL_TARGET, sql = %redact_sql(sql), error = %e, "statement failed");
+                anyhow::Error::from(e)
+            }
+        })
+    }
+
+    // Unix time of the latest write to any of `tables`, as recorded in TABLE_CHANGES
+    pub fn last_changed(&self, tables: &[&str]) -> Result<Option<i64>> {
+        let placeholders = vec!["?"; tables.len()].join(", ");
+        let sql = format!(
+            r#"SELECT MAX("changed_at") FROM "TABLE_CHANGES" WHERE "table_name" IN ({})"#,
+            placeholders
+        );
+
+        let _timer = StatementTimer::start(&sql);
+        Ok(self
+            .connection
+            .query_row(&sql, rusqlite::params_from_iter(tables), |row| row.get(0))?)
+    }
+
+    // Pushes configured values the triggers depend on into SETTINGS, and brings
+    // existing rows in line if they changed since the last start
+    pub fn apply_settings(&mut self, graduation_credits: i32) -> Result<&mut Self> {
+        let current: i32 = self.connection.query_row(
+            r#"SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits'"#,
+            [],
+            |row| row.get(0),
+        )?;
+        if current == graduation_credits {
+            return Ok(self);
+        }
+
+        let tx = self.connection.transaction()?;
+        tx.execute(
+            r#"INSERT INTO "SETTINGS" ("key", "value") VALUES ('graduation_credits', ?1)
+            ON CONFLICT("key") DO UPDATE SET "value" = excluded."value""#,
+            [graduation_credits],
+        )?;
+        tx.execute_batch(
+            r#"
+            UPDATE "STUDENT_ACCOUNT"
+            SET "can_grad" = CASE
+                WHEN COALESCE((
+                    SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END)
+                    FROM "STUDENT_COURSES"
+                    JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+                    WHERE "STUDENT_COURSES"."student_id" = "STUDENT_ACCOUNT"."student_id"
+                ), 0) >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+                ELSE 0
+            END;
+            "#,
+        )?;
+        tx.commit()?;
+
+        Ok(self)
+    }
+}
+
+// The user_version a fully migrated database reports
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+const MIGRATIONS: &[&str] = &[
+    // 1: login attempt log and brute-force throttling
+    r#"
+    CREATE TABLE IF NOT EXISTS "LOGIN_ATTEMPTS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "email" TEXT NOT NULL,
+        "ip" TEXT NOT NULL,
+        "success" BOOLEAN NOT NULL,
+        "reason" TEXT NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE INDEX IF NOT EXISTS "login_attempts_email" ON "LOGIN_ATTEMPTS" ("email");
+
+    CREATE TABLE IF NOT EXISTS "LOGIN_THROTTLE" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "scope" TEXT NOT NULL,
+        "key" TEXT NOT NULL,
+        "failures" INTEGER NOT NULL,
+        "last_failure" INTEGER NOT NULL,
+        "locked_until" INTEGER NOT NULL,
+        UNIQUE("scope", "key"),
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+    "#,
+    // 2: login sessions and TOTP second factor
+    r#"
+    CREATE TABLE IF NOT EXISTS "SESSIONS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "token_hash" TEXT NOT NULL UNIQUE,
+        "user_id" INTEGER NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        "last_seen" INTEGER NOT NULL,
+        "expires_at" INTEGER NOT NULL,
+        "ip" TEXT NOT NULL,
+        "user_agent" TEXT NOT NULL,
+        "pending_mfa" BOOLEAN NOT NULL,
+        FOREIGN KEY ("user_id") REFERENCES "USERS"("id"),
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE TABLE IF NOT EXISTS "USER_TOTP" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "user_id" INTEGER NOT NULL UNIQUE,
+        "secret" TEXT NOT NULL,
+        "enabled" BOOLEAN NOT NULL,
+        "last_used_step" INTEGER NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        FOREIGN KEY ("user_id") REFERENCES "USERS"("id"),
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE TABLE IF NOT EXISTS "RECOVERY_CODES" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "user_id" INTEGER NOT NULL,
+        "code_hash" TEXT NOT NULL,
+        "used" BOOLEAN NOT NULL,
+        FOREIGN KEY ("user_id") REFERENCES "USERS"("id"),
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE TRIGGER IF NOT EXISTS "clear_auth_on_delete"
+    AFTER DELETE ON "USERS"
+    FOR EACH ROW
+    BEGIN
+        DELETE FROM "SESSIONS" WHERE "user_id" = OLD."id";
+        DELETE FROM "USER_TOTP" WHERE "user_id" = OLD."id";
+        DELETE FROM "RECOVERY_CODES" WHERE "user_id" = OLD."id";
+    END;
+    "#,
+    // 3: role based permissions, seeded with the rules that used to be hard-coded
+    r#"
+    CREATE TABLE IF NOT EXISTS "ROLE_PERMISSIONS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "role" TEXT NOT NULL,
+        "permission" TEXT NOT NULL,
+        UNIQUE("role", "permission"),
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'account.update'),
+        ('admin', 'account.delete'),
+        ('admin', 'user.update'),
+        ('admin', 'user.suspend'),
+        ('admin', 'user.role'),
+        ('admin', 'user.delete'),
+        ('admin', 'user.unlock'),
+        ('admin', 'course.create'),
+        ('admin', 'course.update'),
+        ('admin', 'course.delete'),
+        ('admin', 'course.manage_any'),
+        ('admin', 'grade.write'),
+        ('admin', 'enrollment.self'),
+        ('admin', 'department.create'),
+        ('admin', 'department.delete'),
+        ('admin', 'department.manage'),
+        ('admin', 'stats.read'),
+        ('admin', 'security.audit'),
+        ('admin', 'role.manage'),
+        ('admin', 'admin.access'),
+        ('teacher', 'account.update'),
+        ('teacher', 'account.delete'),
+        ('teacher', 'course.create'),
+        ('teacher', 'course.update'),
+        ('teacher', 'course.delete'),
+        ('teacher', 'grade.write'),
+        ('student', 'account.update'),
+        ('student', 'account.delete'),
+        ('student', 'enrollment.self');
+    "#,
+    // 4: admin invitations and the audit trail
+    r#"
+    CREATE TABLE IF NOT EXISTS "ADMIN_INVITATIONS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "token_hash" TEXT NOT NULL UNIQUE,
+        "email" TEXT NOT NULL,
+        "created_by" INTEGER NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        "expires_at" INTEGER NOT NULL,
+        "used_at" INTEGER NOT NULL,
+        "used_by" INTEGER NOT NULL,
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE TABLE IF NOT EXISTS "AUDIT_LOG" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "actor_id" INTEGER NOT NULL,
+        "action" TEXT NOT NULL,
+        "target" TEXT NOT NULL,
+        "details" TEXT NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'admin.invite');
+    "#,
+    // 5: API keys for service accounts
+    r#"
+    CREATE TABLE IF NOT EXISTS "API_KEYS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "user_id" INTEGER NOT NULL,
+        "name" TEXT NOT NULL,
+        "prefix" TEXT NOT NULL,
+        "key_hash" TEXT NOT NULL UNIQUE,
+        "scopes" TEXT NOT NULL,
+        "created_by" INTEGER NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        "expires_at" INTEGER NOT NULL,
+        "last_used" INTEGER NOT NULL,
+        "revoked" BOOLEAN NOT NULL,
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+
+    CREATE TRIGGER IF NOT EXISTS "clear_api_keys_on_delete"
+    AFTER DELETE ON "USERS"
+    FOR EACH ROW
+    BEGIN
+        DELETE FROM "API_KEYS" WHERE "user_id" = OLD."id";
+    END;
+
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'apikey.manage');
+    "#,
+    // 6: in-flight single sign-on logins
+    r#"
+    CREATE TABLE IF NOT EXISTS "OIDC_LOGINS" (
+        "id" INTEGER NOT NULL UNIQUE,
+        "state_hash" TEXT NOT NULL UNIQUE,
+        "nonce" TEXT NOT NULL,
+        "code_verifier" TEXT NOT NULL,
+        "created_at" INTEGER NOT NULL,
+        "expires_at" INTEGER NOT NULL,
+        PRIMARY KEY("id" AUTOINCREMENT)
+    );
+    "#,
+    // 7: impersonation; 0 means the session or action belongs to the user themself
+    r#"
+    ALTER TABLE "SESSIONS" ADD COLUMN "impersonator_id" INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE "AUDIT_LOG" ADD COLUMN "impersonator_id" INTEGER NOT NULL DEFAULT 0;
+
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'user.impersonate');
+    "#,
+    // 8: session management on behalf of other users
+    r#"
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'session.manage');
+    "#,
+    // 9: configurable settings read by the triggers (graduation threshold)
+    r#"
+    CREATE TABLE IF NOT EXISTS "SETTINGS" (
+        "key" TEXT NOT NULL UNIQUE,
+        "value" INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    INSERT OR IGNORE INTO "SETTINGS" ("key", "value") VALUES ('graduation_credits', 120);
+
+    DROP TRIGGER IF EXISTS "update_student_cgpa_insert";
+    CREATE TRIGGER "update_student_cgpa_insert"
+    AFTER INSERT ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END) FROM "STUDENT_COURSES" WHERE "student_id" = NEW."student_id"), 0)
+                >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "id" = NEW."student_id";
+    END;
+
+    DROP TRIGGER IF EXISTS "update_student_cgpa_update";
+    CREATE TRIGGER "update_student_cgpa_update"
+    AFTER UPDATE ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END) FROM "STUDENT_COURSES" WHERE "student_id" = NEW."student_id"), 0)
+                >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "id" = NEW."student_id";
+    END;
+
+    DROP TRIGGER IF EXISTS "update_student_cgpa_delete";
+    CREATE TRIGGER "update_student_cgpa_delete"
+    AFTER DELETE ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = OLD."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END) FROM "STUDENT_COURSES" WHERE "student_id" = OLD."student_id"), 0)
+                >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "id" = OLD."student_id";
+    END;
+    "#,
+    // 10: read access to users, which used to be open to anyone
+    r#"
+    INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES
+        ('admin', 'user.read'),
+        ('admin', 'roster.read'),
+        ('teacher', 'roster.read');
+    "#,
+    // 11: when the catalog tables last changed, for Last-Modified on catalog reads
+    r#"
+    CREATE TABLE IF NOT EXISTS "TABLE_CHANGES" (
+        "table_name" TEXT NOT NULL UNIQUE,
+        "changed_at" INTEGER NOT NULL,
+        PRIMARY KEY("table_name")
+    );
+
+    INSERT OR IGNORE INTO "TABLE_CHANGES" ("table_name", "changed_at") VALUES
+        ('COURSES', CAST(strftime('%s', 'now') AS INTEGER)),
+        ('DEPARTMENTS', CAST(strftime('%s', 'now') AS INTEGER)),
+        ('TEACHER_ACCOUNT', CAST(strftime('%s', 'now') AS INTEGER)),
+        ('USERS', CAST(strftime('%s', 'now') AS INTEGER));
+
+    CREATE TRIGGER IF NOT EXISTS "track_courses_insert"
+    AFTER INSERT ON "COURSES"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'COURSES';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_courses_update"
+    AFTER UPDATE ON "COURSES"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'COURSES';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_courses_delete"
+    AFTER DELETE ON "COURSES"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'COURSES';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_departments_insert"
+    AFTER INSERT ON "DEPARTMENTS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'DEPARTMENTS';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_departments_update"
+    AFTER UPDATE ON "DEPARTMENTS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'DEPARTMENTS';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_departments_delete"
+    AFTER DELETE ON "DEPARTMENTS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'DEPARTMENTS';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_teacher_account_insert"
+    AFTER INSERT ON "TEACHER_ACCOUNT"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'TEACHER_ACCOUNT';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_teacher_account_update"
+    AFTER UPDATE ON "TEACHER_ACCOUNT"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'TEACHER_ACCOUNT';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_teacher_account_delete"
+    AFTER DELETE ON "TEACHER_ACCOUNT"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'TEACHER_ACCOUNT';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_users_insert"
+    AFTER INSERT ON "USERS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'USERS';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_users_update"
+    AFTER UPDATE ON "USERS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'USERS';
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS "track_users_delete"
+    AFTER DELETE ON "USERS"
+    BEGIN
+        UPDATE "TABLE_CHANGES" SET "changed_at" = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE "table_name" = 'USERS';
+    END;
+    "#,
+    // 12: the grade triggers updated the account whose row id matched the user id, and
+    // summed credits from a column STUDENT_COURSES does not have; recompute every account
+    r#"
+    DROP TRIGGER IF EXISTS "update_student_cgpa_insert";
+    CREATE TRIGGER "update_student_cgpa_insert"
+    AFTER INSERT ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((
+                SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END)
+                FROM "STUDENT_COURSES"
+                JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+                WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+            ), 0) >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "student_id" = NEW."student_id";
+    END;
+
+    DROP TRIGGER IF EXISTS "update_student_cgpa_update";
+    CREATE TRIGGER "update_student_cgpa_update"
+    AFTER UPDATE ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((
+                SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END)
+                FROM "STUDENT_COURSES"
+                JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+                WHERE "STUDENT_COURSES"."student_id" = NEW."student_id"
+            ), 0) >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "student_id" = NEW."student_id";
+    END;
+
+    DROP TRIGGER IF EXISTS "update_student_cgpa_delete";
+    CREATE TRIGGER "update_student_cgpa_delete"
+    AFTER DELETE ON "STUDENT_COURSES"
+    FOR EACH ROW
+    BEGIN
+        UPDATE "STUDENT_ACCOUNT"
+        SET "cgpa" = COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = OLD."student_id"
+        ), 0.0),
+        "can_grad" = CASE
+            WHEN COALESCE((
+                SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END)
+                FROM "STUDENT_COURSES"
+                JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+                WHERE "STUDENT_COURSES"."student_id" = OLD."student_id"
+            ), 0) >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+            ELSE 0
+        END
+        WHERE "student_id" = OLD."student_id";
+    END;
+
+    UPDATE "STUDENT_ACCOUNT"
+    SET "cgpa" = COALESCE((
+        SELECT SUM(CASE WHEN "grade" >= 0 THEN "grade" * "cr_cost" ELSE 0 END) / NULLIF(SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END), 0)
+        FROM "STUDENT_COURSES"
+        JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+        WHERE "STUDENT_COURSES"."student_id" = "STUDENT_ACCOUNT"."student_id"
+    ), 0.0),
+    "can_grad" = CASE
+        WHEN COALESCE((
+            SELECT SUM(CASE WHEN "grade" >= 0 THEN "cr_cost" ELSE 0 END)
+            FROM "STUDENT_COURSES"
+            JOIN "COURSES" ON "STUDENT_COURSES"."course_id" = "COURSES"."id"
+            WHERE "STUDENT_COURSES"."student_id" = "STUDENT_ACCOUNT"."student_id"
+        ), 0) >= (SELECT "value" FROM "SETTINGS" WHERE "key" = 'graduation_credits') THEN 1
+        ELSE 0
+    END;
+    "#,
+];
+
+// Logs a statement and how long it ran for once dropped, i.e. after its rows have been read
+pub struct StatementTimer<'a> {
+    sql: &'a str,
+    started: Instant,
+}
+
+impl<'a> StatementTimer<'a> {
+    pub fn start(sql: &'a str) -> Self {
+        Self {
+            sql,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StatementTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        metrics::observe_statement(self.sql, elapsed);
+
+        tracing::debug!(
+            target: SQL_TARGET,
+            sql = %redact_sql(self.sql),
+            elapsed_ms = elapsed * 1000.0,
+            "statement"
+        );
+    }
+}
+
+impl Drop for DatabaseConnection {
+    fn drop(&mut self) {
+        metrics::connection_closed();
+    }
 }
@@ -1,6 +1,9 @@
 use std::fmt::{Display, Formatter};
+use rusqlite::types::Value;
 use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use super::db_driver::Join;
+use super::rbac::{Permission, Role};
 
 pub enum Action {
     Insert,
@@ -14,7 +17,17 @@ pub enum Table {
     TeacherAccount,
     Courses,
     StudentCourses,
-    Departments
+    Departments,
+    LoginAttempts,
+    LoginThrottle,
+    Sessions,
+    UserTotp,
+    RecoveryCodes,
+    RolePermissions,
+    AdminInvitations,
+    AuditLog,
+    ApiKeys,
+    OidcLogins
 }
 
 impl Display for Table {
@@ -25,7 +38,17 @@ impl Display for Table {
             Table::TeacherAccount => write!(f, r#""TEACHER_ACCOUNTS""#),
             Table::Courses => write!(f, r#""COURSES""#),
             Table::StudentCourses => write!(f, r#""STUDENT_COURSES""#),
-            Table::Departments => write!(f, r#""DEPARTMENTS""#)
+            Table::Departments => write!(f, r#""DEPARTMENTS""#),
+            Table::LoginAttempts => write!(f, r#""LOGIN_ATTEMPTS""#),
+            Table::LoginThrottle => write!(f, r#""LOGIN_THROTTLE""#),
+            Table::Sessions => write!(f, r#""SESSIONS""#),
+            Table::UserTotp => write!(f, r#""USER_TOTP""#),
+            Table::RecoveryCodes => write!(f, r#""RECOVERY_CODES""#),
+            Table::RolePermissions => write!(f, r#""ROLE_PERMISSIONS""#),
+            Table::AdminInvitations => write!(f, r#""ADMIN_INVITATIONS""#),
+            Table::AuditLog => write!(f, r#""AUDIT_LOG""#),
+            Table::ApiKeys => write!(f, r#""API_KEYS""#),
+            Table::OidcLogins => write!(f, r#""OIDC_LOGINS""#)
         }
     }
 }
@@ -37,60 +60,86 @@ impl Table {
 }
 
 pub trait ToSQL {
-    fn to_sql(&self, a: Action) -> String;
+    fn to_sql(&self, a: Action) -> Statement;
+}
+
+// SQL with ?N placeholders and the values bound to them. Values never become part of
+// the SQL text, so nothing a client sends can change what a statement does.
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+impl Statement {
+    pub fn new(sql: &str, params: Vec<Value>) -> Self {
+        Self {
+            sql: sql.to_owned(),
+            params,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i32,
     pub username: String,
+    // the Argon2 hash; responses use dto::UserView, and this is never serialized regardless
+    #[serde(skip_serializing)]
     pub password: String,
     pub email: String,
     pub phone: String,
     pub verified: bool,
     pub suspended: bool,
     pub forcenewpw: bool,
-    pub role: String,
+    pub role: Role,
 }
 
 impl ToSQL for User {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
+            Action::Insert => Statement::new(
                 "INSERT INTO USERS (username, password, email, phone, 
-                    verified, suspended, forcenewpw, role) VALUES ('{}', '{}', '{}', '{}', {}, {}, {}, '{}')",
-                self.username, self.password, self.email, self.phone, 
-                    self.verified, self.suspended, self.forcenewpw, self.role
+                    verified, suspended, forcenewpw, role) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                vec![
+                    self.username.clone().into(), self.password.clone().into(), self.email.clone().into(), self.phone.clone().into(),
+                    self.verified.into(), self.suspended.into(), self.forcenewpw.into(), self.role.to_string().into(),
+                ],
             ),
 
             Action::Update => {
-                if self.password == "" {
-                    return format!(
-                        "UPDATE USERS SET username = '{}', email = '{}', phone = '{}', 
-                            verified = {}, suspended = {}, forcenewpw = {}, role = '{}' 
-                            WHERE id = {}",
-                        self.username, self.email, self.phone,
-                        self.verified, self.suspended, self.forcenewpw, self.role, self.id
+                if self.password.is_empty() {
+                    return Statement::new(
+                        "UPDATE USERS SET username = ?1, email = ?2, phone = ?3, 
+                            verified = ?4, suspended = ?5, forcenewpw = ?6, role = ?7 
+                            WHERE id = ?8",
+                        vec![
+                            self.username.clone().into(), self.email.clone().into(), self.phone.clone().into(),
+                            self.verified.into(), self.suspended.into(), self.forcenewpw.into(), self.role.to_string().into(),
+                            self.id.into(),
+                        ],
                     )
                 }
 
-                format!(
-                    "UPDATE USERS SET username = '{}', password = '{}', email = '{}', phone = '{}', 
-                        verified = {}, suspended = {}, forcenewpw = {}, role = '{}' 
-                        WHERE id = {}",
-                    self.username, self.password, self.email, self.phone, 
-                        self.verified, self.suspended, self.forcenewpw, self.role, self.id
+                Statement::new(
+                    "UPDATE USERS SET username = ?1, password = ?2, email = ?3, phone = ?4, 
+                        verified = ?5, suspended = ?6, forcenewpw = ?7, role = ?8 
+                        WHERE id = ?9",
+                    vec![
+                        self.username.clone().into(), self.password.clone().into(), self.email.clone().into(), self.phone.clone().into(),
+                        self.verified.into(), self.suspended.into(), self.forcenewpw.into(), self.role.to_string().into(),
+                        self.id.into(),
+                    ],
                 )
             },
 
-            Action::Delete => format!(
-                "DELETE FROM USERS WHERE id = {}", self.id
+            Action::Delete => Statement::new(
+                "DELETE FROM USERS WHERE id = ?1", vec![self.id.into()]
             )
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct StudentAccount {
     pub id: i32,
     pub student_id: i32,
@@ -104,30 +153,35 @@ pub struct StudentAccount {
 }
 
 impl ToSQL for StudentAccount {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
+            Action::Insert => Statement::new(
                 "INSERT INTO STUDENT_ACCOUNT (student_id, advisor_id, discipline, enrollment, cgpa, can_grad, cur_credit, cum_credit) 
-                VALUES ({}, {}, '{}', '{}', {}, {}, {}, {})",
-                self.student_id, self.advisor_id, self.discipline, self.enrollment, self.cgpa, self.can_grad, self.cur_credit, self.cum_credit
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                vec![
+                    self.student_id.into(), self.advisor_id.into(), self.discipline.clone().into(), self.enrollment.clone().into(),
+                    self.cgpa.into(), self.can_grad.into(), self.cur_credit.into(), self.cum_credit.into(),
+                ],
             ),
 
-            Action::Update => format!(
-                "UPDATE STUDENT_ACCOUNT SET student_id = {}, advisor_id = {}, discipline = '{}', 
-                enrollment = '{}', cgpa = {}, can_grad = {}, cur_credit = {}, cum_credit = {} 
-                WHERE id = {}",
-                self.student_id, self.advisor_id, self.discipline, self.enrollment, self.cgpa, 
-                self.can_grad, self.cur_credit, self.cum_credit, self.id
+            Action::Update => Statement::new(
+                "UPDATE STUDENT_ACCOUNT SET student_id = ?1, advisor_id = ?2, discipline = ?3, 
+                enrollment = ?4, cgpa = ?5, can_grad = ?6, cur_credit = ?7, cum_credit = ?8 
+                WHERE id = ?9",
+                vec![
+                    self.student_id.into(), self.advisor_id.into(), self.discipline.clone().into(), self.enrollment.clone().into(),
+                    self.cgpa.into(), self.can_grad.into(), self.cur_credit.into(), self.cum_credit.into(), self.id.into(),
+                ],
             ),
 
-            Action::Delete => format!(
-                "DELETE FROM STUDENT_ACCOUNT WHERE id = {}", self.id
+            Action::Delete => Statement::new(
+                "DELETE FROM STUDENT_ACCOUNT WHERE id = ?1", vec![self.id.into()]
             )
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct TeacherAccount {
     pub id: i32,
     pub teacher_id: i32,
@@ -135,26 +189,26 @@ pub struct TeacherAccount {
 }
 
 impl ToSQL for TeacherAccount {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
-                r#"INSERT INTO "TEACHER_ACCOUNT" ("teacher_id", "dept_id") VALUES ({}, {})"#,
-                self.teacher_id, self.dept_id
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "TEACHER_ACCOUNT" ("teacher_id", "dept_id") VALUES (?1, ?2)"#,
+                vec![self.teacher_id.into(), self.dept_id.into()],
             ),
 
-            Action::Update => format!(
-                r#"UPDATE "TEACHER_ACCOUNT" SET "teacher_id" = {}, "dept_id" = {} WHERE "id" = {}"#,
-                self.teacher_id, self.dept_id, self.id
+            Action::Update => Statement::new(
+                r#"UPDATE "TEACHER_ACCOUNT" SET "teacher_id" = ?1, "dept_id" = ?2 WHERE "id" = ?3"#,
+                vec![self.teacher_id.into(), self.dept_id.into(), self.id.into()],
             ),
 
-            Action::Delete => format!(
-                r#"DELETE FROM "TEACHER_ACCOUNT" WHERE "id" = {}"#, self.id
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "TEACHER_ACCOUNT" WHERE "id" = ?1"#, vec![self.id.into()]
             )
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Courses {
     pub id: i32,
     pub teacher_id: i32,
@@ -166,28 +220,33 @@ pub struct Courses {
 }
 
 impl ToSQL for Courses {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
+            Action::Insert => Statement::new(
                 r#"INSERT INTO "COURSES" ("teacher_id", "course", "course_nr", "description", "cr_cost", "timeslots") 
-                VALUES ({}, '{}', '{}', '{}', {}, '{}')"#,
-                self.teacher_id, self.course, self.course_nr, self.description, self.cr_cost, self.timeslots
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+                vec![
+                    self.teacher_id.into(), self.course.clone().into(), self.course_nr.clone().into(),
+                    self.description.clone().into(), self.cr_cost.into(), self.timeslots.clone().into(),
+                ],
             ),
 
-            Action::Update => format!(
-                r#"UPDATE COURSES SET teacher_id = {}, course = '{}', course_nr = '{}', description = '{}', cr_cost = {}, timeslots = '{}' WHERE id = {}"#,
-                self.teacher_id, self.course, self.course_nr, 
-                self.description, self.cr_cost, self.timeslots, self.id
+            Action::Update => Statement::new(
+                r#"UPDATE COURSES SET teacher_id = ?1, course = ?2, course_nr = ?3, description = ?4, cr_cost = ?5, timeslots = ?6 WHERE id = ?7"#,
+                vec![
+                    self.teacher_id.into(), self.course.clone().into(), self.course_nr.clone().into(),
+                    self.description.clone().into(), self.cr_cost.into(), self.timeslots.clone().into(), self.id.into(),
+                ],
             ),
             
-            Action::Delete => format!(
-                r#"DELETE FROM COURSES WHERE "id" = {}"#, self.id
+            Action::Delete => Statement::new(
+                r#"DELETE FROM COURSES WHERE "id" = ?1"#, vec![self.id.into()]
             )
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, ToSchema)]
 pub struct StudentCourse {
     pub student_id: i32,
     pub course_id: i32,
@@ -196,48 +255,412 @@ pub struct StudentCourse {
 }
 
 impl ToSQL for StudentCourse {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
+            Action::Insert => Statement::new(
                 "INSERT INTO student_courses (student_id, course_id, grade, semester) 
-                VALUES ({}, {}, {}, '{}')",
-                self.student_id, self.course_id, self.grade, self.semester
+                VALUES (?1, ?2, ?3, ?4)",
+                vec![self.student_id.into(), self.course_id.into(), self.grade.into(), self.semester.clone().into()],
             ),
 
-            Action::Update => format!(
-                "UPDATE student_courses SET student_id = {}, course_id = {}, grade = {}, semester = '{}' 
-                WHERE student_id = {} AND course_id = {}",
-                self.student_id, self.course_id, self.grade, self.semester, self.student_id, self.course_id
+            Action::Update => Statement::new(
+                "UPDATE student_courses SET grade = ?3, semester = ?4 
+                WHERE student_id = ?1 AND course_id = ?2",
+                vec![self.student_id.into(), self.course_id.into(), self.grade.into(), self.semester.clone().into()],
             ),
 
-            Action::Delete => format!(
-                "DELETE FROM student_courses WHERE student_id = {} AND course_id = {}", self.student_id, self.course_id
+            Action::Delete => Statement::new(
+                "DELETE FROM student_courses WHERE student_id = ?1 AND course_id = ?2",
+                vec![self.student_id.into(), self.course_id.into()],
             )
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Departments {
     pub id: i32,
     pub name: String,
 }
 
 impl ToSQL for Departments {
-    fn to_sql(&self, a: Action) -> String {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                "INSERT INTO departments (name) VALUES (?1)",
+                vec![self.name.clone().into()],
+            ),
+
+            Action::Update => Statement::new(
+                "UPDATE departments SET name = ?1 WHERE id = ?2",
+                vec![self.name.clone().into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                "DELETE FROM departments WHERE id = ?1", vec![self.id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LoginAttempt {
+    pub id: i32,
+    pub email: String,
+    pub ip: String,
+    pub success: bool,
+    pub reason: String,
+    pub created_at: i64,
+}
+
+impl ToSQL for LoginAttempt {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "LOGIN_ATTEMPTS" ("email", "ip", "success", "reason", "created_at") 
+                VALUES (?1, ?2, ?3, ?4, ?5)"#,
+                vec![
+                    self.email.clone().into(), self.ip.clone().into(), self.success.into(),
+                    self.reason.clone().into(), self.created_at.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "LOGIN_ATTEMPTS" SET "email" = ?1, "ip" = ?2, "success" = ?3, "reason" = ?4, "created_at" = ?5 WHERE "id" = ?6"#,
+                vec![
+                    self.email.clone().into(), self.ip.clone().into(), self.success.into(),
+                    self.reason.clone().into(), self.created_at.into(), self.id.into(),
+                ],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "LOGIN_ATTEMPTS" WHERE "id" = ?1"#, vec![self.id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LoginThrottle {
+    pub id: i32,
+    pub scope: String,
+    pub key: String,
+    pub failures: i32,
+    pub last_failure: i64,
+    pub locked_until: i64,
+}
+
+impl ToSQL for LoginThrottle {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            // a throttle row is unique per (scope, key), so inserting doubles as an upsert
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "LOGIN_THROTTLE" ("scope", "key", "failures", "last_failure", "locked_until") 
+                VALUES (?1, ?2, ?3, ?4, ?5) 
+                ON CONFLICT ("scope", "key") DO UPDATE SET "failures" = excluded."failures", 
+                "last_failure" = excluded."last_failure", "locked_until" = excluded."locked_until""#,
+                vec![
+                    self.scope.clone().into(), self.key.clone().into(), self.failures.into(),
+                    self.last_failure.into(), self.locked_until.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "LOGIN_THROTTLE" SET "failures" = ?3, "last_failure" = ?4, "locked_until" = ?5 
+                WHERE "scope" = ?1 AND "key" = ?2"#,
+                vec![
+                    self.scope.clone().into(), self.key.clone().into(), self.failures.into(),
+                    self.last_failure.into(), self.locked_until.into(),
+                ],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "LOGIN_THROTTLE" WHERE "scope" = ?1 AND "key" = ?2"#,
+                vec![self.scope.clone().into(), self.key.clone().into()],
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub expires_at: i64,
+    pub ip: String,
+    pub user_agent: String,
+    pub pending_mfa: bool,
+    // the admin viewing the site as `user_id`, or 0
+    pub impersonator_id: i32,
+}
+
+impl ToSQL for Session {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "SESSIONS" ("token_hash", "user_id", "created_at", "last_seen", "expires_at", "ip", "user_agent", "pending_mfa", "impersonator_id") 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+                vec![
+                    self.token_hash.clone().into(), self.user_id.into(), self.created_at.into(), self.last_seen.into(),
+                    self.expires_at.into(), self.ip.clone().into(), self.user_agent.clone().into(),
+                    self.pending_mfa.into(), self.impersonator_id.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "SESSIONS" SET "last_seen" = ?1, "expires_at" = ?2, "pending_mfa" = ?3 WHERE "id" = ?4"#,
+                vec![self.last_seen.into(), self.expires_at.into(), self.pending_mfa.into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "SESSIONS" WHERE "id" = ?1"#, vec![self.id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserTotp {
+    pub id: i32,
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: i64,
+    pub created_at: i64,
+}
+
+impl ToSQL for UserTotp {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            // one secret per user, so a new enrollment replaces any previous one
+            Action::Insert => Statement::new(
+                r#"INSERT OR REPLACE INTO "USER_TOTP" ("user_id", "secret", "enabled", "last_used_step", "created_at") 
+                VALUES (?1, ?2, ?3, ?4, ?5)"#,
+                vec![
+                    self.user_id.into(), self.secret.clone().into(), self.enabled.into(),
+                    self.last_used_step.into(), self.created_at.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "USER_TOTP" SET "secret" = ?1, "enabled" = ?2, "last_used_step" = ?3 WHERE "user_id" = ?4"#,
+                vec![self.secret.clone().into(), self.enabled.into(), self.last_used_step.into(), self.user_id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "USER_TOTP" WHERE "user_id" = ?1"#, vec![self.user_id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used: bool,
+}
+
+impl ToSQL for RecoveryCode {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "RECOVERY_CODES" ("user_id", "code_hash", "used") VALUES (?1, ?2, ?3)"#,
+                vec![self.user_id.into(), self.code_hash.clone().into(), self.used.into()],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "RECOVERY_CODES" SET "used" = ?1 WHERE "id" = ?2"#,
+                vec![self.used.into(), self.id.into()],
+            ),
+
+            // removing by user clears the whole set when codes are regenerated
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "RECOVERY_CODES" WHERE "user_id" = ?1"#, vec![self.user_id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RolePermission {
+    pub id: i32,
+    pub role: Role,
+    pub permission: Permission,
+}
+
+impl ToSQL for RolePermission {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES (?1, ?2)"#,
+                vec![self.role.to_string().into(), self.permission.to_string().into()],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "ROLE_PERMISSIONS" SET "role" = ?1, "permission" = ?2 WHERE "id" = ?3"#,
+                vec![self.role.to_string().into(), self.permission.to_string().into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "ROLE_PERMISSIONS" WHERE "role" = ?1 AND "permission" = ?2"#,
+                vec![self.role.to_string().into(), self.permission.to_string().into()],
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AdminInvitation {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub email: String,
+    pub created_by: i32,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub used_at: i64,
+    pub used_by: i32,
+}
+
+impl ToSQL for AdminInvitation {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "ADMIN_INVITATIONS" ("token_hash", "email", "created_by", "created_at", "expires_at", "used_at", "used_by") 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+                vec![
+                    self.token_hash.clone().into(), self.email.clone().into(), self.created_by.into(), self.created_at.into(),
+                    self.expires_at.into(), self.used_at.into(), self.used_by.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "ADMIN_INVITATIONS" SET "expires_at" = ?1, "used_at" = ?2, "used_by" = ?3 WHERE "id" = ?4"#,
+                vec![self.expires_at.into(), self.used_at.into(), self.used_by.into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "ADMIN_INVITATIONS" WHERE "id" = ?1"#, vec![self.id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor_id: i32,
+    pub action: String,
+    pub target: String,
+    pub details: String,
+    pub created_at: i64,
+    // set when an admin acted while impersonating `actor_id`
+    pub impersonator_id: i32,
+}
+
+impl ToSQL for AuditEntry {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "AUDIT_LOG" ("actor_id", "action", "target", "details", "created_at", "impersonator_id") 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+                vec![
+                    self.actor_id.into(), self.action.clone().into(), self.target.clone().into(),
+                    self.details.clone().into(), self.created_at.into(), self.impersonator_id.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "AUDIT_LOG" SET "details" = ?1 WHERE "id" = ?2"#,
+                vec![self.details.clone().into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "AUDIT_LOG" WHERE "id" = ?1"#, vec![self.id.into()]
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    // first characters of the key, kept so admins can tell keys apart
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    // comma separated permissions
+    pub scopes: String,
+    pub created_by: i32,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub last_used: i64,
+    pub revoked: bool,
+}
+
+impl ToSQL for ApiKey {
+    fn to_sql(&self, a: Action) -> Statement {
+        match a {
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "API_KEYS" ("user_id", "name", "prefix", "key_hash", "scopes", "created_by", "created_at", "expires_at", "last_used", "revoked") 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
+                vec![
+                    self.user_id.into(), self.name.clone().into(), self.prefix.clone().into(), self.key_hash.clone().into(),
+                    self.scopes.clone().into(), self.created_by.into(), self.created_at.into(), self.expires_at.into(),
+                    self.last_used.into(), self.revoked.into(),
+                ],
+            ),
+
+            Action::Update => Statement::new(
+                r#"UPDATE "API_KEYS" SET "name" = ?1, "expires_at" = ?2, "last_used" = ?3, "revoked" = ?4 WHERE "id" = ?5"#,
+                vec![self.name.clone().into(), self.expires_at.into(), self.last_used.into(), self.revoked.into(), self.id.into()],
+            ),
+
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "API_KEYS" WHERE "id" = ?1"#, vec![self.id.into()]
+            )
+        }
+    }
+}
+
+// An authorization request that has been sent to the identity provider but not completed yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcLogin {
+    pub id: i32,
+    pub state_hash: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+impl ToSQL for OidcLogin {
+    fn to_sql(&self, a: Action) -> Statement {
         match a {
-            Action::Insert => format!(
-                "INSERT INTO departments (name) VALUES ('{}')",
-                self.name
+            Action::Insert => Statement::new(
+                r#"INSERT INTO "OIDC_LOGINS" ("state_hash", "nonce", "code_verifier", "created_at", "expires_at") 
+                VALUES (?1, ?2, ?3, ?4, ?5)"#,
+                vec![
+                    self.state_hash.clone().into(), self.nonce.clone().into(), self.code_verifier.clone().into(),
+                    self.created_at.into(), self.expires_at.into(),
+                ],
             ),
 
-            Action::Update => format!(
-                "UPDATE departments SET name = '{}' WHERE id = {}",
-                self.name, self.id
+            Action::Update => Statement::new(
+                r#"UPDATE "OIDC_LOGINS" SET "expires_at" = ?1 WHERE "id" = ?2"#,
+                vec![self.expires_at.into(), self.id.into()],
             ),
 
-            Action::Delete => format!(
-                "DELETE FROM departments WHERE id = {}", self.id
+            Action::Delete => Statement::new(
+                r#"DELETE FROM "OIDC_LOGINS" WHERE "id" = ?1"#, vec![self.id.into()]
             )
         }
     }
@@ -0,0 +1,105 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use regex::Regex;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::Span;
+use tracing_actix_web::{RequestId, RootSpanBuilder};
+use tracing_subscriber::EnvFilter;
+
+use super::config::{LogFormat, LoggingConfig};
+use super::metrics;
+
+pub const SQL_TARGET: &str = "ums::sql";
+
+static STRING_LITERAL: OnceLock<Regex> = OnceLock::new();
+
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.format {
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+        LogFormat::Text => subscriber.init(),
+    }
+}
+
+// Statements embed their values, including password and token hashes, so only their
+// shape is logged: every string literal becomes '?'
+pub fn redact_sql(sql: &str) -> String {
+    STRING_LITERAL
+        .get_or_init(|| Regex::new(r"'(?:[^']|'')*'").unwrap())
+        .replace_all(sql, "'?'")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Root span of every request. Headers and query strings are left out on purpose:
+// they carry passwords, session tokens, API keys and SSO codes.
+// user_id and impersonator_id are filled in by ServerConnection once it knows them.
+pub struct RequestSpan;
+
+struct RequestStart(Instant);
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        request.extensions_mut().insert(RequestStart(Instant::now()));
+
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        let route = request.match_pattern().unwrap_or_default();
+
+        tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %request.method(),
+            path = %request.path(),
+            route = %route,
+            user_id = tracing::field::Empty,
+            impersonator_id = tracing::field::Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        let response = match outcome {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::error!(parent: &span, error = %e, "request failed");
+                return;
+            }
+        };
+
+        let request = response.request();
+        let status = response.status().as_u16();
+        let latency = request
+            .extensions()
+            .get::<RequestStart>()
+            .map(|start| start.0.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        let latency_ms = latency * 1000.0;
+
+        metrics::observe_request(
+            request.method().as_str(),
+            request.match_pattern().as_deref().unwrap_or("unmatched"),
+            status,
+            latency,
+        );
+
+        if response.status().is_server_error() {
+            tracing::error!(parent: &span, status, latency_ms, "request completed");
+        } else if response.status().is_client_error() {
+            tracing::warn!(parent: &span, status, latency_ms, "request completed");
+        } else {
+            tracing::info!(parent: &span, status, latency_ms, "request completed");
+        }
+    }
+}
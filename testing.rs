@@ -0,0 +1,209 @@
+use actix_web::{get, post, web, App, HttpResponse, HttpServer};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::config::Config;
+use super::db_driver::DbDriver;
+use super::filter::{Filter, UsersFilter};
+use super::oidc::OidcConfig;
+use super::rbac::{Permission, Role};
+use super::server_connection_impl::ServerConnection;
+use super::table_models::User;
+use super::tokens;
+
+// meets the default password policy
+pub const PASSWORD: &str = "Corr3ct&Horse!Battery";
+
+static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+
+// A throwaway database for one test, removed again when dropped
+pub struct TestDb {
+    pub config: Arc<Config>,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(mut config: Config) -> Self {
+        let n = NEXT_DB.fetch_add(1, Ordering::SeqCst);
+        config.database.path = std::env::temp_dir()
+            .join(format!("ums-test-{}-{}.db", std::process::id(), n))
+            .to_string_lossy()
+            .into_owned();
+
+        // the smallest Argon2 parameters, so hashing does not dominate test time
+        config.password_hashing.memory_kib = 8;
+        config.password_hashing.iterations = 1;
+        config.password_hashing.parallelism = 1;
+
+        DbDriver::prepare(&config).expect("test database is created");
+        Self { config: Arc::new(config) }
+    }
+
+    pub fn connect(&self) -> ServerConnection {
+        ServerConnection::new(self.config.clone()).expect("test database opens")
+    }
+
+    // Registers an account with PASSWORD and returns it as stored
+    pub fn user(&self, role: Role, email: &str) -> User {
+        let mut conn = self.connect();
+        conn.register_user(User {
+            id: 0,
+            username: email.split('@').next().unwrap_or(email).to_owned(),
+            password: PASSWORD.to_owned(),
+            email: email.to_owned(),
+            phone: String::new(),
+            verified: true,
+            suspended: false,
+            forcenewpw: false,
+            role,
+        })
+        .expect("test user registers");
+
+        conn.get_users_by_filters(vec![Filter::Users(UsersFilter::Email(email.to_owned()))])
+            .expect("test user is found")
+            .into_iter()
+            .next()
+            .expect("test user is found")
+    }
+
+    // Straight to the database, for states no endpoint can put an account in by itself
+    pub fn suspend(&self, user: &User) {
+        rusqlite::Connection::open(&self.config.database.path)
+            .and_then(|c| c.execute("UPDATE USERS SET suspended = 1 WHERE id = ?1", [user.id]))
+            .expect("test user is suspended");
+    }
+
+    pub fn grant(&self, role: Role, permission: Permission) {
+        rusqlite::Connection::open(&self.config.database.path)
+            .and_then(|c| {
+                c.execute(
+                    r#"INSERT OR IGNORE INTO "ROLE_PERMISSIONS" ("role", "permission") VALUES (?1, ?2)"#,
+                    [role.to_string(), permission.as_str().to_owned()],
+                )
+            })
+            .expect("permission is granted");
+    }
+
+    // A connection that has passed the password check as `user`
+    pub fn signed_in(&self, user: &User) -> ServerConnection {
+        let mut conn = self.connect();
+        conn.login(user.email.clone(), PASSWORD.to_owned())
+            .expect("test user signs in");
+        conn
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.config.database.path, suffix));
+        }
+    }
+}
+
+pub const MOCK_IDP_KEY_ID: &str = "mock-key";
+
+// An OpenID provider on a local port: it publishes an ES256 key, and its token endpoint
+// answers with the authorization code itself as the ID token, so tests pick the token
+pub struct MockIdp {
+    pub issuer: String,
+    key: rcgen::KeyPair,
+}
+
+impl MockIdp {
+    pub async fn start() -> Self {
+        let key = rcgen::KeyPair::generate().expect("key pair generates");
+        let point = key.public_key_raw();
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": tokens::base64_url(&point[1..33]),
+            "y": tokens::base64_url(&point[33..65]),
+            "kid": MOCK_IDP_KEY_ID,
+            "alg": "ES256",
+            "use": "sig",
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("port is free");
+        let issuer = format!("http://{}", listener.local_addr().expect("bound"));
+
+        let state = web::Data::new((issuer.clone(), jwk));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .service(mock_discovery)
+                .service(mock_jwks)
+                .service(mock_token)
+        })
+        .workers(1)
+        .listen(listener)
+        .expect("mock identity provider listens")
+        .run();
+        actix_web::rt::spawn(server);
+
+        Self { issuer, key }
+    }
+
+    pub fn config(&self) -> OidcConfig {
+        OidcConfig {
+            issuer: self.issuer.clone(),
+            client_id: String::from("ums"),
+            client_secret: String::from("secret"),
+            redirect_uri: String::from("http://localhost/api/v1/login/sso/callback"),
+            frontend_url: String::from("http://localhost/signed-in"),
+        }
+    }
+
+    // Claims a real provider would issue for `email`
+    pub fn claims(&self, email: &str, nonce: &str) -> Value {
+        let now = chrono::Utc::now().timestamp();
+        json!({
+            "iss": self.issuer,
+            "aud": "ums",
+            "sub": email,
+            "iat": now,
+            "exp": now + 300,
+            "email": email,
+            "email_verified": true,
+            "name": "Mock User",
+            "nonce": nonce,
+        })
+    }
+
+    // Signed with the published key
+    pub fn id_token(&self, claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(MOCK_IDP_KEY_ID.to_owned());
+        let key = EncodingKey::from_ec_pem(self.key.serialize_pem().as_bytes()).expect("valid key");
+        jsonwebtoken::encode(&header, claims, &key).expect("token encodes")
+    }
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn mock_discovery(state: web::Data<(String, Value)>) -> HttpResponse {
+    let issuer = &state.0;
+    HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    }))
+}
+
+#[get("/jwks")]
+async fn mock_jwks(state: web::Data<(String, Value)>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({"keys": [state.1]}))
+}
+
+#[post("/token")]
+async fn mock_token(form: web::Form<std::collections::HashMap<String, String>>) -> HttpResponse {
+    match form.get("code") {
+        Some(code) => HttpResponse::Ok().json(json!({"id_token": code, "token_type": "Bearer"})),
+        None => HttpResponse::BadRequest().finish(),
+    }
+}
@@ -0,0 +1,215 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::anyhow;
+use anyhow::Result;
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::config::{Config, TlsConfig};
+
+// Hands out whichever certificate was loaded last, so renewals apply without a restart
+#[derive(Debug)]
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|c| c.clone())
+    }
+}
+
+pub fn server_config(tls: &TlsConfig) -> Result<rustls::ServerConfig> {
+    let resolver = Arc::new(ReloadingCert {
+        current: RwLock::new(Arc::new(load(tls)?)),
+    });
+    watch(tls.clone(), resolver.clone());
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+// Default service of the plain HTTP listener
+pub async fn redirect_to_https(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    // Config::validate insists on it; the Host header would let anyone pick the target
+    let Some(host) = &config.tls.public_host else {
+        return HttpResponse::MisdirectedRequest().finish();
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let location = match config.server.port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+    HttpResponse::PermanentRedirect()
+        .insert_header(("Location", location))
+        .finish()
+}
+
+fn load(tls: &TlsConfig) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in '{}'.", tls.cert_path));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&tls.key_path)?))?
+        .ok_or_else(|| anyhow!("No private key found in '{}'.", tls.key_path))?;
+    let key = any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key in '{}': {}", tls.key_path, e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(tls: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&tls.cert_path).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(&tls.key_path).and_then(|m| m.modified()).ok()?;
+
+    Some((cert, key))
+}
+
+// Polls the files rather than relying on platform file events, which miss the
+// symlink swaps certificate managers tend to do
+fn watch(tls: TlsConfig, resolver: Arc<ReloadingCert>) {
+    std::thread::spawn(move || {
+        let mut last = modified(&tls);
+
+        loop {
+            std::thread::sleep(Duration::from_secs(tls.reload_interval_secs));
+
+            let current = modified(&tls);
+            if current == last {
+                continue;
+            }
+
+            // a half-written pair fails to load; `last` stays put so it is retried next round
+            match load(&tls) {
+                Ok(key) => {
+                    if let Ok(mut c) = resolver.current.write() {
+                        *c = Arc::new(key);
+                    }
+                    last = current;
+                    tracing::info!(cert_path = %tls.cert_path, "reloaded TLS certificate");
+                }
+                Err(e) => tracing::warn!(error = %e, "keeping the previous TLS certificate"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    // A fresh self-signed pair on disk, removed again when dropped
+    struct SelfSigned {
+        tls: TlsConfig,
+    }
+
+    impl SelfSigned {
+        fn new(name: &str) -> Self {
+            let pair = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+            let dir = std::env::temp_dir();
+            let prefix = format!("ums-tls-{}-{}", std::process::id(), name);
+
+            let tls = TlsConfig {
+                enabled: true,
+                cert_path: dir.join(format!("{}-cert.pem", prefix)).to_string_lossy().into_owned(),
+                key_path: dir.join(format!("{}-key.pem", prefix)).to_string_lossy().into_owned(),
+                ..TlsConfig::default()
+            };
+            std::fs::write(&tls.cert_path, pair.cert.pem()).unwrap();
+            std::fs::write(&tls.key_path, pair.key_pair.serialize_pem()).unwrap();
+
+            Self { tls }
+        }
+    }
+
+    impl Drop for SelfSigned {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.tls.cert_path);
+            let _ = std::fs::remove_file(&self.tls.key_path);
+        }
+    }
+
+    #[test]
+    fn loads_a_self_signed_pair() {
+        let files = SelfSigned::new("load");
+
+        let key = load(&files.tls).unwrap();
+        assert_eq!(key.cert.len(), 1);
+
+        let config = server_config(&files.tls).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_files_without_a_certificate_or_key() {
+        let files = SelfSigned::new("swapped");
+        let swapped = TlsConfig {
+            cert_path: files.tls.key_path.clone(),
+            key_path: files.tls.cert_path.clone(),
+            ..files.tls.clone()
+        };
+
+        assert!(load(&swapped).is_err());
+    }
+
+    #[test]
+    fn redirect_listener_needs_a_public_host() {
+        let files = SelfSigned::new("validate");
+        let mut config = Config {
+            tls: files.tls.clone(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.tls.redirect_http_port = Some(8000);
+        assert!(config.validate().is_err());
+
+        config.tls.public_host = Some(String::from("ums.aubg.edu"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_the_configured_host_whatever_the_request_says() {
+        let mut config = Config::default();
+        config.tls.public_host = Some(String::from("ums.aubg.edu"));
+        config.server.port = 443;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .default_service(web::to(redirect_to_https)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/api/v1/courses?page=2")
+            .insert_header(("Host", "attacker.example"))
+            .to_request();
+        let res = call_service(&app, req).await;
+
+        assert_eq!(res.status(), actix_web::http::StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers().get("Location").unwrap(),
+            "https://ums.aubg.edu/api/v1/courses?page=2"
+        );
+    }
+}